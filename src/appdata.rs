@@ -1,15 +1,31 @@
 use std::path::PathBuf;
 use std::io::Write;
-use std::collections::HashMap;
 use serde::{Deserialize, Serialize};
 use rand::Rng;
-use mysql::prelude::Queryable;
-use mysql::{Row, Params, params};
+use crate::reaper::RetentionConfig;
+use crate::crypto::EncryptionConfig;
+use crate::email::NormalizationConfig;
+use crate::username::UsernamePolicy;
+use crate::profile::ProfileConfig;
+use crate::mail::MailConfig;
+use crate::scim::ScimConfig;
+use crate::lockout::LockoutConfig;
+use crate::users::RegistrationConfig;
+use crate::breach::BreachConfig;
+use crate::password_policy::PasswordPolicy;
+use crate::challenge::ChallengeConfig;
+use crate::devices::DeviceAlertConfig;
+use crate::sessions::{SessionBindingConfig, StepUpConfig};
+use crate::ipfilter::{IpFilter, IpFilterConfig};
+use crate::ratelimit::{RateLimitConfig, RateLimiter};
+use crate::migrations;
 
 #[derive(Clone)]
 pub struct AppData {
-    pub database:       Database,
-    pub environment:    Environment
+    pub database:           Database,
+    pub environment:        Environment,
+    pub rate_limiter:       RateLimiter,
+    pub ip_filter:          IpFilter
 }
 
 #[derive(Deserialize, Serialize, Clone)]
@@ -19,7 +35,58 @@ pub struct Environment {
     pub mysql_username: String,
     pub mysql_password: String,
 
-    pub password_pepper:  String
+    pub password_pepper:  String,
+
+    #[serde(default)]
+    pub email_encryption:   EncryptionConfig,
+
+    #[serde(default)]
+    pub email_normalization: NormalizationConfig,
+
+    #[serde(default)]
+    pub username_policy:    UsernamePolicy,
+
+    #[serde(default)]
+    pub profile:            ProfileConfig,
+
+    #[serde(default)]
+    pub mail:               MailConfig,
+
+    #[serde(default)]
+    pub retention:      RetentionConfig,
+
+    #[serde(default)]
+    pub scim:               ScimConfig,
+
+    #[serde(default)]
+    pub rate_limit:         RateLimitConfig,
+
+    #[serde(default)]
+    pub lockout:            LockoutConfig,
+
+    #[serde(default)]
+    pub registration:       RegistrationConfig,
+
+    #[serde(default)]
+    pub breach:             BreachConfig,
+
+    #[serde(default)]
+    pub password_policy:    PasswordPolicy,
+
+    #[serde(default)]
+    pub challenge:          ChallengeConfig,
+
+    #[serde(default)]
+    pub device_alerts:      DeviceAlertConfig,
+
+    #[serde(default)]
+    pub step_up:            StepUpConfig,
+
+    #[serde(default)]
+    pub session_binding:    SessionBindingConfig,
+
+    #[serde(default)]
+    pub ip_filter:          IpFilterConfig
 }
 
 #[derive(Clone)]
@@ -28,10 +95,12 @@ pub struct Database {
 }
 
 impl AppData {
-    pub fn new(database: Database, environment: Environment) -> AppData {
+    pub fn new(database: Database, environment: Environment, ip_filter: IpFilter) -> AppData {
         AppData {
             database,
-            environment
+            environment,
+            rate_limiter: RateLimiter::new(),
+            ip_filter
         }
     }
 }
@@ -54,6 +123,34 @@ impl Environment {
         Self::get_environment_from_file()
     }
 
+    /// An example configuration, with freshly generated secrets and placeholders for the database credentials
+    pub fn example() -> Environment {
+        Environment {
+            mysql_host: "YOUR_MYSQL_HOST".to_string(),
+            mysql_database: "YOUR MYSQL_DATABASE".to_string(),
+            mysql_username: "YOUR MYSQL_USERNAME".to_string(),
+            mysql_password: "YOUR_MYSQL_PASSWORD".to_string(),
+            password_pepper: rand::thread_rng().sample_iter(&rand::distributions::Alphanumeric).take(64).map(char::from).collect(),
+            email_encryption: EncryptionConfig::generate(),
+            email_normalization: NormalizationConfig::default(),
+            username_policy: UsernamePolicy::default(),
+            profile: ProfileConfig::default(),
+            mail: MailConfig::default(),
+            retention: RetentionConfig::default(),
+            scim: ScimConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+            lockout: LockoutConfig::default(),
+            registration: RegistrationConfig::default(),
+            breach: BreachConfig::default(),
+            password_policy: PasswordPolicy::default(),
+            challenge: ChallengeConfig::generate(),
+            device_alerts: DeviceAlertConfig::default(),
+            step_up: StepUpConfig::default(),
+            session_binding: SessionBindingConfig::default(),
+            ip_filter: IpFilterConfig::default()
+        }
+    }
+
     fn get_environment_from_file() -> Environment {
         //Determine the platform, and thus the location of the config file
         //Windows:          C:\Program Files\TwinsightContentDashboard\config.yml
@@ -98,13 +195,7 @@ impl Environment {
             }
 
             //Example Configuration file content
-            let example_config = Environment {
-                mysql_host: "YOUR_MYSQL_HOST".to_string(),
-                mysql_database: "YOUR MYSQL_DATABASE".to_string(),
-                mysql_username: "YOUR MYSQL_USERNAME".to_string(),
-                mysql_password: "YOUR_MYSQL_PASSWORD".to_string(),
-                password_pepper: rand::thread_rng().sample_iter(&rand::distributions::Alphanumeric).take(64).map(char::from).collect()
-            };
+            let example_config = Self::example();
 
             //Serialize to a String
             let example_config_as_str = serde_yaml::to_string(&example_config).unwrap();
@@ -136,13 +227,36 @@ impl Environment {
         }
 
         //Deserialize the configuration file content
-        let environment: serde_yaml::Result<Environment> = serde_yaml::from_str(&config_file_content.unwrap());
+        let config_file_content = config_file_content.unwrap();
+        let environment: serde_yaml::Result<Environment> = serde_yaml::from_str(&config_file_content);
         if environment.is_err() {
             eprintln!("Something went wrong deserializing the configuration file content (appdata.rs): {:?}", environment.err());
             std::process::exit(1);
         }
 
-        environment.unwrap().clone()
+        let mut environment = environment.unwrap();
+
+        //Configuration files written before email addresses were encrypted have no keys yet. They are generated and
+        //added to the file, as the addresses encrypted with them couldn't be read after a restart otherwise
+        let config_file_value = serde_yaml::from_str::<serde_yaml::Value>(&config_file_content).unwrap_or(serde_yaml::Value::Null);
+        if config_file_value.get("email_encryption").is_none() {
+            environment.email_encryption = EncryptionConfig::generate();
+
+            let mut section = std::collections::BTreeMap::new();
+            section.insert("email_encryption", &environment.email_encryption);
+            let section_as_str = serde_yaml::to_string(&section).unwrap();
+
+            let append_operation = std::fs::OpenOptions::new().append(true).open(config_path.as_path())
+                .and_then(|mut config_file| config_file.write_all(format!("\n{}\n", section_as_str.trim_start_matches("---\n")).as_bytes()));
+            if append_operation.is_err() {
+                eprintln!("An error occurred while adding the email encryption keys to the configuration file (appdata.rs): {:?}", append_operation.err());
+                std::process::exit(1);
+            }
+
+            println!("Generated email encryption keys and added them to the configuration file at {}. Keep a backup of them, the stored email addresses can't be read without them.", config_path.as_path().to_str().unwrap());
+        }
+
+        environment
     }
 
     fn get_environment_from_vars() -> Environment {
@@ -173,18 +287,57 @@ impl Environment {
             Self::env_variable_not_set("PASSWORD_PEPPER");
         }
 
+        let email_encryption = EncryptionConfig::from_vars();
+        if let Err(name) = email_encryption {
+            Self::env_variable_not_set(name);
+        }
+
         Environment {
             mysql_host:         mysql_host.unwrap(),
             mysql_database:     mysql_database.unwrap(),
             mysql_username:     mysql_username.unwrap(),
             mysql_password:     mysql_password.unwrap(),
-            password_pepper:    password_pepper.unwrap()
+            password_pepper:    password_pepper.unwrap(),
+            email_encryption:   email_encryption.ok().unwrap(),
+            email_normalization: NormalizationConfig::from_vars(),
+            username_policy:    Self::optional_var("USERNAME_POLICY", UsernamePolicy::default()),
+            profile:            ProfileConfig::from_vars(),
+            mail:               MailConfig::from_vars(),
+            retention:          RetentionConfig::from_vars(),
+            scim:               ScimConfig::from_vars(),
+            rate_limit:         RateLimitConfig::from_vars(),
+            lockout:            LockoutConfig::from_vars(),
+            registration:       RegistrationConfig::from_vars(),
+            breach:             BreachConfig::from_vars(),
+            password_policy:    PasswordPolicy::from_vars(),
+            challenge:          ChallengeConfig::from_vars(),
+            device_alerts:      DeviceAlertConfig::from_vars(),
+            step_up:            StepUpConfig::from_vars(),
+            session_binding:    SessionBindingConfig::from_vars(),
+            ip_filter:          IpFilterConfig::from_vars()
         }
     }
 
     fn env_variable_not_set(name: &str) {
         eprintln!("Required environmental variable '{}' not set. Exiting", name);
     }
+
+    /// Read an optional environmental variable, falling back to `default` if it is not set.
+    /// Exits if the variable is set, but can not be parsed
+    pub fn optional_var<T: std::str::FromStr>(name: &str, default: T) -> T {
+        let value = std::env::var(name);
+        if value.is_err() {
+            return default;
+        }
+
+        let parsed = value.unwrap().parse::<T>();
+        if parsed.is_err() {
+            eprintln!("Environmental variable '{}' has an invalid value. Exiting", name);
+            std::process::exit(1);
+        }
+
+        parsed.ok().unwrap()
+    }
 }
 
 impl Database {
@@ -209,7 +362,13 @@ impl Database {
         }
     }
 
-    pub fn check_db(&self, environment: &Environment) -> Result<bool, ()> {
+    /// Check if the error is caused by violating the unique index `key` (MySQL error 1062, ER_DUP_ENTRY)
+    pub fn is_duplicate_entry(error: &mysql::Error, key: &str) -> bool {
+        matches!(error, mysql::Error::MySqlError(e) if e.code == 1062 && e.message.contains(key))
+    }
+
+    /// Check whether all migrations have been applied to the database
+    pub fn check_db(&self) -> Result<bool, ()> {
         let conn_wrapped = self.pool.get_conn();
         if conn_wrapped.is_err() {
             eprintln!("An error occurred (appdata.rs): {:?}", conn_wrapped.err().unwrap());
@@ -217,37 +376,18 @@ impl Database {
         }
         let mut conn = conn_wrapped.unwrap();
 
-        let sql_fetch_tables_wrapped = conn.exec::<Row, &str, Params>("SELECT table_name FROM INFORMATION_SCHEMA.TABLES WHERE TABLE_SCHEMA = :table_schema", params! {
-            "table_schema" => environment.mysql_database.clone()
-        });
-
-        if sql_fetch_tables_wrapped.is_err() {
-            eprintln!("An error occurred (appdata.rs): {:?}", sql_fetch_tables_wrapped.unwrap());
-            return Err(());
+        let current_version = migrations::current_version(&mut conn)?;
+        let latest_version = migrations::latest_version();
+        if current_version < latest_version {
+            eprintln!("Database is at schema version {}, expected version {}", current_version, latest_version);
+            return Ok(false);
         }
 
-        let sql_fetch_tables = sql_fetch_tables_wrapped.unwrap();
-        let mut required_tables_map = HashMap::new();
-        required_tables_map.insert("users".to_string(), false);
-        required_tables_map.insert("sessions".to_string(), false);
-
-        for row in sql_fetch_tables {
-            let table_name = row.get::<String, &str>("table_name").unwrap();
-            required_tables_map.insert(table_name.clone(), true);
-        }
-
-        let mut db_passed = true;
-        for entry in required_tables_map.iter() {
-            if *entry.1 == false {
-                eprintln!("Missing table: '{}'", entry.0);
-                db_passed = false;
-            }
-        }
-        Ok(db_passed)
+        Ok(true)
     }
 
+    /// Apply all pending migrations
     pub fn init_db(&self, environment: &Environment) -> Result<(), ()> {
-        //Create a connection
         let conn_wrapped = self.pool.get_conn();
         if conn_wrapped.is_err() {
             eprintln!("An error occurred (appdata.rs): {:?}", conn_wrapped.err().unwrap());
@@ -255,24 +395,6 @@ impl Database {
         }
         let mut conn = conn_wrapped.unwrap();
 
-        //Create 'sessions' table
-        let sql_create_sessions_table = conn.query::<usize, &str>(format!("CREATE TABLE `{}`.`sessions` ( `session_id` VARCHAR(64) NOT NULL , `user_id` VARCHAR(64) NOT NULL , `expiry` BIGINT NOT NULL , PRIMARY KEY (`session_id`)) ENGINE = InnoDB;", environment.mysql_database.clone()).as_str());
-
-        if sql_create_sessions_table.is_err() {
-            eprintln!("An error occurred (appdata.rs): {:?}", sql_create_sessions_table.err().unwrap());
-            return Err(());
-        }
-        println!("Created table 'sessions'");
-
-        //Create 'users' table
-        let sql_create_users_table = conn.query::<usize, &str>(format!("CREATE TABLE `{}`.`users` ( `user_id` VARCHAR(64) NOT NULL , `email` VARCHAR(255) NOT NULL , `password` VARCHAR(255) NOT NULL , `salt` VARCHAR(16) NOT NULL , PRIMARY KEY (`user_id`)) ENGINE = InnoDB;", environment.mysql_database.clone()).as_str());
-
-        if sql_create_users_table.is_err() {
-            eprintln!("An error occurred (appdata.rs): {:?}", sql_create_users_table.err().unwrap());
-            return Err(());
-        }
-        println!("Created table 'users'.");
-
-        Ok(())
+        migrations::migrate(&mut conn, environment)
     }
 }
\ No newline at end of file
@@ -0,0 +1,606 @@
+use crate::appdata::{Database, Environment};
+use crate::{crypto, email};
+
+use std::io::{BufRead, BufReader, Write};
+use mysql::prelude::Queryable;
+use mysql::{PooledConn, Row, Params, params};
+use serde::{Deserialize, Serialize};
+use sha2::{Sha256, Digest};
+
+/// Identifies a backup archive
+const ARCHIVE_FORMAT: &str = "login_server-backup";
+/// Version of the archive format, increased whenever its structure changes
+const ARCHIVE_VERSION: u32 = 2;
+/// PBKDF2 iterations for deriving the key of encrypted archives
+const KDF_ITERATIONS: u32 = 200_000;
+/// Bounds on the iterations an archive may ask for. Fewer would make the passphrase easy to guess,
+/// more would let a crafted archive keep a restore busy for hours
+const MIN_KDF_ITERATIONS: u32 = 100_000;
+const MAX_KDF_ITERATIONS: u32 = 10_000_000;
+
+/// The first line of an archive. It is never encrypted, so a restore knows how to read the rest.
+/// In encrypted archives the second line is a MAC of the first, and every record is sealed with the header's hash
+/// and its own index as additional data, so the header can't be changed and records can't be moved between archives
+/// or reordered
+#[derive(Serialize, Deserialize)]
+struct Header {
+    format:             String,
+    version:            u32,
+    created_at:         i64,
+    /// Fingerprint of the password pepper. Native password hashes only verify with the same pepper
+    pepper_fingerprint: String,
+    /// Set if the records are encrypted with a key derived from a passphrase
+    encryption:         Option<ArchiveEncryption>
+}
+
+#[derive(Serialize, Deserialize)]
+struct ArchiveEncryption {
+    kdf:        String,
+    iterations: u32,
+    /// Base64 encoded
+    salt:       String
+}
+
+/// Every line after the header, and its MAC, is one record. Encrypted archives have every record sealed on its own
+/// and base64 encoded, so archives can be written and read as a stream
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Record {
+    User(Box<UserRecord>),
+    Session(SessionRecord),
+    Organization(OrganizationRecord),
+    Membership(MembershipRecord),
+    /// The last record. A missing end record means the archive was truncated
+    End { users: u64, sessions: u64 }
+}
+
+/// A user with everything needed to recreate their row. The email address is stored decrypted,
+/// so the archive can be restored into an environment with other encryption keys
+#[derive(Serialize, Deserialize)]
+struct UserRecord {
+    user_id:            String,
+    email:              String,
+    username:           Option<String>,
+    password:           String,
+    salt:               String,
+    password_algorithm: Option<String>,
+    display_name:       Option<String>,
+    locale:             Option<String>,
+    timezone:           Option<String>,
+    avatar_url:         Option<String>,
+    attributes:         Option<String>,
+    deleted_at:         Option<i64>,
+    status:             String,
+    status_until:       Option<i64>,
+    status_reason:      Option<String>,
+    roles:              Vec<String>,
+    //Added in version 2
+    #[serde(default)]
+    external_id:                Option<String>,
+    #[serde(default)]
+    failed_logins:              u32,
+    #[serde(default)]
+    last_failed_login:          Option<i64>,
+    #[serde(default)]
+    login_locked_until:         Option<i64>,
+    #[serde(default)]
+    password_reset_required:    bool,
+    #[serde(default)]
+    email_verified:             bool
+}
+
+#[derive(Serialize, Deserialize)]
+struct SessionRecord {
+    session_id:     String,
+    user_id:        String,
+    expiry:         i64,
+    active_org_id:  Option<String>,
+    //Added in version 2
+    #[serde(default)]
+    authenticated_at:   Option<i64>,
+    #[serde(default)]
+    auth_methods:       String,
+    #[serde(default)]
+    bound_ip_prefix:    Option<String>,
+    #[serde(default)]
+    bound_user_agent:   Option<String>,
+    #[serde(default)]
+    bound_key:          Option<String>
+}
+
+#[derive(Serialize, Deserialize)]
+struct OrganizationRecord {
+    org_id:     String,
+    name:       String,
+    created_at: i64
+}
+
+#[derive(Serialize, Deserialize)]
+struct MembershipRecord {
+    org_id:     String,
+    user_id:    String,
+    role:       String,
+    joined_at:  i64
+}
+
+pub struct ExportOptions {
+    /// Export the users' unexpired sessions too
+    pub include_sessions:   bool,
+    /// Only export the members of this organization, together with the organization and its memberships
+    pub org_id:             Option<String>,
+    /// Encrypt the archive with a key derived from this passphrase
+    pub passphrase:         Option<String>
+}
+
+#[derive(Default)]
+pub struct RestoreReport {
+    pub restored:       u64,
+    /// Users which already exist with the same ID, e.g. from an earlier run of the same restore
+    pub unchanged:      u64,
+    /// Users whose email address or username belongs to another account
+    pub conflicts:      Vec<String>,
+    pub sessions:       u64,
+    pub memberships:    u64
+}
+
+/// Fingerprint of the password pepper, which doesn't reveal the pepper itself
+fn pepper_fingerprint(pepper: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update("login_server pepper fingerprint");
+    hasher.update(pepper);
+
+    hasher.finalize().iter().take(8).map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// The keys of an encrypted archive, derived from the key the passphrase gives: one sealing the records
+/// and one authenticating the header
+struct ArchiveKeys {
+    records:    Vec<u8>,
+    header:     Vec<u8>
+}
+
+impl ArchiveKeys {
+    fn derive(passphrase: &str, salt: &[u8], iterations: u32) -> ArchiveKeys {
+        let key = crypto::derive_key(passphrase, salt, iterations);
+
+        ArchiveKeys {
+            records:    crypto::hmac_sha256(&key, b"login_server backup records"),
+            header:     crypto::hmac_sha256(&key, b"login_server backup header")
+        }
+    }
+}
+
+/// The additional data a record is sealed with
+fn record_aad(header_hash: &[u8], index: u64) -> Vec<u8> {
+    let mut aad = header_hash.to_vec();
+    aad.extend_from_slice(&index.to_be_bytes());
+    aad
+}
+
+struct ArchiveWriter<'a> {
+    writer:         &'a mut dyn Write,
+    key:            Option<Vec<u8>>,
+    header_hash:    Vec<u8>,
+    index:          u64
+}
+
+impl ArchiveWriter<'_> {
+    fn write(&mut self, record: &Record) -> Result<(), ()> {
+        let json = serde_json::to_string(record).map_err(|e| eprintln!("An error occurred (backup.rs): {:?}", e))?;
+
+        let line = match &self.key {
+            Some(key) => base64::encode(crypto::seal(key, json.as_bytes(), &record_aad(&self.header_hash, self.index))?),
+            None => json
+        };
+        self.index += 1;
+
+        writeln!(self.writer, "{}", line).map_err(|e| eprintln!("Unable to write the archive (backup.rs): {:?}", e))
+    }
+}
+
+/// Stream a query's rows into the archive, converting each row to a record
+fn write_rows(conn: &mut PooledConn, archive: &mut ArchiveWriter, query: &str, params: Params, to_record: &dyn Fn(Row) -> Result<Record, ()>) -> Result<u64, ()> {
+    let sql_fetch_rows = conn.exec_iter(query, params);
+    if sql_fetch_rows.is_err() {
+        eprintln!("An error occurred (backup.rs): {:?}", sql_fetch_rows.err().unwrap());
+        return Err(());
+    }
+
+    let mut count = 0;
+    for row in sql_fetch_rows.unwrap() {
+        let row = row.map_err(|e| eprintln!("An error occurred (backup.rs): {:?}", e))?;
+        archive.write(&to_record(row)?)?;
+        count += 1;
+    }
+
+    Ok(count)
+}
+
+/// Export users into an archive, written to `writer` as it is read from the database
+pub fn export(database: &Database, environment: &Environment, writer: &mut dyn Write, options: &ExportOptions) -> Result<(u64, u64), ()> {
+    let conn_wrapped = database.pool.get_conn();
+    if conn_wrapped.is_err() {
+        eprintln!("An error occurred (backup.rs): {:?}", conn_wrapped.err().unwrap());
+        return Err(());
+    }
+    let mut conn = conn_wrapped.unwrap();
+
+    let (encryption, keys) = match &options.passphrase {
+        Some(passphrase) => {
+            let salt = crypto::random_bytes(16);
+            let keys = ArchiveKeys::derive(passphrase, &salt, KDF_ITERATIONS);
+            (Some(ArchiveEncryption { kdf: "pbkdf2-sha256".to_string(), iterations: KDF_ITERATIONS, salt: base64::encode(salt) }), Some(keys))
+        },
+        None => (None, None)
+    };
+
+    let header = Header {
+        format:             ARCHIVE_FORMAT.to_string(),
+        version:            ARCHIVE_VERSION,
+        created_at:         chrono::Utc::now().timestamp(),
+        pepper_fingerprint: pepper_fingerprint(&environment.password_pepper),
+        encryption
+    };
+
+    let header = serde_json::to_string(&header).unwrap();
+    writeln!(writer, "{}", header).map_err(|e| eprintln!("Unable to write the archive (backup.rs): {:?}", e))?;
+    if let Some(keys) = &keys {
+        writeln!(writer, "{}", base64::encode(crypto::hmac_sha256(&keys.header, header.as_bytes()))).map_err(|e| eprintln!("Unable to write the archive (backup.rs): {:?}", e))?;
+    }
+
+    let mut archive = ArchiveWriter { writer, key: keys.map(|keys| keys.records), header_hash: Sha256::digest(header.as_bytes()).to_vec(), index: 0 };
+
+    //Restricting the export to an organization's members
+    let (member_join, member_params) = match &options.org_id {
+        Some(org_id) => {
+            let sql_fetch_org = conn.exec_first::<Row, &str, Params>("SELECT org_id, name, created_at FROM organizations WHERE org_id = :org_id", params! {
+                "org_id" => org_id
+            });
+
+            let org = match sql_fetch_org {
+                Ok(Some(org)) => org,
+                Ok(None) => {
+                    eprintln!("Organization '{}' does not exist.", org_id);
+                    return Err(());
+                },
+                Err(e) => {
+                    eprintln!("An error occurred (backup.rs): {:?}", e);
+                    return Err(());
+                }
+            };
+
+            archive.write(&Record::Organization(OrganizationRecord {
+                org_id:     org.get::<String, &str>("org_id").unwrap(),
+                name:       org.get::<String, &str>("name").unwrap(),
+                created_at: org.get::<i64, &str>("created_at").unwrap()
+            }))?;
+
+            ("INNER JOIN org_memberships ON org_memberships.user_id = users.user_id AND org_memberships.org_id = :org_id", params! { "org_id" => org_id })
+        },
+        None => ("", Params::Empty)
+    };
+
+    let users = write_rows(&mut conn, &mut archive, &format!("SELECT users.user_id, users.email, users.username, users.password, users.salt, users.password_algorithm, users.display_name, users.locale, users.timezone, users.avatar_url, users.attributes, users.deleted_at, users.status, users.status_until, users.status_reason, users.external_id, users.failed_logins, users.last_failed_login, users.login_locked_until, users.password_reset_required, users.email_verified, (SELECT GROUP_CONCAT(user_roles.role SEPARATOR ',') FROM user_roles WHERE user_roles.user_id = users.user_id) AS roles FROM users {} ORDER BY users.user_id", member_join), member_params.clone(), &|row| {
+        let user_id = row.get::<String, &str>("user_id").unwrap();
+        let email = environment.email_encryption.decrypt(&row.get::<String, &str>("email").unwrap());
+        if email.is_err() {
+            eprintln!("Unable to decrypt the email address of user '{}' (backup.rs)", user_id);
+            return Err(());
+        }
+
+        Ok(Record::User(Box::new(UserRecord {
+            email:              email.unwrap(),
+            username:           row.get::<Option<String>, &str>("username").unwrap(),
+            password:           row.get::<String, &str>("password").unwrap(),
+            salt:               row.get::<String, &str>("salt").unwrap(),
+            password_algorithm: row.get::<Option<String>, &str>("password_algorithm").unwrap(),
+            display_name:       row.get::<Option<String>, &str>("display_name").unwrap(),
+            locale:             row.get::<Option<String>, &str>("locale").unwrap(),
+            timezone:           row.get::<Option<String>, &str>("timezone").unwrap(),
+            avatar_url:         row.get::<Option<String>, &str>("avatar_url").unwrap(),
+            attributes:         row.get::<Option<String>, &str>("attributes").unwrap(),
+            deleted_at:         row.get::<Option<i64>, &str>("deleted_at").unwrap(),
+            status:             row.get::<String, &str>("status").unwrap(),
+            status_until:       row.get::<Option<i64>, &str>("status_until").unwrap(),
+            status_reason:      row.get::<Option<String>, &str>("status_reason").unwrap(),
+            roles:              row.get::<Option<String>, &str>("roles").unwrap()
+                .map(|roles| roles.split(',').map(str::to_string).collect())
+                .unwrap_or_default(),
+            external_id:                row.get::<Option<String>, &str>("external_id").unwrap(),
+            failed_logins:              row.get::<u32, &str>("failed_logins").unwrap(),
+            last_failed_login:          row.get::<Option<i64>, &str>("last_failed_login").unwrap(),
+            login_locked_until:         row.get::<Option<i64>, &str>("login_locked_until").unwrap(),
+            password_reset_required:    row.get::<bool, &str>("password_reset_required").unwrap(),
+            email_verified:             row.get::<bool, &str>("email_verified").unwrap(),
+            user_id
+        })))
+    })?;
+
+    if options.org_id.is_some() {
+        write_rows(&mut conn, &mut archive, "SELECT org_id, user_id, role, joined_at FROM org_memberships WHERE org_id = :org_id ORDER BY user_id", member_params.clone(), &|row| {
+            Ok(Record::Membership(MembershipRecord {
+                org_id:     row.get::<String, &str>("org_id").unwrap(),
+                user_id:    row.get::<String, &str>("user_id").unwrap(),
+                role:       row.get::<String, &str>("role").unwrap(),
+                joined_at:  row.get::<i64, &str>("joined_at").unwrap()
+            }))
+        })?;
+    }
+
+    let mut sessions = 0;
+    if options.include_sessions {
+        let member_params = match &options.org_id {
+            Some(org_id) => params! { "org_id" => org_id, "now" => chrono::Utc::now().timestamp() },
+            None => params! { "now" => chrono::Utc::now().timestamp() }
+        };
+
+        sessions = write_rows(&mut conn, &mut archive, &format!("SELECT sessions.session_id, sessions.user_id, sessions.expiry, sessions.active_org_id, sessions.authenticated_at, sessions.auth_methods, sessions.bound_ip_prefix, sessions.bound_user_agent, sessions.bound_key FROM sessions INNER JOIN users ON users.user_id = sessions.user_id {} WHERE sessions.expiry > :now ORDER BY sessions.user_id", member_join), member_params, &|row| {
+            Ok(Record::Session(SessionRecord {
+                session_id:     row.get::<String, &str>("session_id").unwrap(),
+                user_id:        row.get::<String, &str>("user_id").unwrap(),
+                expiry:         row.get::<i64, &str>("expiry").unwrap(),
+                active_org_id:  row.get::<Option<String>, &str>("active_org_id").unwrap(),
+                authenticated_at:   row.get::<Option<i64>, &str>("authenticated_at").unwrap(),
+                auth_methods:       row.get::<String, &str>("auth_methods").unwrap(),
+                bound_ip_prefix:    row.get::<Option<String>, &str>("bound_ip_prefix").unwrap(),
+                bound_user_agent:   row.get::<Option<String>, &str>("bound_user_agent").unwrap(),
+                bound_key:          row.get::<Option<String>, &str>("bound_key").unwrap()
+            }))
+        })?;
+    }
+
+    archive.write(&Record::End { users, sessions })?;
+    archive.writer.flush().map_err(|e| eprintln!("Unable to write the archive (backup.rs): {:?}", e))?;
+
+    Ok((users, sessions))
+}
+
+/// Read the records of an archive one by one. Errors describe what is wrong with the archive
+struct ArchiveReader {
+    lines:          std::io::Lines<BufReader<std::fs::File>>,
+    key:            Option<Vec<u8>>,
+    header_hash:    Vec<u8>,
+    line:           u64,
+    index:          u64
+}
+
+impl ArchiveReader {
+    fn open(path: &str, environment: &Environment, passphrase: Option<&str>) -> Result<ArchiveReader, String> {
+        let file = std::fs::File::open(path).map_err(|e| format!("Unable to open '{}': {}", path, e))?;
+        let mut lines = BufReader::new(file).lines();
+
+        let header_line = lines.next()
+            .ok_or_else(|| "The archive is empty.".to_string())?
+            .map_err(|e| format!("Unable to read the archive: {}", e))?;
+        let header: Header = serde_json::from_str(&header_line).map_err(|e| format!("Invalid archive header: {}", e))?;
+
+        if header.format != ARCHIVE_FORMAT {
+            return Err("This is not a backup archive.".to_string());
+        }
+
+        if header.version > ARCHIVE_VERSION {
+            return Err(format!("Archive version {} is newer than the supported version {}.", header.version, ARCHIVE_VERSION));
+        }
+
+        if header.pepper_fingerprint != pepper_fingerprint(&environment.password_pepper) {
+            return Err("The archive was created with a different password pepper, its password hashes would not verify.".to_string());
+        }
+
+        let keys = match (header.encryption, passphrase) {
+            (None, _) => None,
+            (Some(_), None) => return Err("The archive is encrypted, a passphrase is required.".to_string()),
+            (Some(_), Some(_)) if header.version < 2 => return Err(format!("Encrypted archives of version {} can't be authenticated, restore them with the release which created them.", header.version)),
+            (Some(encryption), Some(passphrase)) => {
+                if encryption.kdf != "pbkdf2-sha256" {
+                    return Err(format!("Unsupported key derivation '{}'.", encryption.kdf));
+                }
+
+                if !(MIN_KDF_ITERATIONS..=MAX_KDF_ITERATIONS).contains(&encryption.iterations) {
+                    return Err(format!("Invalid archive header: the key derivation has to use between {} and {} iterations.", MIN_KDF_ITERATIONS, MAX_KDF_ITERATIONS));
+                }
+
+                let salt = base64::decode(&encryption.salt).map_err(|_| "Invalid archive header: malformed salt.".to_string())?;
+                Some(ArchiveKeys::derive(passphrase, &salt, encryption.iterations))
+            }
+        };
+
+        let mut line = 1;
+        if let Some(keys) = &keys {
+            let mac = lines.next()
+                .ok_or_else(|| "The archive is truncated, its header MAC is missing.".to_string())?
+                .map_err(|e| format!("Unable to read the archive: {}", e))?;
+            line += 1;
+
+            let expected_mac = base64::encode(crypto::hmac_sha256(&keys.header, header_line.as_bytes()));
+            if !crypto::constant_time_eq(mac.as_bytes(), expected_mac.as_bytes()) {
+                return Err("The archive header can't be authenticated. Is the passphrase correct?".to_string());
+            }
+        }
+
+        Ok(ArchiveReader { lines, key: keys.map(|keys| keys.records), header_hash: Sha256::digest(header_line.as_bytes()).to_vec(), line, index: 0 })
+    }
+}
+
+impl Iterator for ArchiveReader {
+    type Item = Result<Record, String>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let line = match self.lines.next()? {
+            Ok(line) => line,
+            Err(e) => return Some(Err(format!("Unable to read the archive: {}", e)))
+        };
+        self.line += 1;
+        self.index += 1;
+
+        let json = match &self.key {
+            Some(key) => {
+                let aad = record_aad(&self.header_hash, self.index - 1);
+                let plaintext = base64::decode(&line).ok().and_then(|sealed| crypto::open(key, &sealed, &aad).ok());
+                match plaintext.and_then(|plaintext| String::from_utf8(plaintext).ok()) {
+                    Some(json) => json,
+                    None => return Some(Err(format!("Line {} can't be decrypted, it has been modified or moved.", self.line)))
+                }
+            },
+            None => line
+        };
+
+        Some(serde_json::from_str::<Record>(&json).map_err(|e| format!("Invalid record on line {}: {}", self.line, e)))
+    }
+}
+
+/// Check an archive completely before anything is restored from it
+fn validate(path: &str, environment: &Environment, passphrase: Option<&str>) -> Result<(), String> {
+    let (mut users, mut sessions) = (0, 0);
+    let mut reader = ArchiveReader::open(path, environment, passphrase)?;
+
+    while let Some(record) = reader.next() {
+        match record? {
+            Record::User(user) => {
+                if email::parse(&user.email, &environment.email_normalization).is_none() {
+                    return Err(format!("User '{}' on line {} has an invalid email address.", user.user_id, reader.line));
+                }
+
+                users += 1;
+            },
+            Record::Session(_) => sessions += 1,
+            Record::Organization(_) | Record::Membership(_) => {},
+            Record::End { users: expected_users, sessions: expected_sessions } => {
+                if reader.next().is_some() {
+                    return Err(format!("Unexpected records after the end of the archive on line {}.", reader.line));
+                }
+
+                if (users, sessions) != (expected_users, expected_sessions) {
+                    return Err(format!("The archive should contain {} user(s) and {} session(s), but contains {} and {}.", expected_users, expected_sessions, users, sessions));
+                }
+
+                return Ok(());
+            }
+        }
+    }
+
+    Err("The archive is truncated, its end is missing.".to_string())
+}
+
+/// Whether a user with this ID exists, in which case their related rows can be restored
+fn user_exists(conn: &mut PooledConn, user_id: &str) -> Result<bool, String> {
+    conn.exec_first::<u8, &str, _>("SELECT 1 FROM users WHERE user_id = :user_id", params! { "user_id" => user_id })
+        .map(|row| row.is_some())
+        .map_err(|e| format!("Database error: {:?}", e))
+}
+
+fn restore_user(conn: &mut PooledConn, environment: &Environment, user: &UserRecord, report: &mut RestoreReport) -> Result<(), String> {
+    //Restoring is idempotent, users which were restored before are left alone
+    if user_exists(conn, &user.user_id)? {
+        report.unchanged += 1;
+    } else {
+        let email_normalized = email::parse(&user.email, &environment.email_normalization).unwrap();
+        let email_encrypted = environment.email_encryption.encrypt(&user.email).map_err(|_| "Unable to encrypt email address.".to_string())?;
+
+        let sql_insert_user = conn.exec_drop("INSERT INTO users (user_id, email, email_index, username, password, salt, password_algorithm, display_name, locale, timezone, avatar_url, attributes, deleted_at, status, status_until, status_reason, external_id, failed_logins, last_failed_login, login_locked_until, password_reset_required, email_verified) VALUES (:user_id, :email, :email_index, :username, :password, :salt, :password_algorithm, :display_name, :locale, :timezone, :avatar_url, :attributes, :deleted_at, :status, :status_until, :status_reason, :external_id, :failed_logins, :last_failed_login, :login_locked_until, :password_reset_required, :email_verified)", params! {
+            "user_id" => &user.user_id,
+            "email" => email_encrypted,
+            "email_index" => environment.email_encryption.blind_index(&email_normalized),
+            "username" => &user.username,
+            "password" => &user.password,
+            "salt" => &user.salt,
+            "password_algorithm" => &user.password_algorithm,
+            "display_name" => &user.display_name,
+            "locale" => &user.locale,
+            "timezone" => &user.timezone,
+            "avatar_url" => &user.avatar_url,
+            "attributes" => &user.attributes,
+            "deleted_at" => user.deleted_at,
+            "status" => &user.status,
+            "status_until" => user.status_until,
+            "status_reason" => &user.status_reason,
+            "external_id" => &user.external_id,
+            "failed_logins" => user.failed_logins,
+            "last_failed_login" => user.last_failed_login,
+            "login_locked_until" => user.login_locked_until,
+            "password_reset_required" => user.password_reset_required,
+            "email_verified" => user.email_verified
+        });
+
+        match sql_insert_user {
+            Ok(_) => report.restored += 1,
+            Err(e) if Database::is_duplicate_entry(&e, "users_email_index") => {
+                report.conflicts.push(format!("User '{}': an account with email address '{}' already exists.", user.user_id, user.email));
+                return Ok(());
+            },
+            Err(e) if Database::is_duplicate_entry(&e, "users_username") => {
+                report.conflicts.push(format!("User '{}': username '{}' is already taken.", user.user_id, user.username.as_deref().unwrap_or_default()));
+                return Ok(());
+            },
+            Err(e) => return Err(format!("Database error: {:?}", e))
+        }
+    }
+
+    //Only roles which exist in this environment are assigned
+    for role in user.roles.iter() {
+        conn.exec_drop("INSERT IGNORE INTO user_roles (user_id, role) SELECT :user_id, name FROM roles WHERE name = :role", params! {
+            "user_id" => &user.user_id,
+            "role" => role
+        }).map_err(|e| format!("Database error: {:?}", e))?;
+    }
+
+    Ok(())
+}
+
+/// Restore an archive. The whole archive is validated first, and nothing is changed if it is invalid.
+/// Users whose email address or username already belongs to another account are skipped and reported.
+/// Restoring the same archive again changes nothing
+pub fn restore(database: &Database, environment: &Environment, path: &str, passphrase: Option<&str>, dry_run: bool) -> Result<RestoreReport, String> {
+    validate(path, environment, passphrase)?;
+    if dry_run {
+        return Ok(RestoreReport::default());
+    }
+
+    let mut conn = database.pool.get_conn().map_err(|e| format!("Unable to connect to the database: {:?}", e))?;
+    let mut report = RestoreReport::default();
+
+    for record in ArchiveReader::open(path, environment, passphrase)? {
+        match record? {
+            Record::User(user) => restore_user(&mut conn, environment, &user, &mut report)?,
+            Record::Organization(org) => {
+                conn.exec_drop("INSERT IGNORE INTO organizations (org_id, name, created_at) VALUES (:org_id, :name, :created_at)", params! {
+                    "org_id" => org.org_id,
+                    "name" => org.name,
+                    "created_at" => org.created_at
+                }).map_err(|e| format!("Database error: {:?}", e))?;
+            },
+            //Memberships and sessions of users which weren't restored are skipped
+            Record::Membership(membership) => {
+                if user_exists(&mut conn, &membership.user_id)? {
+                    conn.exec_drop("INSERT IGNORE INTO org_memberships (org_id, user_id, role, joined_at) VALUES (:org_id, :user_id, :role, :joined_at)", params! {
+                        "org_id" => membership.org_id,
+                        "user_id" => membership.user_id,
+                        "role" => membership.role,
+                        "joined_at" => membership.joined_at
+                    }).map_err(|e| format!("Database error: {:?}", e))?;
+
+                    report.memberships += conn.affected_rows();
+                }
+            },
+            Record::Session(session) => {
+                if session.expiry > chrono::Utc::now().timestamp() && user_exists(&mut conn, &session.user_id)? {
+                    conn.exec_drop("INSERT IGNORE INTO sessions (session_id, user_id, expiry, active_org_id, authenticated_at, auth_methods, bound_ip_prefix, bound_user_agent, bound_key) VALUES (:session_id, :user_id, :expiry, :active_org_id, :authenticated_at, :auth_methods, :bound_ip_prefix, :bound_user_agent, :bound_key)", params! {
+                        "session_id" => session.session_id,
+                        "user_id" => session.user_id,
+                        "expiry" => session.expiry,
+                        "active_org_id" => session.active_org_id,
+                        "authenticated_at" => session.authenticated_at,
+                        "auth_methods" => session.auth_methods,
+                        "bound_ip_prefix" => session.bound_ip_prefix,
+                        "bound_user_agent" => session.bound_user_agent,
+                        "bound_key" => session.bound_key
+                    }).map_err(|e| format!("Database error: {:?}", e))?;
+
+                    report.sessions += conn.affected_rows();
+                }
+            },
+            Record::End { .. } => break
+        }
+    }
+
+    Ok(report)
+}
@@ -0,0 +1,150 @@
+use crate::appdata::{AppData, Environment};
+
+use std::path::Path;
+use std::time::Duration;
+use actix_web::web;
+use serde::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+
+pub const BREACHED_PASSWORD: &str = "This password has appeared in a data breach, and can't be used. Choose a different password.";
+
+/// The characters of the SHA-1 hash which are used as the prefix, both for file names and for the range API
+const PREFIX_LENGTH: usize = 5;
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum BreachBackend {
+    /// Passwords are not screened
+    Disabled,
+    /// A directory with a file per prefix, e.g. `21BD1` or `21BD1.txt`, as written by the Pwned Passwords downloader
+    Local,
+    /// An HTTP API compatible with the Pwned Passwords range API
+    Http
+}
+
+impl std::str::FromStr for BreachBackend {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "disabled" => Ok(BreachBackend::Disabled),
+            "local" => Ok(BreachBackend::Local),
+            "http" => Ok(BreachBackend::Http),
+            _ => Err(())
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct BreachConfig {
+    pub backend:              BreachBackend,
+    /// Directory of the local corpus
+    pub directory:            String,
+    /// URL the prefix is appended to
+    pub api_url:              String,
+    pub api_timeout_seconds:  u64,
+    /// How often a password must have appeared in breaches to be rejected
+    pub min_occurrences:      u64,
+    /// Check the password on every login, and require a breached password to be changed
+    pub check_on_login:       bool
+}
+
+impl Default for BreachConfig {
+    fn default() -> Self {
+        BreachConfig {
+            backend:              BreachBackend::Disabled,
+            directory:            "/var/lib/login_server/pwned-passwords".to_string(),
+            api_url:              "https://api.pwnedpasswords.com/range/".to_string(),
+            api_timeout_seconds:  5,
+            min_occurrences:      1,
+            check_on_login:       false
+        }
+    }
+}
+
+impl BreachConfig {
+    pub fn from_vars() -> BreachConfig {
+        let default = BreachConfig::default();
+
+        BreachConfig {
+            backend:              Environment::optional_var("BREACH_BACKEND", default.backend),
+            directory:            Environment::optional_var("BREACH_DIRECTORY", default.directory),
+            api_url:              Environment::optional_var("BREACH_API_URL", default.api_url),
+            api_timeout_seconds:  Environment::optional_var("BREACH_API_TIMEOUT_SECONDS", default.api_timeout_seconds),
+            min_occurrences:      Environment::optional_var("BREACH_MIN_OCCURRENCES", default.min_occurrences),
+            check_on_login:       std::env::var("BREACH_CHECK_ON_LOGIN").map(|enabled| enabled == "TRUE").unwrap_or(false)
+        }
+    }
+
+    /// Check whether a password appears in the breach corpus. Only the first characters of its SHA-1 hash leave this function.
+    /// If the corpus can't be read the password is let through, so an outage doesn't block registrations
+    pub fn is_breached(&self, password: &str) -> bool {
+        let hash: String = Sha1::digest(password.as_bytes()).iter().map(|byte| format!("{:02X}", byte)).collect();
+        let (prefix, suffix) = hash.split_at(PREFIX_LENGTH);
+
+        let range = match self.backend {
+            BreachBackend::Disabled => return false,
+            BreachBackend::Local => self.read_local(prefix),
+            BreachBackend::Http => self.fetch_range(prefix)
+        };
+
+        match range {
+            Ok(range) => occurrences(&range, suffix) >= self.min_occurrences.max(1),
+            Err(e) => {
+                eprintln!("Unable to check a password against the breach corpus (breach.rs): {}", e);
+                false
+            }
+        }
+    }
+
+    fn read_local(&self, prefix: &str) -> Result<String, String> {
+        let directory = Path::new(&self.directory);
+        let path = vec![directory.join(prefix), directory.join(format!("{}.txt", prefix))].into_iter().find(|path| path.exists());
+
+        match path {
+            Some(path) => std::fs::read_to_string(&path).map_err(|e| format!("{}: {}", path.display(), e)),
+            None => Err(format!("no file for prefix '{}' in '{}'", prefix, self.directory))
+        }
+    }
+
+    fn fetch_range(&self, prefix: &str) -> Result<String, String> {
+        let client = reqwest::blocking::Client::builder()
+            .timeout(Duration::from_secs(self.api_timeout_seconds))
+            .build()
+            .map_err(|e| e.to_string())?;
+
+        //Padding hides the prefix's real amount of suffixes from anyone watching the response sizes
+        let response = client.get(format!("{}{}", self.api_url, prefix))
+            .header("Add-Padding", "true")
+            .header("User-Agent", "login_server")
+            .send()
+            .and_then(|response| response.error_for_status())
+            .map_err(|e| e.to_string())?;
+
+        response.text().map_err(|e| e.to_string())
+    }
+}
+
+/// Run `BreachConfig::is_breached` on the thread pool for blocking work, so waiting on the corpus doesn't hold up the server's thread
+pub async fn is_breached_in_background(data: &web::Data<AppData>, password: &str) -> bool {
+    let data = data.clone();
+    let password = password.to_string();
+
+    match web::block(move || Ok::<_, ()>(data.environment.breach.is_breached(&password))).await {
+        Ok(breached) => breached,
+        Err(e) => {
+            eprintln!("Unable to check a password against the breach corpus (breach.rs): {:?}", e);
+            false
+        }
+    }
+}
+
+/// Find the amount of occurrences of a suffix in a range of `<suffix>:<occurrences>` lines. Padding lines have 0 occurrences
+fn occurrences(range: &str, suffix: &str) -> u64 {
+    range.lines()
+        .filter_map(|line| line.trim().split_once(':'))
+        .find(|(line_suffix, _)| line_suffix.eq_ignore_ascii_case(suffix))
+        .and_then(|(_, occurrences)| occurrences.trim().parse().ok())
+        .unwrap_or(0)
+}
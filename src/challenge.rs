@@ -0,0 +1,496 @@
+use crate::appdata::{AppData, Database, Environment};
+use crate::{client, crypto, ratelimit};
+use crate::ratelimit::BucketConfig;
+
+use std::net::IpAddr;
+use std::time::Duration;
+use actix_web::{web, HttpRequest};
+use hmac::{Hmac, Mac, NewMac};
+use mysql::prelude::Queryable;
+use mysql::{PooledConn, params};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+pub const CHALLENGE_REQUIRED: &str = "Solve the challenge to continue.";
+
+/// Every bit doubles the expected work, a browser needs years to solve a challenge of this difficulty
+const MAX_DIFFICULTY: u32 = 64;
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ChallengeMode {
+    /// No challenge is required
+    Disabled,
+    /// A proof-of-work challenge issued by `/auth/challenge`
+    Pow,
+    /// A CAPTCHA, verified by the configured provider
+    Captcha
+}
+
+impl std::str::FromStr for ChallengeMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "disabled" => Ok(ChallengeMode::Disabled),
+            "pow" => Ok(ChallengeMode::Pow),
+            "captcha" => Ok(ChallengeMode::Captcha),
+            _ => Err(())
+        }
+    }
+}
+
+/// CAPTCHA services. The real ones all verify responses with the same `siteverify` API, only at a different URL
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CaptchaProviderKind {
+    Hcaptcha,
+    Recaptcha,
+    Turnstile
+}
+
+impl std::str::FromStr for CaptchaProviderKind {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hcaptcha" => Ok(CaptchaProviderKind::Hcaptcha),
+            "recaptcha" => Ok(CaptchaProviderKind::Recaptcha),
+            "turnstile" => Ok(CaptchaProviderKind::Turnstile),
+            _ => Err(())
+        }
+    }
+}
+
+/// Configuration of the challenge which is required on registration, and on login after suspicious activity
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct ChallengeConfig {
+    pub mode:                       ChallengeMode,
+    /// The amount of leading zero bits the proof-of-work hash needs. Every bit doubles the expected work
+    pub difficulty:                 u32,
+    /// How long a proof-of-work challenge can be solved
+    pub validity_seconds:           i64,
+    /// Base64 encoded key signing the proof-of-work challenges. Servers behind the same load balancer need the same key
+    pub secret:                     String,
+    pub captcha_provider:           Option<CaptchaProviderKind>,
+    /// The provider's key for the site, which clients need to show the CAPTCHA
+    pub captcha_site_key:           String,
+    /// The provider's secret key, which responses are verified with
+    pub captcha_secret:             String,
+    pub captcha_timeout_seconds:    u64,
+    /// Failed logins from an IP address before its logins need a challenge
+    pub login_failures_per_ip:      Option<BucketConfig>,
+    /// Failed logins for an email address or username before its logins need a challenge
+    pub login_failures_per_account: Option<BucketConfig>
+}
+
+impl Default for ChallengeConfig {
+    fn default() -> Self {
+        ChallengeConfig {
+            mode:                       ChallengeMode::Disabled,
+            difficulty:                 20,
+            validity_seconds:           300,
+            secret:                     String::new(),
+            captcha_provider:           None,
+            captcha_site_key:           String::new(),
+            captcha_secret:             String::new(),
+            captcha_timeout_seconds:    5,
+            login_failures_per_ip:      BucketConfig::new(5, 1),
+            login_failures_per_account: BucketConfig::new(5, 1)
+        }
+    }
+}
+
+impl ChallengeConfig {
+    /// Generate a configuration with a fresh random signing key, used for the example configuration file
+    pub fn generate() -> ChallengeConfig {
+        ChallengeConfig {
+            secret: base64::encode(crypto::random_bytes(32)),
+            ..ChallengeConfig::default()
+        }
+    }
+
+    pub fn from_vars() -> ChallengeConfig {
+        let default = ChallengeConfig::default();
+
+        let captcha_provider = std::env::var("CHALLENGE_CAPTCHA_PROVIDER").ok().map(|provider| match provider.parse() {
+            Ok(provider) => provider,
+            Err(_) => {
+                eprintln!("Environmental variable 'CHALLENGE_CAPTCHA_PROVIDER' has an invalid value. Exiting");
+                std::process::exit(1);
+            }
+        });
+
+        //The failure buckets are configured like the rate limits, e.g. CHALLENGE_LOGIN_FAILURES_PER_IP=5/1
+        ChallengeConfig {
+            mode:                       Environment::optional_var("CHALLENGE_MODE", default.mode),
+            difficulty:                 Environment::optional_var("CHALLENGE_DIFFICULTY", default.difficulty),
+            validity_seconds:           Environment::optional_var("CHALLENGE_VALIDITY_SECONDS", default.validity_seconds),
+            secret:                     Environment::optional_var("CHALLENGE_SECRET", default.secret),
+            captcha_provider,
+            captcha_site_key:           Environment::optional_var("CHALLENGE_CAPTCHA_SITE_KEY", default.captcha_site_key),
+            captcha_secret:             Environment::optional_var("CHALLENGE_CAPTCHA_SECRET", default.captcha_secret),
+            captcha_timeout_seconds:    Environment::optional_var("CHALLENGE_CAPTCHA_TIMEOUT_SECONDS", default.captcha_timeout_seconds),
+            login_failures_per_ip:      BucketConfig::from_var("CHALLENGE_LOGIN_FAILURES_PER_IP", default.login_failures_per_ip),
+            login_failures_per_account: BucketConfig::from_var("CHALLENGE_LOGIN_FAILURES_PER_ACCOUNT", default.login_failures_per_account)
+        }
+    }
+
+    /// Verify that the challenges of the configured mode can be issued and checked. Returns a description of the problem found
+    pub fn validate(&self) -> Result<(), String> {
+        match self.mode {
+            ChallengeMode::Disabled => Ok(()),
+            ChallengeMode::Pow => {
+                match base64::decode(&self.secret) {
+                    Ok(key) if key.len() >= 32 => {},
+                    _ => return Err("Challenge secret is not a base64 encoded key of at least 256 bits".to_string())
+                }
+
+                if self.difficulty > MAX_DIFFICULTY {
+                    return Err(format!("Challenge difficulty may be at most {}", MAX_DIFFICULTY));
+                }
+
+                Ok(())
+            },
+            ChallengeMode::Captcha => {
+                if self.captcha_provider.is_none() {
+                    return Err("The CAPTCHA mode needs a CAPTCHA provider".to_string());
+                }
+
+                if self.captcha_site_key.trim().is_empty() || self.captcha_secret.trim().is_empty() {
+                    return Err("The CAPTCHA mode needs the site key and secret of the CAPTCHA provider".to_string());
+                }
+
+                Ok(())
+            }
+        }
+    }
+
+    pub fn provider(&self) -> Option<Box<dyn CaptchaProvider + Send>> {
+        let url = match self.captcha_provider? {
+            CaptchaProviderKind::Hcaptcha => "https://api.hcaptcha.com/siteverify",
+            CaptchaProviderKind::Recaptcha => "https://www.google.com/recaptcha/api/siteverify",
+            CaptchaProviderKind::Turnstile => "https://challenges.cloudflare.com/turnstile/v0/siteverify"
+        };
+
+        Some(Box::new(SiteverifyProvider {
+            url,
+            secret:     self.captcha_secret.clone(),
+            timeout:    Duration::from_secs(self.captcha_timeout_seconds)
+        }))
+    }
+
+    /// Issue a proof-of-work challenge, formatted as `<expiry>.<difficulty>.<random>.<signature>`.
+    /// The challenge carries everything needed to verify it, so the server doesn't have to store it
+    pub fn issue(&self) -> (String, i64) {
+        let expiry = chrono::Utc::now().timestamp() + self.validity_seconds;
+        let random: String = crypto::random_bytes(16).iter().map(|byte| format!("{:02x}", byte)).collect();
+
+        let payload = format!("{}.{}.{}", expiry, self.difficulty, random);
+        let signature = self.sign(&payload);
+
+        (format!("{}.{}", payload, signature), expiry)
+    }
+
+    fn sign(&self, payload: &str) -> String {
+        let key = base64::decode(&self.secret).expect("Challenge secret was validated at startup");
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(&key).unwrap();
+        mac.update(payload.as_bytes());
+
+        mac.finalize().into_bytes().iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    /// Verify a solved proof-of-work challenge, returning its expiry. The solution is any string for which
+    /// SHA-256(`<challenge>:<solution>`) starts with the challenge's difficulty in zero bits
+    fn verify_pow(&self, challenge: &str, solution: &str) -> Option<i64> {
+        let (payload, signature) = challenge.rsplit_once('.')?;
+        if !crypto::constant_time_eq(self.sign(payload).as_bytes(), signature.as_bytes()) {
+            return None;
+        }
+
+        let mut parts = payload.splitn(3, '.');
+        let expiry = parts.next()?.parse::<i64>().ok()?;
+        let difficulty = parts.next()?.parse::<u32>().ok()?;
+        if expiry < chrono::Utc::now().timestamp() {
+            return None;
+        }
+
+        let hash = Sha256::digest(format!("{}:{}", challenge, solution).as_bytes());
+        if leading_zero_bits(&hash) < difficulty {
+            return None;
+        }
+
+        Some(expiry)
+    }
+
+    /// Verify a solved proof-of-work challenge and spend it, so each challenge is accepted once.
+    /// `spend` is only called for a valid solution, and if it fails the solution is refused
+    fn accept_pow<F>(&self, challenge: &str, solution: &str, spend: F) -> bool
+    where F: FnOnce(&str, i64) -> Result<bool, ()> {
+        match self.verify_pow(challenge, solution) {
+            Some(expiry) => spend(challenge, expiry).unwrap_or(false),
+            None => false
+        }
+    }
+}
+
+/// Verifies the responses of a CAPTCHA service
+pub trait CaptchaProvider {
+    /// Whether the response proves a human solved the CAPTCHA. `Err` if the provider couldn't be asked
+    fn verify(&self, response: &str, ip: Option<IpAddr>) -> Result<bool, String>;
+}
+
+/// A provider with a `siteverify` endpoint, which takes the secret and response as a form and answers with `{"success": bool}`
+pub struct SiteverifyProvider {
+    url:        &'static str,
+    secret:     String,
+    timeout:    Duration
+}
+
+#[derive(Deserialize)]
+struct SiteverifyResponse {
+    success:    bool
+}
+
+impl CaptchaProvider for SiteverifyProvider {
+    fn verify(&self, response: &str, ip: Option<IpAddr>) -> Result<bool, String> {
+        let client = reqwest::blocking::Client::builder()
+            .timeout(self.timeout)
+            .build()
+            .map_err(|e| e.to_string())?;
+
+        let mut form = vec![("secret", self.secret.clone()), ("response", response.to_string())];
+        if let Some(ip) = ip {
+            form.push(("remoteip", ip.to_string()));
+        }
+
+        let verification = client.post(self.url)
+            .form(&form)
+            .send()
+            .and_then(|response| response.error_for_status())
+            .and_then(|response| response.json::<SiteverifyResponse>())
+            .map_err(|e| e.to_string())?;
+
+        Ok(verification.success)
+    }
+}
+
+/// Mark a proof-of-work challenge as used, until it expires, so a solution can't be replayed. Spent challenges are kept
+/// in the database, so a challenge spent on one server can't be replayed on another. Returns false if it was used before
+fn spend(conn: &mut PooledConn, challenge: &str, expiry: i64) -> Result<bool, ()> {
+    let sql_insert_challenge = conn.exec_drop("INSERT INTO spent_challenges (challenge, expiry) VALUES (:challenge, :expiry)", params! {
+        "challenge" => crypto::hash_token(challenge),
+        "expiry" => expiry
+    });
+
+    match sql_insert_challenge {
+        Ok(_) => Ok(true),
+        Err(e) if Database::is_duplicate_entry(&e, "PRIMARY") => Ok(false),
+        Err(e) => {
+            eprintln!("An error occurred (challenge.rs): {:?}", e);
+            Err(())
+        }
+    }
+}
+
+/// Verify the challenge sent with a request. For proof-of-work `challenge` is the issued challenge and `solution` the
+/// client's solution, for a CAPTCHA `solution` is the provider's response.
+/// If the challenge can't be checked the request is refused, as letting it through would let the bots in too
+pub async fn verify(data: &web::Data<AppData>, req: &HttpRequest, challenge: Option<&str>, solution: Option<&str>) -> bool {
+    let config = &data.environment.challenge;
+
+    match (config.mode, challenge, solution) {
+        (ChallengeMode::Disabled, _, _) => true,
+        (ChallengeMode::Pow, Some(challenge), Some(solution)) => config.accept_pow(challenge, solution, |challenge, expiry| {
+            let conn_wrapped = data.database.pool.get_conn();
+            if conn_wrapped.is_err() {
+                eprintln!("An error occurred (challenge.rs): {:?}", conn_wrapped.err().unwrap());
+                return Err(());
+            }
+
+            spend(&mut conn_wrapped.unwrap(), challenge, expiry)
+        }),
+        (ChallengeMode::Captcha, _, Some(solution)) => {
+            let provider = match config.provider() {
+                Some(provider) => provider,
+                None => return false
+            };
+
+            //The provider is asked over HTTP, which mustn't hold up the server's thread
+            let solution = solution.to_string();
+            let ip = client::ip(req);
+            match web::block(move || provider.verify(&solution, ip)).await {
+                Ok(valid) => valid,
+                Err(e) => {
+                    eprintln!("Unable to verify a CAPTCHA response (challenge.rs): {:?}", e);
+                    false
+                }
+            }
+        },
+        _ => false
+    }
+}
+
+/// Whether a login needs a challenge, because of recent failed logins from the IP address or for the account.
+/// Failures are counted for identifiers without an account too, so requiring a challenge doesn't tell whether an account exists
+pub fn login_requires_challenge(data: &AppData, req: &HttpRequest, identifier: &str) -> bool {
+    if data.environment.challenge.mode == ChallengeMode::Disabled {
+        return false;
+    }
+
+    failure_buckets(data, req, identifier).iter().any(|(key, bucket)| data.rate_limiter.is_empty(data, key, bucket))
+}
+
+/// Count a failed login towards requiring a challenge
+pub fn record_login_failure(data: &AppData, req: &HttpRequest, identifier: &str) {
+    if data.environment.challenge.mode == ChallengeMode::Disabled {
+        return;
+    }
+
+    for (key, bucket) in failure_buckets(data, req, identifier) {
+        data.rate_limiter.take_token(data, &key, &bucket);
+    }
+}
+
+fn failure_buckets(data: &AppData, req: &HttpRequest, identifier: &str) -> Vec<(String, BucketConfig)> {
+    let config = &data.environment.challenge;
+    let ip = client::ip(req).map(|ip| ip.to_string()).unwrap_or_else(|| "unknown".to_string());
+
+    let mut buckets = Vec::new();
+    if let Some(bucket) = config.login_failures_per_ip {
+        buckets.push((format!("challenge:ip:{}", ip), bucket));
+    }
+
+    if let Some(bucket) = config.login_failures_per_account {
+        buckets.push((format!("challenge:email:{}", ratelimit::identifier_key(data, identifier)), bucket));
+    }
+
+    buckets
+}
+
+fn leading_zero_bits(hash: &[u8]) -> u32 {
+    let mut bits = 0;
+    for byte in hash {
+        bits += byte.leading_zeros();
+        if *byte != 0 {
+            break;
+        }
+    }
+
+    bits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn pow_config(difficulty: u32) -> ChallengeConfig {
+        ChallengeConfig {
+            mode: ChallengeMode::Pow,
+            difficulty,
+            ..ChallengeConfig::generate()
+        }
+    }
+
+    fn solve(challenge: &str, difficulty: u32) -> String {
+        (0u64..).map(|nonce| nonce.to_string())
+            .find(|solution| leading_zero_bits(&Sha256::digest(format!("{}:{}", challenge, solution).as_bytes())) >= difficulty)
+            .unwrap()
+    }
+
+    fn wrong_solution(challenge: &str, difficulty: u32) -> String {
+        (0u64..).map(|nonce| nonce.to_string())
+            .find(|solution| leading_zero_bits(&Sha256::digest(format!("{}:{}", challenge, solution).as_bytes())) < difficulty)
+            .unwrap()
+    }
+
+    #[test]
+    fn counts_leading_zero_bits() {
+        assert_eq!(leading_zero_bits(&[0xff]), 0);
+        assert_eq!(leading_zero_bits(&[0x00, 0x10]), 11);
+        assert_eq!(leading_zero_bits(&[0x00, 0x00, 0x01, 0x00]), 23);
+        assert_eq!(leading_zero_bits(&[0x00, 0x00]), 16);
+    }
+
+    #[test]
+    fn accepts_a_solved_challenge() {
+        let config = pow_config(8);
+        let (challenge, expiry) = config.issue();
+
+        assert_eq!(config.verify_pow(&challenge, &solve(&challenge, 8)), Some(expiry));
+    }
+
+    #[test]
+    fn rejects_an_insufficient_solution() {
+        let config = pow_config(8);
+        let (challenge, _) = config.issue();
+
+        assert_eq!(config.verify_pow(&challenge, &wrong_solution(&challenge, 8)), None);
+    }
+
+    #[test]
+    fn rejects_a_lowered_difficulty() {
+        let config = pow_config(16);
+        let (challenge, _) = config.issue();
+
+        //The signature covers the difficulty, so lowering it invalidates the challenge
+        let forged = challenge.replacen(".16.", ".0.", 1);
+        assert_eq!(config.verify_pow(&forged, "0"), None);
+    }
+
+    #[test]
+    fn rejects_a_challenge_signed_with_another_key() {
+        let (challenge, _) = pow_config(0).issue();
+        assert_eq!(pow_config(0).verify_pow(&challenge, "0"), None);
+    }
+
+    #[test]
+    fn rejects_an_expired_challenge() {
+        let config = ChallengeConfig { validity_seconds: -1, ..pow_config(0) };
+        let (challenge, _) = config.issue();
+
+        assert_eq!(config.verify_pow(&challenge, "0"), None);
+    }
+
+    #[test]
+    fn rejects_malformed_challenges() {
+        let config = pow_config(0);
+        for challenge in ["", "no-signature", "1.2.3", "x.0.abc.def"] {
+            assert_eq!(config.verify_pow(challenge, "0"), None);
+        }
+    }
+
+    #[test]
+    fn accepts_a_solution_once() {
+        let config = pow_config(4);
+        let (challenge, _) = config.issue();
+        let solution = solve(&challenge, 4);
+
+        let mut spent = HashSet::new();
+        let mut spend = |challenge: &str, _expiry: i64| Ok(spent.insert(crypto::hash_token(challenge)));
+
+        assert!(config.accept_pow(&challenge, &solution, &mut spend));
+        assert!(!config.accept_pow(&challenge, &solution, &mut spend));
+    }
+
+    #[test]
+    fn only_spends_valid_solutions() {
+        let config = pow_config(8);
+        let (challenge, _) = config.issue();
+
+        let mut spent = false;
+        assert!(!config.accept_pow(&challenge, &wrong_solution(&challenge, 8), |_, _| { spent = true; Ok(true) }));
+        assert!(!spent);
+    }
+
+    #[test]
+    fn refuses_when_the_challenge_cant_be_spent() {
+        let config = pow_config(0);
+        let (challenge, _) = config.issue();
+
+        assert!(!config.accept_pow(&challenge, "0", |_, _| Err(())));
+    }
+}
@@ -0,0 +1,439 @@
+use crate::appdata::{Database, Environment};
+use crate::users::CreateError;
+use crate::{backup, import, migrations, reaper, sessions, username, users};
+use crate::backup::ExportOptions;
+use crate::import::ImportFormat;
+use crate::password_policy::Violation;
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use mysql::PooledConn;
+use mysql::prelude::Queryable;
+use mysql::params;
+use rand::Rng;
+
+/// The command line interface. Without a subcommand, the server is started
+pub fn app() -> App<'static, 'static> {
+    App::new("login_server")
+        .version(env!("CARGO_PKG_VERSION"))
+        .about("Login server, and the tools to maintain it")
+        .setting(AppSettings::VersionlessSubcommands)
+        .subcommand(SubCommand::with_name("serve")
+            .about("Start the server. This is the default when no subcommand is given"))
+        .subcommand(SubCommand::with_name("migrate")
+            .about("Apply pending database migrations"))
+        .subcommand(SubCommand::with_name("check-db")
+            .about("Check whether the database schema is up to date. Exits with 1 if it isn't"))
+        .subcommand(SubCommand::with_name("create-user")
+            .about("Create a user")
+            .arg(Arg::with_name("email").long("email").takes_value(true).required(true).help("Email address of the user"))
+            .arg(Arg::with_name("username").long("username").takes_value(true).help("Username of the user"))
+            .arg(Arg::with_name("password-stdin").long("password-stdin").help("Read the password of the user from the first line of stdin. A random password is generated and printed if omitted"))
+            .arg(Arg::with_name("role").long("role").takes_value(true).multiple(true).number_of_values(1).help("Role to assign to the user, may be repeated")))
+        .subcommand(SubCommand::with_name("reset-password")
+            .about("Set a new password for a user, revoking their sessions")
+            .arg(Arg::with_name("user").long("user").takes_value(true).required(true).help("Email address, username or ID of the user"))
+            .arg(Arg::with_name("password-stdin").long("password-stdin").help("Read the new password from the first line of stdin. A random password is generated and printed if omitted")))
+        .subcommand(SubCommand::with_name("grant-role")
+            .about("Assign roles to a user, e.g. the admin role to the first administrator")
+            .arg(Arg::with_name("user").long("user").takes_value(true).required(true).help("Email address, username or ID of the user"))
+            .arg(Arg::with_name("role").long("role").takes_value(true).required(true).multiple(true).number_of_values(1).help("Role to assign to the user, may be repeated")))
+        .subcommand(SubCommand::with_name("revoke-sessions")
+            .about("Revoke all sessions of a user")
+            .arg(Arg::with_name("user").long("user").takes_value(true).required(true).help("Email address, username or ID of the user")))
+        .subcommand(SubCommand::with_name("import-users")
+            .about("Create users in bulk from a CSV or JSON lines file with password hashes of another system, printing a report")
+            .arg(Arg::with_name("file").long("file").takes_value(true).required(true).help("The file to import"))
+            .arg(Arg::with_name("format").long("format").takes_value(true).possible_values(&["csv", "jsonl"]).help("Format of the file. Taken from the file's extension if omitted")))
+        .subcommand(SubCommand::with_name("export-users")
+            .about("Export users into a backup archive. Set BACKUP_PASSPHRASE to encrypt it")
+            .arg(Arg::with_name("output").long("output").takes_value(true).required(true).help("The file to write the archive to, or '-' for stdout"))
+            .arg(Arg::with_name("include-sessions").long("include-sessions").help("Include the users' unexpired sessions"))
+            .arg(Arg::with_name("org").long("org").takes_value(true).help("Only export the members of this organization")))
+        .subcommand(SubCommand::with_name("restore-users")
+            .about("Restore users from a backup archive. Set BACKUP_PASSPHRASE if the archive is encrypted")
+            .arg(Arg::with_name("input").long("input").takes_value(true).required(true).help("The archive to restore"))
+            .arg(Arg::with_name("dry-run").long("dry-run").help("Only validate the archive")))
+        .subcommand(SubCommand::with_name("purge-expired")
+            .about("Purge expired rows according to the retention policy once, and exit"))
+        .subcommand(SubCommand::with_name("generate-config")
+            .about("Print an example configuration file with freshly generated secrets"))
+}
+
+/// Run a maintenance subcommand. Returns the exit code
+pub fn run(name: &str, matches: &ArgMatches) -> i32 {
+    //Generating a configuration must work before there is one
+    if name == "generate-config" {
+        return generate_config();
+    }
+
+    let mut environment = Environment::new();
+    if let Err(e) = environment.email_encryption.validate() {
+        eprintln!("Invalid email encryption configuration: {}.", e);
+        return 1;
+    }
+
+    if let Err(e) = environment.password_policy.load_blocklist() {
+        eprintln!("Unable to read the password blocklist: {}.", e);
+        return 1;
+    }
+
+    let database = Database::new(&environment);
+    match name {
+        "migrate" => migrate(&database, &environment),
+        "check-db" => check_db(&database),
+        "purge-expired" => purge_expired(&database, &environment),
+        "export-users" => export_users(&database, &environment, matches),
+        "restore-users" => restore_users(&database, &environment, matches),
+        _ => {
+            //Everything else works on users, which needs an up to date schema
+            if !matches!(database.check_db(), Ok(true)) {
+                eprintln!("The database schema is not up to date. Run the 'migrate' subcommand first.");
+                return 1;
+            }
+
+            let conn_wrapped = database.pool.get_conn();
+            if conn_wrapped.is_err() {
+                eprintln!("An error occurred (cli.rs): {:?}", conn_wrapped.err().unwrap());
+                return 1;
+            }
+            let mut conn = conn_wrapped.unwrap();
+
+            match name {
+                "create-user" => create_user(&mut conn, &environment, matches),
+                "reset-password" => reset_password(&mut conn, &environment, matches),
+                "grant-role" => grant_role(&mut conn, &environment, matches),
+                "revoke-sessions" => revoke_sessions(&mut conn, &environment, matches),
+                "import-users" => import_users(&mut conn, &environment, matches),
+                _ => unreachable!("Unknown subcommand '{}'", name)
+            }
+        }
+    }
+}
+
+fn generate_config() -> i32 {
+    let config = serde_yaml::to_string(&Environment::example());
+    if config.is_err() {
+        eprintln!("An error occurred (cli.rs): {:?}", config.err().unwrap());
+        return 1;
+    }
+
+    print!("{}", config.unwrap());
+    0
+}
+
+fn migrate(database: &Database, environment: &Environment) -> i32 {
+    if database.init_db(environment).is_err() {
+        eprintln!("Something went wrong migrating the database.");
+        return 1;
+    }
+
+    println!("Database is at schema version {}.", migrations::latest_version());
+    0
+}
+
+fn check_db(database: &Database) -> i32 {
+    match database.check_db() {
+        Ok(true) => {
+            println!("Database is up to date at schema version {}.", migrations::latest_version());
+            0
+        },
+        Ok(false) => 1,
+        Err(_) => {
+            eprintln!("Something went wrong checking the database.");
+            1
+        }
+    }
+}
+
+fn purge_expired(database: &Database, environment: &Environment) -> i32 {
+    let reaped = reaper::reap_expired(database, &environment.retention);
+    if reaped.is_err() {
+        eprintln!("Something went wrong purging expired rows.");
+        return 1;
+    }
+
+    for (table, count) in reaped.unwrap() {
+        println!("Purged {} expired row(s) from '{}'", count, table);
+    }
+
+    0
+}
+
+/// The passphrase of backup archives is taken from the environment, so it doesn't end up in the shell history
+fn backup_passphrase() -> Option<String> {
+    std::env::var("BACKUP_PASSPHRASE").ok().filter(|passphrase| !passphrase.is_empty())
+}
+
+fn export_users(database: &Database, environment: &Environment, matches: &ArgMatches) -> i32 {
+    let options = ExportOptions {
+        include_sessions:   matches.is_present("include-sessions"),
+        org_id:             matches.value_of("org").map(str::to_string),
+        passphrase:         backup_passphrase()
+    };
+
+    if options.passphrase.is_none() {
+        eprintln!("Warning: BACKUP_PASSPHRASE is not set, the archive will contain email addresses and password hashes unencrypted.");
+    }
+
+    let output = matches.value_of("output").unwrap();
+    let exported = if output == "-" {
+        backup::export(database, environment, &mut std::io::stdout().lock(), &options)
+    } else {
+        let file = std::fs::File::create(output);
+        if file.is_err() {
+            eprintln!("Unable to create '{}': {:?}", output, file.err().unwrap());
+            return 1;
+        }
+
+        backup::export(database, environment, &mut std::io::BufWriter::new(file.unwrap()), &options)
+    };
+
+    match exported {
+        Ok((users, sessions)) => {
+            eprintln!("Exported {} user(s) and {} session(s).", users, sessions);
+            0
+        },
+        Err(_) => {
+            eprintln!("Something went wrong exporting users, the archive is incomplete.");
+            1
+        }
+    }
+}
+
+fn restore_users(database: &Database, environment: &Environment, matches: &ArgMatches) -> i32 {
+    let dry_run = matches.is_present("dry-run");
+    let report = backup::restore(database, environment, matches.value_of("input").unwrap(), backup_passphrase().as_deref(), dry_run);
+
+    let report = match report {
+        Ok(report) => report,
+        Err(e) => {
+            eprintln!("{}", e);
+            return 1;
+        }
+    };
+
+    if dry_run {
+        println!("The archive is valid.");
+        return 0;
+    }
+
+    for conflict in report.conflicts.iter() {
+        eprintln!("{}", conflict);
+    }
+
+    println!("Restored {} user(s), {} already present, {} conflict(s). Restored {} session(s) and {} membership(s).", report.restored, report.unchanged, report.conflicts.len(), report.sessions, report.memberships);
+    if report.conflicts.is_empty() { 0 } else { 1 }
+}
+
+/// The password read from stdin with `--password-stdin`, or a random one which is printed.
+/// Passwords aren't taken as arguments, as those end up in the shell history and are visible to other users in `ps`
+fn password_or_generate(matches: &ArgMatches) -> Option<String> {
+    if !matches.is_present("password-stdin") {
+        let password: String = rand::thread_rng().sample_iter(&rand::distributions::Alphanumeric).take(24).map(char::from).collect();
+        println!("Generated password: {}", password);
+        return Some(password);
+    }
+
+    let mut line = String::new();
+    if let Err(e) = std::io::stdin().read_line(&mut line) {
+        eprintln!("Unable to read the password from stdin: {}", e);
+        return None;
+    }
+
+    let password = line.trim_end_matches(&['\r', '\n'][..]);
+    if password.is_empty() {
+        eprintln!("No password was given on stdin.");
+        return None;
+    }
+
+    Some(password.to_string())
+}
+
+/// Look up the user given with `--user`, printing an error if they don't exist
+fn find_user(conn: &mut PooledConn, environment: &Environment, matches: &ArgMatches) -> Option<String> {
+    let identifier = matches.value_of("user").unwrap();
+    match users::find(conn, environment, identifier) {
+        Ok(Some(user_id)) => Some(user_id),
+        Ok(None) => {
+            eprintln!("User '{}' not found.", identifier);
+            None
+        },
+        Err(_) => None
+    }
+}
+
+/// Print why a password was refused by the password policy. Returns whether it was
+fn refuse(violations: &[Violation]) -> bool {
+    for violation in violations {
+        eprintln!("Password refused: {}", violation.message());
+    }
+
+    !violations.is_empty()
+}
+
+fn create_user(conn: &mut PooledConn, environment: &Environment, matches: &ArgMatches) -> i32 {
+    let username = matches.value_of("username").map(username::normalize);
+    if let Some(username) = &username {
+        if !username::is_valid(username) {
+            eprintln!("Invalid username. Usernames are 3 to 32 characters long, and may only contain letters, digits, '_', '.' and '-'.");
+            return 1;
+        }
+    }
+
+    let password = match password_or_generate(matches) {
+        Some(password) => password,
+        None => return 1
+    };
+
+    //Generated passwords are random enough, only the ones an operator picked are checked
+    if matches.is_present("password-stdin") && refuse(&environment.password_policy.check(environment, &password, matches.value_of("email"))) {
+        return 1;
+    }
+
+    let user_id = match users::create(conn, environment, matches.value_of("email").unwrap(), username.as_deref(), &password) {
+        Ok(user_id) => user_id,
+        Err(CreateError::InvalidEmail) => {
+            eprintln!("Invalid email address.");
+            return 1;
+        },
+        Err(CreateError::EmailTaken) => {
+            eprintln!("An account with this email address already exists.");
+            return 1;
+        },
+        Err(CreateError::UsernameTaken) => {
+            eprintln!("Username is already taken.");
+            return 1;
+        },
+        Err(CreateError::Failed) => return 1
+    };
+
+    if assign_roles(conn, &user_id, matches).is_err() {
+        return 1;
+    }
+
+    println!("Created user {}", user_id);
+    0
+}
+
+/// Assign the roles given with `--role`. Roles which don't exist are skipped
+fn assign_roles(conn: &mut PooledConn, user_id: &str, matches: &ArgMatches) -> Result<(), ()> {
+    for role in matches.values_of("role").into_iter().flatten() {
+        let sql_assign_role = conn.exec_drop("INSERT IGNORE INTO user_roles (user_id, role) SELECT :user_id, name FROM roles WHERE name = :role", params! {
+            "user_id" => user_id,
+            "role" => role
+        });
+
+        if sql_assign_role.is_err() {
+            eprintln!("An error occurred (cli.rs): {:?}", sql_assign_role.err().unwrap());
+            return Err(());
+        }
+
+        if conn.affected_rows() == 0 {
+            eprintln!("Role '{}' does not exist or is already assigned, skipping.", role);
+        }
+    }
+
+    Ok(())
+}
+
+fn grant_role(conn: &mut PooledConn, environment: &Environment, matches: &ArgMatches) -> i32 {
+    let user_id = match find_user(conn, environment, matches) {
+        Some(user_id) => user_id,
+        None => return 1
+    };
+
+    if assign_roles(conn, &user_id, matches).is_err() {
+        return 1;
+    }
+
+    println!("Assigned the roles to user {}", user_id);
+    0
+}
+
+fn reset_password(conn: &mut PooledConn, environment: &Environment, matches: &ArgMatches) -> i32 {
+    let user_id = match find_user(conn, environment, matches) {
+        Some(user_id) => user_id,
+        None => return 1
+    };
+
+    let password = match password_or_generate(matches) {
+        Some(password) => password,
+        None => return 1
+    };
+
+    if matches.is_present("password-stdin") {
+        let email = match users::fetch_email(conn, &environment.email_encryption, &user_id) {
+            Ok(email) => email,
+            Err(_) => return 1
+        };
+
+        let mut violations = environment.password_policy.check(environment, &password, Some(&email));
+        match environment.password_policy.is_reused(conn, environment, &user_id, &password) {
+            Ok(true) => violations.push(Violation::Reused),
+            Ok(false) => {},
+            Err(_) => return 1
+        }
+
+        if refuse(&violations) {
+            return 1;
+        }
+    }
+
+    if users::set_password(conn, &user_id, &password, environment).is_err() {
+        return 1;
+    }
+
+    match sessions::revoke_all(conn, &user_id) {
+        Ok(count) => {
+            println!("Password changed, revoked {} session(s).", count);
+            0
+        },
+        Err(_) => 1
+    }
+}
+
+fn revoke_sessions(conn: &mut PooledConn, environment: &Environment, matches: &ArgMatches) -> i32 {
+    let user_id = match find_user(conn, environment, matches) {
+        Some(user_id) => user_id,
+        None => return 1
+    };
+
+    match sessions::revoke_all(conn, &user_id) {
+        Ok(count) => {
+            println!("Revoked {} session(s).", count);
+            0
+        },
+        Err(_) => 1
+    }
+}
+
+fn import_users(conn: &mut PooledConn, environment: &Environment, matches: &ArgMatches) -> i32 {
+    let file = matches.value_of("file").unwrap();
+    let format = matches.value_of("format")
+        .or_else(|| std::path::Path::new(file).extension().and_then(|extension| extension.to_str()))
+        .and_then(|format| format.parse::<ImportFormat>().ok());
+
+    let format = match format {
+        Some(format) => format,
+        None => {
+            eprintln!("Unable to tell the format of '{}', use --format.", file);
+            return 1;
+        }
+    };
+
+    let input = std::fs::read_to_string(file);
+    if input.is_err() {
+        eprintln!("Unable to read '{}': {:?}", file, input.err().unwrap());
+        return 1;
+    }
+
+    let report = import::import(conn, environment, &input.unwrap(), format);
+    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+    eprintln!("Created {} user(s), {} conflict(s), {} error(s).", report.created, report.conflicts, report.errors);
+
+    if report.conflicts > 0 || report.errors > 0 {
+        return 1;
+    }
+
+    0
+}
@@ -0,0 +1,110 @@
+use crate::appdata::AppData;
+use crate::ipfilter::Cidr;
+
+use std::net::IpAddr;
+use actix_web::{web, HttpRequest};
+use actix_web::http::HeaderMap;
+
+/// The IP address of the client which sent the request, looking through the configured trusted proxies
+pub fn ip(req: &HttpRequest) -> Option<IpAddr> {
+    let peer = req.peer_addr().map(|addr| addr.ip());
+
+    match req.app_data::<web::Data<AppData>>() {
+        Some(data) => resolve(peer, req.headers(), data.ip_filter.trusted_proxies()),
+        None => peer
+    }
+}
+
+/// The IP address of the client, given the address of the peer which connected to us. If the peer is a trusted proxy,
+/// `X-Forwarded-For` is followed from the nearest hop back, up to the first address which isn't a trusted proxy.
+/// Anything before that address could have been made up by the client
+pub fn resolve(peer: Option<IpAddr>, headers: &HeaderMap, trusted_proxies: &[Cidr]) -> Option<IpAddr> {
+    let mut ip = peer?;
+    let is_trusted = |ip: &IpAddr| trusted_proxies.iter().any(|proxy| proxy.contains(ip));
+
+    //Every proxy appends the address it received the request from, so the nearest hop is last
+    let forwarded: Vec<&str> = headers.get_all("X-Forwarded-For")
+        .filter_map(|header| header.to_str().ok())
+        .flat_map(|header| header.split(','))
+        .map(str::trim)
+        .collect();
+
+    for hop in forwarded.iter().rev() {
+        if !is_trusted(&ip) {
+            break;
+        }
+
+        match hop.parse::<IpAddr>() {
+            Ok(hop) => ip = hop,
+            Err(_) => break
+        }
+    }
+
+    Some(ip)
+}
+
+/// The client's `User-Agent` header, truncated to 255 characters
+pub fn user_agent(req: &HttpRequest) -> Option<String> {
+    let user_agent = req.headers().get("User-Agent")?.to_str().ok()?;
+    Some(user_agent.chars().take(255).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::{HeaderName, HeaderValue};
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn forwarded_for(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append(HeaderName::from_static("x-forwarded-for"), HeaderValue::from_str(value).unwrap());
+        }
+
+        headers
+    }
+
+    fn proxies(proxies: &[&str]) -> Vec<Cidr> {
+        proxies.iter().map(|proxy| proxy.parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn ignores_forwarded_for_without_trusted_proxies() {
+        let headers = forwarded_for(&["198.51.100.1"]);
+        assert_eq!(resolve(Some(ip("192.0.2.1")), &headers, &[]), Some(ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn ignores_forwarded_for_from_untrusted_peers() {
+        let headers = forwarded_for(&["198.51.100.1"]);
+        assert_eq!(resolve(Some(ip("192.0.2.1")), &headers, &proxies(&["10.0.0.0/8"])), Some(ip("192.0.2.1")));
+    }
+
+    #[test]
+    fn follows_a_chain_of_trusted_proxies() {
+        let headers = forwarded_for(&["198.51.100.1, 10.0.0.2", "10.0.0.3"]);
+        assert_eq!(resolve(Some(ip("10.0.0.1")), &headers, &proxies(&["10.0.0.0/8"])), Some(ip("198.51.100.1")));
+    }
+
+    #[test]
+    fn stops_at_the_first_untrusted_hop() {
+        //The client made up 10.9.9.9 and 203.0.113.9, only the hop appended by the trusted proxy can be relied on
+        let headers = forwarded_for(&["10.9.9.9, 203.0.113.9, 198.51.100.1"]);
+        assert_eq!(resolve(Some(ip("10.0.0.1")), &headers, &proxies(&["10.0.0.0/8"])), Some(ip("198.51.100.1")));
+    }
+
+    #[test]
+    fn stops_at_an_invalid_hop() {
+        let headers = forwarded_for(&["198.51.100.1, garbage"]);
+        assert_eq!(resolve(Some(ip("10.0.0.1")), &headers, &proxies(&["10.0.0.0/8"])), Some(ip("10.0.0.1")));
+    }
+
+    #[test]
+    fn has_no_address_without_a_peer() {
+        let headers = forwarded_for(&["198.51.100.1"]);
+        assert_eq!(resolve(None, &headers, &proxies(&["10.0.0.0/8"])), None);
+    }
+}
@@ -0,0 +1,350 @@
+use crate::appdata::{Database, Environment};
+
+use std::collections::HashMap;
+use aes_gcm::{Aes256Gcm, Nonce};
+use aes_gcm::aead::{Aead, NewAead, Payload};
+use hmac::{Hmac, Mac, NewMac};
+use mysql::prelude::Queryable;
+use mysql::{Row, Params, params};
+use openssl::bn::BigNum;
+use openssl::ecdsa::EcdsaSig;
+use openssl::hash::MessageDigest;
+use openssl::pkey::{Id, PKey, Public};
+use openssl::sign::Verifier;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sha2::{Sha256, Digest};
+
+/// Version prefix of the encrypted value format:
+/// `v1$<key id>$<base64(nonce || wrapped data key)>$<base64(nonce || ciphertext)>`
+const FORMAT_VERSION: &str = "v1";
+const SEPARATOR: char = '$';
+const NONCE_LENGTH: usize = 12;
+
+/// Configuration for encrypting PII columns at rest.
+///
+/// Every value is encrypted with its own random data key, which in turn is wrapped with one of the key encryption keys.
+/// Rotating to a new key encryption key thus only requires re-wrapping the data keys
+#[derive(Deserialize, Serialize, Clone, Default)]
+pub struct EncryptionConfig {
+    /// ID of the key used to wrap new data keys
+    pub active_key_id:  String,
+    /// Key encryption keys by their ID, base64 encoded 256-bit keys.
+    /// Retired keys must be kept here until all values have been re-wrapped with the active key
+    pub keys:           HashMap<String, String>,
+    /// Base64 encoded key for the deterministic blind index, used to look up values without decrypting them.
+    /// Changing this key invalidates every index
+    pub blind_index_key: String
+}
+
+impl EncryptionConfig {
+    /// Generate a configuration with a fresh random key, used for the example configuration file
+    pub fn generate() -> EncryptionConfig {
+        let mut keys = HashMap::new();
+        keys.insert("1".to_string(), base64::encode(random_bytes(32)));
+
+        EncryptionConfig {
+            active_key_id:      "1".to_string(),
+            keys,
+            blind_index_key:    base64::encode(random_bytes(32))
+        }
+    }
+
+    pub fn from_vars() -> Result<EncryptionConfig, &'static str> {
+        use std::env::var;
+
+        let active_key_id = var("EMAIL_ENCRYPTION_KEY_ID").map_err(|_| "EMAIL_ENCRYPTION_KEY_ID")?;
+        let blind_index_key = var("EMAIL_BLIND_INDEX_KEY").map_err(|_| "EMAIL_BLIND_INDEX_KEY")?;
+
+        //EMAIL_ENCRYPTION_KEYS is formatted as 'id:key,id:key'
+        let keys_var = var("EMAIL_ENCRYPTION_KEYS").map_err(|_| "EMAIL_ENCRYPTION_KEYS")?;
+        let keys = keys_var.split(',')
+            .filter(|entry| !entry.trim().is_empty())
+            .map(|entry| {
+                let mut parts = entry.splitn(2, ':');
+                let id = parts.next().unwrap_or_default().trim().to_string();
+                let key = parts.next().unwrap_or_default().trim().to_string();
+                (id, key)
+            })
+            .collect();
+
+        Ok(EncryptionConfig {
+            active_key_id,
+            keys,
+            blind_index_key
+        })
+    }
+
+    /// Verify that all keys are usable. Returns a description of the first problem found
+    pub fn validate(&self) -> Result<(), String> {
+        if !self.keys.contains_key(&self.active_key_id) {
+            return Err(format!("Active encryption key '{}' is not configured", self.active_key_id));
+        }
+
+        for (id, key) in self.keys.iter() {
+            if id.is_empty() || id.contains(SEPARATOR) {
+                return Err(format!("Encryption key ID '{}' must be non-empty and may not contain '{}'", id, SEPARATOR));
+            }
+
+            if decode_key(key).is_none() {
+                return Err(format!("Encryption key '{}' is not a base64 encoded 256-bit key", id));
+            }
+        }
+
+        if decode_key(&self.blind_index_key).is_none() {
+            return Err("Blind index key is not a base64 encoded 256-bit key".to_string());
+        }
+
+        Ok(())
+    }
+
+    /// Encrypt a value with a new data key, wrapped with the active key encryption key
+    pub fn encrypt(&self, plaintext: &str) -> Result<String, ()> {
+        let kek = self.key(&self.active_key_id)?;
+        let data_key = random_bytes(32);
+
+        let wrapped_key = seal(&kek, &data_key, &[])?;
+        let ciphertext = seal(&data_key, plaintext.as_bytes(), &[])?;
+
+        Ok(format!("{v}{s}{kid}{s}{key}{s}{ct}",
+            v =     FORMAT_VERSION,
+            s =     SEPARATOR,
+            kid =   self.active_key_id,
+            key =   base64::encode(wrapped_key),
+            ct =    base64::encode(ciphertext)
+        ))
+    }
+
+    /// Decrypt a value produced by `encrypt`
+    pub fn decrypt(&self, value: &str) -> Result<String, ()> {
+        let (key_id, wrapped_key, ciphertext) = parse(value)?;
+        let kek = self.key(key_id)?;
+
+        let data_key = open(&kek, &wrapped_key, &[])?;
+        let plaintext = open(&data_key, &ciphertext, &[])?;
+
+        String::from_utf8(plaintext).map_err(|_| ())
+    }
+
+    /// Whether the value's data key is wrapped with a key other than the active key
+    pub fn needs_rewrap(&self, value: &str) -> bool {
+        match parse(value) {
+            Ok((key_id, _, _)) => key_id != self.active_key_id,
+            Err(_) => false
+        }
+    }
+
+    /// Re-wrap the value's data key with the active key encryption key. The ciphertext itself is left untouched
+    pub fn rewrap(&self, value: &str) -> Result<String, ()> {
+        let (key_id, wrapped_key, ciphertext) = parse(value)?;
+        let old_kek = self.key(key_id)?;
+        let new_kek = self.key(&self.active_key_id)?;
+
+        let data_key = open(&old_kek, &wrapped_key, &[])?;
+        let rewrapped_key = seal(&new_kek, &data_key, &[])?;
+
+        Ok(format!("{v}{s}{kid}{s}{key}{s}{ct}",
+            v =     FORMAT_VERSION,
+            s =     SEPARATOR,
+            kid =   self.active_key_id,
+            key =   base64::encode(rewrapped_key),
+            ct =    base64::encode(ciphertext)
+        ))
+    }
+
+    /// Compute the deterministic blind index of a value, as a hex string.
+    /// Values should be normalized first, as the index is only equal for byte-for-byte equal values
+    pub fn blind_index(&self, value: &str) -> String {
+        let key = decode_key(&self.blind_index_key).expect("Blind index key was validated at startup");
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(&key).unwrap();
+        mac.update(value.as_bytes());
+
+        mac.finalize().into_bytes().iter().map(|byte| format!("{:02x}", byte)).collect()
+    }
+
+    fn key(&self, key_id: &str) -> Result<Vec<u8>, ()> {
+        let key = self.keys.get(key_id).and_then(|key| decode_key(key));
+        if key.is_none() {
+            eprintln!("Encryption key '{}' is not configured (crypto.rs)", key_id);
+            return Err(());
+        }
+
+        Ok(key.unwrap())
+    }
+}
+
+/// Re-wrap every encrypted email address whose data key is not wrapped with the active key
+pub fn rewrap_emails(database: &Database, environment: &Environment) -> Result<usize, ()> {
+    let config = &environment.email_encryption;
+
+    let conn_wrapped = database.pool.get_conn();
+    if conn_wrapped.is_err() {
+        eprintln!("An error occurred (crypto.rs): {:?}", conn_wrapped.err().unwrap());
+        return Err(());
+    }
+    let mut conn = conn_wrapped.unwrap();
+
+    let active_prefix = format!("{}{}{}{}%", FORMAT_VERSION, SEPARATOR, config.active_key_id, SEPARATOR);
+    let sql_fetch_users = conn.exec::<Row, &str, Params>("SELECT user_id, email FROM users WHERE email NOT LIKE :active_prefix", params! {
+        "active_prefix" => active_prefix
+    });
+
+    if sql_fetch_users.is_err() {
+        eprintln!("An error occurred (crypto.rs): {:?}", sql_fetch_users.err().unwrap());
+        return Err(());
+    }
+
+    let mut rewrapped = 0;
+    for row in sql_fetch_users.unwrap() {
+        let user_id = row.get::<String, &str>("user_id").unwrap();
+        let email = row.get::<String, &str>("email").unwrap();
+
+        if !config.needs_rewrap(&email) {
+            continue;
+        }
+
+        let email_rewrapped = config.rewrap(&email);
+        if email_rewrapped.is_err() {
+            eprintln!("Unable to re-wrap the email address of user '{}' (crypto.rs)", user_id);
+            return Err(());
+        }
+
+        let sql_update_email = conn.exec_drop("UPDATE users SET email = :email WHERE user_id = :user_id", params! {
+            "email" => email_rewrapped.unwrap(),
+            "user_id" => user_id
+        });
+
+        if sql_update_email.is_err() {
+            eprintln!("An error occurred (crypto.rs): {:?}", sql_update_email.err().unwrap());
+            return Err(());
+        }
+
+        rewrapped += 1;
+    }
+
+    Ok(rewrapped)
+}
+
+/// Generate a random token, e.g. for a confirmation link. Only its hash should be stored
+pub fn random_token() -> String {
+    rand::thread_rng().sample_iter(&rand::distributions::Alphanumeric).take(64).map(char::from).collect()
+}
+
+/// Hash a token for storage, as a hex string
+pub fn hash_token(token: &str) -> String {
+    Sha256::digest(token.as_bytes()).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Compare two byte strings in time which only depends on their length, not on where they differ
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+
+    a.iter().zip(b.iter()).fold(0u8, |difference, (a, b)| difference | (a ^ b)) == 0
+}
+
+fn parse(value: &str) -> Result<(&str, Vec<u8>, Vec<u8>), ()> {
+    let parts: Vec<&str> = value.split(SEPARATOR).collect();
+    if parts.len() != 4 || parts[0] != FORMAT_VERSION {
+        return Err(());
+    }
+
+    let wrapped_key = base64::decode(parts[2]).map_err(|_| ())?;
+    let ciphertext = base64::decode(parts[3]).map_err(|_| ())?;
+
+    Ok((parts[1], wrapped_key, ciphertext))
+}
+
+/// Encrypt with AES-256-GCM, prepending the random nonce to the output. `aad` is authenticated but not encrypted,
+/// and has to be passed to `open` again
+pub fn seal(key: &[u8], plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, ()> {
+    let cipher = Aes256Gcm::new_from_slice(key).map_err(|_| ())?;
+    let mut nonce = [0u8; NONCE_LENGTH];
+    rand::thread_rng().fill(&mut nonce);
+
+    let ciphertext = cipher.encrypt(&Nonce::from(nonce), Payload { msg: plaintext, aad }).map_err(|_| ())?;
+
+    let mut output = nonce.to_vec();
+    output.extend(ciphertext);
+    Ok(output)
+}
+
+/// Decrypt the output of `seal`, with the same additional authenticated data
+pub fn open(key: &[u8], sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>, ()> {
+    if sealed.len() < NONCE_LENGTH {
+        return Err(());
+    }
+
+    let cipher = Aes256Gcm::new_from_slice(key).map_err(|_| ())?;
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LENGTH);
+    let mut nonce_bytes = [0u8; NONCE_LENGTH];
+    nonce_bytes.copy_from_slice(nonce);
+
+    cipher.decrypt(&Nonce::from(nonce_bytes), Payload { msg: ciphertext, aad }).map_err(|_| ())
+}
+
+/// Parse a base64 encoded DER SubjectPublicKeyInfo. Only P-256 ECDSA and Ed25519 keys are accepted
+pub fn parse_public_key(key_base64: &str) -> Option<PKey<Public>> {
+    let key = PKey::public_key_from_der(&base64::decode(key_base64).ok()?).ok()?;
+
+    match key.id() {
+        Id::EC if key.ec_key().ok()?.group().curve_name() == Some(openssl::nid::Nid::X9_62_PRIME256V1) => Some(key),
+        Id::ED25519 => Some(key),
+        _ => None
+    }
+}
+
+/// Verify a signature made with a key accepted by `parse_public_key`. ECDSA signatures use SHA-256, and may be either
+/// DER encoded or the raw `r || s` WebCrypto produces
+pub fn verify_signature(key: &PKey<Public>, message: &[u8], signature: &[u8]) -> bool {
+    let verified = if key.id() == Id::ED25519 {
+        Verifier::new_without_digest(key).and_then(|mut verifier| verifier.verify_oneshot(signature, message))
+    } else {
+        let signature = if signature.len() == 64 {
+            let (r, s) = signature.split_at(32);
+            match BigNum::from_slice(r).and_then(|r| EcdsaSig::from_private_components(r, BigNum::from_slice(s)?)).and_then(|signature| signature.to_der()) {
+                Ok(signature) => signature,
+                Err(_) => return false
+            }
+        } else {
+            signature.to_vec()
+        };
+
+        Verifier::new(MessageDigest::sha256(), key).and_then(|mut verifier| {
+            verifier.update(message)?;
+            verifier.verify(&signature)
+        })
+    };
+
+    verified.unwrap_or(false)
+}
+
+fn decode_key(key: &str) -> Option<Vec<u8>> {
+    match base64::decode(key) {
+        Ok(key) if key.len() == 32 => Some(key),
+        _ => None
+    }
+}
+
+pub fn random_bytes(length: usize) -> Vec<u8> {
+    let mut bytes = vec![0u8; length];
+    rand::thread_rng().fill(bytes.as_mut_slice());
+    bytes
+}
+
+/// HMAC-SHA256 of a message
+pub fn hmac_sha256(key: &[u8], message: &[u8]) -> Vec<u8> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(message);
+
+    mac.finalize().into_bytes().to_vec()
+}
+
+/// Derive a 256-bit key from a passphrase with PBKDF2-HMAC-SHA256
+pub fn derive_key(passphrase: &str, salt: &[u8], iterations: u32) -> Vec<u8> {
+    let mut key = vec![0u8; 32];
+    pbkdf2::pbkdf2::<Hmac<Sha256>>(passphrase.as_bytes(), salt, iterations, &mut key);
+    key
+}
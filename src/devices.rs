@@ -0,0 +1,196 @@
+use crate::appdata::Environment;
+use crate::{client, crypto, mail, sessions, users};
+
+use std::net::IpAddr;
+use actix_web::HttpRequest;
+use mysql::prelude::Queryable;
+use mysql::{PooledConn, Row, params};
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct DeviceAlertConfig {
+    /// Mail the owner of an account when it is logged in to from a new device or network
+    pub enabled:                bool,
+    /// How long the link reporting a login which wasn't the owner's can be used
+    pub report_validity_hours:  i64
+}
+
+impl Default for DeviceAlertConfig {
+    fn default() -> Self {
+        DeviceAlertConfig {
+            enabled:                true,
+            report_validity_hours:  72
+        }
+    }
+}
+
+impl DeviceAlertConfig {
+    pub fn from_vars() -> DeviceAlertConfig {
+        let default = DeviceAlertConfig::default();
+
+        DeviceAlertConfig {
+            enabled:                Environment::optional_var("DEVICE_ALERTS_ENABLED", "TRUE".to_string()) != "FALSE",
+            report_validity_hours:  Environment::optional_var("DEVICE_ALERTS_REPORT_VALIDITY_HOURS", default.report_validity_hours)
+        }
+    }
+}
+
+/// What a login is recognized by. Deliberately coarse, so browser updates and address changes within a network
+/// don't look like a new device
+pub struct Fingerprint {
+    /// Browser and operating system, e.g. 'Firefox on Windows'
+    pub user_agent_family:  String,
+    /// The /24 of an IPv4 address or the /48 of an IPv6 address. Empty if the address is unknown
+    pub ip_prefix:          String,
+    /// Hash of the user agent family and the device ID the client sent, if any
+    pub device:             String
+}
+
+impl Fingerprint {
+    pub fn from_request(req: &HttpRequest, device_id: Option<&str>) -> Fingerprint {
+        let user_agent_family = user_agent_family(client::user_agent(req).as_deref().unwrap_or_default());
+        let ip_prefix = client::ip(req).map(|ip| ip_prefix(&ip)).unwrap_or_default();
+        let device = crypto::hash_token(&format!("{}\n{}", user_agent_family, device_id.unwrap_or_default()));
+
+        Fingerprint {
+            user_agent_family,
+            ip_prefix,
+            device
+        }
+    }
+}
+
+/// Remember the device and network of a successful login. If the account has logged in before, but never from this
+/// device or network, its owner is mailed a link to report the login, which revokes its sessions
+pub fn record_login(conn: &mut PooledConn, environment: &Environment, user_id: &str, fingerprint: &Fingerprint) -> Result<(), ()> {
+    let sql_fetch_devices = conn.exec::<Row, &str, _>("SELECT device, ip_prefix FROM known_devices WHERE user_id = :user_id", params! {
+        "user_id" => user_id
+    });
+
+    if sql_fetch_devices.is_err() {
+        eprintln!("An error occurred (devices.rs): {:?}", sql_fetch_devices.err().unwrap());
+        return Err(());
+    }
+
+    let known_devices = sql_fetch_devices.unwrap();
+    let new_device = !known_devices.iter().any(|row| row.get::<String, &str>("device").unwrap() == fingerprint.device);
+    let new_network = !known_devices.iter().any(|row| row.get::<String, &str>("ip_prefix").unwrap() == fingerprint.ip_prefix);
+
+    let now = chrono::Utc::now().timestamp();
+    let sql_upsert_device = conn.exec_drop("INSERT INTO known_devices (user_id, device, ip_prefix, user_agent_family, first_seen, last_seen) VALUES (:user_id, :device, :ip_prefix, :user_agent_family, :now, :now) ON DUPLICATE KEY UPDATE last_seen = :now", params! {
+        "user_id" => user_id,
+        "device" => &fingerprint.device,
+        "ip_prefix" => &fingerprint.ip_prefix,
+        "user_agent_family" => &fingerprint.user_agent_family,
+        "now" => now
+    });
+
+    if sql_upsert_device.is_err() {
+        eprintln!("An error occurred (devices.rs): {:?}", sql_upsert_device.err().unwrap());
+        return Err(());
+    }
+
+    //The first login of an account has nothing to compare with
+    if !environment.device_alerts.enabled || known_devices.is_empty() || !(new_device || new_network) {
+        return Ok(());
+    }
+
+    send_alert(conn, environment, user_id, fingerprint, new_device)
+}
+
+fn send_alert(conn: &mut PooledConn, environment: &Environment, user_id: &str, fingerprint: &Fingerprint, new_device: bool) -> Result<(), ()> {
+    let token = crypto::random_token();
+    let sql_insert_token = conn.exec_drop("INSERT INTO login_reports (token, user_id, expiry) VALUES (:token, :user_id, :expiry)", params! {
+        "token" => crypto::hash_token(&token),
+        "user_id" => user_id,
+        "expiry" => chrono::Utc::now().timestamp() + environment.device_alerts.report_validity_hours * 3600
+    });
+
+    if sql_insert_token.is_err() {
+        eprintln!("An error occurred (devices.rs): {:?}", sql_insert_token.err().unwrap());
+        return Err(());
+    }
+
+    let location = if fingerprint.ip_prefix.is_empty() { "an unknown network".to_string() } else { format!("the network {}", fingerprint.ip_prefix) };
+    let what = if new_device { "a new device" } else { "a new network" };
+
+    let email = users::fetch_email(conn, &environment.email_encryption, user_id)?;
+    let mail_config = &environment.mail;
+    mail::send(mail_config, &email, &format!("New login to your account from {}", what), &format!(
+        "Your account was just logged in to from {} ({}, from {}).\n\nIf this was you, you can ignore this mail.\n\nIf it was not, open this link within {} hours to log out everywhere. You will have to change your password before using your account again:\n{}",
+        what,
+        fingerprint.user_agent_family,
+        location,
+        environment.device_alerts.report_validity_hours,
+        mail_config.link(&format!("/auth/login/report?token={}", token))
+    ));
+
+    Ok(())
+}
+
+/// Report a login as not being the owner's, with the token of an alert link. Every session of the account is revoked,
+/// and its password has to be changed with a link mailed to the owner. Returns false if the token is unknown or expired
+pub fn report_with_token(conn: &mut PooledConn, environment: &Environment, token: &str) -> Result<bool, ()> {
+    let sql_fetch_token = conn.exec_first::<String, &str, _>("SELECT user_id FROM login_reports WHERE token = :token AND expiry > :now", params! {
+        "token" => crypto::hash_token(token),
+        "now" => chrono::Utc::now().timestamp()
+    });
+
+    let user_id = match sql_fetch_token {
+        Ok(Some(user_id)) => user_id,
+        Ok(None) => return Ok(false),
+        Err(e) => {
+            eprintln!("An error occurred (devices.rs): {:?}", e);
+            return Err(());
+        }
+    };
+
+    //Whoever logged in may have logged in more than once, so every session goes
+    let revoked = sessions::revoke_all(conn, &user_id)?;
+    users::require_password_reset(conn, environment, &user_id)?;
+
+    let sql_delete_tokens = conn.exec_drop("DELETE FROM login_reports WHERE user_id = :user_id", params! {
+        "user_id" => &user_id
+    });
+
+    if sql_delete_tokens.is_err() {
+        eprintln!("An error occurred (devices.rs): {:?}", sql_delete_tokens.err().unwrap());
+        return Err(());
+    }
+
+    println!("A login to account '{}' was reported as not the owner's, revoked {} session(s) and required a password reset", user_id, revoked);
+    Ok(true)
+}
+
+/// The network an address belongs to, as the /24 of an IPv4 address or the /48 of an IPv6 address
+pub fn ip_prefix(ip: &IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => {
+            let octets = ip.octets();
+            format!("{}.{}.{}.0/24", octets[0], octets[1], octets[2])
+        },
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => ip_prefix(&IpAddr::V4(ip)),
+            None => {
+                let segments = ip.segments();
+                format!("{:x}:{:x}:{:x}::/48", segments[0], segments[1], segments[2])
+            }
+        }
+    }
+}
+
+/// Reduce a `User-Agent` header to the browser and operating system, e.g. 'Firefox on Windows'
+pub fn user_agent_family(user_agent: &str) -> String {
+    //Order matters, as e.g. Edge and Chrome both claim to be Safari too
+    let browsers = [("Edg/", "Edge"), ("OPR/", "Opera"), ("Firefox/", "Firefox"), ("Chrome/", "Chrome"), ("Chromium/", "Chromium"), ("Safari/", "Safari"), ("curl/", "curl")];
+    let systems = [("Android", "Android"), ("iPhone", "iOS"), ("iPad", "iOS"), ("Windows", "Windows"), ("Mac OS X", "macOS"), ("CrOS", "ChromeOS"), ("Linux", "Linux")];
+
+    let browser = browsers.iter().find(|(token, _)| user_agent.contains(token)).map(|(_, name)| *name).unwrap_or("Unknown browser");
+    let system = systems.iter().find(|(token, _)| user_agent.contains(token)).map(|(_, name)| *name);
+
+    match system {
+        Some(system) => format!("{} on {}", browser, system),
+        None => browser.to_string()
+    }
+}
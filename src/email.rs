@@ -0,0 +1,164 @@
+use crate::appdata::Environment;
+
+use regex::Regex;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use unicode_normalization::UnicodeNormalization;
+
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct NormalizationConfig {
+    /// Apply provider-specific rules, e.g. Gmail ignoring dots and '+' suffixes in the local part.
+    /// Changing this on an existing database requires the email index to be rebuilt
+    pub provider_rules:     bool
+}
+
+impl NormalizationConfig {
+    pub fn from_vars() -> NormalizationConfig {
+        NormalizationConfig {
+            provider_rules:     Environment::optional_var("EMAIL_PROVIDER_RULES", false)
+        }
+    }
+}
+
+/// Check if the email address is syntactically valid
+pub fn is_valid(email: &str) -> bool {
+    //Lazy static so the regular expression only gets compiled once
+    //Since compiling can take up to a couple milliseconds, we don't want it to happen on every request
+    lazy_static! {
+        static ref EMAIL_REGEX: Regex = Regex::new(r#"(([^<>()\[\]\\.,;:\s@"]+(\.[^<>()\[\]\\.,;:\s@"]+)*)|(".+"))@((\[[0-9]{1,3}\.[0-9]{1,3}\.[0-9]{1,3}\.[0-9]{1,3}])|(([a-zA-Z\-0-9]+\.)+[a-zA-Z]{2,}))"#).unwrap();
+    }
+
+    EMAIL_REGEX.is_match(email)
+}
+
+/// Validate an email address as entered by a user, returning its normalized form if it is valid.
+/// The normalized form is validated, so internationalized domains are checked in their ASCII form
+pub fn parse(email: &str, config: &NormalizationConfig) -> Option<String> {
+    let email_normalized = normalize(email, config).ok()?;
+    if !is_valid(&email_normalized) {
+        return None;
+    }
+
+    Some(email_normalized)
+}
+
+/// Normalize an email address to its canonical form, which identifies an account.
+///
+/// The address is trimmed, the local part is NFC normalized and case folded, and the domain is converted to its
+/// lowercase ASCII (IDNA) form. If enabled, provider-specific rules are applied on top of that.
+/// Returns an error if the address has no '@' or the domain is not a valid IDNA domain
+pub fn normalize(email: &str, config: &NormalizationConfig) -> Result<String, ()> {
+    let email = email.trim();
+
+    let at = email.rfind('@');
+    if at.is_none() {
+        return Err(());
+    }
+    let (local_part, domain) = email.split_at(at.unwrap());
+    let domain = &domain[1..];
+
+    if local_part.is_empty() || domain.is_empty() {
+        return Err(());
+    }
+
+    let mut local_part: String = local_part.nfc().collect::<String>().to_lowercase();
+    let mut domain = idna::domain_to_ascii(domain).map_err(|_| ())?;
+
+    if config.provider_rules {
+        apply_provider_rules(&mut local_part, &mut domain);
+    }
+
+    Ok(format!("{}@{}", local_part, domain))
+}
+
+/// The form an address is indexed and looked up by. This is its normalized form, or the trimmed lowercase address if it
+/// can't be normalized, e.g. an imported address with an invalid domain, so the owners of such accounts can still log in
+pub fn index_form(email: &str, config: &NormalizationConfig) -> String {
+    normalize(email, config).unwrap_or_else(|_| email.trim().to_lowercase())
+}
+
+fn apply_provider_rules(local_part: &mut String, domain: &mut String) {
+    //Sub-addressing: 'user+tag@' is delivered to 'user@'
+    let strips_tag = matches!(domain.as_str(), "gmail.com" | "googlemail.com" | "outlook.com" | "hotmail.com" | "live.com" | "icloud.com" | "fastmail.com" | "protonmail.com" | "proton.me");
+    if strips_tag {
+        if let Some(plus) = local_part.find('+') {
+            local_part.truncate(plus);
+        }
+    }
+
+    //Gmail ignores dots in the local part, and googlemail.com is an alias of gmail.com
+    if domain == "gmail.com" || domain == "googlemail.com" {
+        local_part.retain(|c| c != '.');
+        *domain = "gmail.com".to_string();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_provider_rules(provider_rules: bool) -> NormalizationConfig {
+        NormalizationConfig { provider_rules }
+    }
+
+    #[test]
+    fn trims_and_lowercases() {
+        let config = with_provider_rules(false);
+        assert_eq!(normalize("  Alice.Smith+News@Example.COM ", &config), Ok("alice.smith+news@example.com".to_string()));
+    }
+
+    #[test]
+    fn keeps_dots_and_tags_without_provider_rules() {
+        let config = with_provider_rules(false);
+        assert_eq!(normalize("a.b+c@gmail.com", &config), Ok("a.b+c@gmail.com".to_string()));
+    }
+
+    #[test]
+    fn converts_internationalized_domains_to_ascii() {
+        let config = with_provider_rules(false);
+        assert_eq!(normalize("user@Bücher.example", &config), Ok("user@xn--bcher-kva.example".to_string()));
+    }
+
+    #[test]
+    fn normalizes_the_local_part_to_nfc() {
+        let config = with_provider_rules(false);
+        assert_eq!(normalize("Jose\u{301}@example.com", &config), normalize("jos\u{e9}@example.com", &config));
+    }
+
+    #[test]
+    fn rejects_addresses_without_both_parts() {
+        let config = with_provider_rules(false);
+        assert!(normalize("example.com", &config).is_err());
+        assert!(normalize("@example.com", &config).is_err());
+        assert!(normalize("user@", &config).is_err());
+    }
+
+    #[test]
+    fn strips_gmail_dots_tags_and_alias_domain() {
+        let config = with_provider_rules(true);
+        assert_eq!(normalize("A.Lice+news@googlemail.com", &config), Ok("alice@gmail.com".to_string()));
+        assert_eq!(normalize("a.lice@gmail.com", &config), Ok("alice@gmail.com".to_string()));
+    }
+
+    #[test]
+    fn strips_tags_but_keeps_dots_for_other_providers() {
+        let config = with_provider_rules(true);
+        assert_eq!(normalize("first.last+shop@outlook.com", &config), Ok("first.last@outlook.com".to_string()));
+        assert_eq!(normalize("first.last+shop@proton.me", &config), Ok("first.last@proton.me".to_string()));
+    }
+
+    #[test]
+    fn leaves_other_domains_alone() {
+        let config = with_provider_rules(true);
+        assert_eq!(normalize("first.last+shop@example.com", &config), Ok("first.last+shop@example.com".to_string()));
+    }
+
+    #[test]
+    fn indexes_unnormalizable_addresses_as_lowercase() {
+        let config = with_provider_rules(false);
+        assert_eq!(index_form(" User@ ", &config), "user@");
+        assert_eq!(index_form("No-At-Sign", &config), "no-at-sign");
+        assert_eq!(index_form("Alice@Example.com", &config), "alice@example.com");
+    }
+}
@@ -0,0 +1,2 @@
+pub mod roles;
+pub mod users;
\ No newline at end of file
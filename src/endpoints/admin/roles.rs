@@ -0,0 +1,277 @@
+use crate::appdata::AppData;
+use crate::rbac;
+
+use actix_web::{web, get, put, delete, HttpRequest, HttpResponse};
+use mysql::prelude::Queryable;
+use mysql::{Row, TxOpts, params};
+use regex::Regex;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize)]
+pub struct RoleRequest {
+    description:    Option<String>,
+    permissions:    Vec<String>
+}
+
+#[derive(Serialize)]
+pub struct Role {
+    name:           String,
+    description:    Option<String>,
+    permissions:    Vec<String>
+}
+
+#[derive(Serialize, Default)]
+pub struct RolesResponse {
+    status:     i16,
+    message:    Option<&'static str>,
+    roles:      Option<Vec<Role>>
+}
+
+#[derive(Serialize)]
+pub struct RoleResponse {
+    status:     i16,
+    message:    Option<&'static str>
+}
+
+fn is_valid_permission(permission: &str) -> bool {
+    lazy_static! {
+        static ref PERMISSION_REGEX: Regex = Regex::new(r"^(\*|[a-z0-9_]+(\.[a-z0-9_]+)*(\.\*)?)$").unwrap();
+    }
+
+    permission.len() <= 128 && PERMISSION_REGEX.is_match(permission)
+}
+
+/// List all roles and their permissions
+#[get("/admin/roles")]
+pub async fn get_roles(data: web::Data<AppData>, req: HttpRequest) -> HttpResponse {
+    let conn_wrapped = data.database.pool.get_conn();
+    if conn_wrapped.is_err() {
+        eprintln!("An error occurred (roles.rs): {:?}", conn_wrapped.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+    let mut conn = conn_wrapped.unwrap();
+
+    if let Err(response) = rbac::require_permission(&mut conn, &req, rbac::ROLES_READ) {
+        return response;
+    }
+
+    let sql_fetch_roles = conn.query::<Row, &str>("SELECT roles.name, roles.description, role_permissions.permission FROM roles LEFT JOIN role_permissions ON role_permissions.role = roles.name ORDER BY roles.name, role_permissions.permission");
+    if sql_fetch_roles.is_err() {
+        eprintln!("An error occurred (roles.rs): {:?}", sql_fetch_roles.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    //Rows are ordered by role, so permissions of the same role are adjacent
+    let mut roles: Vec<Role> = Vec::new();
+    for row in sql_fetch_roles.unwrap() {
+        let name = row.get::<String, &str>("name").unwrap();
+        let permission = row.get::<Option<String>, &str>("permission").unwrap();
+
+        if roles.last().map(|role| role.name != name).unwrap_or(true) {
+            roles.push(Role {
+                name,
+                description: row.get::<Option<String>, &str>("description").unwrap(),
+                permissions: Vec::new()
+            });
+        }
+
+        if let Some(permission) = permission {
+            roles.last_mut().unwrap().permissions.push(permission);
+        }
+    }
+
+    let response = RolesResponse { status: 200, message: None, roles: Some(roles) };
+    HttpResponse::Ok().json(&response)
+}
+
+/// Create a role, or replace the description and permissions of an existing role
+#[put("/admin/roles/{name}")]
+pub async fn put_role(data: web::Data<AppData>, req: HttpRequest, name: web::Path<String>, body: web::Json<RoleRequest>) -> HttpResponse {
+    let name = name.into_inner();
+    if !rbac::is_valid_role(&name) {
+        let response = RoleResponse { status: 400, message: Some("Invalid role name.") };
+        return HttpResponse::Ok().json(&response);
+    }
+
+    if name == rbac::ADMIN_ROLE {
+        let response = RoleResponse { status: 403, message: Some("The admin role can't be changed.") };
+        return HttpResponse::Ok().json(&response);
+    }
+
+    if !body.permissions.iter().all(|permission| is_valid_permission(permission)) {
+        let response = RoleResponse { status: 400, message: Some("Invalid permission.") };
+        return HttpResponse::Ok().json(&response);
+    }
+
+    let conn_wrapped = data.database.pool.get_conn();
+    if conn_wrapped.is_err() {
+        eprintln!("An error occurred (roles.rs): {:?}", conn_wrapped.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+    let mut conn = conn_wrapped.unwrap();
+
+    if let Err(response) = rbac::require_permission(&mut conn, &req, rbac::ROLES_MANAGE) {
+        return response;
+    }
+
+    let tx = conn.start_transaction(TxOpts::default());
+    if tx.is_err() {
+        eprintln!("An error occurred (roles.rs): {:?}", tx.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+    let mut tx = tx.unwrap();
+
+    let sql_upsert_role = tx.exec_drop("INSERT INTO roles (name, description) VALUES (:name, :description) ON DUPLICATE KEY UPDATE description = VALUES(description)", params! {
+        "name" => name.clone(),
+        "description" => body.description.clone()
+    });
+
+    if sql_upsert_role.is_err() {
+        eprintln!("An error occurred (roles.rs): {:?}", sql_upsert_role.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let sql_delete_permissions = tx.exec_drop("DELETE FROM role_permissions WHERE role = :role", params! {
+        "role" => name.clone()
+    });
+
+    if sql_delete_permissions.is_err() {
+        eprintln!("An error occurred (roles.rs): {:?}", sql_delete_permissions.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let sql_insert_permissions = tx.exec_batch("INSERT IGNORE INTO role_permissions (role, permission) VALUES (:role, :permission)", body.permissions.iter().map(|permission| params! {
+        "role" => name.clone(),
+        "permission" => permission
+    }));
+
+    if sql_insert_permissions.is_err() {
+        eprintln!("An error occurred (roles.rs): {:?}", sql_insert_permissions.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let commit = tx.commit();
+    if commit.is_err() {
+        eprintln!("An error occurred (roles.rs): {:?}", commit.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let response = RoleResponse { status: 200, message: None };
+    HttpResponse::Ok().json(&response)
+}
+
+/// Delete a role, revoking it from every user it was assigned to
+#[delete("/admin/roles/{name}")]
+pub async fn delete_role(data: web::Data<AppData>, req: HttpRequest, name: web::Path<String>) -> HttpResponse {
+    let name = name.into_inner();
+    if name == rbac::ADMIN_ROLE {
+        let response = RoleResponse { status: 403, message: Some("The admin role can't be changed.") };
+        return HttpResponse::Ok().json(&response);
+    }
+
+    let conn_wrapped = data.database.pool.get_conn();
+    if conn_wrapped.is_err() {
+        eprintln!("An error occurred (roles.rs): {:?}", conn_wrapped.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+    let mut conn = conn_wrapped.unwrap();
+
+    if let Err(response) = rbac::require_permission(&mut conn, &req, rbac::ROLES_MANAGE) {
+        return response;
+    }
+
+    for (table, column) in &[("user_roles", "role"), ("role_permissions", "role"), ("roles", "name")] {
+        let sql_delete = conn.exec_drop(format!("DELETE FROM {} WHERE {} = :name", table, column), params! {
+            "name" => name.clone()
+        });
+
+        if sql_delete.is_err() {
+            eprintln!("An error occurred (roles.rs): {:?}", sql_delete.err().unwrap());
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    let response = RoleResponse { status: 200, message: None };
+    HttpResponse::Ok().json(&response)
+}
+
+/// Assign a role to a user
+#[put("/admin/users/{user_id}/roles/{role}")]
+pub async fn put_user_role(data: web::Data<AppData>, req: HttpRequest, path: web::Path<(String, String)>) -> HttpResponse {
+    let (user_id, role) = path.into_inner();
+
+    let conn_wrapped = data.database.pool.get_conn();
+    if conn_wrapped.is_err() {
+        eprintln!("An error occurred (roles.rs): {:?}", conn_wrapped.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+    let mut conn = conn_wrapped.unwrap();
+
+    if let Err(response) = rbac::require_permission(&mut conn, &req, rbac::ROLES_MANAGE) {
+        return response;
+    }
+
+    //Only assign roles which exist to users who exist
+    let sql_assign_role = conn.exec_drop("INSERT IGNORE INTO user_roles (user_id, role) SELECT users.user_id, roles.name FROM users INNER JOIN roles ON roles.name = :role WHERE users.user_id = :user_id", params! {
+        "user_id" => user_id.clone(),
+        "role" => role.clone()
+    });
+
+    if sql_assign_role.is_err() {
+        eprintln!("An error occurred (roles.rs): {:?}", sql_assign_role.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    if conn.affected_rows() == 0 {
+        let sql_check_assigned = conn.exec_first::<u8, &str, _>("SELECT 1 FROM user_roles WHERE user_id = :user_id AND role = :role", params! {
+            "user_id" => user_id,
+            "role" => role
+        });
+
+        if !matches!(sql_check_assigned, Ok(Some(_))) {
+            let response = RoleResponse { status: 404, message: Some("User or role not found.") };
+            return HttpResponse::Ok().json(&response);
+        }
+    }
+
+    let response = RoleResponse { status: 200, message: None };
+    HttpResponse::Ok().json(&response)
+}
+
+/// Revoke a role from a user
+#[delete("/admin/users/{user_id}/roles/{role}")]
+pub async fn delete_user_role(data: web::Data<AppData>, req: HttpRequest, path: web::Path<(String, String)>) -> HttpResponse {
+    let (user_id, role) = path.into_inner();
+
+    let conn_wrapped = data.database.pool.get_conn();
+    if conn_wrapped.is_err() {
+        eprintln!("An error occurred (roles.rs): {:?}", conn_wrapped.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+    let mut conn = conn_wrapped.unwrap();
+
+    let session = match rbac::require_permission(&mut conn, &req, rbac::ROLES_MANAGE) {
+        Ok(session) => session,
+        Err(response) => return response
+    };
+
+    //Admins can't lock themselves out
+    if session.user_id == user_id && role == rbac::ADMIN_ROLE {
+        let response = RoleResponse { status: 403, message: Some("You can't revoke your own admin role.") };
+        return HttpResponse::Ok().json(&response);
+    }
+
+    let sql_revoke_role = conn.exec_drop("DELETE FROM user_roles WHERE user_id = :user_id AND role = :role", params! {
+        "user_id" => user_id,
+        "role" => role
+    });
+
+    if sql_revoke_role.is_err() {
+        eprintln!("An error occurred (roles.rs): {:?}", sql_revoke_role.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let response = RoleResponse { status: 200, message: None };
+    HttpResponse::Ok().json(&response)
+}
@@ -0,0 +1,506 @@
+use crate::appdata::AppData;
+use crate::{email, import, lockout, password_policy, rbac, sessions, username, users};
+use crate::import::{ImportFormat, ImportReport};
+use crate::users::AccountState;
+use crate::password_policy::Violation;
+
+use actix_web::{web, get, post, put, delete, HttpRequest, HttpResponse};
+use mysql::prelude::Queryable;
+use mysql::{PooledConn, Row, Params, params};
+use serde::{Deserialize, Serialize};
+
+/// The amount of users per page if the request doesn't say otherwise
+const DEFAULT_PER_PAGE: u32 = 50;
+/// The largest page size a request may ask for
+const MAX_PER_PAGE: u32 = 500;
+
+#[derive(Deserialize)]
+pub struct SearchQuery {
+    /// An email address, a username prefix or a user ID. Lists all users if absent
+    query:      Option<String>,
+    page:       Option<u32>,
+    per_page:   Option<u32>
+}
+
+#[derive(Deserialize)]
+pub struct ImportQuery {
+    /// 'csv' or 'jsonl'
+    format:     String
+}
+
+#[derive(Deserialize)]
+pub struct SetPasswordRequest {
+    password:   String
+}
+
+#[derive(Serialize)]
+pub struct AdminUser {
+    user_id:            String,
+    email:              Option<String>,
+    username:           Option<String>,
+    display_name:       Option<String>,
+    #[serde(flatten)]
+    account_state:      AccountState,
+    /// Set while the user is scheduled for deletion
+    deleted_at:         Option<i64>,
+    failed_logins:      u32,
+    /// Set while logging in with a password is locked after too many failed logins
+    login_locked_until: Option<i64>
+}
+
+#[derive(Serialize)]
+pub struct AdminSession {
+    /// Only a prefix is shown, the full session ID is a credential
+    session_id_prefix:  String,
+    expiry:             i64,
+    active_org_id:      Option<String>
+}
+
+#[derive(Serialize, Default)]
+pub struct UsersResponse {
+    status:     i16,
+    message:    Option<&'static str>,
+    users:      Option<Vec<AdminUser>>,
+    total:      Option<u64>,
+    page:       Option<u32>,
+    per_page:   Option<u32>
+}
+
+#[derive(Serialize, Default)]
+pub struct UserResponse {
+    status:     i16,
+    message:    Option<&'static str>,
+    user:       Option<AdminUser>,
+    roles:      Option<Vec<String>>,
+    sessions:   Option<Vec<AdminSession>>,
+    /// The amount of sessions revoked
+    revoked:    Option<u64>,
+    /// Why a new password was refused
+    #[serde(skip_serializing_if = "Option::is_none")]
+    violations: Option<Vec<Violation>>
+}
+
+#[derive(Serialize, Default)]
+pub struct ImportResponse {
+    status:     i16,
+    message:    Option<&'static str>,
+    report:     Option<ImportReport>
+}
+
+fn respond(status: i16, message: &'static str) -> HttpResponse {
+    HttpResponse::Ok().json(&UserResponse { status, message: Some(message), ..Default::default() })
+}
+
+/// Refuse acting on a user with permissions the caller doesn't have, such as an admin, as the caller could take over their account
+fn require_outranks(conn: &mut PooledConn, user_id: &str, target_id: &str) -> Result<(), HttpResponse> {
+    match rbac::holds_permissions_of(conn, user_id, target_id) {
+        Ok(true) => Ok(()),
+        Ok(false) => Err(respond(403, "The user has permissions you don't have.")),
+        Err(_) => Err(HttpResponse::InternalServerError().finish())
+    }
+}
+
+fn to_admin_user(data: &AppData, row: &Row) -> AdminUser {
+    let user_id = row.get::<String, &str>("user_id").unwrap();
+
+    let email = data.environment.email_encryption.decrypt(&row.get::<String, &str>("email").unwrap());
+    if email.is_err() {
+        eprintln!("Unable to decrypt the email address of user '{}' (users.rs)", user_id);
+    }
+
+    AdminUser {
+        email:              email.ok(),
+        username:           row.get::<Option<String>, &str>("username").unwrap(),
+        display_name:       row.get::<Option<String>, &str>("display_name").unwrap(),
+        account_state:      AccountState::from_row(row),
+        deleted_at:         row.get::<Option<i64>, &str>("deleted_at").unwrap(),
+        failed_logins:      row.get::<u32, &str>("failed_logins").unwrap(),
+        login_locked_until: row.get::<Option<i64>, &str>("login_locked_until").unwrap(),
+        user_id
+    }
+}
+
+/// Search users, one page at a time. Email addresses are encrypted, so they can only be matched exactly
+#[get("/admin/users")]
+pub async fn get_users(data: web::Data<AppData>, req: HttpRequest, query: web::Query<SearchQuery>) -> HttpResponse {
+    let page = query.page.unwrap_or(1).max(1);
+    let per_page = query.per_page.unwrap_or(DEFAULT_PER_PAGE).clamp(1, MAX_PER_PAGE);
+
+    let (filter, params) = match query.query.as_deref().map(str::trim).filter(|query| !query.is_empty()) {
+        None => (String::new(), Params::Empty),
+        Some(query) if query.contains('@') => {
+            ("WHERE email_index = :email_index".to_string(), params! {
+                "email_index" => data.environment.email_encryption.blind_index(&email::index_form(query, &data.environment.email_normalization))
+            })
+        },
+        Some(query) => {
+            //Escape LIKE wildcards, usernames may contain '_'
+            let prefix = username::normalize(query).replace('\\', "\\\\").replace('%', "\\%").replace('_', "\\_");
+            ("WHERE user_id = :user_id OR username LIKE :username".to_string(), params! {
+                "user_id" => query,
+                "username" => format!("{}%", prefix)
+            })
+        }
+    };
+
+    let conn_wrapped = data.database.pool.get_conn();
+    if conn_wrapped.is_err() {
+        eprintln!("An error occurred (users.rs): {:?}", conn_wrapped.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+    let mut conn = conn_wrapped.unwrap();
+
+    if let Err(response) = rbac::require_permission(&mut conn, &req, rbac::USERS_READ) {
+        return response;
+    }
+
+    let sql_count_users = conn.exec_first::<u64, String, Params>(format!("SELECT COUNT(*) FROM users {}", filter), params.clone());
+    if sql_count_users.is_err() {
+        eprintln!("An error occurred (users.rs): {:?}", sql_count_users.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let offset = (page as u64 - 1) * per_page as u64;
+    let sql_fetch_users = conn.exec::<Row, String, Params>(format!("SELECT user_id, email, username, display_name, status, status_until, status_reason, deleted_at, failed_logins, login_locked_until FROM users {} ORDER BY username IS NULL, username, user_id LIMIT {} OFFSET {}", filter, per_page, offset), params);
+    if sql_fetch_users.is_err() {
+        eprintln!("An error occurred (users.rs): {:?}", sql_fetch_users.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let users = sql_fetch_users.unwrap().iter()
+        .map(|row| to_admin_user(&data, row))
+        .collect();
+
+    let response = UsersResponse {
+        status:     200,
+        message:    None,
+        users:      Some(users),
+        total:      Some(sql_count_users.unwrap().unwrap_or(0)),
+        page:       Some(page),
+        per_page:   Some(per_page)
+    };
+    HttpResponse::Ok().json(&response)
+}
+
+/// View a single user, with their roles
+#[get("/admin/users/{user_id}")]
+pub async fn get_user(data: web::Data<AppData>, req: HttpRequest, user_id: web::Path<String>) -> HttpResponse {
+    let user_id = user_id.into_inner();
+
+    let conn_wrapped = data.database.pool.get_conn();
+    if conn_wrapped.is_err() {
+        eprintln!("An error occurred (users.rs): {:?}", conn_wrapped.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+    let mut conn = conn_wrapped.unwrap();
+
+    if let Err(response) = rbac::require_permission(&mut conn, &req, rbac::USERS_READ) {
+        return response;
+    }
+
+    let sql_fetch_user = conn.exec_first::<Row, &str, Params>("SELECT user_id, email, username, display_name, status, status_until, status_reason, deleted_at, failed_logins, login_locked_until FROM users WHERE user_id = :user_id", params! {
+        "user_id" => user_id.clone()
+    });
+
+    let user = match sql_fetch_user {
+        Ok(Some(row)) => to_admin_user(&data, &row),
+        Ok(None) => return respond(404, "User not found."),
+        Err(e) => {
+            eprintln!("An error occurred (users.rs): {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    let roles = rbac::user_roles(&mut conn, &user_id);
+    if roles.is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let response = UserResponse { status: 200, user: Some(user), roles: roles.ok(), ..Default::default() };
+    HttpResponse::Ok().json(&response)
+}
+
+/// List the active sessions of a user
+#[get("/admin/users/{user_id}/sessions")]
+pub async fn get_user_sessions(data: web::Data<AppData>, req: HttpRequest, user_id: web::Path<String>) -> HttpResponse {
+    let user_id = user_id.into_inner();
+
+    let conn_wrapped = data.database.pool.get_conn();
+    if conn_wrapped.is_err() {
+        eprintln!("An error occurred (users.rs): {:?}", conn_wrapped.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+    let mut conn = conn_wrapped.unwrap();
+
+    if let Err(response) = rbac::require_permission(&mut conn, &req, rbac::USERS_READ) {
+        return response;
+    }
+
+    match users::exists(&mut conn, &user_id) {
+        Ok(true) => {},
+        Ok(false) => return respond(404, "User not found."),
+        Err(_) => return HttpResponse::InternalServerError().finish()
+    }
+
+    let sql_fetch_sessions = conn.exec::<Row, &str, Params>("SELECT session_id, expiry, active_org_id FROM sessions WHERE user_id = :user_id AND expiry > :now ORDER BY expiry", params! {
+        "user_id" => user_id,
+        "now" => chrono::Utc::now().timestamp()
+    });
+
+    if sql_fetch_sessions.is_err() {
+        eprintln!("An error occurred (users.rs): {:?}", sql_fetch_sessions.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let user_sessions = sql_fetch_sessions.unwrap().iter()
+        .map(|row| AdminSession {
+            session_id_prefix:  row.get::<String, &str>("session_id").unwrap().chars().take(8).collect(),
+            expiry:             row.get::<i64, &str>("expiry").unwrap(),
+            active_org_id:      row.get::<Option<String>, &str>("active_org_id").unwrap()
+        })
+        .collect();
+
+    let response = UserResponse { status: 200, sessions: Some(user_sessions), ..Default::default() };
+    HttpResponse::Ok().json(&response)
+}
+
+/// Log a user out everywhere, by revoking all their sessions
+#[delete("/admin/users/{user_id}/sessions")]
+pub async fn delete_user_sessions(data: web::Data<AppData>, req: HttpRequest, user_id: web::Path<String>) -> HttpResponse {
+    let user_id = user_id.into_inner();
+
+    let conn_wrapped = data.database.pool.get_conn();
+    if conn_wrapped.is_err() {
+        eprintln!("An error occurred (users.rs): {:?}", conn_wrapped.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+    let mut conn = conn_wrapped.unwrap();
+
+    let session = match rbac::require_permission(&mut conn, &req, rbac::USERS_MANAGE) {
+        Ok(session) => session,
+        Err(response) => return response
+    };
+
+    if let Err(response) = require_outranks(&mut conn, &session.user_id, &user_id) {
+        return response;
+    }
+
+    let revoked = sessions::revoke_all(&mut conn, &user_id);
+    if revoked.is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let response = UserResponse { status: 200, revoked: revoked.ok(), ..Default::default() };
+    HttpResponse::Ok().json(&response)
+}
+
+/// Change the state of a user. Disabling or banning a user revokes their sessions,
+/// and a locked user can't log in or use their sessions until the lock runs out
+#[put("/admin/users/{user_id}/state")]
+pub async fn put_user_state(data: web::Data<AppData>, req: HttpRequest, user_id: web::Path<String>, body: web::Json<AccountState>) -> HttpResponse {
+    let account_state = body.into_inner();
+    match &account_state {
+        AccountState::Locked { until } if *until <= chrono::Utc::now().timestamp() => return respond(400, "A lock has to end in the future."),
+        AccountState::Banned { reason: Some(reason) } if reason.chars().count() > 255 => return respond(400, "The reason can be at most 255 characters long."),
+        _ => {}
+    }
+
+    set_state(&data, &req, &user_id.into_inner(), account_state)
+}
+
+/// Disable a user. Their sessions are revoked, and they can't log in until they are enabled again
+#[post("/admin/users/{user_id}/disable")]
+pub async fn post_disable_user(data: web::Data<AppData>, req: HttpRequest, user_id: web::Path<String>) -> HttpResponse {
+    set_state(&data, &req, &user_id.into_inner(), AccountState::Disabled)
+}
+
+/// Make a user active again, whatever their state was
+#[post("/admin/users/{user_id}/enable")]
+pub async fn post_enable_user(data: web::Data<AppData>, req: HttpRequest, user_id: web::Path<String>) -> HttpResponse {
+    set_state(&data, &req, &user_id.into_inner(), AccountState::Active)
+}
+
+/// Lift the login lockout of a user, and forget their failed logins
+#[post("/admin/users/{user_id}/unlock")]
+pub async fn post_unlock_user(data: web::Data<AppData>, req: HttpRequest, user_id: web::Path<String>) -> HttpResponse {
+    let user_id = user_id.into_inner();
+
+    let conn_wrapped = data.database.pool.get_conn();
+    if conn_wrapped.is_err() {
+        eprintln!("An error occurred (users.rs): {:?}", conn_wrapped.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+    let mut conn = conn_wrapped.unwrap();
+
+    if let Err(response) = rbac::require_permission(&mut conn, &req, rbac::USERS_MANAGE) {
+        return response;
+    }
+
+    match users::exists(&mut conn, &user_id) {
+        Ok(true) => {},
+        Ok(false) => return respond(404, "User not found."),
+        Err(_) => return HttpResponse::InternalServerError().finish()
+    }
+
+    if lockout::unlock(&mut conn, &user_id).is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let response = UserResponse { status: 200, ..Default::default() };
+    HttpResponse::Ok().json(&response)
+}
+
+fn set_state(data: &AppData, req: &HttpRequest, user_id: &str, account_state: AccountState) -> HttpResponse {
+    let conn_wrapped = data.database.pool.get_conn();
+    if conn_wrapped.is_err() {
+        eprintln!("An error occurred (users.rs): {:?}", conn_wrapped.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+    let mut conn = conn_wrapped.unwrap();
+
+    let session = match rbac::require_permission(&mut conn, req, rbac::USERS_MANAGE) {
+        Ok(session) => session,
+        Err(response) => return response
+    };
+
+    //Admins can't lock themselves out
+    if account_state != AccountState::Active && session.user_id == user_id {
+        return respond(403, "You can't change the state of your own account.");
+    }
+
+    match users::exists(&mut conn, user_id) {
+        Ok(true) => {},
+        Ok(false) => return respond(404, "User not found."),
+        Err(_) => return HttpResponse::InternalServerError().finish()
+    }
+
+    if let Err(response) = require_outranks(&mut conn, &session.user_id, user_id) {
+        return response;
+    }
+
+    if users::set_state(&mut conn, user_id, &account_state).is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let response = UserResponse { status: 200, ..Default::default() };
+    HttpResponse::Ok().json(&response)
+}
+
+/// Set a temporary password for a user, for example after they lost access to their account.
+/// Their sessions are revoked, so the new password has to be used to log in again
+#[put("/admin/users/{user_id}/password")]
+pub async fn put_user_password(data: web::Data<AppData>, req: HttpRequest, user_id: web::Path<String>, body: web::Json<SetPasswordRequest>) -> HttpResponse {
+    let user_id = user_id.into_inner();
+
+    let conn_wrapped = data.database.pool.get_conn();
+    if conn_wrapped.is_err() {
+        eprintln!("An error occurred (users.rs): {:?}", conn_wrapped.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+    let mut conn = conn_wrapped.unwrap();
+
+    let session = match rbac::require_permission(&mut conn, &req, rbac::USERS_MANAGE) {
+        Ok(session) => session,
+        Err(response) => return response
+    };
+
+    match users::exists(&mut conn, &user_id) {
+        Ok(true) => {},
+        Ok(false) => return respond(404, "User not found."),
+        Err(_) => return HttpResponse::InternalServerError().finish()
+    }
+
+    if let Err(response) = require_outranks(&mut conn, &session.user_id, &user_id) {
+        return response;
+    }
+
+    let violations = match password_policy::check_for_user(&mut conn, &data, &user_id, &body.password).await {
+        Ok(violations) => violations,
+        Err(_) => return HttpResponse::InternalServerError().finish()
+    };
+
+    if let Some(violation) = violations.first() {
+        let response = UserResponse { status: 400, message: Some(violation.message()), violations: Some(violations.clone()), ..Default::default() };
+        return HttpResponse::Ok().json(&response);
+    }
+
+    if users::set_password(&mut conn, &user_id, &body.password, &data.environment).is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let revoked = sessions::revoke_all(&mut conn, &user_id);
+    if revoked.is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let response = UserResponse { status: 200, revoked: revoked.ok(), ..Default::default() };
+    HttpResponse::Ok().json(&response)
+}
+
+/// Permanently delete a user right away, without a grace period
+#[delete("/admin/users/{user_id}")]
+pub async fn delete_user(data: web::Data<AppData>, req: HttpRequest, user_id: web::Path<String>) -> HttpResponse {
+    let user_id = user_id.into_inner();
+
+    let conn_wrapped = data.database.pool.get_conn();
+    if conn_wrapped.is_err() {
+        eprintln!("An error occurred (users.rs): {:?}", conn_wrapped.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+    let mut conn = conn_wrapped.unwrap();
+
+    let session = match rbac::require_permission(&mut conn, &req, rbac::USERS_MANAGE) {
+        Ok(session) => session,
+        Err(response) => return response
+    };
+
+    if session.user_id == user_id {
+        return respond(403, "You can't delete your own account here.");
+    }
+
+    match users::exists(&mut conn, &user_id) {
+        Ok(true) => {},
+        Ok(false) => return respond(404, "User not found."),
+        Err(_) => return HttpResponse::InternalServerError().finish()
+    }
+
+    if let Err(response) = require_outranks(&mut conn, &session.user_id, &user_id) {
+        return response;
+    }
+
+    match users::delete(&mut conn, &user_id) {
+        Ok(true) => {},
+        Ok(false) => return respond(409, "The user is the only owner of an organization. Make someone else an owner first."),
+        Err(_) => return HttpResponse::InternalServerError().finish()
+    }
+
+    let response = UserResponse { status: 200, ..Default::default() };
+    HttpResponse::Ok().json(&response)
+}
+
+/// Import users in bulk from CSV or JSON lines in the request body, keeping their password hashes.
+/// The report lists the outcome of every row
+#[post("/admin/users/import")]
+pub async fn post_import_users(data: web::Data<AppData>, req: HttpRequest, query: web::Query<ImportQuery>, body: String) -> HttpResponse {
+    let format = query.format.parse::<ImportFormat>();
+    if format.is_err() {
+        let response = ImportResponse { status: 400, message: Some("Format must be 'csv' or 'jsonl'."), ..Default::default() };
+        return HttpResponse::Ok().json(&response);
+    }
+
+    let conn_wrapped = data.database.pool.get_conn();
+    if conn_wrapped.is_err() {
+        eprintln!("An error occurred (users.rs): {:?}", conn_wrapped.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+    let mut conn = conn_wrapped.unwrap();
+
+    if let Err(response) = rbac::require_permission(&mut conn, &req, rbac::USERS_IMPORT) {
+        return response;
+    }
+
+    let report = import::import(&mut conn, &data.environment, &body, format.unwrap());
+
+    let response = ImportResponse { status: 200, message: None, report: Some(report) };
+    HttpResponse::Ok().json(&response)
+}
@@ -0,0 +1,341 @@
+use crate::appdata::AppData;
+use crate::endpoints::auth::profile::{self, Profile};
+use crate::{lockout, orgs, rbac, sessions, users};
+
+use actix_web::{web, post, HttpRequest, HttpResponse};
+use mysql::prelude::Queryable;
+use mysql::{Row, Params, params};
+use serde::{Deserialize, Serialize};
+
+/// Version of the export format, increased whenever its structure changes
+const EXPORT_VERSION: u32 = 4;
+
+const SOLE_OWNER: &str = "You are the only owner of an organization. Make someone else an owner, or leave it, before deleting your account.";
+
+#[derive(Deserialize)]
+pub struct DeleteAccountForm {
+    session_id:         String,
+    password_base64:    String
+}
+
+#[derive(Serialize, Default)]
+pub struct DeleteAccountResponse {
+    status:         i16,
+    message:        Option<&'static str>,
+    /// When the account will be deleted permanently, if deletion has a grace period
+    deletion_date:  Option<i64>
+}
+
+#[derive(Deserialize)]
+pub struct ExportAccountForm {
+    session_id:     String
+}
+
+#[derive(Serialize)]
+pub struct AccountExport {
+    format:           &'static str,
+    version:          u32,
+    exported_at:      i64,
+    profile:          Profile,
+    account:          ExportedAccountState,
+    roles:            Vec<String>,
+    organizations:    Vec<ExportedMembership>,
+    sessions:         Vec<ExportedSession>,
+    login_history:    Vec<ExportedLogin>,
+    security_events:  Vec<ExportedSecurityEvent>,
+    devices:          Vec<ExportedDevice>
+}
+
+/// The state kept on the account besides the profile
+#[derive(Serialize)]
+pub struct ExportedAccountState {
+    email_verified:             bool,
+    status:                     String,
+    status_until:               Option<i64>,
+    status_reason:              Option<String>,
+    external_id:                Option<String>,
+    password_reset_required:    bool,
+    failed_logins:              u32,
+    last_failed_login:          Option<i64>,
+    login_locked_until:         Option<i64>
+}
+
+#[derive(Serialize)]
+pub struct ExportedMembership {
+    org_id:         String,
+    name:           String,
+    role:           String,
+    joined_at:      i64
+}
+
+#[derive(Serialize)]
+pub struct ExportedSession {
+    /// Only a prefix is exported, the full session ID is a credential
+    session_id_prefix:  String,
+    expiry:             i64
+}
+
+#[derive(Serialize)]
+pub struct ExportedLogin {
+    timestamp:      i64,
+    ip:             Option<String>,
+    user_agent:     Option<String>,
+    success:        bool
+}
+
+#[derive(Serialize)]
+pub struct ExportedSecurityEvent {
+    timestamp:      i64,
+    event:          String,
+    details:        Option<String>,
+    ip:             Option<String>,
+    user_agent:     Option<String>
+}
+
+#[derive(Serialize)]
+pub struct ExportedDevice {
+    user_agent_family:  String,
+    ip_prefix:          String,
+    first_seen:         i64,
+    last_seen:          i64
+}
+
+/// Delete the account the session belongs to. The user has to provide their password again,
+/// and the session has to have authenticated recently. Wrong passwords count towards the account's lockout.
+///
+/// All sessions are revoked right away. If a grace period is configured, the account is only scheduled for deletion,
+/// and logging in before the grace period ends cancels it
+#[post("/auth/account/delete")]
+pub async fn post_delete_account(data: web::Data<AppData>, req: HttpRequest, form: web::Form<DeleteAccountForm>) -> HttpResponse {
+    let password_wrapped = base64::decode(form.password_base64.as_bytes());
+    if password_wrapped.is_err() {
+        return HttpResponse::BadRequest().body(password_wrapped.err().unwrap().to_string());
+    }
+    let password = String::from_utf8(password_wrapped.unwrap()).unwrap();
+
+    let conn_wrapped = data.database.pool.get_conn();
+    if conn_wrapped.is_err() {
+        eprintln!("An error occurred (account.rs): {:?}", conn_wrapped.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+    let mut conn = conn_wrapped.unwrap();
+
+    let session = match sessions::require_form_session(&mut conn, &req, &form.session_id) {
+        Ok(session) => session,
+        Err(response) => return response
+    };
+
+    //Deleting needs the password even from a session which authenticated recently, being fresh is required on top of it
+    if let Err(response) = sessions::require_fresh(&data.environment, &session) {
+        return response;
+    }
+
+    //Re-authenticate the user
+    match lockout::check_password(&mut conn, &data.environment, &session.user_id, &password) {
+        Ok(true) => {},
+        Ok(false) => {
+            let response = DeleteAccountResponse { status: 401, message: Some("Password is incorrect."), ..Default::default() };
+            return HttpResponse::Ok().json(&response);
+        },
+        Err(_) => return HttpResponse::InternalServerError().finish()
+    }
+
+    //Checked before scheduling too, as the account can't be logged in to for handing the organization over afterwards
+    match orgs::sole_owned(&mut conn, &session.user_id) {
+        Ok(org_ids) if org_ids.is_empty() => {},
+        Ok(_) => {
+            let response = DeleteAccountResponse { status: 409, message: Some(SOLE_OWNER), ..Default::default() };
+            return HttpResponse::Ok().json(&response);
+        },
+        Err(_) => return HttpResponse::InternalServerError().finish()
+    }
+
+    let grace_period = data.environment.retention.deletion_grace_seconds;
+    if grace_period <= 0 {
+        match users::delete(&mut conn, &session.user_id) {
+            Ok(true) => {},
+            Ok(false) => {
+                let response = DeleteAccountResponse { status: 409, message: Some(SOLE_OWNER), ..Default::default() };
+                return HttpResponse::Ok().json(&response);
+            },
+            Err(_) => return HttpResponse::InternalServerError().finish()
+        }
+
+        let response = DeleteAccountResponse { status: 200, message: Some("Account deleted."), ..Default::default() };
+        return HttpResponse::Ok().json(&response);
+    }
+
+    if sessions::revoke_all(&mut conn, &session.user_id).is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let now = chrono::Utc::now().timestamp();
+    let sql_schedule_deletion = conn.exec_drop("UPDATE users SET deleted_at = :deleted_at WHERE user_id = :user_id", params! {
+        "deleted_at" => now,
+        "user_id" => session.user_id
+    });
+
+    if sql_schedule_deletion.is_err() {
+        eprintln!("An error occurred (account.rs): {:?}", sql_schedule_deletion.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let response = DeleteAccountResponse { status: 200, message: Some("Account scheduled for deletion. Log in before the deletion date to cancel."), deletion_date: Some(now + grace_period) };
+    HttpResponse::Ok().json(&response)
+}
+
+/// Export everything stored about the user the session belongs to, as a JSON document
+#[post("/auth/account/export")]
+pub async fn post_export_account(data: web::Data<AppData>, req: HttpRequest, form: web::Form<ExportAccountForm>) -> HttpResponse {
+    let conn_wrapped = data.database.pool.get_conn();
+    if conn_wrapped.is_err() {
+        eprintln!("An error occurred (account.rs): {:?}", conn_wrapped.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+    let mut conn = conn_wrapped.unwrap();
+
+    let session = match sessions::require_form_session(&mut conn, &req, &form.session_id) {
+        Ok(session) => session,
+        Err(response) => return response
+    };
+
+    let profile = profile::fetch_profile(&mut conn, &data, &session.user_id);
+    if profile.is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let sql_fetch_account = conn.exec_first::<Row, &str, Params>("SELECT email_verified, status, status_until, status_reason, external_id, password_reset_required, failed_logins, last_failed_login, login_locked_until FROM users WHERE user_id = :user_id", params! {
+        "user_id" => session.user_id.clone()
+    });
+
+    let account = match sql_fetch_account {
+        Ok(Some(row)) => ExportedAccountState {
+            email_verified:             row.get::<bool, &str>("email_verified").unwrap(),
+            status:                     row.get::<String, &str>("status").unwrap(),
+            status_until:               row.get::<Option<i64>, &str>("status_until").unwrap(),
+            status_reason:              row.get::<Option<String>, &str>("status_reason").unwrap(),
+            external_id:                row.get::<Option<String>, &str>("external_id").unwrap(),
+            password_reset_required:    row.get::<bool, &str>("password_reset_required").unwrap(),
+            failed_logins:              row.get::<u32, &str>("failed_logins").unwrap(),
+            last_failed_login:          row.get::<Option<i64>, &str>("last_failed_login").unwrap(),
+            login_locked_until:         row.get::<Option<i64>, &str>("login_locked_until").unwrap()
+        },
+        Ok(None) => return HttpResponse::InternalServerError().finish(),
+        Err(e) => {
+            eprintln!("An error occurred (account.rs): {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    let roles = rbac::user_roles(&mut conn, &session.user_id);
+    if roles.is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let sql_fetch_memberships = conn.exec::<Row, &str, Params>("SELECT organizations.org_id, organizations.name, org_memberships.role, org_memberships.joined_at FROM org_memberships INNER JOIN organizations ON organizations.org_id = org_memberships.org_id WHERE org_memberships.user_id = :user_id ORDER BY org_memberships.joined_at", params! {
+        "user_id" => session.user_id.clone()
+    });
+
+    if sql_fetch_memberships.is_err() {
+        eprintln!("An error occurred (account.rs): {:?}", sql_fetch_memberships.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let exported_memberships = sql_fetch_memberships.unwrap().iter()
+        .map(|row| ExportedMembership {
+            org_id:     row.get::<String, &str>("org_id").unwrap(),
+            name:       row.get::<String, &str>("name").unwrap(),
+            role:       row.get::<String, &str>("role").unwrap(),
+            joined_at:  row.get::<i64, &str>("joined_at").unwrap()
+        })
+        .collect();
+
+    let sql_fetch_sessions = conn.exec::<Row, &str, Params>("SELECT session_id, expiry FROM sessions WHERE user_id = :user_id ORDER BY expiry", params! {
+        "user_id" => session.user_id.clone()
+    });
+
+    if sql_fetch_sessions.is_err() {
+        eprintln!("An error occurred (account.rs): {:?}", sql_fetch_sessions.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let exported_sessions = sql_fetch_sessions.unwrap().iter()
+        .map(|row| ExportedSession {
+            session_id_prefix:  row.get::<String, &str>("session_id").unwrap().chars().take(8).collect(),
+            expiry:             row.get::<i64, &str>("expiry").unwrap()
+        })
+        .collect();
+
+    let sql_fetch_logins = conn.exec::<Row, &str, Params>("SELECT timestamp, ip, user_agent, success FROM login_history WHERE user_id = :user_id ORDER BY timestamp", params! {
+        "user_id" => session.user_id.clone()
+    });
+
+    if sql_fetch_logins.is_err() {
+        eprintln!("An error occurred (account.rs): {:?}", sql_fetch_logins.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let exported_logins = sql_fetch_logins.unwrap().iter()
+        .map(|row| ExportedLogin {
+            timestamp:  row.get::<i64, &str>("timestamp").unwrap(),
+            ip:         row.get::<Option<String>, &str>("ip").unwrap(),
+            user_agent: row.get::<Option<String>, &str>("user_agent").unwrap(),
+            success:    row.get::<bool, &str>("success").unwrap()
+        })
+        .collect();
+
+    let sql_fetch_events = conn.exec::<Row, &str, Params>("SELECT timestamp, event, details, ip, user_agent FROM security_events WHERE user_id = :user_id ORDER BY timestamp", params! {
+        "user_id" => session.user_id.clone()
+    });
+
+    if sql_fetch_events.is_err() {
+        eprintln!("An error occurred (account.rs): {:?}", sql_fetch_events.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let exported_events = sql_fetch_events.unwrap().iter()
+        .map(|row| ExportedSecurityEvent {
+            timestamp:  row.get::<i64, &str>("timestamp").unwrap(),
+            event:      row.get::<String, &str>("event").unwrap(),
+            details:    row.get::<Option<String>, &str>("details").unwrap(),
+            ip:         row.get::<Option<String>, &str>("ip").unwrap(),
+            user_agent: row.get::<Option<String>, &str>("user_agent").unwrap()
+        })
+        .collect();
+
+    let sql_fetch_devices = conn.exec::<Row, &str, Params>("SELECT user_agent_family, ip_prefix, first_seen, last_seen FROM known_devices WHERE user_id = :user_id ORDER BY first_seen", params! {
+        "user_id" => session.user_id.clone()
+    });
+
+    if sql_fetch_devices.is_err() {
+        eprintln!("An error occurred (account.rs): {:?}", sql_fetch_devices.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let exported_devices = sql_fetch_devices.unwrap().iter()
+        .map(|row| ExportedDevice {
+            user_agent_family:  row.get::<String, &str>("user_agent_family").unwrap(),
+            ip_prefix:          row.get::<String, &str>("ip_prefix").unwrap(),
+            first_seen:         row.get::<i64, &str>("first_seen").unwrap(),
+            last_seen:          row.get::<i64, &str>("last_seen").unwrap()
+        })
+        .collect();
+
+    let export = AccountExport {
+        format:           "login_server-account-export",
+        version:          EXPORT_VERSION,
+        exported_at:      chrono::Utc::now().timestamp(),
+        profile:          profile.unwrap(),
+        account,
+        roles:            roles.unwrap(),
+        organizations:    exported_memberships,
+        sessions:         exported_sessions,
+        login_history:    exported_logins,
+        security_events:  exported_events,
+        devices:          exported_devices
+    };
+
+    HttpResponse::Ok()
+        .header("Content-Disposition", "attachment; filename=\"account-export.json\"")
+        .json(&export)
+}
@@ -0,0 +1,43 @@
+use crate::appdata::AppData;
+use crate::challenge::{CaptchaProviderKind, ChallengeMode};
+
+use actix_web::{web, get, HttpResponse};
+use serde::Serialize;
+
+#[derive(Serialize)]
+pub struct ChallengeResponse {
+    status:             i16,
+    mode:               ChallengeMode,
+    /// The proof-of-work challenge to solve
+    #[serde(skip_serializing_if = "Option::is_none")]
+    challenge:          Option<String>,
+    /// The amount of leading zero bits SHA-256(`<challenge>:<solution>`) needs
+    #[serde(skip_serializing_if = "Option::is_none")]
+    difficulty:         Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expiry:             Option<i64>,
+    /// The CAPTCHA provider whose response has to be sent as the solution
+    #[serde(skip_serializing_if = "Option::is_none")]
+    captcha_provider:   Option<CaptchaProviderKind>,
+    /// The provider's key for this site, to show the CAPTCHA with
+    #[serde(skip_serializing_if = "Option::is_none")]
+    captcha_site_key:   Option<String>
+}
+
+/// Get a challenge to solve before registering, or logging in after failed logins.
+/// The solution is sent as `challenge_solution`, along with the proof-of-work challenge as `challenge`
+#[get("/auth/challenge")]
+pub async fn get_challenge(data: web::Data<AppData>) -> HttpResponse {
+    let config = &data.environment.challenge;
+
+    let response = match config.mode {
+        ChallengeMode::Disabled => ChallengeResponse { status: 200, mode: config.mode, challenge: None, difficulty: None, expiry: None, captcha_provider: None, captcha_site_key: None },
+        ChallengeMode::Pow => {
+            let (challenge, expiry) = config.issue();
+            ChallengeResponse { status: 200, mode: config.mode, challenge: Some(challenge), difficulty: Some(config.difficulty), expiry: Some(expiry), captcha_provider: None, captcha_site_key: None }
+        },
+        ChallengeMode::Captcha => ChallengeResponse { status: 200, mode: config.mode, challenge: None, difficulty: None, expiry: None, captcha_provider: config.captcha_provider, captcha_site_key: Some(config.captcha_site_key.clone()) }
+    };
+
+    HttpResponse::Ok().json(&response)
+}
@@ -0,0 +1,242 @@
+use crate::appdata::{AppData, Database};
+use crate::{crypto, email, mail, pages, sessions, users};
+
+use actix_web::{web, get, post, HttpRequest, HttpResponse};
+use mysql::prelude::Queryable;
+use mysql::{Row, Params, TxOpts, params};
+use serde::{Deserialize, Serialize};
+
+/// How long the confirmation link of an email change is valid for, in hours
+const CONFIRMATION_VALIDITY_HOURS: i64 = 24;
+
+const CONFIRMATION_SENT: &str = "A confirmation link has been sent to the new address.";
+
+#[derive(Deserialize)]
+pub struct ChangeEmailForm {
+    session_id:         String,
+    new_email_base64:   String
+}
+
+#[derive(Deserialize)]
+pub struct TokenForm {
+    token:      String
+}
+
+#[derive(Serialize)]
+pub struct ChangeEmailResponse {
+    status:     i16,
+    message:    Option<&'static str>
+}
+
+/// Request a change of email address. A confirmation link is sent to the new address,
+/// and the current address is notified with a link to cancel the change.
+/// If the new address already belongs to an account, its owner is told instead, and the response is the same,
+/// so sessions can't be used to find out which addresses have an account.
+/// The session has to have authenticated recently, see `/auth/reauthenticate`
+#[post("/auth/email/change")]
+pub async fn post_change_email(data: web::Data<AppData>, req: HttpRequest, form: web::Form<ChangeEmailForm>) -> HttpResponse {
+    let new_email_wrapped = base64::decode(form.new_email_base64.as_bytes());
+    if new_email_wrapped.is_err() {
+        return HttpResponse::BadRequest().body(new_email_wrapped.err().unwrap().to_string());
+    }
+    let new_email = String::from_utf8(new_email_wrapped.unwrap()).unwrap().trim().to_string();
+
+    let new_email_normalized = email::parse(&new_email, &data.environment.email_normalization);
+    if new_email_normalized.is_none() {
+        let response = ChangeEmailResponse { status: 400, message: Some("Invalid E-mail address.") };
+        return HttpResponse::Ok().json(&response);
+    }
+
+    let conn_wrapped = data.database.pool.get_conn();
+    if conn_wrapped.is_err() {
+        eprintln!("An error occurred (email.rs): {:?}", conn_wrapped.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+    let mut conn = conn_wrapped.unwrap();
+
+    let session = match sessions::require_form_session(&mut conn, &req, &form.session_id) {
+        Ok(session) => session,
+        Err(response) => return response
+    };
+
+    if let Err(response) = sessions::require_fresh(&data.environment, &session) {
+        return response;
+    }
+
+    let new_email_index = data.environment.email_encryption.blind_index(&new_email_normalized.unwrap());
+    let sql_check_email = conn.exec::<Row, &str, Params>("SELECT 1 FROM users WHERE email_index = :email_index", params! {
+        "email_index" => new_email_index.clone()
+    });
+
+    if sql_check_email.is_err() {
+        eprintln!("An error occurred (email.rs): {:?}", sql_check_email.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let mail_config = &data.environment.mail;
+    if !sql_check_email.unwrap().is_empty() {
+        mail::send(mail_config, &new_email, "Someone tried to use your email address",
+            "Someone tried to change the email address of another account to this address, but it already belongs to your account. Nothing has been changed, and you can ignore this mail.");
+
+        let response = ChangeEmailResponse { status: 200, message: Some(CONFIRMATION_SENT) };
+        return HttpResponse::Ok().json(&response);
+    }
+
+    let current_email = users::fetch_email(&mut conn, &data.environment.email_encryption, &session.user_id);
+    if current_email.is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let new_email_encrypted = data.environment.email_encryption.encrypt(&new_email);
+    if new_email_encrypted.is_err() {
+        eprintln!("Unable to encrypt email address (email.rs)");
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    //A user can only have one pending change, a new request replaces the previous one
+    let confirm_token = crypto::random_token();
+    let cancel_token = crypto::random_token();
+    let expiry = (chrono::Utc::now() + chrono::Duration::hours(CONFIRMATION_VALIDITY_HOURS)).timestamp();
+
+    let sql_insert_change = conn.exec_drop("REPLACE INTO email_changes (user_id, confirm_token, cancel_token, new_email, new_email_index, expiry) VALUES (:user_id, :confirm_token, :cancel_token, :new_email, :new_email_index, :expiry)", params! {
+        "user_id" => session.user_id,
+        "confirm_token" => crypto::hash_token(&confirm_token),
+        "cancel_token" => crypto::hash_token(&cancel_token),
+        "new_email" => new_email_encrypted.unwrap(),
+        "new_email_index" => new_email_index,
+        "expiry" => expiry
+    });
+
+    if sql_insert_change.is_err() {
+        eprintln!("An error occurred (email.rs): {:?}", sql_insert_change.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    mail::send(mail_config, &new_email, "Confirm your new email address", &format!(
+        "A request was made to change the email address of your account to this address.\n\nConfirm the change within {} hours by opening this link:\n{}\n\nIf you did not request this, you can ignore this mail.",
+        CONFIRMATION_VALIDITY_HOURS,
+        mail_config.link(&format!("/auth/email/confirm?token={}", confirm_token))
+    ));
+
+    mail::send(mail_config, &current_email.unwrap(), "Your email address is being changed", &format!(
+        "A request was made to change the email address of your account to {}.\n\nIf you did not request this, cancel the change by opening this link, and change your password:\n{}",
+        new_email,
+        mail_config.link(&format!("/auth/email/cancel?token={}", cancel_token))
+    ));
+
+    let response = ChangeEmailResponse { status: 200, message: Some(CONFIRMATION_SENT) };
+    HttpResponse::Ok().json(&response)
+}
+
+/// The page the confirmation link opens, which posts the token back to confirm the change
+#[get("/auth/email/confirm")]
+pub async fn get_confirm_email_change(query: web::Query<TokenForm>) -> HttpResponse {
+    pages::confirmation("Confirm your new email address", "Confirm that this address should become the email address of your account.", "/auth/email/confirm", &query.token, "Confirm")
+}
+
+/// Confirm a pending email change, swapping the address in a single transaction
+#[post("/auth/email/confirm")]
+pub async fn post_confirm_email_change(data: web::Data<AppData>, form: web::Form<TokenForm>) -> HttpResponse {
+    let conn_wrapped = data.database.pool.get_conn();
+    if conn_wrapped.is_err() {
+        eprintln!("An error occurred (email.rs): {:?}", conn_wrapped.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+    let mut conn = conn_wrapped.unwrap();
+
+    let tx = conn.start_transaction(TxOpts::default());
+    if tx.is_err() {
+        eprintln!("An error occurred (email.rs): {:?}", tx.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+    let mut tx = tx.unwrap();
+
+    let sql_fetch_change = tx.exec_first::<Row, &str, Params>("SELECT user_id, new_email, new_email_index, expiry FROM email_changes WHERE confirm_token = :confirm_token FOR UPDATE", params! {
+        "confirm_token" => crypto::hash_token(&form.token)
+    });
+
+    if sql_fetch_change.is_err() {
+        eprintln!("An error occurred (email.rs): {:?}", sql_fetch_change.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let row = sql_fetch_change.unwrap();
+    let expired = row.as_ref().map(|row| chrono::Utc::now().timestamp() >= row.get::<i64, &str>("expiry").unwrap()).unwrap_or(true);
+    if expired {
+        let response = ChangeEmailResponse { status: 404, message: Some("Unknown or expired link.") };
+        return HttpResponse::Ok().json(&response);
+    }
+
+    let row = row.unwrap();
+    let user_id = row.get::<String, &str>("user_id").unwrap();
+
+    //The new address received the confirmation link, so it is verified
+    let sql_update_email = tx.exec_drop("UPDATE users SET email = :email, email_index = :email_index, email_verified = TRUE WHERE user_id = :user_id", params! {
+        "email" => row.get::<String, &str>("new_email").unwrap(),
+        "email_index" => row.get::<String, &str>("new_email_index").unwrap(),
+        "user_id" => user_id.clone()
+    });
+
+    if let Err(e) = sql_update_email {
+        //Someone registered the new address after the change was requested
+        if Database::is_duplicate_entry(&e, "users_email_index") {
+            let response = ChangeEmailResponse { status: 409, message: Some("Account already exists.") };
+            return HttpResponse::Ok().json(&response);
+        }
+
+        eprintln!("An error occurred (email.rs): {:?}", e);
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let sql_delete_change = tx.exec_drop("DELETE FROM email_changes WHERE user_id = :user_id", params! {
+        "user_id" => user_id
+    });
+
+    if sql_delete_change.is_err() {
+        eprintln!("An error occurred (email.rs): {:?}", sql_delete_change.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let commit = tx.commit();
+    if commit.is_err() {
+        eprintln!("An error occurred (email.rs): {:?}", commit.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let response = ChangeEmailResponse { status: 200, message: Some("Your email address has been changed.") };
+    HttpResponse::Ok().json(&response)
+}
+
+/// The page the cancellation link opens, which posts the token back to cancel the change
+#[get("/auth/email/cancel")]
+pub async fn get_cancel_email_change(query: web::Query<TokenForm>) -> HttpResponse {
+    pages::confirmation("Cancel the email address change", "Cancel the change of your account's email address. If you did not request it, change your password too.", "/auth/email/cancel", &query.token, "Cancel the change")
+}
+
+/// Cancel a pending email change, using the link sent to the current address
+#[post("/auth/email/cancel")]
+pub async fn post_cancel_email_change(data: web::Data<AppData>, form: web::Form<TokenForm>) -> HttpResponse {
+    let conn_wrapped = data.database.pool.get_conn();
+    if conn_wrapped.is_err() {
+        eprintln!("An error occurred (email.rs): {:?}", conn_wrapped.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+    let mut conn = conn_wrapped.unwrap();
+
+    let sql_delete_change = conn.exec_drop("DELETE FROM email_changes WHERE cancel_token = :cancel_token", params! {
+        "cancel_token" => crypto::hash_token(&form.token)
+    });
+
+    if sql_delete_change.is_err() {
+        eprintln!("An error occurred (email.rs): {:?}", sql_delete_change.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    if conn.affected_rows() == 0 {
+        let response = ChangeEmailResponse { status: 404, message: Some("Unknown or expired link.") };
+        return HttpResponse::Ok().json(&response);
+    }
+
+    let response = ChangeEmailResponse { status: 200, message: Some("The email change has been cancelled.") };
+    HttpResponse::Ok().json(&response)
+}
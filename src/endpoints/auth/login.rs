@@ -1,32 +1,60 @@
 use crate::appdata::AppData;
+use crate::{breach, challenge, devices, email, ipfilter, lockout, password, password_policy, ratelimit, sessions, username, users};
+use crate::sessions::SessionBinding;
+use crate::users::AccountState;
 
-use actix_web::{post, HttpResponse, web};
+use actix_web::{post, HttpRequest, HttpResponse, web};
 use mysql::prelude::Queryable;
-use mysql::{Row, Params, params};
-use sha2::{Sha512Trunc256, Digest};
-use rand::Rng;
+use mysql::{PooledConn, Row, Params, params};
 use serde::{Deserialize, Serialize};
 
+/// The account is identified by either `identifier_base64`, which may be an email address or a username,
+/// or, for older clients, `email_base64`
 #[derive(Deserialize)]
 pub struct LoginForm {
-    email_base64:       String,
-    password_base64:    String
+    identifier_base64:  Option<String>,
+    email_base64:       Option<String>,
+    password_base64:    String,
+    /// An ID the client keeps for the device, to recognize it with more certainty than the user agent
+    device_id:          Option<String>,
+    /// Base64 encoded DER public key to bind the session to, see `sessions::SessionBinding`
+    binding_key:        Option<String>,
+    /// Only needed after failed logins, see `/auth/challenge`
+    challenge:          Option<String>,
+    challenge_solution: Option<String>
 }
 
+/// Unchanged since before usernames were accepted, as clients may match on it
+const INVALID_CREDENTIALS: &str = "E-mail and password combination is invalid, or the account does not exist.";
+
 #[derive(Serialize)]
 pub struct LoginResponse {
-    status:     i16,
-    message:    Option<String>,
-    session_id: Option<String>,
-    expiry:     Option<i64>
+    status:                  i16,
+    message:                 Option<String>,
+    session_id:              Option<String>,
+    expiry:                  Option<i64>,
+    /// Why the account can't log in, if it is not active
+    #[serde(skip_serializing_if = "Option::is_none")]
+    account_state:           Option<AccountState>,
+    /// Set if the password has to be changed before the account is used, e.g. because it appeared in a data breach
+    #[serde(skip_serializing_if = "Option::is_none")]
+    password_reset_required: Option<bool>,
+    /// Set if a challenge has to be solved before logging in
+    #[serde(skip_serializing_if = "Option::is_none")]
+    challenge_required:      Option<bool>
 }
 
 #[post("/auth/login")]
-pub async fn post_login(data: web::Data<AppData>, form: web::Form<LoginForm>) -> HttpResponse {
+pub async fn post_login(data: web::Data<AppData>, req: HttpRequest, form: web::Form<LoginForm>) -> HttpResponse {
+
+    let identifier_base64 = form.identifier_base64.as_ref().or_else(|| form.email_base64.as_ref());
+    if identifier_base64.is_none() {
+        return HttpResponse::BadRequest().body("Missing field 'identifier_base64'");
+    }
 
-    let email_wrapped = base64::decode(form.email_base64.clone().as_bytes());
-    if email_wrapped.is_err() {
-        return HttpResponse::BadRequest().body(email_wrapped.err().unwrap().to_string());
+    let identifier_wrapped = base64::decode(identifier_base64.unwrap().as_bytes());
+    if identifier_wrapped.is_err() {
+        return HttpResponse::BadRequest().body(identifier_wrapped.err().unwrap().to_string());
     }
 
     let password_wrapped = base64::decode(form.password_base64.clone().as_bytes());
@@ -34,9 +62,28 @@ pub async fn post_login(data: web::Data<AppData>, form: web::Form<LoginForm>) ->
         return HttpResponse::BadRequest().body(password_wrapped.err().unwrap().to_string());
     }
 
-    let email = String::from_utf8(email_wrapped.unwrap()).unwrap();
+    let identifier = String::from_utf8(identifier_wrapped.unwrap()).unwrap();
     let password = String::from_utf8(password_wrapped.unwrap()).unwrap();
 
+    let binding = match SessionBinding::from_request(&data.environment.session_binding, &req, form.binding_key.as_deref()) {
+        Ok(binding) => binding,
+        Err(message) => {
+            let response = LoginResponse { status: 400, message: Some(message.to_string()), session_id: None, expiry: None, account_state: None, password_reset_required: None, challenge_required: None };
+            return HttpResponse::Ok().json(&response);
+        }
+    };
+
+    //Guesses are limited per account too, so they can't be spread over many IP addresses
+    if let Err(response) = ratelimit::check_identifier(&data, ratelimit::Endpoint::Login, &identifier) {
+        return response;
+    }
+
+    //After failed logins from the IP address or for the account, bots have to get past a challenge first
+    if challenge::login_requires_challenge(&data, &req, &identifier) && !challenge::verify(&data, &req, form.challenge.as_deref(), form.challenge_solution.as_deref()).await {
+        let response = LoginResponse { status: 428, message: Some(challenge::CHALLENGE_REQUIRED.to_string()), session_id: None, expiry: None, account_state: None, password_reset_required: None, challenge_required: Some(true) };
+        return HttpResponse::Ok().json(&response);
+    }
+
     let conn_wrapped = data.database.pool.get_conn();
     if conn_wrapped.is_err() {
         eprintln!("An error occurred (login.rs): {:?}", conn_wrapped.err().unwrap());
@@ -44,9 +91,17 @@ pub async fn post_login(data: web::Data<AppData>, form: web::Form<LoginForm>) ->
     }
     let mut conn = conn_wrapped.unwrap();
 
-    let sql_fetch_user_wrapped = conn.exec::<Row, &str, Params>("SELECT password, salt, user_id FROM users WHERE email = :email", params! {
-        "email" => email.clone()
-    });
+    //Usernames can't contain an '@', so anything with one is an email address
+    let sql_fetch_user_wrapped = if identifier.contains('@') {
+        //Email addresses are encrypted, so we look them up by the blind index of their normalized form
+        conn.exec::<Row, &str, Params>("SELECT password, salt, password_algorithm, user_id, deleted_at, status, status_until, status_reason, password_reset_required FROM users WHERE email_index = :email_index", params! {
+            "email_index" => data.environment.email_encryption.blind_index(&email::index_form(&identifier, &data.environment.email_normalization))
+        })
+    } else {
+        conn.exec::<Row, &str, Params>("SELECT password, salt, password_algorithm, user_id, deleted_at, status, status_until, status_reason, password_reset_required FROM users WHERE username = :username", params! {
+            "username" => username::normalize(&identifier)
+        })
+    };
 
     if sql_fetch_user_wrapped.is_err() {
         eprintln!("An error occurred (login.rs): {:?}", sql_fetch_user_wrapped.err().unwrap());
@@ -56,8 +111,14 @@ pub async fn post_login(data: web::Data<AppData>, form: web::Form<LoginForm>) ->
     let sql_fetch_user = sql_fetch_user_wrapped.unwrap();
     let row_count = sql_fetch_user.len();
 
+    //Spend as much time on an unknown account as on a wrong password, so timing doesn't tell whether an account exists
     if row_count == 0 {
-        let response = LoginResponse { status: 401, message: Some("E-mail and password combination is invalid, or the account does not exist.".to_string()), session_id: None, expiry: None };
+        if refuse_unknown_account(&data, &mut conn, &req, &password).is_err() {
+            return HttpResponse::InternalServerError().finish();
+        }
+
+        challenge::record_login_failure(&data, &req, &identifier);
+        let response = LoginResponse { status: 401, message: Some(INVALID_CREDENTIALS.to_string()), session_id: None, expiry: None, account_state: None, password_reset_required: None, challenge_required: None };
         return HttpResponse::Ok().json(&response);
     }
 
@@ -66,44 +127,151 @@ pub async fn post_login(data: web::Data<AppData>, form: web::Form<LoginForm>) ->
         return HttpResponse::InternalServerError().finish();
     }
 
-    let (password_from_db, salt, user_id) = {
-        let row = sql_fetch_user.get(0).unwrap();
+    let (password_from_db, salt, password_algorithm, user_id, deleted_at, account_state, mut password_reset_required) = {
+        let row = sql_fetch_user.first().unwrap();
         let password = row.get::<String, &str>("password").unwrap();
         let salt = row.get::<String, &str>("salt").unwrap();
+        let password_algorithm = row.get::<Option<String>, &str>("password_algorithm").unwrap();
         let user_id = row.get::<String, &str>("user_id").unwrap();
+        let deleted_at = row.get::<Option<i64>, &str>("deleted_at").unwrap();
+        let account_state = AccountState::from_row(row);
+        let password_reset_required = row.get::<bool, &str>("password_reset_required").unwrap();
 
-        (password, salt, user_id)
+        (password, salt, password_algorithm, user_id, deleted_at, account_state, password_reset_required)
     };
 
-    let mut hasher = Sha512Trunc256::new();
-    hasher.update(&password);
-    hasher.update(&salt);
-    hasher.update(&data.environment.password_pepper);
+    //A locked or backed off account is refused without checking the password, with the same response as a wrong password,
+    //so guesses can't continue and it doesn't tell whether the account exists
+    let attempt = match lockout::begin_attempt(&mut conn, &data.environment.lockout, &user_id) {
+        Ok(Some(attempt)) => attempt,
+        Ok(None) => {
+            //Checked against the stored hash, whose algorithm may be slower or faster than ours, but never used
+            password::verify_stored(&password, &salt, &data.environment.password_pepper, &password_from_db, password_algorithm.as_deref());
+            if users::record_login(&mut conn, &user_id, &req, false).is_err() {
+                return HttpResponse::InternalServerError().finish();
+            }
 
-    let password_hash = base64::encode(hasher.finalize());
-    let password_bcrypt = bcrypt::hash_with_salt(&password_hash, 10, &salt.as_bytes()).unwrap();
+            challenge::record_login_failure(&data, &req, &identifier);
+            let response = LoginResponse { status: 401, message: Some(INVALID_CREDENTIALS.to_string()), session_id: None, expiry: None, account_state: None, password_reset_required: None, challenge_required: None };
+            return HttpResponse::Ok().json(response);
+        },
+        Err(_) => return HttpResponse::InternalServerError().finish()
+    };
 
-    let password_finalized = password_bcrypt.format_for_version(bcrypt::Version::TwoY);
+    if !password::verify_stored(&password, &salt, &data.environment.password_pepper, &password_from_db, password_algorithm.as_deref()) {
+        if users::record_login(&mut conn, &user_id, &req, false).is_err() || lockout::record_failure(&mut conn, &data.environment, &user_id, &attempt).is_err() {
+            return HttpResponse::InternalServerError().finish();
+        }
 
-    if password_finalized != password_from_db {
-        let response = LoginResponse { status: 401, message: Some("E-mail and password combination is invalid, or the account does not exist.".to_string()), session_id: None, expiry: None };
+        challenge::record_login_failure(&data, &req, &identifier);
+        let response = LoginResponse { status: 401, message: Some(INVALID_CREDENTIALS.to_string()), session_id: None, expiry: None, account_state: None, password_reset_required: None, challenge_required: None };
         return HttpResponse::Ok().json(response);
     }
 
-    let session_id: String = rand::thread_rng().sample_iter(&rand::distributions::Alphanumeric).take(64).map(char::from).collect();
-    let expiry = (chrono::Utc::now() + chrono::Duration::days(30)).timestamp();
+    if data.environment.lockout.enabled && lockout::record_success(&mut conn, &user_id).is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    //Only tell someone who knows the password that the account is not active
+    if account_state != AccountState::Active {
+        if users::record_login(&mut conn, &user_id, &req, false).is_err() {
+            return HttpResponse::InternalServerError().finish();
+        }
+
+        let (status, message) = account_state.denial();
+        let response = LoginResponse { status, message: Some(message.to_string()), session_id: None, expiry: None, account_state: Some(account_state), password_reset_required: None, challenge_required: None };
+        return HttpResponse::Ok().json(&response);
+    }
+
+    //Roles can be restricted to networks, e.g. administrators to the corporate network
+    match ipfilter::permits_user(&mut conn, &req, &user_id) {
+        Ok(true) => {},
+        Ok(false) => {
+            if users::record_login(&mut conn, &user_id, &req, false).is_err() {
+                return HttpResponse::InternalServerError().finish();
+            }
+
+            let response = LoginResponse { status: 403, message: Some("Logging in from this network is not allowed.".to_string()), session_id: None, expiry: None, account_state: None, password_reset_required: None, challenge_required: None };
+            return HttpResponse::Ok().json(&response);
+        },
+        Err(_) => return HttpResponse::InternalServerError().finish()
+    }
+
+    //Imported hashes are replaced with a native one as soon as we know the password. The system they were imported from
+    //may have had weaker rules than ours, so a password which doesn't meet the policy has to be changed
+    if password_algorithm.is_some() {
+        if users::rehash_password(&mut conn, &user_id, &password, &data.environment).is_err() {
+            return HttpResponse::InternalServerError().finish();
+        }
+
+        let email = match users::fetch_email(&mut conn, &data.environment.email_encryption, &user_id) {
+            Ok(email) => email,
+            Err(_) => return HttpResponse::InternalServerError().finish()
+        };
+
+        let violations = match password_policy::check_in_background(&data, &password, Some(&email)).await {
+            Ok(violations) => violations,
+            Err(_) => return HttpResponse::InternalServerError().finish()
+        };
+
+        if !password_reset_required && !violations.is_empty() {
+            if users::require_password_reset(&mut conn, &data.environment, &user_id).is_err() {
+                return HttpResponse::InternalServerError().finish();
+            }
+
+            password_reset_required = true;
+        }
+    }
+
+    //Passwords which have appeared in a breach since they were set have to be changed
+    if !password_reset_required && data.environment.breach.check_on_login && breach::is_breached_in_background(&data, &password).await {
+        if users::require_password_reset(&mut conn, &data.environment, &user_id).is_err() {
+            return HttpResponse::InternalServerError().finish();
+        }
+
+        password_reset_required = true;
+    }
+
+    //Logging in during the grace period cancels a scheduled deletion
+    let mut message = None;
+    if deleted_at.is_some() {
+        let sql_cancel_deletion = conn.exec_drop("UPDATE users SET deleted_at = NULL WHERE user_id = :user_id", params! {
+            "user_id" => user_id.clone()
+        });
 
-    let sql_write_session_id = conn.exec::<usize, &str, Params>("INSERT INTO sessions (session_id, user_id, expiry) VALUES (:session_id, :user_id, :expiry)", params! {
-        "session_id" => session_id.clone(),
-        "user_id" => user_id,
-        "expiry" => expiry.clone()
-    });
+        if sql_cancel_deletion.is_err() {
+            eprintln!("An error occurred (login.rs): {:?}", sql_cancel_deletion.err().unwrap());
+            return HttpResponse::InternalServerError().finish();
+        }
 
-    if sql_write_session_id.is_err() {
-        eprintln!("An error occurred (login.rs): {:?}", sql_write_session_id.err().unwrap());
+        message = Some("Account deletion cancelled.".to_string());
+    }
+
+    if users::record_login(&mut conn, &user_id, &req, true).is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    //Logins from a device or network the account hasn't been used from are mailed to its owner
+    let fingerprint = devices::Fingerprint::from_request(&req, form.device_id.as_deref());
+    if devices::record_login(&mut conn, &data.environment, &user_id, &fingerprint).is_err() {
         return HttpResponse::InternalServerError().finish();
     }
 
-    let response = LoginResponse { status: 200, message: None, session_id: Some(session_id), expiry: Some(expiry) };
-    return HttpResponse::Ok().json(&response);
+    let session = sessions::create(&mut conn, &user_id, sessions::AuthMethod::Password, &binding);
+    if session.is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+    let (session_id, expiry) = session.unwrap();
+
+    let password_reset_required = Some(password_reset_required).filter(|required| *required);
+    let response = LoginResponse { status: 200, message, session_id: Some(session_id), expiry: Some(expiry), account_state: None, password_reset_required, challenge_required: None };
+    HttpResponse::Ok().json(&response)
+}
+
+/// Do the work of a wrong password for an identifier without an account: hash the password, count the attempt and record
+/// the failed login, so neither timing nor load tells whether the account exists. The login is recorded without a user
+fn refuse_unknown_account(data: &AppData, conn: &mut PooledConn, req: &HttpRequest, password: &str) -> Result<(), ()> {
+    password::simulate_verify(password, &data.environment.password_pepper);
+    lockout::simulate_attempt(conn, &data.environment.lockout)?;
+    users::record_login(conn, "", req, false)
 }
\ No newline at end of file
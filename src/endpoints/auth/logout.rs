@@ -35,7 +35,7 @@ pub async fn post_logout(data: web::Data<AppData>, form: web::Form<LogoutRequest
         return HttpResponse::InternalServerError().finish();
     }
 
-    if sql_verify_session_id.unwrap().len() == 0 {
+    if sql_verify_session_id.unwrap().is_empty() {
         //session_id doesn't exist
         let response = LogoutResponse { status: 401 };
         return HttpResponse::Ok().json(&response);
@@ -2,3 +2,11 @@ pub mod login;
 pub mod register;
 pub mod logout;
 pub mod session;
+pub mod profile;
+pub mod account;
+pub mod email;
+pub mod unlock;
+pub mod password;
+pub mod challenge;
+pub mod report;
+pub mod reauthenticate;
@@ -0,0 +1,188 @@
+use crate::appdata::AppData;
+use crate::{lockout, pages, password_policy, sessions, users};
+use crate::password_policy::Violation;
+
+use actix_web::{web, get, post, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize)]
+pub struct ChangePasswordForm {
+    session_id:              String,
+    current_password_base64: String,
+    new_password_base64:     String
+}
+
+#[derive(Deserialize)]
+pub struct ResetLinkForm {
+    token:      String
+}
+
+#[derive(Deserialize)]
+pub struct ResetPasswordForm {
+    token:          String,
+    /// Posted by the page the link opens, so it isn't base64 encoded like the passwords of the API
+    new_password:   String
+}
+
+#[derive(Deserialize)]
+pub struct RequestResetForm {
+    session_id: String
+}
+
+#[derive(Serialize, Default)]
+pub struct ChangePasswordResponse {
+    status:     i16,
+    message:    Option<&'static str>,
+    /// The amount of other sessions which were logged out
+    revoked:    Option<u64>,
+    /// Why the new password was refused
+    #[serde(skip_serializing_if = "Option::is_none")]
+    violations: Option<Vec<Violation>>
+}
+
+/// Change the password of the account the session belongs to. The user has to provide their current password,
+/// and the session has to have authenticated recently. Wrong passwords count towards the account's lockout.
+/// Every other session is logged out, the session which made the change stays valid.
+/// While the user is required to change their password this is refused, as whoever else knows the current password
+/// could make the change too. The new password is set with the link mailed to them instead, see `post_reset_password`
+#[post("/auth/password/change")]
+pub async fn post_change_password(data: web::Data<AppData>, req: HttpRequest, form: web::Form<ChangePasswordForm>) -> HttpResponse {
+    let current_password_wrapped = base64::decode(form.current_password_base64.as_bytes());
+    if current_password_wrapped.is_err() {
+        return HttpResponse::BadRequest().body(current_password_wrapped.err().unwrap().to_string());
+    }
+    let current_password = String::from_utf8(current_password_wrapped.unwrap()).unwrap();
+
+    let new_password_wrapped = base64::decode(form.new_password_base64.as_bytes());
+    if new_password_wrapped.is_err() {
+        return HttpResponse::BadRequest().body(new_password_wrapped.err().unwrap().to_string());
+    }
+
+    let new_password = String::from_utf8(new_password_wrapped.unwrap()).unwrap();
+
+    let conn_wrapped = data.database.pool.get_conn();
+    if conn_wrapped.is_err() {
+        eprintln!("An error occurred (password.rs): {:?}", conn_wrapped.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+    let mut conn = conn_wrapped.unwrap();
+
+    let session = match sessions::require_form_session(&mut conn, &req, &form.session_id) {
+        Ok(session) => session,
+        Err(response) => return response
+    };
+
+    //A recent authentication doesn't stand in for the password, someone at an unlocked device has a fresh session too
+    if let Err(response) = sessions::require_fresh(&data.environment, &session) {
+        return response;
+    }
+
+    match lockout::check_password(&mut conn, &data.environment, &session.user_id, &current_password) {
+        Ok(true) => {},
+        Ok(false) => {
+            let response = ChangePasswordResponse { status: 401, message: Some("Password is incorrect."), ..Default::default() };
+            return HttpResponse::Ok().json(&response);
+        },
+        Err(_) => return HttpResponse::InternalServerError().finish()
+    }
+
+    let violations = match password_policy::check_for_user(&mut conn, &data, &session.user_id, &new_password).await {
+        Ok(violations) => violations,
+        Err(_) => return HttpResponse::InternalServerError().finish()
+    };
+
+    if let Some(violation) = violations.first() {
+        let response = ChangePasswordResponse { status: 400, message: Some(violation.message()), violations: Some(violations.clone()), ..Default::default() };
+        return HttpResponse::Ok().json(&response);
+    }
+
+    if users::set_password(&mut conn, &session.user_id, &new_password, &data.environment).is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let revoked = sessions::revoke_others(&mut conn, &session.user_id, &session.session_id);
+    if revoked.is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let response = ChangePasswordResponse { status: 200, message: Some("Your password has been changed."), revoked: revoked.ok(), violations: None };
+    HttpResponse::Ok().json(&response)
+}
+
+/// The page the password reset link opens, which posts the token back with the new password
+#[get("/auth/password/reset")]
+pub async fn get_reset_password(query: web::Query<ResetLinkForm>) -> HttpResponse {
+    pages::new_password("Choose a new password", "Your password has to be changed before your account can be used again. You will be logged out everywhere.", "/auth/password/reset", &query.token, "Change password")
+}
+
+/// Set a new password with the link mailed when the user was required to change it. The link can only be used once,
+/// and every session of the account is logged out
+#[post("/auth/password/reset")]
+pub async fn post_reset_password(data: web::Data<AppData>, form: web::Form<ResetPasswordForm>) -> HttpResponse {
+    let conn_wrapped = data.database.pool.get_conn();
+    if conn_wrapped.is_err() {
+        eprintln!("An error occurred (password.rs): {:?}", conn_wrapped.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+    let mut conn = conn_wrapped.unwrap();
+
+    let user_id = match users::password_reset_user(&mut conn, &form.token) {
+        Ok(Some(user_id)) => user_id,
+        Ok(None) => {
+            let response = ChangePasswordResponse { status: 404, message: Some("Unknown or expired link."), ..Default::default() };
+            return HttpResponse::Ok().json(&response);
+        },
+        Err(_) => return HttpResponse::InternalServerError().finish()
+    };
+
+    let violations = match password_policy::check_for_user(&mut conn, &data, &user_id, &form.new_password).await {
+        Ok(violations) => violations,
+        Err(_) => return HttpResponse::InternalServerError().finish()
+    };
+
+    if let Some(violation) = violations.first() {
+        let response = ChangePasswordResponse { status: 400, message: Some(violation.message()), violations: Some(violations.clone()), ..Default::default() };
+        return HttpResponse::Ok().json(&response);
+    }
+
+    match users::reset_password_with_token(&mut conn, &form.token, &user_id, &form.new_password, &data.environment) {
+        Ok(true) => {},
+        Ok(false) => {
+            let response = ChangePasswordResponse { status: 404, message: Some("Unknown or expired link."), ..Default::default() };
+            return HttpResponse::Ok().json(&response);
+        },
+        Err(_) => return HttpResponse::InternalServerError().finish()
+    }
+
+    let revoked = sessions::revoke_all(&mut conn, &user_id);
+    if revoked.is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let response = ChangePasswordResponse { status: 200, message: Some("Your password has been changed. Log in with your new password."), revoked: revoked.ok(), violations: None };
+    HttpResponse::Ok().json(&response)
+}
+
+/// Mail a new password reset link, e.g. because the previous one expired. Only sessions whose user has to change their
+/// password can request one, and it is always mailed to the account's address
+#[post("/auth/password/reset/request")]
+pub async fn post_request_password_reset(data: web::Data<AppData>, req: HttpRequest, form: web::Form<RequestResetForm>) -> HttpResponse {
+    let conn_wrapped = data.database.pool.get_conn();
+    if conn_wrapped.is_err() {
+        eprintln!("An error occurred (password.rs): {:?}", conn_wrapped.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+    let mut conn = conn_wrapped.unwrap();
+
+    let session = match sessions::require_session_for_password_reset(&mut conn, &req, &form.session_id) {
+        Ok(session) => session,
+        Err(response) => return response
+    };
+
+    if users::send_password_reset_link(&mut conn, &data.environment, &session.user_id).is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let response = ChangePasswordResponse { status: 200, message: Some("A link to choose a new password has been mailed to you."), ..Default::default() };
+    HttpResponse::Ok().json(&response)
+}
@@ -0,0 +1,200 @@
+use crate::appdata::AppData;
+use crate::profile;
+use crate::sessions;
+
+use actix_web::{web, post, HttpRequest, HttpResponse};
+use mysql::prelude::Queryable;
+use mysql::{PooledConn, Row, Params, params};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+#[derive(Deserialize)]
+pub struct ProfileForm {
+    session_id: String
+}
+
+#[derive(Deserialize)]
+pub struct UpdateProfileForm {
+    session_id: String,
+    /// A JSON merge patch (RFC 7396) of the profile
+    patch:      String
+}
+
+#[derive(Serialize)]
+pub struct Profile {
+    user_id:        String,
+    email:          String,
+    username:       Option<String>,
+    display_name:   Option<String>,
+    locale:         Option<String>,
+    timezone:       Option<String>,
+    avatar_url:     Option<String>,
+    attributes:     Map<String, Value>
+}
+
+#[derive(Serialize, Default)]
+pub struct ProfileResponse {
+    status:     i16,
+    message:    Option<String>,
+    profile:    Option<Profile>
+}
+
+/// Get the profile of the user the session belongs to
+#[post("/auth/profile")]
+pub async fn post_profile(data: web::Data<AppData>, req: HttpRequest, form: web::Form<ProfileForm>) -> HttpResponse {
+    let conn_wrapped = data.database.pool.get_conn();
+    if conn_wrapped.is_err() {
+        eprintln!("An error occurred (profile.rs): {:?}", conn_wrapped.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+    let mut conn = conn_wrapped.unwrap();
+
+    let session = match sessions::require_form_session(&mut conn, &req, &form.session_id) {
+        Ok(session) => session,
+        Err(response) => return response
+    };
+
+    let profile = fetch_profile(&mut conn, &data, &session.user_id);
+    if profile.is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let response = ProfileResponse { status: 200, message: None, profile: Some(profile.unwrap()) };
+    HttpResponse::Ok().json(&response)
+}
+
+/// Update the profile of the user the session belongs to.
+///
+/// `patch` is a JSON merge patch (RFC 7396): fields which are absent are left untouched and `null` clears a field.
+/// Custom attributes are merged the same way, key by key
+#[post("/auth/profile/update")]
+pub async fn post_update_profile(data: web::Data<AppData>, req: HttpRequest, form: web::Form<UpdateProfileForm>) -> HttpResponse {
+    let patch = match serde_json::from_str::<Map<String, Value>>(&form.patch) {
+        Ok(patch) => patch,
+        Err(_) => {
+            let response = ProfileResponse { status: 400, message: Some("The patch must be a JSON object.".to_string()), ..Default::default() };
+            return HttpResponse::Ok().json(&response);
+        }
+    };
+
+    let conn_wrapped = data.database.pool.get_conn();
+    if conn_wrapped.is_err() {
+        eprintln!("An error occurred (profile.rs): {:?}", conn_wrapped.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+    let mut conn = conn_wrapped.unwrap();
+
+    let session = match sessions::require_form_session(&mut conn, &req, &form.session_id) {
+        Ok(session) => session,
+        Err(response) => return response
+    };
+
+    let profile = fetch_profile(&mut conn, &data, &session.user_id);
+    if profile.is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+    let mut profile = profile.unwrap();
+
+    for (field, value) in patch.iter() {
+        let validation_result = match field.as_str() {
+            "display_name" => apply_field(&mut profile.display_name, value, profile::validate_display_name),
+            "locale" => apply_field(&mut profile.locale, value, profile::validate_locale),
+            "timezone" => apply_field(&mut profile.timezone, value, profile::validate_timezone),
+            "avatar_url" => apply_field(&mut profile.avatar_url, value, profile::validate_avatar_url),
+            "attributes" => match value {
+                Value::Object(attributes) => {
+                    for (key, value) in attributes {
+                        if value.is_null() {
+                            profile.attributes.remove(key);
+                        } else {
+                            profile.attributes.insert(key.clone(), value.clone());
+                        }
+                    }
+
+                    data.environment.profile.validate_attributes(&profile.attributes)
+                },
+                Value::Null => {
+                    profile.attributes.clear();
+                    Ok(())
+                },
+                _ => Err("Attributes must be an object.".to_string())
+            },
+            _ => Err(format!("Unknown field '{}'.", field))
+        };
+
+        if let Err(message) = validation_result {
+            let response = ProfileResponse { status: 400, message: Some(message), ..Default::default() };
+            return HttpResponse::Ok().json(&response);
+        }
+    }
+
+    let sql_update_profile = conn.exec::<usize, &str, Params>("UPDATE users SET display_name = :display_name, locale = :locale, timezone = :timezone, avatar_url = :avatar_url, attributes = :attributes WHERE user_id = :user_id", params! {
+        "display_name" => profile.display_name.clone(),
+        "locale" => profile.locale.clone(),
+        "timezone" => profile.timezone.clone(),
+        "avatar_url" => profile.avatar_url.clone(),
+        "attributes" => Value::Object(profile.attributes.clone()).to_string(),
+        "user_id" => session.user_id
+    });
+
+    if sql_update_profile.is_err() {
+        eprintln!("An error occurred (profile.rs): {:?}", sql_update_profile.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let response = ProfileResponse { status: 200, message: None, profile: Some(profile) };
+    HttpResponse::Ok().json(&response)
+}
+
+/// Set or, if `value` is `null`, clear a profile field
+fn apply_field(field: &mut Option<String>, value: &Value, validate: fn(&str) -> Result<(), &'static str>) -> Result<(), String> {
+    match value {
+        Value::Null => {
+            *field = None;
+            Ok(())
+        },
+        Value::String(value) => {
+            validate(value)?;
+            *field = Some(value.clone());
+            Ok(())
+        },
+        _ => Err("Profile fields must be a string or null.".to_string())
+    }
+}
+
+pub fn fetch_profile(conn: &mut PooledConn, data: &AppData, user_id: &str) -> Result<Profile, ()> {
+    let sql_fetch_user = conn.exec::<Row, &str, Params>("SELECT email, username, display_name, locale, timezone, avatar_url, attributes FROM users WHERE user_id = :user_id", params! {
+        "user_id" => user_id
+    });
+
+    if sql_fetch_user.is_err() {
+        eprintln!("An error occurred (profile.rs): {:?}", sql_fetch_user.err().unwrap());
+        return Err(());
+    }
+
+    let sql_fetch_user = sql_fetch_user.unwrap();
+    let row = match sql_fetch_user.first() {
+        Some(row) => row,
+        None => {
+            eprintln!("Session belongs to user '{}', who does not exist (profile.rs)", user_id);
+            return Err(());
+        }
+    };
+
+    let email = data.environment.email_encryption.decrypt(&row.get::<String, &str>("email").unwrap());
+    if email.is_err() {
+        eprintln!("Unable to decrypt the email address of user '{}' (profile.rs)", user_id);
+        return Err(());
+    }
+
+    Ok(Profile {
+        user_id:        user_id.to_string(),
+        email:          email.unwrap(),
+        username:       row.get::<Option<String>, &str>("username").unwrap(),
+        display_name:   row.get::<Option<String>, &str>("display_name").unwrap(),
+        locale:         row.get::<Option<String>, &str>("locale").unwrap(),
+        timezone:       row.get::<Option<String>, &str>("timezone").unwrap(),
+        avatar_url:     row.get::<Option<String>, &str>("avatar_url").unwrap(),
+        attributes:     profile::parse_attributes(row.get::<Option<String>, &str>("attributes").unwrap())
+    })
+}
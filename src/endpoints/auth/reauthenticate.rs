@@ -0,0 +1,60 @@
+use crate::appdata::AppData;
+use crate::{lockout, sessions};
+use crate::sessions::AuthMethod;
+
+use actix_web::{web, post, HttpRequest, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize)]
+pub struct ReauthenticateForm {
+    session_id:         String,
+    password_base64:    String
+}
+
+#[derive(Serialize, Default)]
+pub struct ReauthenticateResponse {
+    status:         i16,
+    message:        Option<&'static str>,
+    /// Until when the session may perform sensitive operations
+    fresh_until:    Option<i64>
+}
+
+/// Authenticate again within a session, so it may perform sensitive operations such as changing the email address.
+/// Wrong passwords count towards the account's lockout, like failed logins
+#[post("/auth/reauthenticate")]
+pub async fn post_reauthenticate(data: web::Data<AppData>, req: HttpRequest, form: web::Form<ReauthenticateForm>) -> HttpResponse {
+    let password_wrapped = base64::decode(form.password_base64.as_bytes());
+    if password_wrapped.is_err() {
+        return HttpResponse::BadRequest().body(password_wrapped.err().unwrap().to_string());
+    }
+    let password = String::from_utf8(password_wrapped.unwrap()).unwrap();
+
+    let conn_wrapped = data.database.pool.get_conn();
+    if conn_wrapped.is_err() {
+        eprintln!("An error occurred (reauthenticate.rs): {:?}", conn_wrapped.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+    let mut conn = conn_wrapped.unwrap();
+
+    let session = match sessions::require_form_session(&mut conn, &req, &form.session_id) {
+        Ok(session) => session,
+        Err(response) => return response
+    };
+
+    match lockout::check_password(&mut conn, &data.environment, &session.user_id, &password) {
+        Ok(true) => {},
+        Ok(false) => {
+            let response = ReauthenticateResponse { status: 401, message: Some("Password is incorrect."), ..Default::default() };
+            return HttpResponse::Ok().json(&response);
+        },
+        Err(_) => return HttpResponse::InternalServerError().finish()
+    }
+
+    let fresh_until = sessions::reauthenticate(&mut conn, &data.environment, &session, AuthMethod::Password);
+    if fresh_until.is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let response = ReauthenticateResponse { status: 200, message: None, fresh_until: fresh_until.ok() };
+    HttpResponse::Ok().json(&response)
+}
@@ -1,30 +1,43 @@
 use crate::appdata::AppData;
+use crate::{challenge, email, mail, password_policy, ratelimit, sessions, users};
+use crate::password_policy::Violation;
+use crate::username::{self, UsernamePolicy};
+use crate::users::CreateError;
+use crate::sessions::SessionBinding;
 
-use actix_web::{web, post, HttpResponse};
+use actix_web::{web, post, HttpRequest, HttpResponse};
 use mysql::prelude::Queryable;
-use mysql::{Row, Params, params};
-use rand::Rng;
-use sha2::{Sha512Trunc256, Digest};
+use mysql::{PooledConn, Row, Params, params};
 use serde::{Serialize, Deserialize};
-use regex::Regex;
-use lazy_static::lazy_static;
 
 #[derive(Deserialize)]
 pub struct RegisterForm {
     email_base64:       String,
-    password_base64:    String
+    password_base64:    String,
+    username_base64:    Option<String>,
+    /// Base64 encoded DER public key to bind the session to, see `sessions::SessionBinding`
+    binding_key:        Option<String>,
+    /// See `/auth/challenge`
+    challenge:          Option<String>,
+    challenge_solution: Option<String>
 }
 
 #[derive(Serialize)]
 pub struct RegisterResponse {
-    status:     i16,
-    message:    Option<String>,
-    session_id: Option<String>,
-    expiry:     Option<i64>
+    status:             i16,
+    message:            Option<String>,
+    session_id:         Option<String>,
+    expiry:             Option<i64>,
+    /// Why the password was refused
+    #[serde(skip_serializing_if = "Option::is_none")]
+    violations:         Option<Vec<Violation>>,
+    /// Set if a challenge has to be solved before registering
+    #[serde(skip_serializing_if = "Option::is_none")]
+    challenge_required: Option<bool>
 }
 
 #[post("/auth/register")]
-pub async fn post_register(data: web::Data<AppData>, form: web::Form<RegisterForm>) -> HttpResponse {
+pub async fn post_register(data: web::Data<AppData>, req: HttpRequest, form: web::Form<RegisterForm>) -> HttpResponse {
     let email_wrapped = base64::decode(form.email_base64.clone().as_bytes());
     if email_wrapped.is_err() {
         return HttpResponse::BadRequest().body(email_wrapped.err().unwrap().to_string());
@@ -38,28 +51,84 @@ pub async fn post_register(data: web::Data<AppData>, form: web::Form<RegisterFor
     let email = String::from_utf8(email_wrapped.unwrap()).unwrap();
     let password = String::from_utf8(password_wrapped.unwrap()).unwrap();
 
+    if let Err(response) = ratelimit::check_identifier(&data, ratelimit::Endpoint::Register, &email) {
+        return response;
+    }
+
+    if !challenge::verify(&data, &req, form.challenge.as_deref(), form.challenge_solution.as_deref()).await {
+        let response = RegisterResponse { status: 428, message: Some(challenge::CHALLENGE_REQUIRED.to_string()), session_id: None, expiry: None, violations: None, challenge_required: Some(true) };
+        return HttpResponse::Ok().json(&response);
+    }
+
+    let binding = match SessionBinding::from_request(&data.environment.session_binding, &req, form.binding_key.as_deref()) {
+        Ok(binding) => binding,
+        Err(message) => {
+            let response = RegisterResponse { status: 400, message: Some(message.to_string()), session_id: None, expiry: None, violations: None, challenge_required: None };
+            return HttpResponse::Ok().json(&response);
+        }
+    };
+
+    let username = match &form.username_base64 {
+        Some(username_base64) => {
+            let username_wrapped = base64::decode(username_base64.as_bytes());
+            if username_wrapped.is_err() {
+                return HttpResponse::BadRequest().body(username_wrapped.err().unwrap().to_string());
+            }
+
+            Some(username::normalize(&String::from_utf8(username_wrapped.unwrap()).unwrap()))
+        },
+        None => None
+    };
+
+    //Check the username against the configured policy
+    match (data.environment.username_policy, &username) {
+        (UsernamePolicy::Disabled, Some(_)) => {
+            let response = RegisterResponse { status: 400, message: Some("Usernames are not enabled.".to_string()), session_id: None, expiry: None, violations: None, challenge_required: None };
+            return HttpResponse::Ok().json(&response);
+        },
+        (UsernamePolicy::Required, None) => {
+            let response = RegisterResponse { status: 400, message: Some("A username is required.".to_string()), session_id: None, expiry: None, violations: None, challenge_required: None };
+            return HttpResponse::Ok().json(&response);
+        },
+        (_, Some(username)) if !username::is_valid(username) => {
+            let response = RegisterResponse { status: 400, message: Some("Invalid username. Usernames are 3 to 32 characters long, and may only contain letters, digits, '_', '.' and '-'.".to_string()), session_id: None, expiry: None, violations: None, challenge_required: None };
+            return HttpResponse::Ok().json(&response);
+        },
+        _ => {}
+    }
+
     let conn_wrapped = data.database.pool.get_conn();
     if conn_wrapped.is_err() {
         eprintln!("An error occurred: {:?}", conn_wrapped.err().unwrap());
         return HttpResponse::InternalServerError().finish();
     }
 
-    //Lazy static so the regular expression only gets compiled once
-    //Since compiling can take up to a couple milliseconds, we don't want it to happen on every request
-    lazy_static! {
-        static ref EMAIL_REGEX: Regex = Regex::new(r#"(([^<>()\[\]\\.,;:\s@"]+(\.[^<>()\[\]\\.,;:\s@"]+)*)|(".+"))@((\[[0-9]{1,3}\.[0-9]{1,3}\.[0-9]{1,3}\.[0-9]{1,3}])|(([a-zA-Z\-0-9]+\.)+[a-zA-Z]{2,}))"#).unwrap();
+    let email = email.trim().to_string();
+    let email_normalized = email::parse(&email, &data.environment.email_normalization);
+    if email_normalized.is_none() {
+        let response = RegisterResponse { status: 400, message: Some("Invalid E-mail address.".to_string()), session_id: None, expiry: None, violations: None, challenge_required: None };
+        return HttpResponse::Ok().json(&response);
     }
-    let email_regex_captures = EMAIL_REGEX.captures(&email);
 
-    if email_regex_captures.is_none() {
-        let response = RegisterResponse { status: 400, message: Some("Invalid E-mail address.".to_string()), session_id: None, expiry: None };
+    let violations = match password_policy::check_in_background(&data, &password, Some(&email)).await {
+        Ok(violations) => violations,
+        Err(_) => return HttpResponse::InternalServerError().finish()
+    };
+    if let Some(violation) = violations.first() {
+        let response = RegisterResponse { status: 400, message: Some(violation.message().to_string()), session_id: None, expiry: None, violations: Some(violations.clone()), challenge_required: None };
         return HttpResponse::Ok().json(&response);
     }
 
     let mut conn = conn_wrapped.unwrap();
 
-    let sql_check_email_wrapped = conn.exec::<Row, &str, Params>("SELECT 1 FROM users WHERE email = :email", params! {
-        "email" => email.clone()
+    if data.environment.registration.enumeration_safe {
+        return register_enumeration_safe(&data, &mut conn, &email, username.as_deref(), &password);
+    }
+
+    //Email addresses are encrypted, so we look them up by the blind index of their normalized form
+    let email_index = data.environment.email_encryption.blind_index(&email_normalized.unwrap());
+    let sql_check_email_wrapped = conn.exec::<Row, &str, Params>("SELECT 1 FROM users WHERE email_index = :email_index", params! {
+        "email_index" => email_index.clone()
     });
 
     if sql_check_email_wrapped.is_err() {
@@ -67,51 +136,94 @@ pub async fn post_register(data: web::Data<AppData>, form: web::Form<RegisterFor
         return HttpResponse::InternalServerError().finish();
     }
 
-    if sql_check_email_wrapped.unwrap().len() != 0 {
-        let response = RegisterResponse { status: 409, message: Some("Account already exists.".to_string()), session_id: None, expiry: None };
+    if !sql_check_email_wrapped.unwrap().is_empty() {
+        let response = RegisterResponse { status: 409, message: Some("Account already exists.".to_string()), session_id: None, expiry: None, violations: None, challenge_required: None };
         return HttpResponse::Ok().json(response);
     }
 
-    let salt: String = rand::thread_rng().sample_iter(&rand::distributions::Alphanumeric).take(16).map(char::from).collect();
+    if username.is_some() {
+        let sql_check_username = conn.exec::<Row, &str, Params>("SELECT 1 FROM users WHERE username = :username", params! {
+            "username" => username.clone()
+        });
 
-    let mut hasher = Sha512Trunc256::new();
-    hasher.update(&password);
-    hasher.update(&salt);
-    hasher.update(&data.environment.password_pepper);
+        if sql_check_username.is_err() {
+            eprintln!("An error occurred (register.rs): {:?}", sql_check_username.err().unwrap());
+            return HttpResponse::InternalServerError().finish();
+        }
 
-    let password_hash = base64::encode(hasher.finalize());
-    let password_bcrypt = bcrypt::hash_with_salt(&password_hash, 10, &salt.as_bytes()).unwrap();
-
-    let password_finalized = password_bcrypt.format_for_version(bcrypt::Version::TwoY);
-
-    let session_id: String = rand::thread_rng().sample_iter(&rand::distributions::Alphanumeric).take(64).map(char::from).collect();
-    let user_id: String = rand::thread_rng().sample_iter(&rand::distributions::Alphanumeric).take(64).map(char::from).collect();
-
-    let sql_insert_user = conn.exec::<usize, &str, Params>("INSERT INTO users (user_id, email, password, salt) VALUES (:user_id, :email, :password, :salt)", params! {
-        "user_id" => user_id.clone(),
-        "email" => email,
-        "password" => password_finalized,
-        "salt" => salt
-    });
+        if !sql_check_username.unwrap().is_empty() {
+            let response = RegisterResponse { status: 409, message: Some("Username is already taken.".to_string()), session_id: None, expiry: None, violations: None, challenge_required: None };
+            return HttpResponse::Ok().json(response);
+        }
+    }
 
-    if sql_insert_user.is_err() {
-        eprintln!("An error occurred (register.rs): {:?}", sql_insert_user.err().unwrap());
+    let user_id = match users::create(&mut conn, &data.environment, &email, username.as_deref(), &password) {
+        Ok(user_id) => user_id,
+        //Another request registered the same address or username between our checks and the insert
+        Err(CreateError::EmailTaken) => {
+            let response = RegisterResponse { status: 409, message: Some("Account already exists.".to_string()), session_id: None, expiry: None, violations: None, challenge_required: None };
+            return HttpResponse::Ok().json(response);
+        },
+        Err(CreateError::UsernameTaken) => {
+            let response = RegisterResponse { status: 409, message: Some("Username is already taken.".to_string()), session_id: None, expiry: None, violations: None, challenge_required: None };
+            return HttpResponse::Ok().json(response);
+        },
+        Err(CreateError::InvalidEmail) => {
+            let response = RegisterResponse { status: 400, message: Some("Invalid E-mail address.".to_string()), session_id: None, expiry: None, violations: None, challenge_required: None };
+            return HttpResponse::Ok().json(&response);
+        },
+        Err(CreateError::Failed) => return HttpResponse::InternalServerError().finish()
+    };
+
+    let session = sessions::create(&mut conn, &user_id, sessions::AuthMethod::Password, &binding);
+    if session.is_err() {
         return HttpResponse::InternalServerError().finish();
     }
+    let (session_id, expiry) = session.unwrap();
 
-    let expiry = (chrono::Utc::now() + chrono::Duration::days(30)).timestamp();
-
-    let sql_insert_session_id = conn.exec::<usize, &str, Params>("INSERT INTO sessions (session_id, user_id, expiry) VALUES (:session_id, :user_id, :expiry)", params! {
-        "session_id" => session_id.clone(),
-        "user_id" => user_id,
-        "expiry" => expiry.clone()
-    });
+    let response = RegisterResponse { status: 200, message: None, session_id: Some(session_id), expiry: Some(expiry), violations: None, challenge_required: None };
+    HttpResponse::Ok().json(&response)
+}
 
-    if sql_insert_session_id.is_err() {
-        eprintln!("An error occurred (register.rs): {:?}", sql_insert_session_id.err().unwrap());
-        return HttpResponse::InternalServerError().finish();
+/// Register without telling whether the email address or the username already has an account. The address gets a mail
+/// either way, and an account is only created if neither was taken. Every case hashes the password and attempts the
+/// same insert, so they take as long, and they all get the same response
+fn register_enumeration_safe(data: &AppData, conn: &mut PooledConn, email: &str, username: Option<&str>, password: &str) -> HttpResponse {
+    let mail_config = &data.environment.mail;
+
+    //Which unique key MySQL reports when both are taken isn't defined, so the address is looked up to mail the right message.
+    //This happens for every registration, so the lookup doesn't tell anything by its timing either
+    let email_taken = match users::find(conn, &data.environment, email) {
+        Ok(user_id) => user_id.is_some(),
+        Err(_) => return HttpResponse::InternalServerError().finish()
+    };
+
+    let created = match users::create(conn, &data.environment, email, username, password) {
+        Err(CreateError::EmailTaken) | Err(CreateError::UsernameTaken) if email_taken => Err(CreateError::EmailTaken),
+        created => created
+    };
+
+    match created {
+        Ok(_) => mail::send(mail_config, email, "Welcome", &format!(
+            "Your account has been created. You can now log in at:\n{}",
+            mail_config.link("/")
+        )),
+        Err(CreateError::EmailTaken) => mail::send(mail_config, email, "Someone tried to register with your email address", &format!(
+            "Someone tried to create an account with this email address, but you already have one.\n\nIf this was you, log in at:\n{}\n\nIf it was not, you can ignore this mail.",
+            mail_config.link("/")
+        )),
+        Err(CreateError::UsernameTaken) => mail::send(mail_config, email, "Your account has not been created", &format!(
+            "Someone tried to create an account with this email address, but the username '{}' is already taken.\n\nIf this was you, register again with another username at:\n{}\n\nIf it was not, you can ignore this mail.",
+            username.unwrap_or_default(),
+            mail_config.link("/")
+        )),
+        Err(CreateError::InvalidEmail) => {
+            let response = RegisterResponse { status: 400, message: Some("Invalid E-mail address.".to_string()), session_id: None, expiry: None, violations: None, challenge_required: None };
+            return HttpResponse::Ok().json(&response);
+        },
+        Err(CreateError::Failed) => return HttpResponse::InternalServerError().finish()
     }
 
-    let response = RegisterResponse { status: 200, message: None, session_id: Some(session_id), expiry: Some(expiry)};
-    return HttpResponse::Ok().json(&response);
+    let response = RegisterResponse { status: 200, message: Some("Check your email to continue.".to_string()), session_id: None, expiry: None, violations: None, challenge_required: None };
+    HttpResponse::Ok().json(&response)
 }
\ No newline at end of file
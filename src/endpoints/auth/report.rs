@@ -0,0 +1,46 @@
+use crate::appdata::AppData;
+use crate::{devices, pages};
+
+use actix_web::{web, get, post, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize)]
+pub struct ReportLoginForm {
+    token:      String
+}
+
+#[derive(Serialize)]
+pub struct ReportLoginResponse {
+    status:     i16,
+    message:    Option<&'static str>
+}
+
+/// The page the link mailed about a login from a new device or network opens, which posts the token back to report it
+#[get("/auth/login/report")]
+pub async fn get_report_login(query: web::Query<ReportLoginForm>) -> HttpResponse {
+    pages::confirmation("This wasn't me", "Log out everywhere and require a new password, as someone else logged in to your account.", "/auth/login/report", &query.token, "Log out everywhere")
+}
+
+/// Report a login from a new device or network as not being the owner's, using the link mailed when it happened.
+/// Every session of the account is revoked, and its password has to be changed
+#[post("/auth/login/report")]
+pub async fn post_report_login(data: web::Data<AppData>, form: web::Form<ReportLoginForm>) -> HttpResponse {
+    let conn_wrapped = data.database.pool.get_conn();
+    if conn_wrapped.is_err() {
+        eprintln!("An error occurred (report.rs): {:?}", conn_wrapped.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+    let mut conn = conn_wrapped.unwrap();
+
+    match devices::report_with_token(&mut conn, &data.environment, &form.token) {
+        Ok(true) => {
+            let response = ReportLoginResponse { status: 200, message: Some("You have been logged out everywhere. Choose a new password with the link mailed to you.") };
+            HttpResponse::Ok().json(&response)
+        },
+        Ok(false) => {
+            let response = ReportLoginResponse { status: 404, message: Some("Unknown or expired link.") };
+            HttpResponse::Ok().json(&response)
+        },
+        Err(_) => HttpResponse::InternalServerError().finish()
+    }
+}
\ No newline at end of file
@@ -1,25 +1,60 @@
 use crate::appdata::AppData;
+use crate::orgs::{self, OrgRole};
+use crate::sessions::{self, SessionLookup};
+use crate::users::AccountState;
+use crate::{ipfilter, profile, rbac};
 
-use actix_web::{web, post, HttpResponse};
+use actix_web::{web, post, HttpRequest, HttpResponse};
 use mysql::prelude::Queryable;
 use mysql::{Row, Params, params};
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
 
 #[derive(Deserialize)]
 pub struct SessionRequest {
     session_id: String,
 }
 
+#[derive(Deserialize)]
+pub struct SwitchOrgRequest {
+    session_id: String,
+    /// The organization to make active, empty or absent to clear it
+    org_id:     Option<String>
+}
+
 #[derive(Serialize)]
+pub struct ActiveOrg {
+    org_id:     String,
+    name:       String,
+    role:       OrgRole
+}
+
+#[derive(Serialize, Default)]
 pub struct SessionResponse {
     status:         i16,
     user_id:        Option<String>,
     email:          Option<String>,
+    username:       Option<String>,
+    /// Custom profile attributes marked for inclusion in the session response
+    attributes:     Option<Map<String, Value>>,
+    roles:          Option<Vec<String>>,
+    permissions:    Option<Vec<String>>,
+    /// The organization the session is currently acting in, if any
+    active_org:     Option<ActiveOrg>,
+    /// Why the session can't be used, if its account is not active
+    #[serde(skip_serializing_if = "Option::is_none")]
+    account_state:  Option<AccountState>,
     message:        Option<&'static str>
 }
 
+#[derive(Serialize)]
+pub struct SwitchOrgResponse {
+    status:     i16,
+    message:    Option<&'static str>
+}
+
 #[post("/auth/session")]
-pub async fn post_session(data: web::Data<AppData>, form: web::Form<SessionRequest>) -> HttpResponse {
+pub async fn post_session(data: web::Data<AppData>, req: HttpRequest, form: web::Form<SessionRequest>) -> HttpResponse {
     //Database connection
     let conn_wrapped = data.database.pool.get_conn();
     if conn_wrapped.is_err() {
@@ -28,38 +63,52 @@ pub async fn post_session(data: web::Data<AppData>, form: web::Form<SessionReque
     }
     let mut conn = conn_wrapped.unwrap();
 
-    //Verify the session_id
-    let sql_verify_session_id_wrapped = conn.exec::<Row, &str, Params>("SELECT user_id, expiry FROM sessions WHERE session_id = :session_id", params! {
-        "session_id" => form.session_id.clone()
-    });
-
-    if sql_verify_session_id_wrapped.is_err() {
-        eprintln!("An error occurred (session.rs): {:?}", sql_verify_session_id_wrapped.err());
-        return  HttpResponse::InternalServerError().finish();
-    }
-
-    let sql_verify_session_id = sql_verify_session_id_wrapped.unwrap();
-    if sql_verify_session_id.len() == 0 {
-        let response = SessionResponse { status: 401, user_id: None, email: None, message: Some("Session ID not found.") };
-        return HttpResponse::Ok().json(&response);
+    //Verify the session_id and its expiry
+    let session_lookup = sessions::lookup(&mut conn, &form.session_id);
+    if session_lookup.is_err() {
+        return HttpResponse::InternalServerError().finish();
     }
 
-    let (user_id, expiry) = {
-        let row = sql_verify_session_id.get(0).unwrap();
-        let user_id = row.get::<String, &str>("user_id").unwrap();
-        let expiry = row.get::<i64, &str>("expiry").unwrap();
-
-        (user_id, expiry)
+    let (user_id, active_org_id) = match session_lookup.unwrap() {
+        //A session used from somewhere it isn't bound to has probably been stolen, so it is revoked
+        SessionLookup::Valid(session) => match sessions::enforce_binding(&mut conn, &session, &req) {
+            Ok(true) => (session.user_id, session.active_org_id),
+            Ok(false) => {
+                let response = SessionResponse { status: 401, message: Some("Session revoked, as it was used from somewhere it is not bound to."), ..Default::default() };
+                return HttpResponse::Ok().json(&response);
+            },
+            Err(_) => return HttpResponse::InternalServerError().finish()
+        },
+        SessionLookup::NotFound => {
+            let response = SessionResponse { status: 401, message: Some("Session ID not found."), ..Default::default() };
+            return HttpResponse::Ok().json(&response);
+        },
+        SessionLookup::Expired => {
+            let response = SessionResponse { status: 401, message: Some("Session expired"), ..Default::default() };
+            return HttpResponse::Ok().json(&response);
+        },
+        SessionLookup::Inactive(account_state) => {
+            let (status, message) = account_state.denial();
+            let response = SessionResponse { status, message: Some(message), account_state: Some(account_state), ..Default::default() };
+            return HttpResponse::Ok().json(&response);
+        },
+        SessionLookup::PasswordResetRequired(_) => {
+            let response = SessionResponse { status: 403, message: Some(sessions::PASSWORD_RESET_REQUIRED), ..Default::default() };
+            return HttpResponse::Ok().json(&response);
+        }
     };
 
-    //Verify the expiry
-    if chrono::Utc::now().timestamp() >= expiry {
-        let response = SessionResponse { status: 401, user_id: None, email: None, message: Some("Session expired") };
-        return HttpResponse::Ok().json(&response);
+    match ipfilter::permits_user(&mut conn, &req, &user_id) {
+        Ok(true) => {},
+        Ok(false) => {
+            let response = SessionResponse { status: 403, message: Some("Requests from this network are not allowed."), ..Default::default() };
+            return HttpResponse::Ok().json(&response);
+        },
+        Err(_) => return HttpResponse::InternalServerError().finish()
     }
 
-    //Get the E-mail address
-    let sql_get_email_wrapped = conn.exec::<Row, &str, Params>("SELECT email FROM users WHERE user_id = :user_id", params! {
+    //Get the E-mail address, username and attributes
+    let sql_get_email_wrapped = conn.exec::<Row, &str, Params>("SELECT email, username, attributes FROM users WHERE user_id = :user_id", params! {
         "user_id" => user_id.clone()
     });
 
@@ -69,13 +118,99 @@ pub async fn post_session(data: web::Data<AppData>, form: web::Form<SessionReque
     }
 
     let sql_get_email = sql_get_email_wrapped.unwrap();
-    let email = {
-        let row = sql_get_email.get(0).unwrap();
+    let (email_encrypted, username, attributes) = {
+        let row = sql_get_email.first().unwrap();
         let email = row.get::<String, &str>("email").unwrap();
+        let username = row.get::<Option<String>, &str>("username").unwrap();
+        let attributes = profile::parse_attributes(row.get::<Option<String>, &str>("attributes").unwrap());
 
-        email
+        (email, username, attributes)
     };
 
-    let response = SessionResponse { status: 200, user_id: Some(user_id), email: Some(email), message: None };
+    let email = data.environment.email_encryption.decrypt(&email_encrypted);
+    if email.is_err() {
+        eprintln!("Unable to decrypt the email address of user '{}' (session.rs)", user_id);
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let roles = rbac::user_roles(&mut conn, &user_id);
+    let permissions = rbac::user_permissions(&mut conn, &user_id);
+    if roles.is_err() || permissions.is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    //The membership may have changed since the organization was made active
+    let mut active_org = None;
+    if let Some(org_id) = active_org_id {
+        let sql_fetch_org = conn.exec_first::<Row, &str, Params>("SELECT organizations.name, org_memberships.role FROM org_memberships INNER JOIN organizations ON organizations.org_id = org_memberships.org_id WHERE org_memberships.org_id = :org_id AND org_memberships.user_id = :user_id", params! {
+            "org_id" => org_id.clone(),
+            "user_id" => user_id.clone()
+        });
+
+        if sql_fetch_org.is_err() {
+            eprintln!("An error occurred (session.rs): {:?}", sql_fetch_org.err());
+            return HttpResponse::InternalServerError().finish();
+        }
+
+        active_org = sql_fetch_org.unwrap().and_then(|row| Some(ActiveOrg {
+            org_id,
+            name: row.get::<String, &str>("name").unwrap(),
+            role: row.get::<String, &str>("role").unwrap().parse().ok()?
+        }));
+    }
+
+    let response = SessionResponse {
+        status:         200,
+        user_id:        Some(user_id),
+        email:          Some(email.unwrap()),
+        username,
+        attributes:     Some(data.environment.profile.session_attributes(&attributes)),
+        roles:          roles.ok(),
+        permissions:    permissions.ok(),
+        active_org,
+        account_state:  None,
+        message:        None
+    };
+    HttpResponse::Ok().json(&response)
+}
+
+/// Switch the organization a session is acting in. The user has to be a member of it
+#[post("/auth/session/org")]
+pub async fn post_switch_org(data: web::Data<AppData>, req: HttpRequest, form: web::Form<SwitchOrgRequest>) -> HttpResponse {
+    let conn_wrapped = data.database.pool.get_conn();
+    if conn_wrapped.is_err() {
+        eprintln!("An error occurred (session.rs): {:?}", conn_wrapped.err());
+        return HttpResponse::InternalServerError().finish();
+    }
+    let mut conn = conn_wrapped.unwrap();
+
+    let session = match sessions::require_form_session(&mut conn, &req, &form.session_id) {
+        Ok(session) => session,
+        Err(response) => return response
+    };
+
+    let org_id = form.org_id.clone().filter(|org_id| !org_id.is_empty());
+    if let Some(org_id) = &org_id {
+        match orgs::membership(&mut conn, org_id, &session.user_id) {
+            Ok(Some(_)) => {},
+            Ok(None) => {
+                let response = SwitchOrgResponse { status: 403, message: Some("You are not a member of this organization.") };
+                return HttpResponse::Ok().json(&response);
+            },
+            Err(_) => return HttpResponse::InternalServerError().finish()
+        }
+    }
+
+    let sql_update_session = conn.exec_drop("UPDATE sessions SET active_org_id = :org_id WHERE session_id = :session_id", params! {
+        "org_id" => org_id,
+        "session_id" => session.session_id
+    });
+
+    if sql_update_session.is_err() {
+        eprintln!("An error occurred (session.rs): {:?}", sql_update_session.err());
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let response = SwitchOrgResponse { status: 200, message: None };
     HttpResponse::Ok().json(&response)
 }
\ No newline at end of file
@@ -0,0 +1,45 @@
+use crate::appdata::AppData;
+use crate::{lockout, pages};
+
+use actix_web::{web, get, post, HttpResponse};
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize)]
+pub struct UnlockForm {
+    token:      String
+}
+
+#[derive(Serialize)]
+pub struct UnlockResponse {
+    status:     i16,
+    message:    Option<&'static str>
+}
+
+/// The page the unlock link opens, which posts the token back to lift the lockout
+#[get("/auth/unlock")]
+pub async fn get_unlock(query: web::Query<UnlockForm>) -> HttpResponse {
+    pages::confirmation("Unlock your account", "Lift the lockout on logging in to your account.", "/auth/unlock", &query.token, "Unlock")
+}
+
+/// Lift the login lockout of an account, using the link mailed to its owner when it was locked
+#[post("/auth/unlock")]
+pub async fn post_unlock(data: web::Data<AppData>, form: web::Form<UnlockForm>) -> HttpResponse {
+    let conn_wrapped = data.database.pool.get_conn();
+    if conn_wrapped.is_err() {
+        eprintln!("An error occurred (unlock.rs): {:?}", conn_wrapped.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+    let mut conn = conn_wrapped.unwrap();
+
+    match lockout::unlock_with_token(&mut conn, &form.token) {
+        Ok(true) => {
+            let response = UnlockResponse { status: 200, message: Some("Your account has been unlocked.") };
+            HttpResponse::Ok().json(&response)
+        },
+        Ok(false) => {
+            let response = UnlockResponse { status: 404, message: Some("Unknown or expired link.") };
+            HttpResponse::Ok().json(&response)
+        },
+        Err(_) => HttpResponse::InternalServerError().finish()
+    }
+}
@@ -1 +1,4 @@
-pub mod auth;
\ No newline at end of file
+pub mod admin;
+pub mod auth;
+pub mod orgs;
+pub mod scim;
\ No newline at end of file
@@ -0,0 +1,499 @@
+use crate::appdata::AppData;
+use crate::orgs::{self, OrgRole};
+use crate::{crypto, email, mail, sessions, users};
+
+use actix_web::{web, get, post, put, delete, HttpRequest, HttpResponse};
+use mysql::prelude::Queryable;
+use mysql::{Row, TxOpts, params};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// How long an invitation is valid for, in days
+const INVITATION_VALIDITY_DAYS: i64 = 7;
+
+#[derive(Deserialize)]
+pub struct CreateOrgRequest {
+    name:   String
+}
+
+#[derive(Deserialize)]
+pub struct InviteRequest {
+    email:  String,
+    role:   String
+}
+
+#[derive(Deserialize)]
+pub struct AcceptInvitationRequest {
+    token:  String
+}
+
+#[derive(Deserialize)]
+pub struct ChangeRoleRequest {
+    role:   String
+}
+
+#[derive(Serialize)]
+pub struct Organization {
+    org_id: String,
+    name:   String,
+    role:   OrgRole
+}
+
+#[derive(Serialize)]
+pub struct Member {
+    user_id:        String,
+    username:       Option<String>,
+    display_name:   Option<String>,
+    role:           OrgRole,
+    joined_at:      i64
+}
+
+#[derive(Serialize, Default)]
+pub struct OrgResponse {
+    status:         i16,
+    message:        Option<&'static str>,
+    org_id:         Option<String>,
+    organizations:  Option<Vec<Organization>>,
+    members:        Option<Vec<Member>>
+}
+
+fn respond(status: i16, message: &'static str) -> HttpResponse {
+    HttpResponse::Ok().json(&OrgResponse { status, message: Some(message), ..Default::default() })
+}
+
+/// Create an organization, with the caller as its owner
+#[post("/orgs")]
+pub async fn post_org(data: web::Data<AppData>, req: HttpRequest, body: web::Json<CreateOrgRequest>) -> HttpResponse {
+    let name = body.name.trim();
+    if name.is_empty() || name.chars().count() > 128 {
+        return respond(400, "Organization name must be 1 to 128 characters long.");
+    }
+
+    let conn_wrapped = data.database.pool.get_conn();
+    if conn_wrapped.is_err() {
+        eprintln!("An error occurred (orgs.rs): {:?}", conn_wrapped.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+    let mut conn = conn_wrapped.unwrap();
+
+    let session = match sessions::require_session(&mut conn, &req) {
+        Ok(session) => session,
+        Err(response) => return response
+    };
+
+    let org_id: String = rand::thread_rng().sample_iter(&rand::distributions::Alphanumeric).take(32).map(char::from).collect();
+    let now = chrono::Utc::now().timestamp();
+
+    let tx = conn.start_transaction(TxOpts::default());
+    if tx.is_err() {
+        eprintln!("An error occurred (orgs.rs): {:?}", tx.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+    let mut tx = tx.unwrap();
+
+    let sql_insert_org = tx.exec_drop("INSERT INTO organizations (org_id, name, created_at) VALUES (:org_id, :name, :created_at)", params! {
+        "org_id" => org_id.clone(),
+        "name" => name,
+        "created_at" => now
+    });
+
+    if sql_insert_org.is_err() {
+        eprintln!("An error occurred (orgs.rs): {:?}", sql_insert_org.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let sql_insert_owner = tx.exec_drop("INSERT INTO org_memberships (org_id, user_id, role, joined_at) VALUES (:org_id, :user_id, :role, :joined_at)", params! {
+        "org_id" => org_id.clone(),
+        "user_id" => session.user_id,
+        "role" => OrgRole::Owner.as_str(),
+        "joined_at" => now
+    });
+
+    if sql_insert_owner.is_err() {
+        eprintln!("An error occurred (orgs.rs): {:?}", sql_insert_owner.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let commit = tx.commit();
+    if commit.is_err() {
+        eprintln!("An error occurred (orgs.rs): {:?}", commit.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let response = OrgResponse { status: 200, org_id: Some(org_id), ..Default::default() };
+    HttpResponse::Ok().json(&response)
+}
+
+/// List the organizations the caller is a member of
+#[get("/orgs")]
+pub async fn get_orgs(data: web::Data<AppData>, req: HttpRequest) -> HttpResponse {
+    let conn_wrapped = data.database.pool.get_conn();
+    if conn_wrapped.is_err() {
+        eprintln!("An error occurred (orgs.rs): {:?}", conn_wrapped.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+    let mut conn = conn_wrapped.unwrap();
+
+    let session = match sessions::require_session(&mut conn, &req) {
+        Ok(session) => session,
+        Err(response) => return response
+    };
+
+    let sql_fetch_orgs = conn.exec::<Row, &str, _>("SELECT organizations.org_id, organizations.name, org_memberships.role FROM org_memberships INNER JOIN organizations ON organizations.org_id = org_memberships.org_id WHERE org_memberships.user_id = :user_id ORDER BY organizations.name", params! {
+        "user_id" => session.user_id
+    });
+
+    if sql_fetch_orgs.is_err() {
+        eprintln!("An error occurred (orgs.rs): {:?}", sql_fetch_orgs.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let organizations = sql_fetch_orgs.unwrap().iter()
+        .filter_map(|row| Some(Organization {
+            org_id: row.get::<String, &str>("org_id").unwrap(),
+            name:   row.get::<String, &str>("name").unwrap(),
+            role:   row.get::<String, &str>("role").unwrap().parse().ok()?
+        }))
+        .collect();
+
+    let response = OrgResponse { status: 200, organizations: Some(organizations), ..Default::default() };
+    HttpResponse::Ok().json(&response)
+}
+
+/// List the members of an organization. Only members can see who else is a member
+#[get("/orgs/{org_id}/members")]
+pub async fn get_members(data: web::Data<AppData>, req: HttpRequest, org_id: web::Path<String>) -> HttpResponse {
+    let org_id = org_id.into_inner();
+
+    let conn_wrapped = data.database.pool.get_conn();
+    if conn_wrapped.is_err() {
+        eprintln!("An error occurred (orgs.rs): {:?}", conn_wrapped.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+    let mut conn = conn_wrapped.unwrap();
+
+    let session = match sessions::require_session(&mut conn, &req) {
+        Ok(session) => session,
+        Err(response) => return response
+    };
+
+    match orgs::membership(&mut conn, &org_id, &session.user_id) {
+        Ok(Some(_)) => {},
+        Ok(None) => return respond(404, "Organization not found."),
+        Err(_) => return HttpResponse::InternalServerError().finish()
+    }
+
+    let sql_fetch_members = conn.exec::<Row, &str, _>("SELECT org_memberships.user_id, org_memberships.role, org_memberships.joined_at, users.username, users.display_name FROM org_memberships INNER JOIN users ON users.user_id = org_memberships.user_id WHERE org_memberships.org_id = :org_id ORDER BY org_memberships.joined_at", params! {
+        "org_id" => org_id
+    });
+
+    if sql_fetch_members.is_err() {
+        eprintln!("An error occurred (orgs.rs): {:?}", sql_fetch_members.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let members = sql_fetch_members.unwrap().iter()
+        .filter_map(|row| Some(Member {
+            user_id:        row.get::<String, &str>("user_id").unwrap(),
+            username:       row.get::<Option<String>, &str>("username").unwrap(),
+            display_name:   row.get::<Option<String>, &str>("display_name").unwrap(),
+            role:           row.get::<String, &str>("role").unwrap().parse().ok()?,
+            joined_at:      row.get::<i64, &str>("joined_at").unwrap()
+        }))
+        .collect();
+
+    let response = OrgResponse { status: 200, members: Some(members), ..Default::default() };
+    HttpResponse::Ok().json(&response)
+}
+
+/// Invite someone to an organization by email. Admins can invite members and admins, only owners can invite owners
+#[post("/orgs/{org_id}/invitations")]
+pub async fn post_invitation(data: web::Data<AppData>, req: HttpRequest, org_id: web::Path<String>, body: web::Json<InviteRequest>) -> HttpResponse {
+    let org_id = org_id.into_inner();
+
+    let role = body.role.parse::<OrgRole>();
+    if role.is_err() {
+        return respond(400, "Role must be one of 'owner', 'admin' or 'member'.");
+    }
+    let role = role.unwrap();
+
+    let invitee_email = body.email.trim().to_string();
+    let invitee_email_normalized = email::parse(&invitee_email, &data.environment.email_normalization);
+    if invitee_email_normalized.is_none() {
+        return respond(400, "Invalid E-mail address.");
+    }
+
+    let conn_wrapped = data.database.pool.get_conn();
+    if conn_wrapped.is_err() {
+        eprintln!("An error occurred (orgs.rs): {:?}", conn_wrapped.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+    let mut conn = conn_wrapped.unwrap();
+
+    let session = match sessions::require_session(&mut conn, &req) {
+        Ok(session) => session,
+        Err(response) => return response
+    };
+
+    let caller_role = match orgs::membership(&mut conn, &org_id, &session.user_id) {
+        Ok(Some(caller_role)) => caller_role,
+        Ok(None) => return respond(404, "Organization not found."),
+        Err(_) => return HttpResponse::InternalServerError().finish()
+    };
+
+    if caller_role < OrgRole::Admin || caller_role < role {
+        return respond(403, "You are not allowed to invite members with this role.");
+    }
+
+    let sql_fetch_org_name = conn.exec_first::<String, &str, _>("SELECT name FROM organizations WHERE org_id = :org_id", params! {
+        "org_id" => org_id.clone()
+    });
+
+    let org_name = match sql_fetch_org_name {
+        Ok(Some(org_name)) => org_name,
+        Ok(None) => return respond(404, "Organization not found."),
+        Err(e) => {
+            eprintln!("An error occurred (orgs.rs): {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    let token = crypto::random_token();
+    let sql_insert_invitation = conn.exec_drop("INSERT INTO org_invitations (token, org_id, email_index, role, invited_by, expiry) VALUES (:token, :org_id, :email_index, :role, :invited_by, :expiry)", params! {
+        "token" => crypto::hash_token(&token),
+        "org_id" => org_id,
+        "email_index" => data.environment.email_encryption.blind_index(&invitee_email_normalized.unwrap()),
+        "role" => role.as_str(),
+        "invited_by" => session.user_id,
+        "expiry" => (chrono::Utc::now() + chrono::Duration::days(INVITATION_VALIDITY_DAYS)).timestamp()
+    });
+
+    if sql_insert_invitation.is_err() {
+        eprintln!("An error occurred (orgs.rs): {:?}", sql_insert_invitation.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    mail::send(&data.environment.mail, &invitee_email, &format!("You have been invited to join {}", org_name), &format!(
+        "You have been invited to join the organization '{}' as {}.\n\nTo accept, sign in with this email address and enter the following invitation code within {} days:\n{}\n\nIf you don't have an account yet, register one with this email address first.",
+        org_name,
+        role.as_str(),
+        INVITATION_VALIDITY_DAYS,
+        token
+    ));
+
+    respond(200, "Invitation sent.")
+}
+
+/// Accept an invitation. The invitation has to be addressed to the caller's email address
+#[post("/orgs/invitations/accept")]
+pub async fn post_accept_invitation(data: web::Data<AppData>, req: HttpRequest, body: web::Json<AcceptInvitationRequest>) -> HttpResponse {
+    let conn_wrapped = data.database.pool.get_conn();
+    if conn_wrapped.is_err() {
+        eprintln!("An error occurred (orgs.rs): {:?}", conn_wrapped.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+    let mut conn = conn_wrapped.unwrap();
+
+    let session = match sessions::require_session(&mut conn, &req) {
+        Ok(session) => session,
+        Err(response) => return response
+    };
+
+    let sql_fetch_invitation = conn.exec_first::<Row, &str, _>("SELECT org_id, email_index, role, expiry FROM org_invitations WHERE token = :token", params! {
+        "token" => crypto::hash_token(&body.token)
+    });
+
+    let invitation = match sql_fetch_invitation {
+        Ok(Some(invitation)) => invitation,
+        Ok(None) => return respond(404, "Unknown or expired invitation."),
+        Err(e) => {
+            eprintln!("An error occurred (orgs.rs): {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    let org_id = invitation.get::<String, &str>("org_id").unwrap();
+    let role = invitation.get::<String, &str>("role").unwrap().parse::<OrgRole>().unwrap_or(OrgRole::Member);
+    if chrono::Utc::now().timestamp() >= invitation.get::<i64, &str>("expiry").unwrap() {
+        return respond(404, "Unknown or expired invitation.");
+    }
+
+    //Compare blind indexes, so we don't have to decrypt anything
+    let caller_email = users::fetch_email(&mut conn, &data.environment.email_encryption, &session.user_id);
+    if caller_email.is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let caller_email_index = data.environment.email_encryption.blind_index(&email::index_form(&caller_email.unwrap(), &data.environment.email_normalization));
+    if caller_email_index != invitation.get::<String, &str>("email_index").unwrap() {
+        return respond(403, "This invitation was sent to a different email address.");
+    }
+
+    //Consuming the invitation and joining happen together, so an invitation can only be accepted once
+    let tx = conn.start_transaction(TxOpts::default());
+    if tx.is_err() {
+        eprintln!("An error occurred (orgs.rs): {:?}", tx.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+    let mut tx = tx.unwrap();
+
+    let sql_delete_invitation = tx.exec_drop("DELETE FROM org_invitations WHERE token = :token AND expiry > :now", params! {
+        "token" => crypto::hash_token(&body.token),
+        "now" => chrono::Utc::now().timestamp()
+    });
+
+    if sql_delete_invitation.is_err() {
+        eprintln!("An error occurred (orgs.rs): {:?}", sql_delete_invitation.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    if tx.affected_rows() == 0 {
+        return respond(404, "Unknown or expired invitation.");
+    }
+
+    let sql_fetch_role = tx.exec_first::<String, &str, _>("SELECT role FROM org_memberships WHERE org_id = :org_id AND user_id = :user_id FOR UPDATE", params! {
+        "org_id" => org_id.clone(),
+        "user_id" => session.user_id.clone()
+    });
+
+    if sql_fetch_role.is_err() {
+        eprintln!("An error occurred (orgs.rs): {:?}", sql_fetch_role.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    //Accepting an invitation never lowers the role of an existing member
+    let current_role = sql_fetch_role.unwrap().and_then(|current_role| current_role.parse::<OrgRole>().ok());
+    if current_role.map(|current_role| current_role < role).unwrap_or(true) {
+        let sql_upsert_membership = tx.exec_drop("INSERT INTO org_memberships (org_id, user_id, role, joined_at) VALUES (:org_id, :user_id, :role, :joined_at) ON DUPLICATE KEY UPDATE role = VALUES(role)", params! {
+            "org_id" => org_id.clone(),
+            "user_id" => session.user_id,
+            "role" => role.as_str(),
+            "joined_at" => chrono::Utc::now().timestamp()
+        });
+
+        if sql_upsert_membership.is_err() {
+            eprintln!("An error occurred (orgs.rs): {:?}", sql_upsert_membership.err().unwrap());
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    let commit = tx.commit();
+    if commit.is_err() {
+        eprintln!("An error occurred (orgs.rs): {:?}", commit.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let response = OrgResponse { status: 200, org_id: Some(org_id), ..Default::default() };
+    HttpResponse::Ok().json(&response)
+}
+
+/// Change the role of a member. The caller needs at least the member's current and new role, and must be an admin.
+/// The last owner can't be demoted
+#[put("/orgs/{org_id}/members/{user_id}")]
+pub async fn put_member(data: web::Data<AppData>, req: HttpRequest, path: web::Path<(String, String)>, body: web::Json<ChangeRoleRequest>) -> HttpResponse {
+    let (org_id, user_id) = path.into_inner();
+
+    let role = body.role.parse::<OrgRole>();
+    if role.is_err() {
+        return respond(400, "Role must be one of 'owner', 'admin' or 'member'.");
+    }
+    let role = role.unwrap();
+
+    let conn_wrapped = data.database.pool.get_conn();
+    if conn_wrapped.is_err() {
+        eprintln!("An error occurred (orgs.rs): {:?}", conn_wrapped.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+    let mut conn = conn_wrapped.unwrap();
+
+    let session = match sessions::require_session(&mut conn, &req) {
+        Ok(session) => session,
+        Err(response) => return response
+    };
+
+    let caller_role = match orgs::membership(&mut conn, &org_id, &session.user_id) {
+        Ok(Some(caller_role)) => caller_role,
+        Ok(None) => return respond(404, "Organization not found."),
+        Err(_) => return HttpResponse::InternalServerError().finish()
+    };
+
+    let member_role = match orgs::membership(&mut conn, &org_id, &user_id) {
+        Ok(Some(member_role)) => member_role,
+        Ok(None) => return respond(404, "Member not found."),
+        Err(_) => return HttpResponse::InternalServerError().finish()
+    };
+
+    if caller_role < OrgRole::Admin || caller_role < member_role || caller_role < role {
+        return respond(403, "You are not allowed to change this member's role.");
+    }
+
+    if member_role == OrgRole::Owner && role != OrgRole::Owner {
+        match orgs::owner_count(&mut conn, &org_id) {
+            Ok(1) => return respond(409, "An organization needs at least one owner."),
+            Ok(_) => {},
+            Err(_) => return HttpResponse::InternalServerError().finish()
+        }
+    }
+
+    let sql_update_role = conn.exec_drop("UPDATE org_memberships SET role = :role WHERE org_id = :org_id AND user_id = :user_id", params! {
+        "role" => role.as_str(),
+        "org_id" => org_id,
+        "user_id" => user_id
+    });
+
+    if sql_update_role.is_err() {
+        eprintln!("An error occurred (orgs.rs): {:?}", sql_update_role.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    respond(200, "Role changed.")
+}
+
+/// Remove a member from an organization. Members can always remove themselves, unless they are the last owner
+#[delete("/orgs/{org_id}/members/{user_id}")]
+pub async fn delete_member(data: web::Data<AppData>, req: HttpRequest, path: web::Path<(String, String)>) -> HttpResponse {
+    let (org_id, user_id) = path.into_inner();
+
+    let conn_wrapped = data.database.pool.get_conn();
+    if conn_wrapped.is_err() {
+        eprintln!("An error occurred (orgs.rs): {:?}", conn_wrapped.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+    let mut conn = conn_wrapped.unwrap();
+
+    let session = match sessions::require_session(&mut conn, &req) {
+        Ok(session) => session,
+        Err(response) => return response
+    };
+
+    let caller_role = match orgs::membership(&mut conn, &org_id, &session.user_id) {
+        Ok(Some(caller_role)) => caller_role,
+        Ok(None) => return respond(404, "Organization not found."),
+        Err(_) => return HttpResponse::InternalServerError().finish()
+    };
+
+    let member_role = match orgs::membership(&mut conn, &org_id, &user_id) {
+        Ok(Some(member_role)) => member_role,
+        Ok(None) => return respond(404, "Member not found."),
+        Err(_) => return HttpResponse::InternalServerError().finish()
+    };
+
+    let is_self = session.user_id == user_id;
+    if !is_self && (caller_role < OrgRole::Admin || caller_role < member_role) {
+        return respond(403, "You are not allowed to remove this member.");
+    }
+
+    if member_role == OrgRole::Owner {
+        match orgs::owner_count(&mut conn, &org_id) {
+            Ok(1) => return respond(409, "An organization needs at least one owner."),
+            Ok(_) => {},
+            Err(_) => return HttpResponse::InternalServerError().finish()
+        }
+    }
+
+    if orgs::remove_member(&mut conn, &org_id, &user_id).is_err() {
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    respond(200, "Member removed.")
+}
@@ -0,0 +1,368 @@
+use crate::appdata::AppData;
+use crate::rbac;
+use crate::scim::{self, Filter, ListQuery, PatchRequest};
+
+use actix_web::{web, get, post, put, patch, delete, HttpRequest, HttpResponse};
+use actix_web::http::StatusCode;
+use mysql::prelude::Queryable;
+use mysql::{PooledConn, Row, Params, params};
+use serde_json::{json, Value};
+
+/// Open a connection and check the request's token
+fn connect(data: &AppData, req: &HttpRequest) -> Result<PooledConn, HttpResponse> {
+    data.environment.scim.authorize(req)?;
+
+    data.database.pool.get_conn().map_err(|e| {
+        eprintln!("An error occurred (groups.rs): {:?}", e);
+        HttpResponse::InternalServerError().finish()
+    })
+}
+
+/// Check that a group exists and may be changed by the identity provider: it was created through SCIM, or its role
+/// grants no permissions. Other roles, such as admin, would let the SCIM token hand out access it doesn't have itself
+fn check_mutable(conn: &mut PooledConn, role: &str) -> Result<(), HttpResponse> {
+    let sql_fetch_role = conn.exec_first::<(bool, u64), &str, _>("SELECT scim_managed, (SELECT COUNT(*) FROM role_permissions WHERE role_permissions.role = roles.name) FROM roles WHERE name = :name", params! {
+        "name" => role
+    });
+
+    match sql_fetch_role {
+        Ok(Some((true, _))) | Ok(Some((false, 0))) => Ok(()),
+        Ok(Some(_)) => Err(scim::error(StatusCode::FORBIDDEN, None, "Only groups created through SCIM, or roles without permissions, can be changed through SCIM.")),
+        Ok(None) => Err(scim::error(StatusCode::NOT_FOUND, None, "Group not found.")),
+        Err(e) => {
+            eprintln!("An error occurred (groups.rs): {:?}", e);
+            Err(HttpResponse::InternalServerError().finish())
+        }
+    }
+}
+
+/// Turn a group's display name into a role name, e.g. 'Support Team' into 'support-team'
+fn role_name(display_name: &str) -> String {
+    display_name.trim().to_lowercase().split_whitespace().collect::<Vec<&str>>().join("-")
+}
+
+fn to_resource(data: &AppData, conn: &mut PooledConn, row: &Row) -> Result<Value, ()> {
+    let name = row.get::<String, &str>("name").unwrap();
+    let description = row.get::<Option<String>, &str>("description").unwrap();
+
+    let sql_fetch_members = conn.exec::<String, &str, _>("SELECT user_id FROM user_roles WHERE role = :role ORDER BY user_id", params! {
+        "role" => &name
+    });
+
+    if sql_fetch_members.is_err() {
+        eprintln!("An error occurred (groups.rs): {:?}", sql_fetch_members.err().unwrap());
+        return Err(());
+    }
+
+    let members: Vec<Value> = sql_fetch_members.unwrap().into_iter()
+        .map(|user_id| json!({
+            "value": user_id,
+            "$ref": data.environment.mail.link(&format!("/scim/v2/Users/{}", user_id)),
+            "type": "User"
+        }))
+        .collect();
+
+    Ok(json!({
+        "schemas": [scim::GROUP_SCHEMA],
+        "id": name,
+        "displayName": description.unwrap_or_else(|| name.clone()),
+        "members": members,
+        "meta": {
+            "resourceType": "Group",
+            "location": data.environment.mail.link(&format!("/scim/v2/Groups/{}", name))
+        }
+    }))
+}
+
+/// Respond with a group after it was changed, 404 if it doesn't exist
+fn respond_with_group(conn: &mut PooledConn, data: &AppData, role: &str, status: StatusCode) -> HttpResponse {
+    let sql_fetch_role = conn.exec_first::<Row, &str, _>("SELECT name, description FROM roles WHERE name = :name", params! {
+        "name" => role
+    });
+
+    let row = match sql_fetch_role {
+        Ok(Some(row)) => row,
+        Ok(None) => return scim::error(StatusCode::NOT_FOUND, None, "Group not found."),
+        Err(e) => {
+            eprintln!("An error occurred (groups.rs): {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    };
+
+    match to_resource(data, conn, &row) {
+        Ok(resource) => scim::respond(status, resource),
+        Err(_) => HttpResponse::InternalServerError().finish()
+    }
+}
+
+/// The user IDs of a `members` value
+fn member_ids(members: &Value) -> Result<Vec<String>, HttpResponse> {
+    let members = match members {
+        Value::Array(members) => members.iter().collect::<Vec<&Value>>(),
+        Value::Object(_) => vec![members],
+        Value::Null => Vec::new(),
+        _ => return Err(scim::error(StatusCode::BAD_REQUEST, Some("invalidValue"), "Invalid members."))
+    };
+
+    members.into_iter()
+        .map(|member| member.get("value").and_then(Value::as_str).map(str::to_string))
+        .collect::<Option<Vec<String>>>()
+        .ok_or_else(|| scim::error(StatusCode::BAD_REQUEST, Some("invalidValue"), "Every member needs a value."))
+}
+
+/// Assign a role to users. Users who don't exist are skipped
+fn add_members(conn: &mut PooledConn, role: &str, user_ids: &[String]) -> Result<(), HttpResponse> {
+    let sql_assign_role = conn.exec_batch("INSERT IGNORE INTO user_roles (user_id, role) SELECT users.user_id, :role FROM users WHERE users.user_id = :user_id", user_ids.iter().map(|user_id| params! {
+        "role" => role,
+        "user_id" => user_id
+    }));
+
+    sql_assign_role.map_err(|e| {
+        eprintln!("An error occurred (groups.rs): {:?}", e);
+        HttpResponse::InternalServerError().finish()
+    })
+}
+
+/// Revoke a role from users, or from every user if `user_ids` is `None`
+fn remove_members(conn: &mut PooledConn, role: &str, user_ids: Option<&[String]>) -> Result<(), HttpResponse> {
+    let sql_revoke_role = match user_ids {
+        Some(user_ids) => conn.exec_batch("DELETE FROM user_roles WHERE role = :role AND user_id = :user_id", user_ids.iter().map(|user_id| params! {
+            "role" => role,
+            "user_id" => user_id
+        })),
+        None => conn.exec_drop("DELETE FROM user_roles WHERE role = :role", params! {
+            "role" => role
+        })
+    };
+
+    sql_revoke_role.map_err(|e| {
+        eprintln!("An error occurred (groups.rs): {:?}", e);
+        HttpResponse::InternalServerError().finish()
+    })
+}
+
+fn set_display_name(conn: &mut PooledConn, role: &str, display_name: &Value) -> Result<(), HttpResponse> {
+    let display_name = match display_name.as_str() {
+        Some(display_name) if !display_name.trim().is_empty() && display_name.len() <= 255 => display_name.trim(),
+        _ => return Err(scim::error(StatusCode::BAD_REQUEST, Some("invalidValue"), "Invalid displayName."))
+    };
+
+    let sql_update_description = conn.exec_drop("UPDATE roles SET description = :description WHERE name = :name", params! {
+        "description" => display_name,
+        "name" => role
+    });
+
+    sql_update_description.map_err(|e| {
+        eprintln!("An error occurred (groups.rs): {:?}", e);
+        HttpResponse::InternalServerError().finish()
+    })
+}
+
+/// List groups, optionally filtered by `displayName` or `id`
+#[get("/scim/v2/Groups")]
+pub async fn get_groups(data: web::Data<AppData>, req: HttpRequest, query: web::Query<ListQuery>) -> HttpResponse {
+    let mut conn = match connect(&data, &req) {
+        Ok(conn) => conn,
+        Err(response) => return response
+    };
+
+    let (condition, params) = match query.filter.as_deref().map(str::parse::<Filter>) {
+        None => (String::new(), Params::Empty),
+        Some(Err(_)) => return scim::error(StatusCode::BAD_REQUEST, Some("invalidFilter"), "Only filters of the form 'attribute eq \"value\"' are supported."),
+        Some(Ok(filter)) => match filter.attribute.as_str() {
+            "displayname" => ("WHERE COALESCE(description, name) = :value".to_string(), params! { "value" => filter.value }),
+            "id" => ("WHERE name = :value".to_string(), params! { "value" => filter.value }),
+            _ => return scim::error(StatusCode::BAD_REQUEST, Some("invalidFilter"), "Groups can only be filtered by displayName or id.")
+        }
+    };
+
+    let sql_count_roles = conn.exec_first::<u64, String, Params>(format!("SELECT COUNT(*) FROM roles {}", condition), params.clone());
+    if sql_count_roles.is_err() {
+        eprintln!("An error occurred (groups.rs): {:?}", sql_count_roles.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let (start_index, count) = query.page();
+    let sql_fetch_roles = conn.exec::<Row, String, Params>(format!("SELECT name, description FROM roles {} ORDER BY name LIMIT {} OFFSET {}", condition, count, start_index - 1), params);
+    if sql_fetch_roles.is_err() {
+        eprintln!("An error occurred (groups.rs): {:?}", sql_fetch_roles.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let mut resources = Vec::new();
+    for row in sql_fetch_roles.unwrap() {
+        match to_resource(&data, &mut conn, &row) {
+            Ok(resource) => resources.push(resource),
+            Err(_) => return HttpResponse::InternalServerError().finish()
+        }
+    }
+
+    scim::list_response(resources, sql_count_roles.unwrap().unwrap_or(0), start_index)
+}
+
+#[get("/scim/v2/Groups/{id}")]
+pub async fn get_group(data: web::Data<AppData>, req: HttpRequest, id: web::Path<String>) -> HttpResponse {
+    let mut conn = match connect(&data, &req) {
+        Ok(conn) => conn,
+        Err(response) => return response
+    };
+
+    respond_with_group(&mut conn, &data, &id, StatusCode::OK)
+}
+
+/// Create a group as a role without permissions. The role is named after the group's display name
+#[post("/scim/v2/Groups")]
+pub async fn post_group(data: web::Data<AppData>, req: HttpRequest, body: web::Json<Value>) -> HttpResponse {
+    let mut conn = match connect(&data, &req) {
+        Ok(conn) => conn,
+        Err(response) => return response
+    };
+
+    let display_name = body.get("displayName").and_then(Value::as_str).unwrap_or_default();
+    let role = role_name(display_name);
+    if !rbac::is_valid_role(&role) {
+        return scim::error(StatusCode::BAD_REQUEST, Some("invalidValue"), "The displayName must consist of letters, digits, spaces, '_', '.' and '-', and can be at most 64 characters long.");
+    }
+
+    let sql_insert_role = conn.exec_drop("INSERT IGNORE INTO roles (name, description, scim_managed) VALUES (:name, :description, TRUE)", params! {
+        "name" => &role,
+        "description" => display_name.trim()
+    });
+
+    match sql_insert_role {
+        Ok(_) if conn.affected_rows() == 0 => return scim::error(StatusCode::CONFLICT, Some("uniqueness"), "A group with this displayName already exists."),
+        Ok(_) => {},
+        Err(e) => {
+            eprintln!("An error occurred (groups.rs): {:?}", e);
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    if let Some(members) = body.get("members") {
+        let result = member_ids(members).and_then(|user_ids| add_members(&mut conn, &role, &user_ids));
+        if let Err(response) = result {
+            return response;
+        }
+    }
+
+    respond_with_group(&mut conn, &data, &role, StatusCode::CREATED)
+}
+
+/// Replace a group's display name and members
+#[put("/scim/v2/Groups/{id}")]
+pub async fn put_group(data: web::Data<AppData>, req: HttpRequest, id: web::Path<String>, body: web::Json<Value>) -> HttpResponse {
+    let mut conn = match connect(&data, &req) {
+        Ok(conn) => conn,
+        Err(response) => return response
+    };
+
+    if let Err(response) = check_mutable(&mut conn, &id) {
+        return response;
+    }
+
+    if let Some(display_name) = body.get("displayName") {
+        if let Err(response) = set_display_name(&mut conn, &id, display_name) {
+            return response;
+        }
+    }
+
+    let user_ids = match member_ids(body.get("members").unwrap_or(&Value::Null)) {
+        Ok(user_ids) => user_ids,
+        Err(response) => return response
+    };
+
+    let result = remove_members(&mut conn, &id, None).and_then(|_| add_members(&mut conn, &id, &user_ids));
+    if let Err(response) = result {
+        return response;
+    }
+
+    respond_with_group(&mut conn, &data, &id, StatusCode::OK)
+}
+
+/// Change a group's display name or members with PATCH operations
+#[patch("/scim/v2/Groups/{id}")]
+pub async fn patch_group(data: web::Data<AppData>, req: HttpRequest, id: web::Path<String>, body: web::Json<PatchRequest>) -> HttpResponse {
+    let mut conn = match connect(&data, &req) {
+        Ok(conn) => conn,
+        Err(response) => return response
+    };
+
+    if let Err(response) = check_mutable(&mut conn, &id) {
+        return response;
+    }
+
+    for operation in body.operations.iter() {
+        let value = operation.value.clone().unwrap_or(Value::Null);
+        let path = operation.path();
+
+        //A remove of `members[value eq "<id>"]` names the member in the path instead of the value
+        let filtered_member = path.as_deref()
+            .and_then(|path| path.strip_prefix("members[").and_then(|path| path.strip_suffix(']')))
+            .and_then(|filter| filter.parse::<Filter>().ok())
+            .filter(|filter| filter.attribute == "value")
+            .map(|filter| filter.value);
+
+        let result = match (operation.op().as_str(), path.as_deref()) {
+            ("add", Some("members")) => member_ids(&value).and_then(|user_ids| add_members(&mut conn, &id, &user_ids)),
+            ("replace", Some("members")) => member_ids(&value).and_then(|user_ids| {
+                remove_members(&mut conn, &id, None)?;
+                add_members(&mut conn, &id, &user_ids)
+            }),
+            ("remove", Some("members")) if value.is_null() => remove_members(&mut conn, &id, None),
+            ("remove", Some("members")) => member_ids(&value).and_then(|user_ids| remove_members(&mut conn, &id, Some(&user_ids))),
+            ("remove", Some(_)) if filtered_member.is_some() => remove_members(&mut conn, &id, Some(&[filtered_member.unwrap()])),
+            ("add", Some("displayname")) | ("replace", Some("displayname")) => set_display_name(&mut conn, &id, &value),
+            ("add", None) | ("replace", None) => {
+                let mut result = Ok(());
+                if let Some(display_name) = value.get("displayName") {
+                    result = set_display_name(&mut conn, &id, display_name);
+                }
+
+                if let (Ok(_), Some(members)) = (&result, value.get("members")) {
+                    result = member_ids(members).and_then(|user_ids| {
+                        if operation.op() == "replace" {
+                            remove_members(&mut conn, &id, None)?;
+                        }
+
+                        add_members(&mut conn, &id, &user_ids)
+                    });
+                }
+
+                result
+            },
+            _ => Err(scim::error(StatusCode::BAD_REQUEST, Some("invalidPath"), "Only displayName and members can be changed."))
+        };
+
+        if let Err(response) = result {
+            return response;
+        }
+    }
+
+    respond_with_group(&mut conn, &data, &id, StatusCode::OK)
+}
+
+/// Delete a group, revoking its role from every member
+#[delete("/scim/v2/Groups/{id}")]
+pub async fn delete_group(data: web::Data<AppData>, req: HttpRequest, id: web::Path<String>) -> HttpResponse {
+    let mut conn = match connect(&data, &req) {
+        Ok(conn) => conn,
+        Err(response) => return response
+    };
+
+    if let Err(response) = check_mutable(&mut conn, &id) {
+        return response;
+    }
+
+    for (table, column) in &[("user_roles", "role"), ("role_permissions", "role"), ("roles", "name")] {
+        let sql_delete = conn.exec_drop(format!("DELETE FROM {} WHERE {} = :name", table, column), params! {
+            "name" => id.as_str()
+        });
+
+        if sql_delete.is_err() {
+            eprintln!("An error occurred (groups.rs): {:?}", sql_delete.err().unwrap());
+            return HttpResponse::InternalServerError().finish();
+        }
+    }
+
+    HttpResponse::NoContent().finish()
+}
@@ -0,0 +1,468 @@
+use crate::appdata::{AppData, Database};
+use crate::scim::{self, Filter, ListQuery, PatchRequest};
+use crate::users::{self, AccountState, CreateError};
+use crate::username::{self, UsernamePolicy};
+use crate::{email, password_policy, profile};
+
+use actix_web::{web, get, post, put, patch, delete, HttpRequest, HttpResponse};
+use actix_web::http::StatusCode;
+use mysql::prelude::Queryable;
+use mysql::{PooledConn, Row, Params, params};
+use rand::Rng;
+use serde_json::{json, Value};
+
+const SELECT_USERS: &str = "SELECT user_id, email, username, display_name, external_id, status, status_until, status_reason FROM users";
+
+/// Changes to a user, from a SCIM resource or PATCH operations. `None` leaves an attribute unchanged
+#[derive(Default)]
+struct UserChanges {
+    email:          Option<String>,
+    username:       Option<Option<String>>,
+    display_name:   Option<Option<String>>,
+    external_id:    Option<Option<String>>,
+    active:         Option<bool>
+}
+
+impl UserChanges {
+    /// A SCIM `userName` is either an email address or a username
+    fn user_name(&mut self, user_name: &str) {
+        if user_name.contains('@') {
+            if self.email.is_none() {
+                self.email = Some(user_name.to_string());
+            }
+
+            self.username = Some(None);
+        } else {
+            self.username = Some(Some(username::normalize(user_name)));
+        }
+    }
+
+    /// The primary address of a SCIM `emails` value, or the first one
+    fn emails(&mut self, emails: &Value) -> Result<(), &'static str> {
+        let email = match emails {
+            Value::String(email) => Some(email.as_str()),
+            Value::Array(emails) => emails.iter()
+                .find(|email| email.get("primary").and_then(scim::as_bool).unwrap_or(false))
+                .or_else(|| emails.first())
+                .and_then(|email| email.get("value"))
+                .and_then(Value::as_str),
+            _ => None
+        };
+
+        match email {
+            Some(email) => {
+                self.email = Some(email.to_string());
+                Ok(())
+            },
+            None => Err("Invalid emails.")
+        }
+    }
+
+    /// Take the attributes present in a resource. With `replace`, absent attributes are cleared, as for PUT.
+    /// An absent `active` always leaves the state alone, so a PUT can't re-enable a disabled user by accident
+    fn from_resource(resource: &Value, replace: bool) -> Result<UserChanges, &'static str> {
+        let mut changes = UserChanges::default();
+        if replace {
+            changes.display_name = Some(None);
+            changes.external_id = Some(None);
+        }
+
+        if let Some(emails) = resource.get("emails") {
+            changes.emails(emails)?;
+        }
+
+        if let Some(user_name) = resource.get("userName") {
+            changes.user_name(user_name.as_str().ok_or("Invalid userName.")?);
+        }
+
+        if let Some(display_name) = resource.get("displayName") {
+            changes.display_name = Some(scim::as_optional_string(display_name).map_err(|_| "Invalid displayName.")?);
+        }
+
+        if let Some(external_id) = resource.get("externalId") {
+            changes.external_id = Some(scim::as_optional_string(external_id).map_err(|_| "Invalid externalId.")?);
+        }
+
+        if let Some(active) = resource.get("active") {
+            changes.active = Some(scim::as_bool(active).ok_or("Invalid active.")?);
+        }
+
+        Ok(changes)
+    }
+}
+
+fn to_resource(data: &AppData, row: &Row) -> Value {
+    let user_id = row.get::<String, &str>("user_id").unwrap();
+    let username = row.get::<Option<String>, &str>("username").unwrap();
+
+    let email = data.environment.email_encryption.decrypt(&row.get::<String, &str>("email").unwrap());
+    if email.is_err() {
+        eprintln!("Unable to decrypt the email address of user '{}' (users.rs)", user_id);
+    }
+    let email = email.ok();
+
+    //Locked accounts are still provisioned, they are only blocked for a while
+    let active = !matches!(AccountState::from_row(row), AccountState::Disabled | AccountState::Banned { .. });
+
+    json!({
+        "schemas": [scim::USER_SCHEMA],
+        "id": user_id,
+        "externalId": row.get::<Option<String>, &str>("external_id").unwrap(),
+        "userName": username.or_else(|| email.clone()),
+        "displayName": row.get::<Option<String>, &str>("display_name").unwrap(),
+        "active": active,
+        "emails": email.map(|email| vec![json!({ "value": email, "type": "work", "primary": true })]).unwrap_or_default(),
+        "meta": {
+            "resourceType": "User",
+            "location": data.environment.mail.link(&format!("/scim/v2/Users/{}", user_id))
+        }
+    })
+}
+
+fn fetch_resource(conn: &mut PooledConn, data: &AppData, user_id: &str) -> Result<Option<Value>, ()> {
+    let sql_fetch_user = conn.exec_first::<Row, String, Params>(format!("{} WHERE user_id = :user_id", SELECT_USERS), params! {
+        "user_id" => user_id
+    });
+
+    if sql_fetch_user.is_err() {
+        eprintln!("An error occurred (users.rs): {:?}", sql_fetch_user.err().unwrap());
+        return Err(());
+    }
+
+    Ok(sql_fetch_user.unwrap().map(|row| to_resource(data, &row)))
+}
+
+fn set_column(conn: &mut PooledConn, user_id: &str, column: &str, value: Option<String>) -> Result<(), mysql::Error> {
+    conn.exec_drop(format!("UPDATE users SET {} = :value WHERE user_id = :user_id", column), params! {
+        "value" => value,
+        "user_id" => user_id
+    })
+}
+
+/// Apply changes to an existing user. Returns the response which should be returned to the client if they can't be applied
+fn apply(conn: &mut PooledConn, data: &AppData, user_id: &str, changes: UserChanges) -> Result<(), HttpResponse> {
+    let internal_error = |e: mysql::Error| {
+        eprintln!("An error occurred (users.rs): {:?}", e);
+        HttpResponse::InternalServerError().finish()
+    };
+
+    if let Some(new_email) = changes.email {
+        let new_email = new_email.trim();
+        let new_email_normalized = match email::parse(new_email, &data.environment.email_normalization) {
+            Some(new_email_normalized) => new_email_normalized,
+            None => return Err(scim::error(StatusCode::BAD_REQUEST, Some("invalidValue"), "Invalid email address."))
+        };
+
+        let new_email_encrypted = data.environment.email_encryption.encrypt(new_email);
+        if new_email_encrypted.is_err() {
+            eprintln!("Unable to encrypt email address (users.rs)");
+            return Err(HttpResponse::InternalServerError().finish());
+        }
+
+        //A different address isn't verified. MySQL assigns in order, so `email_verified` is compared with the old index
+        let sql_update_email = conn.exec_drop("UPDATE users SET email_verified = email_verified AND email_index = :email_index, email = :email, email_index = :email_index WHERE user_id = :user_id", params! {
+            "email" => new_email_encrypted.unwrap(),
+            "email_index" => data.environment.email_encryption.blind_index(&new_email_normalized),
+            "user_id" => user_id
+        });
+
+        match sql_update_email {
+            Err(e) if Database::is_duplicate_entry(&e, "users_email_index") => return Err(scim::error(StatusCode::CONFLICT, Some("uniqueness"), "An account with this email address already exists.")),
+            Err(e) => return Err(internal_error(e)),
+            Ok(_) => {}
+        }
+    }
+
+    //Usernames are only kept if the server uses them at all
+    if let Some(new_username) = changes.username.filter(|_| data.environment.username_policy != UsernamePolicy::Disabled) {
+        if let Some(new_username) = &new_username {
+            if !username::is_valid(new_username) {
+                return Err(scim::error(StatusCode::BAD_REQUEST, Some("invalidValue"), "Invalid userName. Usernames are 3 to 32 characters long, and may only contain letters, digits, '_', '.' and '-'."));
+            }
+        }
+
+        match set_column(conn, user_id, "username", new_username) {
+            Err(e) if Database::is_duplicate_entry(&e, "users_username") => return Err(scim::error(StatusCode::CONFLICT, Some("uniqueness"), "Username is already taken.")),
+            Err(e) => return Err(internal_error(e)),
+            Ok(_) => {}
+        }
+    }
+
+    if let Some(display_name) = changes.display_name {
+        if let Some(Err(e)) = display_name.as_deref().map(profile::validate_display_name) {
+            return Err(scim::error(StatusCode::BAD_REQUEST, Some("invalidValue"), e));
+        }
+
+        set_column(conn, user_id, "display_name", display_name).map_err(internal_error)?;
+    }
+
+    if let Some(external_id) = changes.external_id {
+        if external_id.as_ref().map(|external_id| external_id.len() > 255).unwrap_or(false) {
+            return Err(scim::error(StatusCode::BAD_REQUEST, Some("invalidValue"), "externalId can be at most 255 characters long."));
+        }
+
+        set_column(conn, user_id, "external_id", external_id).map_err(internal_error)?;
+    }
+
+    //Deactivating a user disables them, which revokes their sessions. Banned users stay banned when reactivated
+    if let Some(active) = changes.active {
+        let sql_fetch_state = conn.exec_first::<Row, &str, Params>("SELECT status, status_until, status_reason FROM users WHERE user_id = :user_id", params! {
+            "user_id" => user_id
+        }).map_err(internal_error)?;
+
+        let current_state = sql_fetch_state.map(|row| AccountState::from_row(&row));
+        let new_state = match (active, current_state) {
+            (false, Some(AccountState::Active)) | (false, Some(AccountState::Locked { .. })) => Some(AccountState::Disabled),
+            (true, Some(AccountState::Disabled)) => Some(AccountState::Active),
+            _ => None
+        };
+
+        if let Some(new_state) = new_state {
+            if users::set_state(conn, user_id, &new_state).is_err() {
+                return Err(HttpResponse::InternalServerError().finish());
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Open a connection and check the request's token
+fn connect(data: &AppData, req: &HttpRequest) -> Result<PooledConn, HttpResponse> {
+    data.environment.scim.authorize(req)?;
+
+    data.database.pool.get_conn().map_err(|e| {
+        eprintln!("An error occurred (users.rs): {:?}", e);
+        HttpResponse::InternalServerError().finish()
+    })
+}
+
+/// Fetch a user after it was changed, 404 if it doesn't exist
+fn respond_with_user(conn: &mut PooledConn, data: &AppData, user_id: &str, status: StatusCode) -> HttpResponse {
+    match fetch_resource(conn, data, user_id) {
+        Ok(Some(resource)) => {
+            let location = resource["meta"]["location"].as_str().unwrap_or_default().to_string();
+            let mut response = scim::respond(status, resource);
+            if let Ok(location) = actix_web::http::HeaderValue::from_str(&location) {
+                response.headers_mut().insert(actix_web::http::header::LOCATION, location);
+            }
+
+            response
+        },
+        Ok(None) => scim::error(StatusCode::NOT_FOUND, None, "User not found."),
+        Err(_) => HttpResponse::InternalServerError().finish()
+    }
+}
+
+/// List users, optionally filtered by `userName`, `emails.value`, `externalId` or `id`
+#[get("/scim/v2/Users")]
+pub async fn get_users(data: web::Data<AppData>, req: HttpRequest, query: web::Query<ListQuery>) -> HttpResponse {
+    let mut conn = match connect(&data, &req) {
+        Ok(conn) => conn,
+        Err(response) => return response
+    };
+
+    let (condition, params) = match query.filter.as_deref().map(str::parse::<Filter>) {
+        None => (String::new(), Params::Empty),
+        Some(Err(_)) => return scim::error(StatusCode::BAD_REQUEST, Some("invalidFilter"), "Only filters of the form 'attribute eq \"value\"' are supported."),
+        Some(Ok(filter)) => {
+            let is_email = filter.attribute == "emails" || filter.attribute.starts_with("emails.") || filter.attribute.starts_with("emails[")
+                || (filter.attribute == "username" && filter.value.contains('@'));
+
+            if is_email {
+                let email_index = data.environment.email_encryption.blind_index(&email::index_form(&filter.value, &data.environment.email_normalization));
+                ("WHERE email_index = :value".to_string(), params! { "value" => email_index })
+            } else {
+                match filter.attribute.as_str() {
+                    "username" => ("WHERE username = :value".to_string(), params! { "value" => username::normalize(&filter.value) }),
+                    "externalid" => ("WHERE external_id = :value".to_string(), params! { "value" => filter.value }),
+                    "id" => ("WHERE user_id = :value".to_string(), params! { "value" => filter.value }),
+                    _ => return scim::error(StatusCode::BAD_REQUEST, Some("invalidFilter"), "Users can only be filtered by userName, emails, externalId or id.")
+                }
+            }
+        }
+    };
+
+    let sql_count_users = conn.exec_first::<u64, String, Params>(format!("SELECT COUNT(*) FROM users {}", condition), params.clone());
+    if sql_count_users.is_err() {
+        eprintln!("An error occurred (users.rs): {:?}", sql_count_users.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let (start_index, count) = query.page();
+    let sql_fetch_users = conn.exec::<Row, String, Params>(format!("{} {} ORDER BY user_id LIMIT {} OFFSET {}", SELECT_USERS, condition, count, start_index - 1), params);
+    if sql_fetch_users.is_err() {
+        eprintln!("An error occurred (users.rs): {:?}", sql_fetch_users.err().unwrap());
+        return HttpResponse::InternalServerError().finish();
+    }
+
+    let resources = sql_fetch_users.unwrap().iter().map(|row| to_resource(&data, row)).collect();
+    scim::list_response(resources, sql_count_users.unwrap().unwrap_or(0), start_index)
+}
+
+#[get("/scim/v2/Users/{id}")]
+pub async fn get_user(data: web::Data<AppData>, req: HttpRequest, id: web::Path<String>) -> HttpResponse {
+    let mut conn = match connect(&data, &req) {
+        Ok(conn) => conn,
+        Err(response) => return response
+    };
+
+    respond_with_user(&mut conn, &data, &id, StatusCode::OK)
+}
+
+/// Provision a user. A given password has to meet the password policy. Users provisioned without a password get a random one,
+/// they are expected to sign in through the identity provider
+#[post("/scim/v2/Users")]
+pub async fn post_user(data: web::Data<AppData>, req: HttpRequest, body: web::Json<Value>) -> HttpResponse {
+    let mut conn = match connect(&data, &req) {
+        Ok(conn) => conn,
+        Err(response) => return response
+    };
+
+    let mut changes = match UserChanges::from_resource(&body, false) {
+        Ok(changes) => changes,
+        Err(e) => return scim::error(StatusCode::BAD_REQUEST, Some("invalidValue"), e)
+    };
+
+    let new_email = match changes.email.take() {
+        Some(new_email) => new_email,
+        None => return scim::error(StatusCode::BAD_REQUEST, Some("invalidValue"), "An email address is required, in emails or userName.")
+    };
+
+    let new_username = changes.username.take().flatten().filter(|_| data.environment.username_policy != UsernamePolicy::Disabled);
+    if let Some(new_username) = &new_username {
+        if !username::is_valid(new_username) {
+            return scim::error(StatusCode::BAD_REQUEST, Some("invalidValue"), "Invalid userName. Usernames are 3 to 32 characters long, and may only contain letters, digits, '_', '.' and '-'.");
+        }
+    }
+
+    let password = match body.get("password").and_then(Value::as_str) {
+        Some(password) => {
+            let violations = match password_policy::check_in_background(&data, password, Some(&new_email)).await {
+                Ok(violations) => violations,
+                Err(_) => return HttpResponse::InternalServerError().finish()
+            };
+
+            if let Some(violation) = violations.first() {
+                return scim::error(StatusCode::BAD_REQUEST, Some("invalidValue"), violation.message());
+            }
+
+            password.to_string()
+        },
+        None => rand::thread_rng().sample_iter(&rand::distributions::Alphanumeric).take(32).map(char::from).collect()
+    };
+
+    let user_id = match users::create(&mut conn, &data.environment, &new_email, new_username.as_deref(), &password) {
+        Ok(user_id) => user_id,
+        Err(CreateError::InvalidEmail) => return scim::error(StatusCode::BAD_REQUEST, Some("invalidValue"), "Invalid email address."),
+        Err(CreateError::EmailTaken) => return scim::error(StatusCode::CONFLICT, Some("uniqueness"), "An account with this email address already exists."),
+        Err(CreateError::UsernameTaken) => return scim::error(StatusCode::CONFLICT, Some("uniqueness"), "Username is already taken."),
+        Err(CreateError::Failed) => return HttpResponse::InternalServerError().finish()
+    };
+
+    if let Err(response) = apply(&mut conn, &data, &user_id, changes) {
+        return response;
+    }
+
+    respond_with_user(&mut conn, &data, &user_id, StatusCode::CREATED)
+}
+
+/// Replace a user. Attributes which are absent are cleared
+#[put("/scim/v2/Users/{id}")]
+pub async fn put_user(data: web::Data<AppData>, req: HttpRequest, id: web::Path<String>, body: web::Json<Value>) -> HttpResponse {
+    let mut conn = match connect(&data, &req) {
+        Ok(conn) => conn,
+        Err(response) => return response
+    };
+
+    match users::exists(&mut conn, &id) {
+        Ok(true) => {},
+        Ok(false) => return scim::error(StatusCode::NOT_FOUND, None, "User not found."),
+        Err(_) => return HttpResponse::InternalServerError().finish()
+    }
+
+    let changes = match UserChanges::from_resource(&body, true) {
+        Ok(changes) => changes,
+        Err(e) => return scim::error(StatusCode::BAD_REQUEST, Some("invalidValue"), e)
+    };
+
+    if let Err(response) = apply(&mut conn, &data, &id, changes) {
+        return response;
+    }
+
+    respond_with_user(&mut conn, &data, &id, StatusCode::OK)
+}
+
+/// Change a user with PATCH operations. Attributes this server doesn't store are ignored
+#[patch("/scim/v2/Users/{id}")]
+pub async fn patch_user(data: web::Data<AppData>, req: HttpRequest, id: web::Path<String>, body: web::Json<PatchRequest>) -> HttpResponse {
+    let mut conn = match connect(&data, &req) {
+        Ok(conn) => conn,
+        Err(response) => return response
+    };
+
+    match users::exists(&mut conn, &id) {
+        Ok(true) => {},
+        Ok(false) => return scim::error(StatusCode::NOT_FOUND, None, "User not found."),
+        Err(_) => return HttpResponse::InternalServerError().finish()
+    }
+
+    for operation in body.operations.iter() {
+        let op = operation.op();
+        let value = operation.value.clone().unwrap_or(Value::Null);
+
+        let changes = match (op.as_str(), operation.path().as_deref()) {
+            ("add", None) | ("replace", None) => UserChanges::from_resource(&value, false),
+            ("add", Some(path)) | ("replace", Some(path)) | ("remove", Some(path)) => {
+                let value = if op == "remove" { Value::Null } else { value };
+                let mut changes = UserChanges::default();
+
+                let result = match path {
+                    "active" => scim::as_bool(&value).map(|active| changes.active = Some(active)).ok_or("Invalid active."),
+                    "displayname" | "name.formatted" => scim::as_optional_string(&value).map(|display_name| changes.display_name = Some(display_name)).map_err(|_| "Invalid displayName."),
+                    "externalid" => scim::as_optional_string(&value).map(|external_id| changes.external_id = Some(external_id)).map_err(|_| "Invalid externalId."),
+                    "username" => value.as_str().map(|user_name| changes.user_name(user_name)).ok_or("Invalid userName."),
+                    path if path == "emails" || path.starts_with("emails[") || path.starts_with("emails.") => changes.emails(&value).map_err(|_| "The email address can be replaced, but not removed."),
+                    _ => Ok(())
+                };
+
+                result.map(|_| changes)
+            },
+            _ => Err("Unsupported operation.")
+        };
+
+        let changes = match changes {
+            Ok(changes) => changes,
+            Err(e) => return scim::error(StatusCode::BAD_REQUEST, Some("invalidValue"), e)
+        };
+
+        if let Err(response) = apply(&mut conn, &data, &id, changes) {
+            return response;
+        }
+    }
+
+    respond_with_user(&mut conn, &data, &id, StatusCode::OK)
+}
+
+/// Deprovision a user by deleting them
+#[delete("/scim/v2/Users/{id}")]
+pub async fn delete_user(data: web::Data<AppData>, req: HttpRequest, id: web::Path<String>) -> HttpResponse {
+    let mut conn = match connect(&data, &req) {
+        Ok(conn) => conn,
+        Err(response) => return response
+    };
+
+    match users::exists(&mut conn, &id) {
+        Ok(true) => {},
+        Ok(false) => return scim::error(StatusCode::NOT_FOUND, None, "User not found."),
+        Err(_) => return HttpResponse::InternalServerError().finish()
+    }
+
+    match users::delete(&mut conn, &id) {
+        Ok(true) => {},
+        Ok(false) => return scim::error(StatusCode::CONFLICT, None, "The user is the only owner of an organization."),
+        Err(_) => return HttpResponse::InternalServerError().finish()
+    }
+
+    HttpResponse::NoContent().finish()
+}
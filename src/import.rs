@@ -0,0 +1,154 @@
+use crate::appdata::Environment;
+use crate::password::ForeignAlgorithm;
+use crate::username::{self, UsernamePolicy};
+use crate::users::{self, CreateError};
+
+use mysql::PooledConn;
+use serde::{Deserialize, Serialize};
+
+/// The formats users can be imported from. Both have the fields of `ImportRecord`,
+/// CSV as a header row followed by one row per user, JSON lines as one object per line
+#[derive(Clone, Copy)]
+pub enum ImportFormat {
+    Csv,
+    JsonLines
+}
+
+impl std::str::FromStr for ImportFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "csv" => Ok(ImportFormat::Csv),
+            "jsonl" | "json-lines" => Ok(ImportFormat::JsonLines),
+            _ => Err(())
+        }
+    }
+}
+
+/// A user to import
+#[derive(Deserialize)]
+pub struct ImportRecord {
+    email:              String,
+    username:           Option<String>,
+    password_hash:      String,
+    /// One of the algorithms of `ForeignAlgorithm`, e.g. 'bcrypt' or 'pbkdf2-sha256'
+    password_algorithm: String,
+    /// Only used by algorithms which don't include the salt in the hash
+    salt:               Option<String>
+}
+
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Created,
+    /// The email address or username already belongs to an account
+    Conflict,
+    Error
+}
+
+#[derive(Serialize)]
+pub struct RowReport {
+    /// The line of the input the user was read from, starting at 1
+    line:       u64,
+    outcome:    Outcome,
+    user_id:    Option<String>,
+    message:    Option<String>
+}
+
+#[derive(Serialize, Default)]
+pub struct ImportReport {
+    pub created:    usize,
+    pub conflicts:  usize,
+    pub errors:     usize,
+    pub rows:       Vec<RowReport>
+}
+
+impl ImportReport {
+    fn push(&mut self, line: u64, result: Result<String, (Outcome, String)>) {
+        let row = match result {
+            Ok(user_id) => RowReport { line, outcome: Outcome::Created, user_id: Some(user_id), message: None },
+            Err((outcome, message)) => RowReport { line, outcome, user_id: None, message: Some(message) }
+        };
+
+        match row.outcome {
+            Outcome::Created => self.created += 1,
+            Outcome::Conflict => self.conflicts += 1,
+            Outcome::Error => self.errors += 1
+        }
+
+        self.rows.push(row);
+    }
+}
+
+/// Parse the input into records, each with the line it starts on. Rows which can't be parsed are errors
+fn parse(input: &str, format: ImportFormat) -> Vec<(u64, Result<ImportRecord, String>)> {
+    match format {
+        ImportFormat::Csv => {
+            let mut reader = csv::ReaderBuilder::new().trim(csv::Trim::All).from_reader(input.as_bytes());
+            let headers = match reader.headers() {
+                Ok(headers) => headers.clone(),
+                Err(e) => return vec![(1, Err(e.to_string()))]
+            };
+
+            reader.records()
+                .map(|record| match record {
+                    Ok(record) => {
+                        let line = record.position().map(|position| position.line()).unwrap_or(0);
+                        (line, record.deserialize::<ImportRecord>(Some(&headers)).map_err(|e| e.to_string()))
+                    },
+                    Err(e) => (e.position().map(|position| position.line()).unwrap_or(0), Err(e.to_string()))
+                })
+                .collect()
+        },
+        ImportFormat::JsonLines => input.lines()
+            .enumerate()
+            .filter(|(_, line)| !line.trim().is_empty())
+            .map(|(index, line)| (index as u64 + 1, serde_json::from_str::<ImportRecord>(line).map_err(|e| e.to_string())))
+            .collect()
+    }
+}
+
+/// Create a single user from a record
+fn import_record(conn: &mut PooledConn, environment: &Environment, record: ImportRecord) -> Result<String, (Outcome, String)> {
+    let algorithm = record.password_algorithm.parse::<ForeignAlgorithm>()
+        .map_err(|_| (Outcome::Error, format!("Unknown password algorithm '{}'.", record.password_algorithm)))?;
+
+    if !algorithm.is_valid_hash(&record.password_hash) {
+        return Err((Outcome::Error, format!("Malformed {} password hash.", algorithm.as_str())));
+    }
+
+    let username = record.username.filter(|username| !username.is_empty()).map(|username| username::normalize(&username));
+    match (environment.username_policy, &username) {
+        (UsernamePolicy::Disabled, Some(_)) => return Err((Outcome::Error, "Usernames are disabled.".to_string())),
+        (UsernamePolicy::Required, None) => return Err((Outcome::Error, "A username is required.".to_string())),
+        (_, Some(username)) if !username::is_valid(username) => return Err((Outcome::Error, format!("Invalid username '{}'.", username))),
+        _ => {}
+    }
+
+    let salt = record.salt.unwrap_or_default();
+    match users::create_with_hash(conn, environment, &record.email, username.as_deref(), &record.password_hash, &salt, Some(algorithm)) {
+        Ok(user_id) => Ok(user_id),
+        Err(CreateError::InvalidEmail) => Err((Outcome::Error, "Invalid E-mail address.".to_string())),
+        Err(CreateError::EmailTaken) => Err((Outcome::Conflict, "An account with this email address already exists.".to_string())),
+        Err(CreateError::UsernameTaken) => Err((Outcome::Conflict, "Username is already taken.".to_string())),
+        Err(CreateError::Failed) => Err((Outcome::Error, "Database error.".to_string()))
+    }
+}
+
+/// Import users in bulk, keeping their password hashes. Each user is created on its own,
+/// so a row which fails doesn't affect the others. Their passwords are re-hashed on their first login
+pub fn import(conn: &mut PooledConn, environment: &Environment, input: &str, format: ImportFormat) -> ImportReport {
+    let mut report = ImportReport::default();
+
+    for (line, record) in parse(input, format) {
+        let result = match record {
+            Ok(record) => import_record(conn, environment, record),
+            Err(e) => Err((Outcome::Error, e))
+        };
+
+        report.push(line, result);
+    }
+
+    report
+}
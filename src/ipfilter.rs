@@ -0,0 +1,369 @@
+use crate::appdata::{AppData, Environment};
+use crate::{client, orgs, rbac};
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::future::Future;
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use mysql::PooledConn;
+use serde::{Deserialize, Serialize};
+
+/// An IP address range in CIDR notation, e.g. `10.0.0.0/8` or `2001:db8::/32`. A bare address is a range of one
+#[derive(Deserialize, Clone, Copy)]
+#[serde(try_from = "String")]
+pub struct Cidr {
+    network:    IpAddr,
+    prefix:     u8
+}
+
+impl std::str::FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (address, prefix) = match s.trim().split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (s.trim(), None)
+        };
+
+        let network = normalize(address.parse::<IpAddr>().map_err(|_| format!("'{}' is not an IP address or CIDR range", s))?);
+        let max_prefix = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u8>().ok().filter(|prefix| *prefix <= max_prefix).ok_or_else(|| format!("'{}' has an invalid prefix length", s))?,
+            None => max_prefix
+        };
+
+        Ok(Cidr { network, prefix })
+    }
+}
+
+impl TryFrom<String> for Cidr {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        value.parse()
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        match (self.network, normalize(*ip)) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => prefix_matches(&network.octets(), &ip.octets(), self.prefix),
+            (IpAddr::V6(network), IpAddr::V6(ip)) => prefix_matches(&network.octets(), &ip.octets(), self.prefix),
+            _ => false
+        }
+    }
+}
+
+/// IPv4 clients of a dual stack server show up as IPv4-mapped IPv6 addresses, which should match IPv4 ranges
+fn normalize(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6.to_ipv4_mapped().map(IpAddr::V4).unwrap_or(ip),
+        ip => ip
+    }
+}
+
+fn prefix_matches(network: &[u8], ip: &[u8], prefix: u8) -> bool {
+    let full_bytes = (prefix / 8) as usize;
+    if network[..full_bytes] != ip[..full_bytes] {
+        return false;
+    }
+
+    let remaining_bits = prefix % 8;
+    if remaining_bits == 0 {
+        return true;
+    }
+
+    let mask = 0xFFu8 << (8 - remaining_bits);
+    network[full_bytes] & mask == ip[full_bytes] & mask
+}
+
+/// Ranges requests are accepted from. An address in `deny` is refused, and if `allow` isn't empty an address has to be in it.
+/// If the address isn't known, e.g. because the request came through a socket without one, it can't be in `allow` either
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct RuleSet {
+    pub allow:  Vec<Cidr>,
+    pub deny:   Vec<Cidr>
+}
+
+impl RuleSet {
+    fn permits(&self, ip: Option<&IpAddr>) -> bool {
+        let ip = match ip {
+            Some(ip) => ip,
+            None => return self.allow.is_empty()
+        };
+
+        if self.deny.iter().any(|cidr| cidr.contains(ip)) {
+            return false;
+        }
+
+        self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip))
+    }
+}
+
+/// The contents of the rules file, e.g.
+///
+/// ```yaml
+/// global:
+///   deny: ["203.0.113.0/24"]
+/// roles:
+///   admin:
+///     allow: ["10.0.0.0/8"]
+/// organizations:
+///   <org ID>:
+///     allow: ["192.0.2.0/24", "2001:db8::/32"]
+/// ```
+#[derive(Deserialize, Clone, Default)]
+#[serde(default)]
+pub struct IpRules {
+    /// Applies to every request
+    pub global:         RuleSet,
+    /// Applies to the logins and sessions of users with the role
+    pub roles:          HashMap<String, RuleSet>,
+    /// Applies to the logins and sessions of the organization's members
+    pub organizations:  HashMap<String, RuleSet>
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct IpFilterConfig {
+    /// YAML file with the rules, see `IpRules`. Without one every address is accepted
+    pub rules_file:         Option<String>,
+    /// How often the rules file is checked for changes
+    pub reload_seconds:     u64,
+    /// Proxies whose `X-Forwarded-For` header is trusted to tell the client's address
+    pub trusted_proxies:    Vec<String>
+}
+
+impl Default for IpFilterConfig {
+    fn default() -> Self {
+        IpFilterConfig {
+            rules_file:         None,
+            reload_seconds:     30,
+            trusted_proxies:    Vec::new()
+        }
+    }
+}
+
+impl IpFilterConfig {
+    pub fn from_vars() -> IpFilterConfig {
+        let default = IpFilterConfig::default();
+
+        //TRUSTED_PROXIES is a comma separated list of addresses or CIDR ranges
+        IpFilterConfig {
+            rules_file:         std::env::var("IP_FILTER_FILE").ok(),
+            reload_seconds:     Environment::optional_var("IP_FILTER_RELOAD_SECONDS", default.reload_seconds),
+            trusted_proxies:    std::env::var("TRUSTED_PROXIES")
+                .map(|proxies| proxies.split(',').map(|proxy| proxy.trim().to_string()).filter(|proxy| !proxy.is_empty()).collect())
+                .unwrap_or(default.trusted_proxies)
+        }
+    }
+}
+
+/// The loaded rules and trusted proxies. Clones share the rules, so a reload applies to all of them
+#[derive(Clone)]
+pub struct IpFilter {
+    rules:              Arc<RwLock<IpRules>>,
+    trusted_proxies:    Arc<Vec<Cidr>>
+}
+
+impl IpFilter {
+    /// Load the rules file and parse the trusted proxies. Returns a description of the first problem found
+    pub fn new(config: &IpFilterConfig) -> Result<IpFilter, String> {
+        let trusted_proxies = config.trusted_proxies.iter().map(|proxy| proxy.parse()).collect::<Result<Vec<Cidr>, String>>()?;
+        let rules = match &config.rules_file {
+            Some(path) => load_rules(path)?,
+            None => IpRules::default()
+        };
+
+        Ok(IpFilter {
+            rules:              Arc::new(RwLock::new(rules)),
+            trusted_proxies:    Arc::new(trusted_proxies)
+        })
+    }
+
+    /// Reload the rules on a separate thread whenever the rules file changes. If the new rules can't be read,
+    /// the old rules stay in place
+    pub fn spawn_reloader(&self, config: &IpFilterConfig) {
+        let path = match &config.rules_file {
+            Some(path) => path.clone(),
+            None => return
+        };
+
+        let rules = self.rules.clone();
+        let interval = Duration::from_secs(config.reload_seconds.max(1));
+        std::thread::spawn(move || {
+            let mut modified = modified_at(&path);
+
+            loop {
+                std::thread::sleep(interval);
+
+                let current = modified_at(&path);
+                if current == modified {
+                    continue;
+                }
+                modified = current;
+
+                match load_rules(&path) {
+                    Ok(new_rules) => {
+                        *rules.write().unwrap() = new_rules;
+                        println!("Reloaded the IP filter rules from '{}'", path);
+                    },
+                    Err(e) => eprintln!("Unable to reload the IP filter rules, keeping the previous rules (ipfilter.rs): {}", e)
+                }
+            }
+        });
+    }
+
+    pub fn trusted_proxies(&self) -> &[Cidr] {
+        &self.trusted_proxies
+    }
+
+    /// Whether the global rules accept requests from the address
+    pub fn permits(&self, ip: Option<&IpAddr>) -> bool {
+        self.rules.read().unwrap().global.permits(ip)
+    }
+
+    fn has_user_rules(&self) -> bool {
+        let rules = self.rules.read().unwrap();
+        !rules.roles.is_empty() || !rules.organizations.is_empty()
+    }
+
+    /// Whether the rules of every role and of every organization accept the address
+    fn permits_user(&self, ip: Option<&IpAddr>, roles: &[String], org_ids: &[String]) -> bool {
+        let rules = self.rules.read().unwrap();
+
+        let roles_permit = roles.iter().filter_map(|role| rules.roles.get(role)).all(|rule_set| rule_set.permits(ip));
+        let orgs_permit = org_ids.iter().filter_map(|org_id| rules.organizations.get(org_id)).all(|rule_set| rule_set.permits(ip));
+
+        roles_permit && orgs_permit
+    }
+}
+
+fn load_rules(path: &str) -> Result<IpRules, String> {
+    let contents = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    serde_yaml::from_str(&contents).map_err(|e| format!("{}: {}", path, e))
+}
+
+fn modified_at(path: &str) -> Option<SystemTime> {
+    std::fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// Whether the user's roles, and every organization they are a member of, accept the request's address.
+/// Only looks up the roles and organizations if any of them have rules
+pub fn permits_user(conn: &mut PooledConn, req: &HttpRequest, user_id: &str) -> Result<bool, ()> {
+    let data = match req.app_data::<web::Data<AppData>>() {
+        Some(data) => data,
+        None => return Ok(true)
+    };
+
+    let filter = &data.ip_filter;
+    if !filter.has_user_rules() {
+        return Ok(true);
+    }
+
+    let roles = rbac::user_roles(conn, user_id)?;
+    let org_ids = orgs::user_orgs(conn, user_id)?;
+
+    Ok(filter.permits_user(client::ip(req).as_ref(), &roles, &org_ids))
+}
+
+#[derive(Serialize)]
+struct DeniedResponse {
+    status:     i16,
+    message:    &'static str
+}
+
+type ResponseFuture = Pin<Box<dyn Future<Output = Result<ServiceResponse, Error>>>>;
+
+/// Middleware refusing requests from addresses the global rules don't accept, before they reach any handler
+pub fn middleware<S>(req: ServiceRequest, srv: &mut S) -> ResponseFuture
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = Error>,
+    S::Future: 'static
+{
+    if let Some(data) = req.app_data::<web::Data<AppData>>().cloned() {
+        let ip = client::resolve(req.peer_addr().map(|addr| addr.ip()), req.headers(), data.ip_filter.trusted_proxies());
+
+        if !data.ip_filter.permits(ip.as_ref()) {
+            let response = HttpResponse::Forbidden().json(&DeniedResponse { status: 403, message: "Requests from this network are not allowed." });
+            return Box::pin(async move { Ok(req.into_response(response)) });
+        }
+    }
+
+    Box::pin(srv.call(req))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_ranges_and_bare_addresses() {
+        let v4: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert_eq!(v4.prefix, 8);
+
+        let v6: Cidr = " 2001:db8::/32 ".parse().unwrap();
+        assert_eq!(v6.prefix, 32);
+
+        let bare: Cidr = "192.0.2.1".parse().unwrap();
+        assert_eq!(bare.prefix, 32);
+
+        let bare_v6: Cidr = "2001:db8::1".parse().unwrap();
+        assert_eq!(bare_v6.prefix, 128);
+    }
+
+    #[test]
+    fn rejects_invalid_ranges() {
+        assert!("not an address".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("2001:db8::/129".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/-1".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn contains_addresses_within_the_prefix() {
+        let cidr: Cidr = "192.0.2.0/25".parse().unwrap();
+        assert!(cidr.contains(&ip("192.0.2.0")));
+        assert!(cidr.contains(&ip("192.0.2.127")));
+        assert!(!cidr.contains(&ip("192.0.2.128")));
+        assert!(!cidr.contains(&ip("2001:db8::1")));
+
+        let cidr: Cidr = "2001:db8::/32".parse().unwrap();
+        assert!(cidr.contains(&ip("2001:db8:ffff::1")));
+        assert!(!cidr.contains(&ip("2001:db9::1")));
+        assert!(!cidr.contains(&ip("192.0.2.1")));
+
+        let everything: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(everything.contains(&ip("203.0.113.7")));
+    }
+
+    #[test]
+    fn matches_ipv4_mapped_addresses_against_ipv4_ranges() {
+        let cidr: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(cidr.contains(&ip("::ffff:10.1.2.3")));
+        assert!(!cidr.contains(&ip("::ffff:11.1.2.3")));
+    }
+
+    #[test]
+    fn refuses_unknown_addresses_under_an_allowlist() {
+        let open = RuleSet { allow: Vec::new(), deny: vec!["203.0.113.0/24".parse().unwrap()] };
+        assert!(open.permits(None));
+        assert!(!open.permits(Some(&ip("203.0.113.7"))));
+
+        let allowlist = RuleSet { allow: vec!["10.0.0.0/8".parse().unwrap()], deny: Vec::new() };
+        assert!(!allowlist.permits(None));
+        assert!(allowlist.permits(Some(&ip("10.1.2.3"))));
+        assert!(!allowlist.permits(Some(&ip("192.0.2.1"))));
+    }
+}
@@ -0,0 +1,313 @@
+use crate::appdata::Environment;
+use crate::{crypto, mail, password, users};
+
+use mysql::prelude::Queryable;
+use mysql::{PooledConn, Row, TxOpts, params};
+use serde::{Deserialize, Serialize};
+
+/// A lockout which starts once an account has had `failures` failed logins in a row
+#[derive(Deserialize, Serialize, Clone)]
+pub struct LockoutStep {
+    pub failures:   u32,
+    /// How long logging in with a password is locked, in seconds
+    pub seconds:    i64
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct LockoutConfig {
+    pub enabled:                        bool,
+    /// Failed logins after which each further attempt has to wait, doubling the wait every failure
+    pub backoff_after:                  u32,
+    pub backoff_base_seconds:           i64,
+    pub backoff_max_seconds:            i64,
+    /// Lockouts, by ascending amount of failures. Every failure from a step on locks the account again, for the highest step reached
+    pub steps:                          Vec<LockoutStep>,
+    /// Mail a link which lifts the lockout to the owner of an account when it is locked
+    pub unlock_by_email:                bool,
+    pub unlock_link_validity_hours:     i64
+}
+
+impl Default for LockoutConfig {
+    fn default() -> Self {
+        LockoutConfig {
+            enabled:                    true,
+            backoff_after:              3,
+            backoff_base_seconds:       1,
+            backoff_max_seconds:        300,
+            steps:                      vec![
+                LockoutStep { failures: 10, seconds: 15 * 60 },
+                LockoutStep { failures: 20, seconds: 60 * 60 },
+                LockoutStep { failures: 30, seconds: 24 * 60 * 60 }
+            ],
+            unlock_by_email:            true,
+            unlock_link_validity_hours: 24
+        }
+    }
+}
+
+impl LockoutConfig {
+    pub fn from_vars() -> LockoutConfig {
+        let default = LockoutConfig::default();
+
+        //LOCKOUT_STEPS is a comma separated list of <failures>:<seconds>, e.g. '10:900,20:3600'
+        let steps = match std::env::var("LOCKOUT_STEPS") {
+            Ok(steps) => steps.split(',')
+                .filter(|step| !step.trim().is_empty())
+                .map(|step| {
+                    let parsed = step.split_once(':').and_then(|(failures, seconds)| {
+                        Some(LockoutStep { failures: failures.trim().parse().ok()?, seconds: seconds.trim().parse().ok()? })
+                    });
+
+                    if parsed.is_none() {
+                        eprintln!("Environmental variable 'LOCKOUT_STEPS' has an invalid entry '{}'. Exiting", step);
+                        std::process::exit(1);
+                    }
+
+                    parsed.unwrap()
+                })
+                .collect(),
+            Err(_) => default.steps
+        };
+
+        LockoutConfig {
+            enabled:                    Environment::optional_var("LOCKOUT_ENABLED", "TRUE".to_string()) != "FALSE",
+            backoff_after:              Environment::optional_var("LOCKOUT_BACKOFF_AFTER", default.backoff_after),
+            backoff_base_seconds:       Environment::optional_var("LOCKOUT_BACKOFF_BASE_SECONDS", default.backoff_base_seconds),
+            backoff_max_seconds:        Environment::optional_var("LOCKOUT_BACKOFF_MAX_SECONDS", default.backoff_max_seconds),
+            steps,
+            unlock_by_email:            Environment::optional_var("LOCKOUT_UNLOCK_BY_EMAIL", "TRUE".to_string()) != "FALSE",
+            unlock_link_validity_hours: Environment::optional_var("LOCKOUT_UNLOCK_LINK_VALIDITY_HOURS", default.unlock_link_validity_hours)
+        }
+    }
+
+    /// The condition an account has to meet to be logged in to: it isn't locked, and the previous failure was long enough
+    /// ago. After `backoff_after` failures the wait starts at `backoff_base_seconds`, doubling every failure
+    fn attempt_condition(&self) -> &'static str {
+        "(login_locked_until IS NULL OR login_locked_until <= :now) AND (last_failed_login IS NULL OR failed_logins < :backoff_after OR \
+            :now >= last_failed_login + LEAST(:backoff_base_seconds * POW(2, LEAST(GREATEST(CAST(failed_logins AS SIGNED) - :backoff_after, 0), 32)), :backoff_max_seconds))"
+    }
+
+    /// The new `login_locked_until` once an account has `failed_logins + 1` failures, as SQL. The account is locked for the
+    /// highest step it has reached, so every failure from the first step on locks it again
+    fn lock_expression(&self) -> String {
+        let mut steps = self.steps.clone();
+        steps.sort_by_key(|step| std::cmp::Reverse(step.failures));
+
+        let cases: String = steps.iter()
+            .map(|step| format!("WHEN failed_logins + 1 >= {} THEN :now + {} ", step.failures, step.seconds))
+            .collect();
+
+        if cases.is_empty() {
+            return "login_locked_until".to_string();
+        }
+
+        format!("CASE {}ELSE login_locked_until END", cases)
+    }
+}
+
+/// A login attempt, counted as failed before its password is checked
+pub struct Attempt {
+    failed_logins:  u32,
+    /// Set if this attempt locked the account
+    locked_for:     Option<i64>
+}
+
+/// Start a login attempt with a password. Returns `None` if the account is locked or its previous failure was too recent.
+///
+/// The attempt counts as a failure right away, and a successful one is undone by `record_success`. Counting and checking
+/// happen in a single UPDATE, so parallel guesses can't all slip through the same backoff window or skip a lockout step
+pub fn begin_attempt(conn: &mut PooledConn, config: &LockoutConfig, user_id: &str) -> Result<Option<Attempt>, ()> {
+    if !config.enabled {
+        return Ok(Some(Attempt { failed_logins: 0, locked_for: None }));
+    }
+
+    let tx = conn.start_transaction(TxOpts::default());
+    if tx.is_err() {
+        eprintln!("An error occurred (lockout.rs): {:?}", tx.err().unwrap());
+        return Err(());
+    }
+    let mut tx = tx.unwrap();
+
+    //MySQL assigns in order, so the lock is computed from the failures before this attempt
+    let now = chrono::Utc::now().timestamp();
+    let sql_count_attempt = tx.exec_drop(format!("UPDATE users SET login_locked_until = {}, failed_logins = failed_logins + 1, last_failed_login = :now WHERE user_id = :user_id AND {}", config.lock_expression(), config.attempt_condition()), params! {
+        "now" => now,
+        "backoff_after" => config.backoff_after,
+        "backoff_base_seconds" => config.backoff_base_seconds,
+        "backoff_max_seconds" => config.backoff_max_seconds,
+        "user_id" => user_id
+    });
+
+    if sql_count_attempt.is_err() {
+        eprintln!("An error occurred (lockout.rs): {:?}", sql_count_attempt.err().unwrap());
+        return Err(());
+    }
+
+    if tx.affected_rows() == 0 {
+        return Ok(None);
+    }
+
+    //The row stays locked until the commit, so this reads what the UPDATE wrote
+    let sql_fetch_failures = tx.exec_first::<(u32, Option<i64>), &str, _>("SELECT failed_logins, login_locked_until FROM users WHERE user_id = :user_id", params! {
+        "user_id" => user_id
+    });
+
+    let (failed_logins, locked_until) = match sql_fetch_failures {
+        Ok(Some(failures)) => failures,
+        Ok(None) => return Err(()),
+        Err(e) => {
+            eprintln!("An error occurred (lockout.rs): {:?}", e);
+            return Err(());
+        }
+    };
+
+    if let Err(e) = tx.commit() {
+        eprintln!("An error occurred (lockout.rs): {:?}", e);
+        return Err(());
+    }
+
+    Ok(Some(Attempt {
+        failed_logins,
+        locked_for: locked_until.filter(|locked_until| *locked_until > now).map(|locked_until| locked_until - now)
+    }))
+}
+
+/// Do the same work as `begin_attempt` for an account which doesn't exist, so refusing it doesn't take less time
+pub fn simulate_attempt(conn: &mut PooledConn, config: &LockoutConfig) -> Result<(), ()> {
+    //User IDs are 64 characters long, so no account has an empty one
+    begin_attempt(conn, config, "").map(|_| ())
+}
+
+/// Finish an attempt whose password was wrong. It has been counted already, this mails the unlock link if it locked the account
+pub fn record_failure(conn: &mut PooledConn, environment: &Environment, user_id: &str, attempt: &Attempt) -> Result<(), ()> {
+    let lockout_seconds = match attempt.locked_for {
+        Some(lockout_seconds) => lockout_seconds,
+        None => return Ok(())
+    };
+
+    println!("Locked logging in to account '{}' for {} seconds after {} failed logins", user_id, lockout_seconds, attempt.failed_logins);
+
+    if environment.lockout.unlock_by_email {
+        send_unlock_link(conn, environment, user_id, lockout_seconds)?;
+    }
+
+    Ok(())
+}
+
+/// Finish an attempt whose password was right, forgetting the failed logins of the account, this attempt included
+pub fn record_success(conn: &mut PooledConn, user_id: &str) -> Result<(), ()> {
+    let sql_reset_failures = conn.exec_drop("UPDATE users SET failed_logins = 0, last_failed_login = NULL, login_locked_until = NULL WHERE user_id = :user_id AND failed_logins > 0", params! {
+        "user_id" => user_id
+    });
+
+    if sql_reset_failures.is_err() {
+        eprintln!("An error occurred (lockout.rs): {:?}", sql_reset_failures.err().unwrap());
+        return Err(());
+    }
+
+    Ok(())
+}
+
+/// Check the password of a user who is logged in already, e.g. before a sensitive operation. Wrong passwords count
+/// towards the lockout like failed logins, and every password is refused while the account is locked, so a stolen
+/// session can't be used to guess the password past the lockout. Returns whether the password is right
+pub fn check_password(conn: &mut PooledConn, environment: &Environment, user_id: &str, password: &str) -> Result<bool, ()> {
+    let sql_fetch_password = conn.exec_first::<Row, &str, _>("SELECT password, salt, password_algorithm FROM users WHERE user_id = :user_id", params! {
+        "user_id" => user_id
+    });
+
+    let row = match sql_fetch_password {
+        Ok(Some(row)) => row,
+        Ok(None) => return Err(()),
+        Err(e) => {
+            eprintln!("An error occurred (lockout.rs): {:?}", e);
+            return Err(());
+        }
+    };
+
+    let password_from_db = row.get::<String, &str>("password").unwrap();
+    let salt = row.get::<String, &str>("salt").unwrap();
+    let password_algorithm = row.get::<Option<String>, &str>("password_algorithm").unwrap();
+
+    let attempt = match begin_attempt(conn, &environment.lockout, user_id)? {
+        Some(attempt) => attempt,
+        None => return Ok(false)
+    };
+
+    if !password::verify_stored(password, &salt, &environment.password_pepper, &password_from_db, password_algorithm.as_deref()) {
+        record_failure(conn, environment, user_id, &attempt)?;
+        return Ok(false);
+    }
+
+    if environment.lockout.enabled {
+        record_success(conn, user_id)?;
+    }
+
+    Ok(true)
+}
+
+/// Lift the lockout of an account, and forget its failed logins and unlock links
+pub fn unlock(conn: &mut PooledConn, user_id: &str) -> Result<(), ()> {
+    let sql_unlock = conn.exec_drop("UPDATE users SET failed_logins = 0, last_failed_login = NULL, login_locked_until = NULL WHERE user_id = :user_id", params! {
+        "user_id" => user_id
+    });
+
+    if sql_unlock.is_err() {
+        eprintln!("An error occurred (lockout.rs): {:?}", sql_unlock.err().unwrap());
+        return Err(());
+    }
+
+    let sql_delete_tokens = conn.exec_drop("DELETE FROM unlock_tokens WHERE user_id = :user_id", params! {
+        "user_id" => user_id
+    });
+
+    if sql_delete_tokens.is_err() {
+        eprintln!("An error occurred (lockout.rs): {:?}", sql_delete_tokens.err().unwrap());
+        return Err(());
+    }
+
+    Ok(())
+}
+
+/// Lift a lockout with the token of an unlock link. Returns false if the token is unknown or expired
+pub fn unlock_with_token(conn: &mut PooledConn, token: &str) -> Result<bool, ()> {
+    let sql_fetch_token = conn.exec_first::<String, &str, _>("SELECT user_id FROM unlock_tokens WHERE token = :token AND expiry > :now", params! {
+        "token" => crypto::hash_token(token),
+        "now" => chrono::Utc::now().timestamp()
+    });
+
+    match sql_fetch_token {
+        Ok(Some(user_id)) => unlock(conn, &user_id).map(|_| true),
+        Ok(None) => Ok(false),
+        Err(e) => {
+            eprintln!("An error occurred (lockout.rs): {:?}", e);
+            Err(())
+        }
+    }
+}
+
+fn send_unlock_link(conn: &mut PooledConn, environment: &Environment, user_id: &str, lockout_seconds: i64) -> Result<(), ()> {
+    let token = crypto::random_token();
+    let sql_insert_token = conn.exec_drop("INSERT INTO unlock_tokens (token, user_id, expiry) VALUES (:token, :user_id, :expiry)", params! {
+        "token" => crypto::hash_token(&token),
+        "user_id" => user_id,
+        "expiry" => chrono::Utc::now().timestamp() + environment.lockout.unlock_link_validity_hours * 3600
+    });
+
+    if sql_insert_token.is_err() {
+        eprintln!("An error occurred (lockout.rs): {:?}", sql_insert_token.err().unwrap());
+        return Err(());
+    }
+
+    let email = users::fetch_email(conn, &environment.email_encryption, user_id)?;
+    let mail_config = &environment.mail;
+    mail::send(mail_config, &email, "Logging in to your account has been locked", &format!(
+        "There were too many failed attempts to log in to your account, so logging in has been locked for {} minutes.\n\nIf these attempts were yours, you can unlock your account right away by opening this link within {} hours:\n{}\n\nIf they were not, someone may be trying to guess your password. Consider changing it once you are logged in.",
+        (lockout_seconds + 59) / 60,
+        environment.lockout.unlock_link_validity_hours,
+        mail_config.link(&format!("/auth/unlock?token={}", token))
+    ));
+
+    Ok(())
+}
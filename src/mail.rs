@@ -0,0 +1,94 @@
+use crate::appdata::Environment;
+
+use lettre::{Message, SmtpTransport, Transport};
+use lettre::transport::smtp::authentication::Credentials;
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct MailConfig {
+    /// SMTP server to send mail through. If not set, mail is written to stdout instead, which is only useful for development
+    pub smtp_host:      Option<String>,
+    pub smtp_port:      u16,
+    pub smtp_username:  Option<String>,
+    pub smtp_password:  Option<String>,
+    /// Sender address, e.g. 'Login Server <noreply@example.com>'
+    pub from:           String,
+    /// Public base URL of this server, used for links in mail
+    pub public_url:     String
+}
+
+impl Default for MailConfig {
+    fn default() -> Self {
+        MailConfig {
+            smtp_host:      None,
+            smtp_port:      587,
+            smtp_username:  None,
+            smtp_password:  None,
+            from:           "Login Server <noreply@localhost>".to_string(),
+            public_url:     "http://localhost:8080".to_string()
+        }
+    }
+}
+
+impl MailConfig {
+    pub fn from_vars() -> MailConfig {
+        let default = Self::default();
+
+        MailConfig {
+            smtp_host:      std::env::var("SMTP_HOST").ok(),
+            smtp_port:      Environment::optional_var("SMTP_PORT", default.smtp_port),
+            smtp_username:  std::env::var("SMTP_USERNAME").ok(),
+            smtp_password:  std::env::var("SMTP_PASSWORD").ok(),
+            from:           Environment::optional_var("MAIL_FROM", default.from),
+            public_url:     Environment::optional_var("PUBLIC_URL", default.public_url)
+        }
+    }
+
+    /// Build an absolute link to a path on this server
+    pub fn link(&self, path: &str) -> String {
+        format!("{}{}", self.public_url.trim_end_matches('/'), path)
+    }
+}
+
+/// Send a plain text mail in the background. Failures are logged, but not reported to the caller
+pub fn send(config: &MailConfig, to: &str, subject: &str, body: &str) {
+    let config = config.clone();
+    let to = to.to_string();
+    let subject = subject.to_string();
+    let body = body.to_string();
+
+    std::thread::spawn(move || {
+        if let Err(e) = send_blocking(&config, &to, &subject, &body) {
+            eprintln!("Unable to send mail '{}' (mail.rs): {}", subject, e);
+        }
+    });
+}
+
+fn send_blocking(config: &MailConfig, to: &str, subject: &str, body: &str) -> Result<(), String> {
+    let smtp_host = match &config.smtp_host {
+        Some(smtp_host) => smtp_host,
+        None => {
+            println!("No SMTP server configured, printing mail instead.\nTo: {}\nSubject: {}\n\n{}\n", to, subject, body);
+            return Ok(());
+        }
+    };
+
+    let message = Message::builder()
+        .from(config.from.parse().map_err(|e| format!("invalid sender: {:?}", e))?)
+        .to(to.parse().map_err(|e| format!("invalid recipient: {:?}", e))?)
+        .subject(subject)
+        .body(body.to_string())
+        .map_err(|e| e.to_string())?;
+
+    let mut transport = SmtpTransport::starttls_relay(smtp_host)
+        .map_err(|e| e.to_string())?
+        .port(config.smtp_port);
+
+    if let (Some(username), Some(password)) = (&config.smtp_username, &config.smtp_password) {
+        transport = transport.credentials(Credentials::new(username.clone(), password.clone()));
+    }
+
+    transport.build().send(&message).map_err(|e| e.to_string())?;
+    Ok(())
+}
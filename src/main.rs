@@ -1,7 +1,33 @@
 mod appdata;
+mod backup;
+mod breach;
+mod challenge;
+mod cli;
+mod client;
+mod crypto;
+mod devices;
+mod email;
 mod endpoints;
+mod import;
+mod ipfilter;
+mod lockout;
+mod mail;
+mod migrations;
+mod orgs;
+mod pages;
+mod password;
+mod password_policy;
+mod profile;
+mod ratelimit;
+mod rbac;
+mod reaper;
+mod scim;
+mod sessions;
+mod username;
+mod users;
 
 use crate::appdata::{Environment, Database, AppData};
+use crate::ipfilter::IpFilter;
 
 use actix_web::{HttpServer, App};
 use actix_cors::Cors;
@@ -9,13 +35,45 @@ use actix_web::middleware::Logger;
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
+    let matches = cli::app().get_matches();
+    match matches.subcommand() {
+        ("", _) | ("serve", _) => serve().await,
+        (name, Some(subcommand_matches)) => std::process::exit(cli::run(name, subcommand_matches)),
+        (name, None) => unreachable!("Subcommand '{}' without arguments", name)
+    }
+}
+
+async fn serve() -> std::io::Result<()> {
     println!("Starting server...");
 
-    let environment = Environment::new();
+    let mut environment = Environment::new();
+    if let Err(e) = environment.email_encryption.validate() {
+        eprintln!("Invalid email encryption configuration: {}. Exiting.", e);
+        std::process::exit(1);
+    }
+
+    if let Err(e) = environment.password_policy.load_blocklist() {
+        eprintln!("Unable to read the password blocklist: {}. Exiting.", e);
+        std::process::exit(1);
+    }
+
+    if let Err(e) = environment.challenge.validate() {
+        eprintln!("Invalid challenge configuration: {}. Exiting.", e);
+        std::process::exit(1);
+    }
+
+    let ip_filter = match IpFilter::new(&environment.ip_filter) {
+        Ok(ip_filter) => ip_filter,
+        Err(e) => {
+            eprintln!("Invalid IP filter configuration: {}. Exiting.", e);
+            std::process::exit(1);
+        }
+    };
+
     let database = Database::new(&environment);
 
     println!("Checking database...");
-    let check_db_result = database.check_db(&environment);
+    let check_db_result = database.check_db();
     if check_db_result.is_err() {
         eprintln!("Something went wrong checking the database (main.rs)! Exiting.");
         std::process::exit(1);
@@ -34,7 +92,26 @@ async fn main() -> std::io::Result<()> {
         println!("Database passed the check.");
     }
 
-    let appdata = AppData::new(database, environment);
+    //Re-wrap email addresses still encrypted with a retired key
+    match crypto::rewrap_emails(&database, &environment) {
+        Ok(0) => {},
+        Ok(count) => println!("Re-wrapped {} email address(es) with encryption key '{}'.", count, environment.email_encryption.active_key_id),
+        Err(_) => {
+            eprintln!("Something went wrong re-wrapping email addresses (main.rs)! Exiting.");
+            std::process::exit(1);
+        }
+    }
+
+    //Purge expired rows in the background
+    reaper::spawn(database.clone(), environment.retention.clone());
+
+    //Pick up changes to the IP filter rules without a restart
+    ip_filter.spawn_reloader(&environment.ip_filter);
+
+    let appdata = AppData::new(database, environment, ip_filter);
+
+    //Forget full rate limit buckets in the background
+    appdata.rate_limiter.spawn_pruner(&appdata.environment.rate_limit);
     println!("Startup complete. Listening on 0.0.0.0:8080");
 
     //Start the Actix HTTP server
@@ -47,6 +124,63 @@ async fn main() -> std::io::Result<()> {
             .service(endpoints::auth::register::post_register)
             .service(endpoints::auth::logout::post_logout)
             .service(endpoints::auth::session::post_session)
+            .service(endpoints::auth::session::post_switch_org)
+            .service(endpoints::auth::profile::post_profile)
+            .service(endpoints::auth::profile::post_update_profile)
+            .service(endpoints::auth::account::post_delete_account)
+            .service(endpoints::auth::account::post_export_account)
+            .service(endpoints::auth::email::post_change_email)
+            .service(endpoints::auth::email::get_confirm_email_change)
+            .service(endpoints::auth::email::post_confirm_email_change)
+            .service(endpoints::auth::email::get_cancel_email_change)
+            .service(endpoints::auth::email::post_cancel_email_change)
+            .service(endpoints::auth::unlock::get_unlock)
+            .service(endpoints::auth::unlock::post_unlock)
+            .service(endpoints::auth::password::post_change_password)
+            .service(endpoints::auth::password::get_reset_password)
+            .service(endpoints::auth::password::post_reset_password)
+            .service(endpoints::auth::password::post_request_password_reset)
+            .service(endpoints::auth::challenge::get_challenge)
+            .service(endpoints::auth::report::get_report_login)
+            .service(endpoints::auth::report::post_report_login)
+            .service(endpoints::auth::reauthenticate::post_reauthenticate)
+            .service(endpoints::admin::roles::get_roles)
+            .service(endpoints::admin::roles::put_role)
+            .service(endpoints::admin::roles::delete_role)
+            .service(endpoints::admin::roles::put_user_role)
+            .service(endpoints::admin::roles::delete_user_role)
+            .service(endpoints::admin::users::post_import_users)
+            .service(endpoints::admin::users::get_users)
+            .service(endpoints::admin::users::get_user)
+            .service(endpoints::admin::users::get_user_sessions)
+            .service(endpoints::admin::users::delete_user_sessions)
+            .service(endpoints::admin::users::put_user_state)
+            .service(endpoints::admin::users::post_disable_user)
+            .service(endpoints::admin::users::post_enable_user)
+            .service(endpoints::admin::users::post_unlock_user)
+            .service(endpoints::admin::users::put_user_password)
+            .service(endpoints::admin::users::delete_user)
+            .service(endpoints::orgs::post_org)
+            .service(endpoints::orgs::get_orgs)
+            .service(endpoints::orgs::post_accept_invitation)
+            .service(endpoints::orgs::get_members)
+            .service(endpoints::orgs::post_invitation)
+            .service(endpoints::orgs::put_member)
+            .service(endpoints::orgs::delete_member)
+            .service(endpoints::scim::users::get_users)
+            .service(endpoints::scim::users::get_user)
+            .service(endpoints::scim::users::post_user)
+            .service(endpoints::scim::users::put_user)
+            .service(endpoints::scim::users::patch_user)
+            .service(endpoints::scim::users::delete_user)
+            .service(endpoints::scim::groups::get_groups)
+            .service(endpoints::scim::groups::get_group)
+            .service(endpoints::scim::groups::post_group)
+            .service(endpoints::scim::groups::put_group)
+            .service(endpoints::scim::groups::patch_group)
+            .service(endpoints::scim::groups::delete_group)
+            .wrap_fn(ratelimit::middleware)
+            .wrap_fn(ipfilter::middleware)
             .wrap(cors)
             .wrap(Logger::default())
     })
@@ -0,0 +1,423 @@
+use crate::appdata::Environment;
+use crate::email;
+
+use std::collections::HashMap;
+use mysql::prelude::Queryable;
+use mysql::{PooledConn, Row, Params, params};
+
+/// A data migration, run after a migration's statements have been executed
+pub type PostMigration = fn(&mut PooledConn, &Environment) -> Result<(), ()>;
+
+/// A single, ordered schema change. Applied migrations are recorded in the `schema_migrations` table
+pub struct Migration {
+    pub version:        u32,
+    pub description:    &'static str,
+    pub statements:     &'static [&'static str],
+    pub post:           Option<PostMigration>
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        description: "Create the 'users' and 'sessions' tables",
+        statements: &[
+            "CREATE TABLE IF NOT EXISTS `sessions` ( `session_id` VARCHAR(64) NOT NULL , `user_id` VARCHAR(64) NOT NULL , `expiry` BIGINT NOT NULL , PRIMARY KEY (`session_id`)) ENGINE = InnoDB;",
+            "CREATE TABLE IF NOT EXISTS `users` ( `user_id` VARCHAR(64) NOT NULL , `email` VARCHAR(255) NOT NULL , `password` VARCHAR(255) NOT NULL , `salt` VARCHAR(16) NOT NULL , PRIMARY KEY (`user_id`)) ENGINE = InnoDB;"
+        ],
+        post: None
+    },
+    Migration {
+        version: 2,
+        description: "Encrypt email addresses at rest and add a blind index",
+        //The column is added by `encrypt_plaintext_emails`, as MySQL has no `ADD COLUMN IF NOT EXISTS`,
+        //and a migration which failed halfway has to be retried without adding it twice
+        statements: &[],
+        post: Some(encrypt_plaintext_emails)
+    },
+    Migration {
+        version: 3,
+        description: "Rebuild the email index from normalized email addresses",
+        statements: &[],
+        post: Some(reindex_emails)
+    },
+    Migration {
+        version: 4,
+        description: "Enforce unique email addresses",
+        statements: &[
+            "ALTER TABLE `users` DROP INDEX `users_email_index`, ADD UNIQUE INDEX `users_email_index` (`email_index`);"
+        ],
+        post: None
+    },
+    Migration {
+        version: 5,
+        description: "Add optional usernames",
+        statements: &[
+            "ALTER TABLE `users` ADD `username` VARCHAR(32) NULL DEFAULT NULL AFTER `email_index`, ADD UNIQUE INDEX `users_username` (`username`);"
+        ],
+        post: None
+    },
+    Migration {
+        version: 6,
+        description: "Add profile fields and custom attributes",
+        statements: &[
+            "ALTER TABLE `users` ADD `display_name` VARCHAR(64) NULL DEFAULT NULL, ADD `locale` VARCHAR(35) NULL DEFAULT NULL, ADD `timezone` VARCHAR(64) NULL DEFAULT NULL, ADD `avatar_url` VARCHAR(2048) NULL DEFAULT NULL, ADD `attributes` TEXT NULL DEFAULT NULL;"
+        ],
+        post: None
+    },
+    Migration {
+        version: 7,
+        description: "Add login history and soft-deleted accounts",
+        statements: &[
+            "CREATE TABLE IF NOT EXISTS `login_history` ( `id` BIGINT UNSIGNED NOT NULL AUTO_INCREMENT , `user_id` VARCHAR(64) NOT NULL , `timestamp` BIGINT NOT NULL , `ip` VARCHAR(45) NULL DEFAULT NULL , `user_agent` VARCHAR(255) NULL DEFAULT NULL , `success` BOOLEAN NOT NULL , PRIMARY KEY (`id`), INDEX `login_history_user_id` (`user_id`), INDEX `login_history_timestamp` (`timestamp`)) ENGINE = InnoDB;",
+            "ALTER TABLE `users` ADD `deleted_at` BIGINT NULL DEFAULT NULL, ADD INDEX `users_deleted_at` (`deleted_at`);"
+        ],
+        post: None
+    },
+    Migration {
+        version: 8,
+        description: "Add pending email changes",
+        statements: &[
+            "CREATE TABLE IF NOT EXISTS `email_changes` ( `user_id` VARCHAR(64) NOT NULL , `confirm_token` VARCHAR(64) NOT NULL , `cancel_token` VARCHAR(64) NOT NULL , `new_email` VARCHAR(1024) NOT NULL , `new_email_index` VARCHAR(64) NOT NULL , `expiry` BIGINT NOT NULL , PRIMARY KEY (`user_id`), UNIQUE INDEX `email_changes_confirm_token` (`confirm_token`), UNIQUE INDEX `email_changes_cancel_token` (`cancel_token`)) ENGINE = InnoDB;"
+        ],
+        post: None
+    },
+    Migration {
+        version: 9,
+        description: "Add roles and permissions",
+        statements: &[
+            "CREATE TABLE IF NOT EXISTS `roles` ( `name` VARCHAR(64) NOT NULL , `description` VARCHAR(255) NULL DEFAULT NULL , PRIMARY KEY (`name`)) ENGINE = InnoDB;",
+            "CREATE TABLE IF NOT EXISTS `role_permissions` ( `role` VARCHAR(64) NOT NULL , `permission` VARCHAR(128) NOT NULL , PRIMARY KEY (`role`, `permission`)) ENGINE = InnoDB;",
+            "CREATE TABLE IF NOT EXISTS `user_roles` ( `user_id` VARCHAR(64) NOT NULL , `role` VARCHAR(64) NOT NULL , PRIMARY KEY (`user_id`, `role`), INDEX `user_roles_role` (`role`)) ENGINE = InnoDB;",
+            "INSERT IGNORE INTO `roles` (`name`, `description`) VALUES ('admin', 'Full access to the administrative endpoints');",
+            "INSERT IGNORE INTO `role_permissions` (`role`, `permission`) VALUES ('admin', '*');"
+        ],
+        post: None
+    },
+    Migration {
+        version: 10,
+        description: "Add organizations, memberships and invitations",
+        statements: &[
+            "CREATE TABLE IF NOT EXISTS `organizations` ( `org_id` VARCHAR(64) NOT NULL , `name` VARCHAR(128) NOT NULL , `created_at` BIGINT NOT NULL , PRIMARY KEY (`org_id`)) ENGINE = InnoDB;",
+            "CREATE TABLE IF NOT EXISTS `org_memberships` ( `org_id` VARCHAR(64) NOT NULL , `user_id` VARCHAR(64) NOT NULL , `role` VARCHAR(16) NOT NULL , `joined_at` BIGINT NOT NULL , PRIMARY KEY (`org_id`, `user_id`), INDEX `org_memberships_user_id` (`user_id`)) ENGINE = InnoDB;",
+            "CREATE TABLE IF NOT EXISTS `org_invitations` ( `token` VARCHAR(64) NOT NULL , `org_id` VARCHAR(64) NOT NULL , `email_index` VARCHAR(64) NOT NULL , `role` VARCHAR(16) NOT NULL , `invited_by` VARCHAR(64) NOT NULL , `expiry` BIGINT NOT NULL , PRIMARY KEY (`token`), INDEX `org_invitations_org_id` (`org_id`)) ENGINE = InnoDB;",
+            "ALTER TABLE `sessions` ADD `active_org_id` VARCHAR(64) NULL DEFAULT NULL;"
+        ],
+        post: None
+    },
+    Migration {
+        version: 11,
+        description: "Add account statuses",
+        statements: &[
+            "ALTER TABLE `users` ADD `status` VARCHAR(16) NOT NULL DEFAULT 'active';"
+        ],
+        post: None
+    },
+    Migration {
+        version: 12,
+        description: "Add locked and banned account states",
+        statements: &[
+            "ALTER TABLE `users` ADD `status_until` BIGINT NULL DEFAULT NULL, ADD `status_reason` VARCHAR(255) NULL DEFAULT NULL;"
+        ],
+        post: None
+    },
+    Migration {
+        version: 13,
+        description: "Allow password hashes imported from other systems",
+        statements: &[
+            "ALTER TABLE `users` MODIFY `salt` VARCHAR(255) NOT NULL, ADD `password_algorithm` VARCHAR(16) NULL DEFAULT NULL;"
+        ],
+        post: None
+    },
+    Migration {
+        version: 14,
+        description: "Add external IDs for SCIM provisioning",
+        statements: &[
+            "ALTER TABLE `users` ADD `external_id` VARCHAR(255) NULL DEFAULT NULL, ADD INDEX `users_external_id` (`external_id`);"
+        ],
+        post: None
+    },
+    Migration {
+        version: 15,
+        description: "Add shared rate limit buckets",
+        statements: &[
+            "CREATE TABLE IF NOT EXISTS `rate_limits` ( `bucket_key` VARCHAR(255) NOT NULL , `tokens` DOUBLE NOT NULL , `updated_at` DOUBLE NOT NULL , PRIMARY KEY (`bucket_key`), INDEX `rate_limits_updated_at` (`updated_at`)) ENGINE = InnoDB;"
+        ],
+        post: None
+    },
+    Migration {
+        version: 16,
+        description: "Add per account login lockout",
+        //The columns are added by `add_lockout_columns`, so a retry doesn't add them twice
+        statements: &[
+            "CREATE TABLE IF NOT EXISTS `unlock_tokens` ( `token` VARCHAR(64) NOT NULL , `user_id` VARCHAR(64) NOT NULL , `expiry` BIGINT NOT NULL , PRIMARY KEY (`token`), INDEX `unlock_tokens_user_id` (`user_id`)) ENGINE = InnoDB;"
+        ],
+        post: Some(add_lockout_columns)
+    },
+    Migration {
+        version: 17,
+        description: "Add forced password resets",
+        statements: &[
+            "ALTER TABLE `users` ADD `password_reset_required` BOOLEAN NOT NULL DEFAULT FALSE;"
+        ],
+        post: None
+    },
+    Migration {
+        version: 18,
+        description: "Add password history",
+        statements: &[
+            "CREATE TABLE IF NOT EXISTS `password_history` ( `id` BIGINT UNSIGNED NOT NULL AUTO_INCREMENT , `user_id` VARCHAR(64) NOT NULL , `password` VARCHAR(255) NOT NULL , `salt` VARCHAR(255) NOT NULL , `password_algorithm` VARCHAR(16) NULL DEFAULT NULL , `created_at` BIGINT NOT NULL , PRIMARY KEY (`id`), INDEX `password_history_user_id` (`user_id`, `created_at`)) ENGINE = InnoDB;"
+        ],
+        post: None
+    },
+    Migration {
+        version: 19,
+        description: "Add known devices and new login alerts",
+        statements: &[
+            "CREATE TABLE IF NOT EXISTS `known_devices` ( `user_id` VARCHAR(64) NOT NULL , `device` VARCHAR(64) NOT NULL , `ip_prefix` VARCHAR(64) NOT NULL , `user_agent_family` VARCHAR(64) NOT NULL , `first_seen` BIGINT NOT NULL , `last_seen` BIGINT NOT NULL , PRIMARY KEY (`user_id`, `device`, `ip_prefix`), INDEX `known_devices_last_seen` (`last_seen`)) ENGINE = InnoDB;",
+            "CREATE TABLE IF NOT EXISTS `login_reports` ( `token` VARCHAR(64) NOT NULL , `user_id` VARCHAR(64) NOT NULL , `expiry` BIGINT NOT NULL , PRIMARY KEY (`token`), INDEX `login_reports_user_id` (`user_id`)) ENGINE = InnoDB;"
+        ],
+        post: None
+    },
+    Migration {
+        version: 20,
+        description: "Track when sessions last authenticated",
+        statements: &[
+            "ALTER TABLE `sessions` ADD `authenticated_at` BIGINT NULL DEFAULT NULL, ADD `auth_methods` VARCHAR(255) NOT NULL DEFAULT '';"
+        ],
+        post: None
+    },
+    Migration {
+        version: 21,
+        description: "Add session binding and security events",
+        //The columns are added by `add_session_binding_columns`, so a retry doesn't add them twice
+        statements: &[
+            "CREATE TABLE IF NOT EXISTS `security_events` ( `id` BIGINT UNSIGNED NOT NULL AUTO_INCREMENT , `user_id` VARCHAR(64) NOT NULL , `timestamp` BIGINT NOT NULL , `event` VARCHAR(64) NOT NULL , `details` VARCHAR(255) NULL DEFAULT NULL , `ip` VARCHAR(45) NULL DEFAULT NULL , `user_agent` VARCHAR(255) NULL DEFAULT NULL , PRIMARY KEY (`id`), INDEX `security_events_user_id` (`user_id`, `timestamp`), INDEX `security_events_timestamp` (`timestamp`)) ENGINE = InnoDB;"
+        ],
+        post: Some(add_session_binding_columns)
+    },
+    Migration {
+        version: 22,
+        description: "Track verified email addresses",
+        statements: &[
+            "ALTER TABLE `users` ADD `email_verified` BOOLEAN NOT NULL DEFAULT FALSE;"
+        ],
+        post: None
+    },
+    Migration {
+        version: 23,
+        description: "Mark the roles created through SCIM",
+        statements: &[
+            "ALTER TABLE `roles` ADD `scim_managed` BOOLEAN NOT NULL DEFAULT FALSE;"
+        ],
+        post: None
+    },
+    Migration {
+        version: 24,
+        description: "Share spent proof-of-work challenges between servers",
+        statements: &[
+            "CREATE TABLE IF NOT EXISTS `spent_challenges` ( `challenge` VARCHAR(64) NOT NULL , `expiry` BIGINT NOT NULL , PRIMARY KEY (`challenge`), INDEX `spent_challenges_expiry` (`expiry`)) ENGINE = InnoDB;"
+        ],
+        post: None
+    },
+    Migration {
+        version: 25,
+        description: "Remember used session proofs",
+        statements: &[
+            "CREATE TABLE IF NOT EXISTS `spent_proofs` ( `proof` VARCHAR(64) NOT NULL , `expiry` BIGINT NOT NULL , PRIMARY KEY (`proof`), INDEX `spent_proofs_expiry` (`expiry`)) ENGINE = InnoDB;"
+        ],
+        post: None
+    },
+    Migration {
+        version: 26,
+        description: "Add password reset links",
+        statements: &[
+            "CREATE TABLE IF NOT EXISTS `password_resets` ( `token` VARCHAR(64) NOT NULL , `user_id` VARCHAR(64) NOT NULL , `expiry` BIGINT NOT NULL , PRIMARY KEY (`token`), INDEX `password_resets_user_id` (`user_id`)) ENGINE = InnoDB;"
+        ],
+        post: None
+    },
+];
+
+/// The version the database should be at after all migrations have been applied
+pub fn latest_version() -> u32 {
+    MIGRATIONS.iter().map(|migration| migration.version).max().unwrap_or(0)
+}
+
+/// The version the database is currently at, 0 if no migrations have been applied
+pub fn current_version(conn: &mut PooledConn) -> Result<u32, ()> {
+    let sql_create_migrations_table = conn.query_drop("CREATE TABLE IF NOT EXISTS `schema_migrations` ( `version` INT UNSIGNED NOT NULL , `applied_at` BIGINT NOT NULL , PRIMARY KEY (`version`)) ENGINE = InnoDB;");
+    if sql_create_migrations_table.is_err() {
+        eprintln!("An error occurred (migrations.rs): {:?}", sql_create_migrations_table.err().unwrap());
+        return Err(());
+    }
+
+    let sql_fetch_version = conn.query_first::<Option<u32>, &str>("SELECT MAX(version) FROM schema_migrations");
+    if sql_fetch_version.is_err() {
+        eprintln!("An error occurred (migrations.rs): {:?}", sql_fetch_version.err().unwrap());
+        return Err(());
+    }
+
+    Ok(sql_fetch_version.unwrap().flatten().unwrap_or(0))
+}
+
+/// Apply all migrations newer than the database's current version, in order
+pub fn migrate(conn: &mut PooledConn, environment: &Environment) -> Result<(), ()> {
+    let current_version = current_version(conn)?;
+
+    for migration in MIGRATIONS.iter().filter(|migration| migration.version > current_version) {
+        println!("Applying migration {}: {}", migration.version, migration.description);
+
+        for statement in migration.statements {
+            let sql_statement = conn.query_drop(statement);
+            if sql_statement.is_err() {
+                eprintln!("An error occurred applying migration {} (migrations.rs): {:?}", migration.version, sql_statement.err().unwrap());
+                return Err(());
+            }
+        }
+
+        if let Some(post) = migration.post {
+            post(conn, environment)?;
+        }
+
+        let sql_record_migration = conn.exec_drop("INSERT INTO schema_migrations (version, applied_at) VALUES (:version, :applied_at)", params! {
+            "version" => migration.version,
+            "applied_at" => chrono::Utc::now().timestamp()
+        });
+
+        if sql_record_migration.is_err() {
+            eprintln!("An error occurred (migrations.rs): {:?}", sql_record_migration.err().unwrap());
+            return Err(());
+        }
+    }
+
+    Ok(())
+}
+
+/// Whether a table in the current database has a column
+fn column_exists(conn: &mut PooledConn, table: &str, column: &str) -> Result<bool, ()> {
+    let sql_check_column = conn.exec_first::<u8, &str, _>("SELECT 1 FROM information_schema.COLUMNS WHERE TABLE_SCHEMA = DATABASE() AND TABLE_NAME = :table AND COLUMN_NAME = :column", params! {
+        "table" => table,
+        "column" => column
+    });
+
+    if sql_check_column.is_err() {
+        eprintln!("An error occurred (migrations.rs): {:?}", sql_check_column.err().unwrap());
+        return Err(());
+    }
+
+    Ok(sql_check_column.unwrap().is_some())
+}
+
+/// Run an `ALTER TABLE` adding columns, unless a previous attempt at the migration already added `column`.
+/// A single `ALTER TABLE` either adds all of its columns or none of them, so checking one is enough
+fn add_columns(conn: &mut PooledConn, table: &str, column: &str, statement: &str) -> Result<(), ()> {
+    if column_exists(conn, table, column)? {
+        return Ok(());
+    }
+
+    let sql_add_columns = conn.query_drop(statement);
+    if sql_add_columns.is_err() {
+        eprintln!("An error occurred (migrations.rs): {:?}", sql_add_columns.err().unwrap());
+        return Err(());
+    }
+
+    Ok(())
+}
+
+fn add_lockout_columns(conn: &mut PooledConn, _environment: &Environment) -> Result<(), ()> {
+    add_columns(conn, "users", "failed_logins", "ALTER TABLE `users` ADD `failed_logins` INT UNSIGNED NOT NULL DEFAULT 0, ADD `last_failed_login` BIGINT NULL DEFAULT NULL, ADD `login_locked_until` BIGINT NULL DEFAULT NULL;")
+}
+
+fn add_session_binding_columns(conn: &mut PooledConn, _environment: &Environment) -> Result<(), ()> {
+    add_columns(conn, "sessions", "bound_ip_prefix", "ALTER TABLE `sessions` ADD `bound_ip_prefix` VARCHAR(64) NULL DEFAULT NULL, ADD `bound_user_agent` VARCHAR(64) NULL DEFAULT NULL, ADD `bound_key` TEXT NULL DEFAULT NULL;")
+}
+
+/// Add the blind index, unless a previous attempt at this migration already did, and encrypt all email addresses
+/// which were stored before encryption was introduced
+fn encrypt_plaintext_emails(conn: &mut PooledConn, environment: &Environment) -> Result<(), ()> {
+    if !column_exists(conn, "users", "email_index")? {
+        let sql_add_index = conn.query_drop("ALTER TABLE `users` MODIFY `email` VARCHAR(1024) NOT NULL, ADD `email_index` VARCHAR(64) NOT NULL DEFAULT '' AFTER `email`, ADD INDEX `users_email_index` (`email_index`);");
+        if sql_add_index.is_err() {
+            eprintln!("An error occurred (migrations.rs): {:?}", sql_add_index.err().unwrap());
+            return Err(());
+        }
+    }
+
+    let sql_fetch_users = conn.query::<Row, &str>("SELECT user_id, email FROM users WHERE email_index = ''");
+    if sql_fetch_users.is_err() {
+        eprintln!("An error occurred (migrations.rs): {:?}", sql_fetch_users.err().unwrap());
+        return Err(());
+    }
+
+    let users = sql_fetch_users.unwrap();
+    for row in users.iter() {
+        let user_id = row.get::<String, &str>("user_id").unwrap();
+        let email = row.get::<String, &str>("email").unwrap();
+
+        let email_encrypted = environment.email_encryption.encrypt(&email)?;
+        let email_index = environment.email_encryption.blind_index(&email);
+
+        let sql_update_user = conn.exec::<usize, &str, Params>("UPDATE users SET email = :email, email_index = :email_index WHERE user_id = :user_id", params! {
+            "email" => email_encrypted,
+            "email_index" => email_index,
+            "user_id" => user_id
+        });
+
+        if sql_update_user.is_err() {
+            eprintln!("An error occurred (migrations.rs): {:?}", sql_update_user.err().unwrap());
+            return Err(());
+        }
+    }
+
+    println!("Encrypted {} email address(es)", users.len());
+    Ok(())
+}
+
+/// Recompute the blind index of every email address from its normalized form.
+/// Fails, without changing anything, if two accounts normalize to the same address. Those have to be resolved by hand
+pub fn reindex_emails(conn: &mut PooledConn, environment: &Environment) -> Result<(), ()> {
+    let sql_fetch_users = conn.query::<Row, &str>("SELECT user_id, email FROM users");
+    if sql_fetch_users.is_err() {
+        eprintln!("An error occurred (migrations.rs): {:?}", sql_fetch_users.err().unwrap());
+        return Err(());
+    }
+
+    let mut indexes: HashMap<String, Vec<String>> = HashMap::new();
+    for row in sql_fetch_users.unwrap() {
+        let user_id = row.get::<String, &str>("user_id").unwrap();
+        let email_encrypted = row.get::<String, &str>("email").unwrap();
+
+        let email = environment.email_encryption.decrypt(&email_encrypted);
+        if email.is_err() {
+            eprintln!("Unable to decrypt the email address of user '{}' (migrations.rs)", user_id);
+            return Err(());
+        }
+        let email = email.unwrap();
+
+        let email_index = environment.email_encryption.blind_index(&email::index_form(&email, &environment.email_normalization));
+
+        indexes.entry(email_index).or_default().push(user_id);
+    }
+
+    let duplicates: Vec<&Vec<String>> = indexes.values().filter(|user_ids| user_ids.len() > 1).collect();
+    if !duplicates.is_empty() {
+        eprintln!("Found {} email address(es) shared by multiple accounts. Merge or remove these accounts before restarting:", duplicates.len());
+        for user_ids in duplicates {
+            eprintln!("\t{}", user_ids.join(", "));
+        }
+        return Err(());
+    }
+
+    for (email_index, user_ids) in indexes {
+        let sql_update_index = conn.exec_drop("UPDATE users SET email_index = :email_index WHERE user_id = :user_id", params! {
+            "email_index" => email_index,
+            "user_id" => &user_ids[0]
+        });
+
+        if sql_update_index.is_err() {
+            eprintln!("An error occurred (migrations.rs): {:?}", sql_update_index.err().unwrap());
+            return Err(());
+        }
+    }
+
+    Ok(())
+}
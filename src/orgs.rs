@@ -0,0 +1,120 @@
+use mysql::prelude::Queryable;
+use mysql::{PooledConn, params};
+use serde::Serialize;
+
+/// Matches the `org_memberships` rows of owners without a co-owner
+pub const SOLE_OWNER_CONDITION: &str = "role = 'owner' AND NOT EXISTS (SELECT 1 FROM org_memberships co_owners WHERE co_owners.org_id = org_memberships.org_id AND co_owners.role = 'owner' AND co_owners.user_id <> org_memberships.user_id)";
+
+/// The role of a member within an organization. Roles are ordered, an owner can do everything an admin can
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum OrgRole {
+    Member,
+    Admin,
+    Owner
+}
+
+impl OrgRole {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            OrgRole::Member => "member",
+            OrgRole::Admin => "admin",
+            OrgRole::Owner => "owner"
+        }
+    }
+}
+
+impl std::str::FromStr for OrgRole {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "member" => Ok(OrgRole::Member),
+            "admin" => Ok(OrgRole::Admin),
+            "owner" => Ok(OrgRole::Owner),
+            _ => Err(())
+        }
+    }
+}
+
+/// The role of a user within an organization, `None` if they are not a member
+pub fn membership(conn: &mut PooledConn, org_id: &str, user_id: &str) -> Result<Option<OrgRole>, ()> {
+    let sql_fetch_role = conn.exec_first::<String, &str, _>("SELECT role FROM org_memberships WHERE org_id = :org_id AND user_id = :user_id", params! {
+        "org_id" => org_id,
+        "user_id" => user_id
+    });
+
+    if sql_fetch_role.is_err() {
+        eprintln!("An error occurred (orgs.rs): {:?}", sql_fetch_role.err().unwrap());
+        return Err(());
+    }
+
+    Ok(sql_fetch_role.unwrap().and_then(|role| role.parse().ok()))
+}
+
+/// The organizations a user is a member of
+pub fn user_orgs(conn: &mut PooledConn, user_id: &str) -> Result<Vec<String>, ()> {
+    let sql_fetch_orgs = conn.exec::<String, &str, _>("SELECT org_id FROM org_memberships WHERE user_id = :user_id", params! {
+        "user_id" => user_id
+    });
+
+    if sql_fetch_orgs.is_err() {
+        eprintln!("An error occurred (orgs.rs): {:?}", sql_fetch_orgs.err().unwrap());
+        return Err(());
+    }
+
+    Ok(sql_fetch_orgs.unwrap())
+}
+
+/// The amount of owners an organization has
+pub fn owner_count(conn: &mut PooledConn, org_id: &str) -> Result<usize, ()> {
+    let sql_count_owners = conn.exec_first::<usize, &str, _>("SELECT COUNT(*) FROM org_memberships WHERE org_id = :org_id AND role = 'owner'", params! {
+        "org_id" => org_id
+    });
+
+    if sql_count_owners.is_err() {
+        eprintln!("An error occurred (orgs.rs): {:?}", sql_count_owners.err().unwrap());
+        return Err(());
+    }
+
+    Ok(sql_count_owners.unwrap().unwrap_or(0))
+}
+
+/// The organizations the user is the only owner of. Takes a transaction too, so it can be checked before deleting the user
+pub fn sole_owned<Q: Queryable>(conn: &mut Q, user_id: &str) -> Result<Vec<String>, ()> {
+    let sql_fetch_orgs = conn.exec::<String, String, _>(format!("SELECT org_id FROM org_memberships WHERE user_id = :user_id AND {}", SOLE_OWNER_CONDITION), params! {
+        "user_id" => user_id
+    });
+
+    if sql_fetch_orgs.is_err() {
+        eprintln!("An error occurred (orgs.rs): {:?}", sql_fetch_orgs.err().unwrap());
+        return Err(());
+    }
+
+    Ok(sql_fetch_orgs.unwrap())
+}
+
+/// Remove a user from an organization. Sessions which had it as their active organization are reset
+pub fn remove_member(conn: &mut PooledConn, org_id: &str, user_id: &str) -> Result<(), ()> {
+    let sql_delete_membership = conn.exec_drop("DELETE FROM org_memberships WHERE org_id = :org_id AND user_id = :user_id", params! {
+        "org_id" => org_id,
+        "user_id" => user_id
+    });
+
+    if sql_delete_membership.is_err() {
+        eprintln!("An error occurred (orgs.rs): {:?}", sql_delete_membership.err().unwrap());
+        return Err(());
+    }
+
+    let sql_reset_sessions = conn.exec_drop("UPDATE sessions SET active_org_id = NULL WHERE user_id = :user_id AND active_org_id = :org_id", params! {
+        "org_id" => org_id,
+        "user_id" => user_id
+    });
+
+    if sql_reset_sessions.is_err() {
+        eprintln!("An error occurred (orgs.rs): {:?}", sql_reset_sessions.err().unwrap());
+        return Err(());
+    }
+
+    Ok(())
+}
@@ -0,0 +1,48 @@
+use actix_web::HttpResponse;
+
+/// A page confirming an action from a link in a mail. Opening the link only shows the page, the action is only taken
+/// when its form is posted, so mail scanners which open every link in a mail can't take it
+pub fn confirmation(title: &str, description: &str, action: &str, token: &str, button: &str) -> HttpResponse {
+    let body = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<meta name=\"robots\" content=\"noindex\">\n<title>{title}</title>\n</head>\n<body>\n<h1>{title}</h1>\n<p>{description}</p>\n<form method=\"post\" action=\"{action}\">\n<input type=\"hidden\" name=\"token\" value=\"{token}\">\n<button type=\"submit\">{button}</button>\n</form>\n</body>\n</html>\n",
+        title =         escape(title),
+        description =   escape(description),
+        action =        escape(action),
+        token =         escape(token),
+        button =        escape(button)
+    );
+
+    //The token is in the URL, so it shouldn't be passed on to other sites
+    HttpResponse::Ok()
+        .header("Referrer-Policy", "no-referrer")
+        .content_type("text/html; charset=utf-8")
+        .body(body)
+}
+
+/// A page for choosing a new password from a link in a mail, which posts the token and the password as `new_password`
+pub fn new_password(title: &str, description: &str, action: &str, token: &str, button: &str) -> HttpResponse {
+    let body = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<meta name=\"robots\" content=\"noindex\">\n<title>{title}</title>\n</head>\n<body>\n<h1>{title}</h1>\n<p>{description}</p>\n<form method=\"post\" action=\"{action}\">\n<input type=\"hidden\" name=\"token\" value=\"{token}\">\n<label>New password <input type=\"password\" name=\"new_password\" autocomplete=\"new-password\" required></label>\n<button type=\"submit\">{button}</button>\n</form>\n</body>\n</html>\n",
+        title =         escape(title),
+        description =   escape(description),
+        action =        escape(action),
+        token =         escape(token),
+        button =        escape(button)
+    );
+
+    HttpResponse::Ok()
+        .header("Referrer-Policy", "no-referrer")
+        .content_type("text/html; charset=utf-8")
+        .body(body)
+}
+
+fn escape(text: &str) -> String {
+    text.chars().map(|c| match c {
+        '&' => "&amp;".to_string(),
+        '<' => "&lt;".to_string(),
+        '>' => "&gt;".to_string(),
+        '"' => "&quot;".to_string(),
+        '\'' => "&#39;".to_string(),
+        c => c.to_string()
+    }).collect()
+}
@@ -0,0 +1,322 @@
+use crate::crypto;
+
+use hmac::Hmac;
+use rand::Rng;
+use sha2::{Sha512Trunc256, Digest};
+use std::str::FromStr;
+
+/// Salt used to simulate verifying a password for accounts which don't exist
+const DUMMY_SALT: &str = "0000000000000000";
+
+//Imported hashes carry their own cost, which every login to the account pays. These are well above what other systems
+//use, e.g. Django's 1 million PBKDF2 iterations, so legitimate hashes are accepted and a bad import can't stall a worker
+const MAX_PBKDF2_ITERATIONS: u32 = 2_000_000;
+/// In bytes. Every block of output costs the full iterations again, SHA-512 gives 64 bytes in one block
+const MAX_PBKDF2_KEY_LENGTH: usize = 64;
+const MAX_BCRYPT_COST: u32 = 14;
+/// In KiB
+const MAX_ARGON2_MEMORY: u32 = 256 * 1024;
+const MAX_ARGON2_ITERATIONS: u32 = 10;
+const MAX_ARGON2_PARALLELISM: u32 = 16;
+
+/// Generate a new random salt for hashing a password
+pub fn generate_salt() -> String {
+    rand::thread_rng().sample_iter(&rand::distributions::Alphanumeric).take(16).map(char::from).collect()
+}
+
+/// Hash a password. The password, salt and pepper are hashed with SHA-512/256 first,
+/// after which the result is hashed again with bcrypt
+pub fn hash(password: &str, salt: &str, pepper: &str) -> String {
+    let mut hasher = Sha512Trunc256::new();
+    hasher.update(password);
+    hasher.update(salt);
+    hasher.update(pepper);
+
+    let password_hash = base64::encode(hasher.finalize());
+    let password_bcrypt = bcrypt::hash_with_salt(&password_hash, 10, salt.as_bytes()).unwrap();
+
+    password_bcrypt.format_for_version(bcrypt::Version::TwoY)
+}
+
+/// Hash a password with a fresh salt, returning the hash and the salt
+pub fn hash_with_new_salt(password: &str, pepper: &str) -> (String, String) {
+    let salt = generate_salt();
+    (hash(password, &salt, pepper), salt)
+}
+
+/// Check a password against a hash produced by `hash`
+pub fn verify(password: &str, salt: &str, pepper: &str, password_hash: &str) -> bool {
+    crypto::constant_time_eq(hash(password, salt, pepper).as_bytes(), password_hash.as_bytes())
+}
+
+/// Do the same work as `verify`, without a hash to check against. Used when there is no account to check the password of,
+/// so the response doesn't come back sooner than it would for an existing account
+pub fn simulate_verify(password: &str, pepper: &str) {
+    verify(password, DUMMY_SALT, pepper, "");
+}
+
+/// Check a password against the hash stored for a user. Users imported from another system
+/// have their original hash stored together with its `algorithm`, native hashes have none
+pub fn verify_stored(password: &str, salt: &str, pepper: &str, password_hash: &str, algorithm: Option<&str>) -> bool {
+    match algorithm {
+        None => verify(password, salt, pepper, password_hash),
+        Some(algorithm) => match algorithm.parse::<ForeignAlgorithm>() {
+            Ok(algorithm) => algorithm.verify(password, salt, password_hash),
+            Err(_) => {
+                eprintln!("Unknown password algorithm '{}' (password.rs)", algorithm);
+                false
+            }
+        }
+    }
+}
+
+/// Password hashing schemes of other systems, which users can be imported with.
+/// These are only verified on the next login, after which the password is hashed with `hash`
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ForeignAlgorithm {
+    /// A modular crypt string, e.g. `$2b$12$...`. No separate salt
+    Bcrypt,
+    /// `<iterations>$<base64 derived key>`, with a separate salt
+    Pbkdf2Sha1,
+    Pbkdf2Sha256,
+    Pbkdf2Sha512,
+    /// Hex encoded digest of the salt followed by the password
+    SaltedSha1,
+    SaltedSha256,
+    SaltedSha512,
+    /// A PHC string, e.g. `$argon2id$v=19$m=...`. No separate salt
+    Argon2
+}
+
+impl ForeignAlgorithm {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ForeignAlgorithm::Bcrypt => "bcrypt",
+            ForeignAlgorithm::Pbkdf2Sha1 => "pbkdf2-sha1",
+            ForeignAlgorithm::Pbkdf2Sha256 => "pbkdf2-sha256",
+            ForeignAlgorithm::Pbkdf2Sha512 => "pbkdf2-sha512",
+            ForeignAlgorithm::SaltedSha1 => "sha1-salted",
+            ForeignAlgorithm::SaltedSha256 => "sha256-salted",
+            ForeignAlgorithm::SaltedSha512 => "sha512-salted",
+            ForeignAlgorithm::Argon2 => "argon2"
+        }
+    }
+
+    /// Check whether a hash is well formed for this algorithm, and its cost is within bounds,
+    /// without verifying a password against it
+    pub fn is_valid_hash(&self, password_hash: &str) -> bool {
+        match self {
+            ForeignAlgorithm::Bcrypt => bcrypt::HashParts::from_str(password_hash).map(|parts| parts.get_cost() <= MAX_BCRYPT_COST).unwrap_or(false),
+            ForeignAlgorithm::Pbkdf2Sha1 | ForeignAlgorithm::Pbkdf2Sha256 | ForeignAlgorithm::Pbkdf2Sha512 => parse_pbkdf2(password_hash).is_some(),
+            ForeignAlgorithm::SaltedSha1 => is_hex_digest(password_hash, 20),
+            ForeignAlgorithm::SaltedSha256 => is_hex_digest(password_hash, 32),
+            ForeignAlgorithm::SaltedSha512 => is_hex_digest(password_hash, 64),
+            ForeignAlgorithm::Argon2 => is_valid_argon2(password_hash)
+        }
+    }
+
+    pub fn verify(&self, password: &str, salt: &str, password_hash: &str) -> bool {
+        //Hashes are checked on import, this also covers ones stored before the bounds existed
+        if !self.is_valid_hash(password_hash) {
+            return false;
+        }
+
+        match self {
+            ForeignAlgorithm::Bcrypt => bcrypt::verify(password, password_hash).unwrap_or(false),
+            ForeignAlgorithm::Pbkdf2Sha1 => verify_pbkdf2(pbkdf2::pbkdf2::<Hmac<sha1::Sha1>>, password, salt, password_hash),
+            ForeignAlgorithm::Pbkdf2Sha256 => verify_pbkdf2(pbkdf2::pbkdf2::<Hmac<sha2::Sha256>>, password, salt, password_hash),
+            ForeignAlgorithm::Pbkdf2Sha512 => verify_pbkdf2(pbkdf2::pbkdf2::<Hmac<sha2::Sha512>>, password, salt, password_hash),
+            ForeignAlgorithm::SaltedSha1 => verify_salted::<sha1::Sha1>(password, salt, password_hash),
+            ForeignAlgorithm::SaltedSha256 => verify_salted::<sha2::Sha256>(password, salt, password_hash),
+            ForeignAlgorithm::SaltedSha512 => verify_salted::<sha2::Sha512>(password, salt, password_hash),
+            ForeignAlgorithm::Argon2 => argon2::verify_encoded(password_hash, password.as_bytes()).unwrap_or(false)
+        }
+    }
+}
+
+impl std::str::FromStr for ForeignAlgorithm {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bcrypt" => Ok(ForeignAlgorithm::Bcrypt),
+            "pbkdf2-sha1" => Ok(ForeignAlgorithm::Pbkdf2Sha1),
+            "pbkdf2-sha256" => Ok(ForeignAlgorithm::Pbkdf2Sha256),
+            "pbkdf2-sha512" => Ok(ForeignAlgorithm::Pbkdf2Sha512),
+            "sha1-salted" => Ok(ForeignAlgorithm::SaltedSha1),
+            "sha256-salted" => Ok(ForeignAlgorithm::SaltedSha256),
+            "sha512-salted" => Ok(ForeignAlgorithm::SaltedSha512),
+            "argon2" => Ok(ForeignAlgorithm::Argon2),
+            _ => Err(())
+        }
+    }
+}
+
+fn is_hex_digest(password_hash: &str, length: usize) -> bool {
+    password_hash.len() == length * 2 && password_hash.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Split a PBKDF2 hash into its iterations and derived key
+fn parse_pbkdf2(password_hash: &str) -> Option<(u32, Vec<u8>)> {
+    let (iterations, key) = password_hash.split_once('$')?;
+    let iterations = iterations.parse::<u32>().ok().filter(|iterations| (1..=MAX_PBKDF2_ITERATIONS).contains(iterations))?;
+    let key = base64::decode(key).ok().filter(|key| !key.is_empty() && key.len() <= MAX_PBKDF2_KEY_LENGTH)?;
+
+    Some((iterations, key))
+}
+
+/// Check the parameters of a PHC string like `$argon2id$v=19$m=65536,t=3,p=4$<salt>$<hash>`
+fn is_valid_argon2(password_hash: &str) -> bool {
+    let parts: Vec<&str> = password_hash.split('$').collect();
+    if parts.len() != 6 || !matches!(parts[1], "argon2d" | "argon2i" | "argon2id") {
+        return false;
+    }
+
+    let mut memory = None;
+    let mut iterations = None;
+    let mut parallelism = None;
+    for param in parts[3].split(',') {
+        let (name, value) = match param.split_once('=') {
+            Some((name, value)) => (name, value.parse::<u32>().ok()),
+            None => return false
+        };
+
+        match name {
+            "m" => memory = value,
+            "t" => iterations = value,
+            "p" => parallelism = value,
+            _ => return false
+        }
+    }
+
+    matches!(memory, Some(1..=MAX_ARGON2_MEMORY)) && matches!(iterations, Some(1..=MAX_ARGON2_ITERATIONS)) && matches!(parallelism, Some(1..=MAX_ARGON2_PARALLELISM))
+}
+
+/// The PBKDF2 function for a specific HMAC, e.g. `pbkdf2::pbkdf2::<Hmac<Sha256>>`
+type Pbkdf2Fn = fn(&[u8], &[u8], u32, &mut [u8]);
+
+fn verify_pbkdf2(derive: Pbkdf2Fn, password: &str, salt: &str, password_hash: &str) -> bool {
+    let (iterations, key) = match parse_pbkdf2(password_hash) {
+        Some(parsed) => parsed,
+        None => return false
+    };
+
+    let mut derived = vec![0u8; key.len()];
+    derive(password.as_bytes(), salt.as_bytes(), iterations, &mut derived);
+
+    crypto::constant_time_eq(&derived, &key)
+}
+
+fn verify_salted<D: Digest>(password: &str, salt: &str, password_hash: &str) -> bool {
+    let mut hasher = D::new();
+    hasher.update(salt);
+    hasher.update(password);
+
+    let digest: String = hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect();
+    crypto::constant_time_eq(digest.as_bytes(), password_hash.to_ascii_lowercase().as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PEPPER: &str = "pepper";
+
+    fn check(algorithm: ForeignAlgorithm, salt: &str, password_hash: &str) {
+        assert!(algorithm.is_valid_hash(password_hash), "{} hash should be valid", algorithm.as_str());
+        assert!(algorithm.verify("password", salt, password_hash), "{} should accept the password", algorithm.as_str());
+        assert!(!algorithm.verify("Password", salt, password_hash), "{} should refuse another password", algorithm.as_str());
+    }
+
+    #[test]
+    fn parses_algorithm_names() {
+        for algorithm in [ForeignAlgorithm::Bcrypt, ForeignAlgorithm::Pbkdf2Sha1, ForeignAlgorithm::Pbkdf2Sha256, ForeignAlgorithm::Pbkdf2Sha512,
+            ForeignAlgorithm::SaltedSha1, ForeignAlgorithm::SaltedSha256, ForeignAlgorithm::SaltedSha512, ForeignAlgorithm::Argon2].iter() {
+            assert_eq!(algorithm.as_str().parse::<ForeignAlgorithm>(), Ok(*algorithm));
+        }
+
+        assert!("md5".parse::<ForeignAlgorithm>().is_err());
+    }
+
+    #[test]
+    fn verifies_bcrypt() {
+        let password_hash = bcrypt::hash("password", 4).unwrap();
+        check(ForeignAlgorithm::Bcrypt, "", &password_hash);
+
+        //From the OpenBSD test vectors
+        assert!(ForeignAlgorithm::Bcrypt.verify("U*U", "", "$2a$05$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW"));
+    }
+
+    #[test]
+    fn verifies_pbkdf2() {
+        check(ForeignAlgorithm::Pbkdf2Sha1, "salt", "4096$SwB5AbdlSJq+rUnZJvch0GWkKcE=");
+        check(ForeignAlgorithm::Pbkdf2Sha256, "salt", "4096$xeR41ZKIyEGqUw22hFxMjZYok6ABzk4RpJY4c6qYE0o=");
+        check(ForeignAlgorithm::Pbkdf2Sha512, "salt", "4096$0Zexsz2wFD4BixLz0dFHnmzevcyXxcD4f2kC4HL0V7UUPzBgJkGz1VzTNZiMs2uEN2Bg7NUy4Dm3QqI5Q0ry1Q==");
+    }
+
+    #[test]
+    fn verifies_salted_digests() {
+        check(ForeignAlgorithm::SaltedSha1, "salt", "59b3e8d637cf97edbe2384cf59cb7453dfe30789");
+        check(ForeignAlgorithm::SaltedSha256, "salt", "13601bda4ea78e55a07b98866d2be6be0744e3866f13c00c811cab608a28f322");
+        check(ForeignAlgorithm::SaltedSha512, "salt", "2908d2c28dfc047741fc590a026ffade237ab2ba7e1266f010fe49bde548b5987a534a86655a0d17f336588e540cd66f67234b152bbb645b4bb85758a1325d64");
+
+        //Some systems store their digests in uppercase
+        assert!(ForeignAlgorithm::SaltedSha1.verify("password", "salt", "59B3E8D637CF97EDBE2384CF59CB7453DFE30789"));
+    }
+
+    #[test]
+    fn verifies_argon2() {
+        let config = argon2::Config { mem_cost: 1024, time_cost: 1, ..argon2::Config::default() };
+        let password_hash = argon2::hash_encoded(b"password", b"saltsaltsalt", &config).unwrap();
+        check(ForeignAlgorithm::Argon2, "", &password_hash);
+    }
+
+    #[test]
+    fn rejects_malformed_hashes() {
+        assert!(!ForeignAlgorithm::Bcrypt.is_valid_hash("$2b$"));
+        assert!(!ForeignAlgorithm::Pbkdf2Sha256.is_valid_hash("xeR41ZKIyEGqUw22hFxMjZYok6ABzk4RpJY4c6qYE0o="));
+        assert!(!ForeignAlgorithm::Pbkdf2Sha256.is_valid_hash("0$xeR41ZKIyEGqUw22hFxMjZYok6ABzk4RpJY4c6qYE0o="));
+        assert!(!ForeignAlgorithm::Pbkdf2Sha256.is_valid_hash("4096$"));
+        assert!(!ForeignAlgorithm::SaltedSha1.is_valid_hash("59b3e8d637cf97edbe2384cf59cb7453dfe3078"));
+        assert!(!ForeignAlgorithm::SaltedSha256.is_valid_hash("59b3e8d637cf97edbe2384cf59cb7453dfe30789"));
+        assert!(!ForeignAlgorithm::Argon2.is_valid_hash("$argon2id$v=19$m=1024,t=1$c2FsdA$aGFzaA"));
+        assert!(!ForeignAlgorithm::Argon2.is_valid_hash("$scrypt$v=19$m=1024,t=1,p=1$c2FsdA$aGFzaA"));
+    }
+
+    #[test]
+    fn rejects_hashes_which_cost_too_much() {
+        assert!(!ForeignAlgorithm::Pbkdf2Sha1.is_valid_hash("4294967295$SwB5AbdlSJq+rUnZJvch0GWkKcE="));
+        assert!(!ForeignAlgorithm::Pbkdf2Sha1.verify("password", "salt", "4294967295$SwB5AbdlSJq+rUnZJvch0GWkKcE="));
+        assert!(ForeignAlgorithm::Pbkdf2Sha1.is_valid_hash(&format!("{}$SwB5AbdlSJq+rUnZJvch0GWkKcE=", MAX_PBKDF2_ITERATIONS)));
+
+        let long_key = base64::encode(vec![0u8; MAX_PBKDF2_KEY_LENGTH + 1]);
+        assert!(!ForeignAlgorithm::Pbkdf2Sha512.is_valid_hash(&format!("1000${}", long_key)));
+
+        assert!(!ForeignAlgorithm::Bcrypt.is_valid_hash("$2a$31$CCCCCCCCCCCCCCCCCCCCC.E5YPO9kmyuRGyh0XouQYb4YMJKvyOeW"));
+
+        assert!(!ForeignAlgorithm::Argon2.is_valid_hash("$argon2id$v=19$m=4194304,t=1,p=1$c2FsdHNhbHQ$aGFzaGhhc2g"));
+        assert!(!ForeignAlgorithm::Argon2.is_valid_hash("$argon2id$v=19$m=1024,t=1000,p=1$c2FsdHNhbHQ$aGFzaGhhc2g"));
+        assert!(ForeignAlgorithm::Argon2.is_valid_hash("$argon2id$v=19$m=65536,t=3,p=4$c2FsdHNhbHQ$aGFzaGhhc2g"));
+    }
+
+    #[test]
+    fn refuses_unknown_algorithms() {
+        assert!(!verify_stored("password", "salt", PEPPER, "59b3e8d637cf97edbe2384cf59cb7453dfe30789", Some("md5")));
+    }
+
+    #[test]
+    fn rehashes_imported_passwords_into_the_native_scheme() {
+        //What a login does with an imported hash: verify it with its algorithm, then store a native hash without one
+        let imported = "4096$xeR41ZKIyEGqUw22hFxMjZYok6ABzk4RpJY4c6qYE0o=";
+        assert!(verify_stored("password", "salt", PEPPER, imported, Some("pbkdf2-sha256")));
+
+        let (password_hash, salt) = hash_with_new_salt("password", PEPPER);
+        assert_ne!(salt, "salt");
+        assert!(verify_stored("password", &salt, PEPPER, &password_hash, None));
+        assert!(!verify_stored("Password", &salt, PEPPER, &password_hash, None));
+        assert!(!verify_stored("password", &salt, "another pepper", &password_hash, None));
+
+        //The native hash can't be mistaken for the imported one
+        assert!(!verify_stored("password", &salt, PEPPER, &password_hash, Some("pbkdf2-sha256")));
+    }
+}
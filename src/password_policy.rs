@@ -0,0 +1,487 @@
+use crate::appdata::{AppData, Environment};
+use crate::{password, users};
+
+use std::collections::HashSet;
+use std::sync::Arc;
+use actix_web::web;
+use mysql::prelude::Queryable;
+use mysql::{PooledConn, Row, params};
+use serde::{Deserialize, Serialize};
+
+/// Passwords every guesser tries first. The configured blocklist is added to these for the entropy estimate
+const COMMON_PASSWORDS: &[&str] = &[
+    "password", "passw0rd", "123456", "12345678", "123456789", "qwerty", "abc123", "letmein", "welcome", "monkey",
+    "dragon", "master", "login", "admin", "princess", "sunshine", "shadow", "football", "baseball", "iloveyou",
+    "trustno1", "superman", "batman", "starwars", "hello", "freedom", "whatever", "secret", "summer", "winter",
+    "spring", "autumn", "michael", "charlie", "jordan", "hunter", "killer", "soccer", "computer", "internet"
+];
+
+/// Rows of the keyboard, for spotting walks like 'qwerty' and 'asdf'
+const KEYBOARD_ROWS: &[&str] = &["`1234567890-=", "qwertyuiop[]\\", "asdfghjkl;'", "zxcvbnm,./"];
+
+/// Substrings longer than this aren't looked up in the dictionary, or taken as a single pattern. Longer repeats, sequences
+/// and walks count as several patterns, which keeps the estimate linear in the length of the password
+const MAX_PATTERN_LENGTH: usize = 32;
+
+/// Only this many characters of a password are estimated, however long `max_length` allows passwords to be.
+/// The characters after them could only add to the estimate
+const MAX_ESTIMATED_LENGTH: usize = 256;
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct PasswordPolicy {
+    pub min_length:                 usize,
+    pub max_length:                 usize,
+    pub require_lowercase:          bool,
+    pub require_uppercase:          bool,
+    pub require_digit:              bool,
+    pub require_symbol:             bool,
+    /// The estimated amount of guesses needed to find the password, as bits. 0 disables the estimate
+    pub min_entropy_bits:           f64,
+    /// Refuse passwords containing the local part of the account's email address
+    pub forbid_email_local_part:    bool,
+    /// File with a blocklisted password on every line, compared case insensitively
+    pub blocklist_file:             Option<String>,
+    /// Refuse the current and this many previous passwords when a password is changed. 0 disables the history
+    pub history:                    usize,
+
+    #[serde(skip)]
+    blocklist:                      Arc<HashSet<String>>
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        PasswordPolicy {
+            min_length:                 8,
+            max_length:                 128,
+            require_lowercase:          false,
+            require_uppercase:          false,
+            require_digit:              false,
+            require_symbol:             false,
+            min_entropy_bits:           30.0,
+            forbid_email_local_part:    true,
+            blocklist_file:             None,
+            history:                    5,
+            blocklist:                  Arc::new(HashSet::new())
+        }
+    }
+}
+
+/// Why a password was refused
+#[derive(Serialize, Clone, PartialEq, Debug)]
+#[serde(tag = "reason", rename_all = "snake_case")]
+pub enum Violation {
+    TooShort { min_length: usize },
+    TooLong { max_length: usize },
+    MissingLowercase,
+    MissingUppercase,
+    MissingDigit,
+    MissingSymbol,
+    TooGuessable { entropy_bits: f64, min_entropy_bits: f64 },
+    ContainsEmail,
+    Blocklisted,
+    Breached,
+    Reused
+}
+
+impl Violation {
+    pub fn message(&self) -> &'static str {
+        match self {
+            Violation::TooShort { .. } => "The password is too short.",
+            Violation::TooLong { .. } => "The password is too long.",
+            Violation::MissingLowercase => "The password must contain a lowercase letter.",
+            Violation::MissingUppercase => "The password must contain an uppercase letter.",
+            Violation::MissingDigit => "The password must contain a digit.",
+            Violation::MissingSymbol => "The password must contain a symbol.",
+            Violation::TooGuessable { .. } => "The password is too easy to guess.",
+            Violation::ContainsEmail => "The password can't contain your email address.",
+            Violation::Blocklisted => "This password is too common, and can't be used.",
+            Violation::Breached => crate::breach::BREACHED_PASSWORD,
+            Violation::Reused => "This password has been used before. Choose a different password."
+        }
+    }
+}
+
+impl PasswordPolicy {
+    pub fn from_vars() -> PasswordPolicy {
+        let default = PasswordPolicy::default();
+        let flag = |name: &str, default: bool| std::env::var(name).map(|value| value == "TRUE").unwrap_or(default);
+
+        PasswordPolicy {
+            min_length:                 Environment::optional_var("PASSWORD_MIN_LENGTH", default.min_length),
+            max_length:                 Environment::optional_var("PASSWORD_MAX_LENGTH", default.max_length),
+            require_lowercase:          flag("PASSWORD_REQUIRE_LOWERCASE", default.require_lowercase),
+            require_uppercase:          flag("PASSWORD_REQUIRE_UPPERCASE", default.require_uppercase),
+            require_digit:              flag("PASSWORD_REQUIRE_DIGIT", default.require_digit),
+            require_symbol:             flag("PASSWORD_REQUIRE_SYMBOL", default.require_symbol),
+            min_entropy_bits:           Environment::optional_var("PASSWORD_MIN_ENTROPY_BITS", default.min_entropy_bits),
+            forbid_email_local_part:    flag("PASSWORD_FORBID_EMAIL_LOCAL_PART", default.forbid_email_local_part),
+            blocklist_file:             std::env::var("PASSWORD_BLOCKLIST_FILE").ok(),
+            history:                    Environment::optional_var("PASSWORD_HISTORY", default.history),
+            blocklist:                  default.blocklist
+        }
+    }
+
+    /// Read the blocklist file, if one is configured
+    pub fn load_blocklist(&mut self) -> Result<(), String> {
+        let path = match &self.blocklist_file {
+            Some(path) => path,
+            None => return Ok(())
+        };
+
+        let contents = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let blocklist = contents.lines()
+            .map(|line| line.trim().to_lowercase())
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .collect();
+
+        self.blocklist = Arc::new(blocklist);
+        Ok(())
+    }
+
+    /// Check a password against every rule which doesn't need the account's password history.
+    /// The breach corpus is only consulted if the password passes everything else
+    pub fn check(&self, environment: &Environment, new_password: &str, email: Option<&str>) -> Vec<Violation> {
+        let mut violations = Vec::new();
+
+        let length = new_password.chars().count();
+        if length < self.min_length {
+            violations.push(Violation::TooShort { min_length: self.min_length });
+        }
+
+        if length > self.max_length {
+            violations.push(Violation::TooLong { max_length: self.max_length });
+        }
+
+        let classes = [
+            (self.require_lowercase, new_password.chars().any(char::is_lowercase), Violation::MissingLowercase),
+            (self.require_uppercase, new_password.chars().any(char::is_uppercase), Violation::MissingUppercase),
+            (self.require_digit, new_password.chars().any(|c| c.is_ascii_digit()), Violation::MissingDigit),
+            (self.require_symbol, new_password.chars().any(|c| !c.is_alphanumeric()), Violation::MissingSymbol)
+        ];
+
+        for (required, present, violation) in classes {
+            if required && !present {
+                violations.push(violation);
+            }
+        }
+
+        let lowercase = new_password.to_lowercase();
+        let local_part = email.and_then(|email| email.rsplit_once('@')).map(|(local_part, _)| local_part.to_lowercase());
+        if let Some(local_part) = local_part.filter(|local_part| self.forbid_email_local_part && local_part.chars().count() >= 3) {
+            if lowercase.contains(&local_part) {
+                violations.push(Violation::ContainsEmail);
+            }
+        }
+
+        if self.blocklist.contains(&lowercase) || COMMON_PASSWORDS.contains(&lowercase.as_str()) {
+            violations.push(Violation::Blocklisted);
+        }
+
+        //Passwords over the maximum length are refused anyway, there is no need to estimate them
+        if self.min_entropy_bits > 0.0 && length <= self.max_length {
+            let entropy_bits = estimate_entropy(new_password, &self.blocklist);
+            if entropy_bits < self.min_entropy_bits {
+                violations.push(Violation::TooGuessable { entropy_bits: entropy_bits.floor(), min_entropy_bits: self.min_entropy_bits });
+            }
+        }
+
+        if violations.is_empty() && environment.breach.is_breached(new_password) {
+            violations.push(Violation::Breached);
+        }
+
+        violations
+    }
+
+    /// Check whether a password is the current password of a user, or one of their last `history` passwords
+    pub fn is_reused(&self, conn: &mut PooledConn, environment: &Environment, user_id: &str, new_password: &str) -> Result<bool, ()> {
+        if self.history == 0 {
+            return Ok(false);
+        }
+
+        let sql_fetch_passwords = conn.exec::<Row, String, _>(format!("(SELECT password, salt, password_algorithm FROM users WHERE user_id = :user_id) UNION ALL (SELECT password, salt, password_algorithm FROM password_history WHERE user_id = :user_id ORDER BY created_at DESC LIMIT {})", self.history), params! {
+            "user_id" => user_id
+        });
+
+        if sql_fetch_passwords.is_err() {
+            eprintln!("An error occurred (password_policy.rs): {:?}", sql_fetch_passwords.err().unwrap());
+            return Err(());
+        }
+
+        let stored: Vec<StoredPassword> = sql_fetch_passwords.unwrap().iter()
+            .map(|row| (row.get::<String, &str>("password").unwrap(), row.get::<String, &str>("salt").unwrap(), row.get::<Option<String>, &str>("password_algorithm").unwrap()))
+            .collect();
+
+        Ok(matches_any(new_password, &environment.password_pepper, &stored))
+    }
+}
+
+/// A password hash, its salt and its algorithm, as stored in `users` and `password_history`
+type StoredPassword = (String, String, Option<String>);
+
+/// Whether a password matches any of the stored passwords
+fn matches_any(new_password: &str, pepper: &str, stored: &[StoredPassword]) -> bool {
+    stored.iter().any(|(password_hash, salt, algorithm)| password::verify_stored(new_password, salt, pepper, password_hash, algorithm.as_deref()))
+}
+
+/// Run `PasswordPolicy::check` on the thread pool for blocking work, as the breach corpus may be an HTTP API
+/// which would otherwise hold up the server's thread while it answers
+pub async fn check_in_background(data: &web::Data<AppData>, new_password: &str, email: Option<&str>) -> Result<Vec<Violation>, ()> {
+    let data = data.clone();
+    let new_password = new_password.to_string();
+    let email = email.map(str::to_string);
+
+    let violations = web::block(move || Ok::<_, ()>(data.environment.password_policy.check(&data.environment, &new_password, email.as_deref()))).await;
+    if violations.is_err() {
+        eprintln!("An error occurred (password_policy.rs): {:?}", violations.err().unwrap());
+        return Err(());
+    }
+
+    Ok(violations.unwrap())
+}
+
+/// Check a new password for an existing user, including their password history
+pub async fn check_for_user(conn: &mut PooledConn, data: &web::Data<AppData>, user_id: &str, new_password: &str) -> Result<Vec<Violation>, ()> {
+    let email = users::fetch_email(conn, &data.environment.email_encryption, user_id)?;
+    let mut violations = check_in_background(data, new_password, Some(&email)).await?;
+
+    if data.environment.password_policy.is_reused(conn, &data.environment, user_id, new_password)? {
+        violations.push(Violation::Reused);
+    }
+
+    Ok(violations)
+}
+
+/// Keep the current password of a user in their history before it is replaced, dropping entries which are no longer needed
+pub fn remember_password(conn: &mut PooledConn, environment: &Environment, user_id: &str) -> Result<(), ()> {
+    let history = environment.password_policy.history;
+    if history == 0 {
+        return Ok(());
+    }
+
+    let sql_insert_history = conn.exec_drop("INSERT INTO password_history (user_id, password, salt, password_algorithm, created_at) SELECT user_id, password, salt, password_algorithm, :now FROM users WHERE user_id = :user_id", params! {
+        "now" => chrono::Utc::now().timestamp(),
+        "user_id" => user_id
+    });
+
+    if sql_insert_history.is_err() {
+        eprintln!("An error occurred (password_policy.rs): {:?}", sql_insert_history.err().unwrap());
+        return Err(());
+    }
+
+    //MySQL doesn't allow a LIMIT in a subquery of a DELETE, so find the oldest entry to keep first
+    let sql_fetch_oldest = conn.exec_first::<i64, String, _>(format!("SELECT created_at FROM password_history WHERE user_id = :user_id ORDER BY created_at DESC LIMIT 1 OFFSET {}", history - 1), params! {
+        "user_id" => user_id
+    });
+
+    let oldest = match sql_fetch_oldest {
+        Ok(Some(oldest)) => oldest,
+        Ok(None) => return Ok(()),
+        Err(e) => {
+            eprintln!("An error occurred (password_policy.rs): {:?}", e);
+            return Err(());
+        }
+    };
+
+    let sql_delete_history = conn.exec_drop("DELETE FROM password_history WHERE user_id = :user_id AND created_at < :oldest", params! {
+        "user_id" => user_id,
+        "oldest" => oldest
+    });
+
+    if sql_delete_history.is_err() {
+        eprintln!("An error occurred (password_policy.rs): {:?}", sql_delete_history.err().unwrap());
+        return Err(());
+    }
+
+    Ok(())
+}
+
+/// A class of characters, and how many characters it has
+type CharacterClass = (fn(&char) -> bool, f64);
+
+/// The amount of symbols a guesser has to consider per character, going by the classes of characters the password uses
+fn cardinality(password: &[char]) -> f64 {
+    let classes: [CharacterClass; 5] = [
+        (char::is_ascii_lowercase, 26.0),
+        (char::is_ascii_uppercase, 26.0),
+        (char::is_ascii_digit, 10.0),
+        (|c| c.is_ascii() && !c.is_ascii_alphanumeric(), 33.0),
+        (|c| !c.is_ascii(), 100.0)
+    ];
+
+    classes.iter()
+        .filter(|(is_member, _)| password.iter().any(is_member))
+        .map(|(_, size)| size)
+        .sum()
+}
+
+/// Whether each character follows the previous one on the same row of the keyboard, in either direction
+fn is_keyboard_walk(chars: &[char]) -> bool {
+    chars.windows(2).all(|pair| {
+        let (a, b) = (pair[0].to_ascii_lowercase(), pair[1].to_ascii_lowercase());
+        KEYBOARD_ROWS.iter().any(|row| {
+            let row: Vec<char> = row.chars().collect();
+            row.windows(2).any(|keys| (keys[0] == a && keys[1] == b) || (keys[0] == b && keys[1] == a))
+        })
+    })
+}
+
+/// The bits needed to guess `chars` as a single pattern, if it is one: a repeated character, a sequence like 'abc' or '987',
+/// a keyboard walk or a dictionary word
+fn pattern_bits(chars: &[char], dictionary: &HashSet<String>, charset_bits: f64) -> Option<f64> {
+    let length = chars.len();
+    let length_bits = (length as f64).log2();
+
+    //A substring can match several patterns, e.g. '12345678' is a sequence and a common password, the cheapest one counts
+    let mut candidates = Vec::new();
+
+    if length >= 3 && chars.iter().all(|c| *c == chars[0]) {
+        candidates.push(charset_bits + length_bits);
+    }
+
+    let steps: Vec<i64> = chars.windows(2).map(|pair| pair[1] as i64 - pair[0] as i64).collect();
+    if length >= 3 && (steps.iter().all(|step| *step == 1) || steps.iter().all(|step| *step == -1)) {
+        //Which sequence, where it starts, its length and its direction
+        candidates.push(charset_bits + length_bits + 1.0);
+    }
+
+    if length >= 4 && is_keyboard_walk(chars) {
+        candidates.push((KEYBOARD_ROWS.iter().map(|row| row.len()).sum::<usize>() as f64).log2() + length_bits + 1.0);
+    }
+
+    if length >= 3 {
+        let word: String = chars.iter().collect::<String>().to_lowercase();
+        if dictionary.contains(&word) || COMMON_PASSWORDS.contains(&word.as_str()) {
+            //Which word, and whether it was capitalized
+            let capitalized = if chars.iter().any(|c| c.is_uppercase()) { 1.0 } else { 0.0 };
+            candidates.push(((dictionary.len() + COMMON_PASSWORDS.len()) as f64).log2() + capitalized);
+        }
+    }
+
+    candidates.into_iter().reduce(f64::min)
+}
+
+/// Estimate how many bits of guessing a password takes, in the style of zxcvbn: the password is split into the cheapest
+/// sequence of patterns and single characters, each single character costing the bits of the password's character classes
+pub fn estimate_entropy(password: &str, dictionary: &HashSet<String>) -> f64 {
+    let chars: Vec<char> = password.chars().take(MAX_ESTIMATED_LENGTH).collect();
+    let charset_bits = cardinality(&chars).max(1.0).log2();
+
+    //cheapest[i] is the fewest bits needed to guess the first i characters
+    let mut cheapest = vec![f64::INFINITY; chars.len() + 1];
+    cheapest[0] = 0.0;
+
+    for end in 1..=chars.len() {
+        for start in end.saturating_sub(MAX_PATTERN_LENGTH)..end {
+            let bits = if end - start == 1 {
+                Some(charset_bits)
+            } else {
+                pattern_bits(&chars[start..end], dictionary, charset_bits)
+            };
+
+            if let Some(bits) = bits {
+                cheapest[end] = cheapest[end].min(cheapest[start] + bits);
+            }
+        }
+    }
+
+    cheapest[chars.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_bits(password: &str, dictionary: &HashSet<String>, expected: f64) {
+        let bits = estimate_entropy(password, dictionary);
+        assert!((bits - expected).abs() < 0.01, "'{}' was estimated at {} bits, expected {}", password, bits, expected);
+    }
+
+    #[test]
+    fn scores_common_passwords_by_their_rank() {
+        let dictionary = HashSet::new();
+        let common = (COMMON_PASSWORDS.len() as f64).log2();
+
+        assert_bits("password", &dictionary, common);
+        assert_bits("12345678", &dictionary, common);
+        //One more bit for the capital
+        assert_bits("Password", &dictionary, common + 1.0);
+    }
+
+    #[test]
+    fn scores_repeats_and_sequences() {
+        let dictionary = HashSet::new();
+
+        //The character, and the length
+        assert_bits("aaaaaaaa", &dictionary, 26f64.log2() + 3.0);
+        //The start, the length and the direction
+        assert_bits("abcdefgh", &dictionary, 26f64.log2() + 3.0 + 1.0);
+        assert_bits("87654321", &dictionary, 10f64.log2() + 3.0 + 1.0);
+    }
+
+    #[test]
+    fn scores_keyboard_walks() {
+        let dictionary = HashSet::new();
+        let keys = (KEYBOARD_ROWS.iter().map(|row| row.len()).sum::<usize>() as f64).log2();
+
+        assert!(is_keyboard_walk(&"qwertyui".chars().collect::<Vec<char>>()));
+        assert!(is_keyboard_walk(&"LKJHG".chars().collect::<Vec<char>>()));
+        assert!(!is_keyboard_walk(&"qwas".chars().collect::<Vec<char>>()));
+
+        assert_bits("qwertyui", &dictionary, keys + 3.0 + 1.0);
+    }
+
+    #[test]
+    fn scores_dictionary_words_in_a_password() {
+        let dictionary: HashSet<String> = ["tortoise".to_string()].iter().cloned().collect();
+        let words = ((COMMON_PASSWORDS.len() + 1) as f64).log2();
+
+        assert_bits("Tortoise", &dictionary, words + 1.0);
+        //The word, then a sequence over lowercase letters and digits
+        assert_bits("tortoise123", &dictionary, words + 36f64.log2() + 3f64.log2() + 1.0);
+        //Without the dictionary every character counts
+        assert_bits("tortoise", &HashSet::new(), 8.0 * 26f64.log2());
+    }
+
+    #[test]
+    fn scores_random_passwords_by_their_character_classes() {
+        let dictionary = HashSet::new();
+
+        assert_bits("xK9#mQ2!vL", &dictionary, 10.0 * 95f64.log2());
+        assert!(estimate_entropy("correct horse battery staple", &dictionary) > 100.0);
+    }
+
+    #[test]
+    fn estimates_long_passwords_quickly() {
+        //Would take minutes if every substring of the whole password was considered
+        let password = "a1b2c3d4e5".repeat(100_000);
+        let started = std::time::Instant::now();
+
+        assert_bits(&password, &HashSet::new(), MAX_ESTIMATED_LENGTH as f64 * 36f64.log2());
+        assert!(started.elapsed() < std::time::Duration::from_secs(5));
+    }
+
+    #[test]
+    fn splits_long_patterns() {
+        //A repeat longer than a single pattern is a few patterns, not a single character each
+        let repeated = "a".repeat(4 * MAX_PATTERN_LENGTH);
+        assert_bits(&repeated, &HashSet::new(), 4.0 * (26f64.log2() + (MAX_PATTERN_LENGTH as f64).log2()));
+    }
+
+    #[test]
+    fn matches_reused_passwords() {
+        let pepper = "pepper";
+        let (current_hash, current_salt) = password::hash_with_new_salt("current password", pepper);
+        let stored = vec![
+            (current_hash, current_salt, None),
+            ("4096$xeR41ZKIyEGqUw22hFxMjZYok6ABzk4RpJY4c6qYE0o=".to_string(), "salt".to_string(), Some("pbkdf2-sha256".to_string()))
+        ];
+
+        assert!(matches_any("current password", pepper, &stored));
+        //An imported password in the history
+        assert!(matches_any("password", pepper, &stored));
+        assert!(!matches_any("a new password", pepper, &stored));
+        assert!(!matches_any("current password", "another pepper", &stored[..1]));
+        assert!(!matches_any("current password", pepper, &[]));
+    }
+}
@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use regex::Regex;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+/// Maximum size of the serialized attributes bag, in bytes
+const MAX_ATTRIBUTES_SIZE: usize = 16 * 1024;
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AttributeType {
+    String,
+    Number,
+    Boolean,
+    /// Any JSON value, including objects and arrays
+    Any
+}
+
+/// Server-side schema of a single custom attribute
+#[derive(Deserialize, Serialize, Clone)]
+pub struct AttributeSchema {
+    #[serde(rename = "type")]
+    pub kind:           AttributeType,
+    /// Include the attribute in the `/auth/session` response
+    #[serde(default)]
+    pub in_session:     bool,
+    /// Maximum length of string attributes, in characters
+    #[serde(default)]
+    pub max_length:     Option<usize>
+}
+
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct ProfileConfig {
+    /// Custom attributes users are allowed to set, by key. Keys not listed here are rejected
+    pub attributes:     HashMap<String, AttributeSchema>
+}
+
+impl ProfileConfig {
+    pub fn from_vars() -> ProfileConfig {
+        //PROFILE_ATTRIBUTES holds the attribute schema as JSON, e.g. '{"company": {"type": "string", "in_session": true}}'
+        let attributes_var = std::env::var("PROFILE_ATTRIBUTES");
+        if attributes_var.is_err() {
+            return ProfileConfig::default();
+        }
+
+        let attributes = serde_json::from_str(&attributes_var.unwrap());
+        if attributes.is_err() {
+            eprintln!("Environmental variable 'PROFILE_ATTRIBUTES' is not a valid attribute schema: {:?}. Exiting", attributes.err().unwrap());
+            std::process::exit(1);
+        }
+
+        ProfileConfig {
+            attributes: attributes.unwrap()
+        }
+    }
+
+    /// Validate a value against the schema of its attribute
+    pub fn validate_attribute(&self, key: &str, value: &Value) -> Result<(), String> {
+        let schema = match self.attributes.get(key) {
+            Some(schema) => schema,
+            None => return Err(format!("Unknown attribute '{}'.", key))
+        };
+
+        let type_matches = match schema.kind {
+            AttributeType::String => value.is_string(),
+            AttributeType::Number => value.is_number(),
+            AttributeType::Boolean => value.is_boolean(),
+            AttributeType::Any => true
+        };
+
+        if !type_matches {
+            return Err(format!("Attribute '{}' has the wrong type.", key));
+        }
+
+        if let (Some(max_length), Some(value)) = (schema.max_length, value.as_str()) {
+            if value.chars().count() > max_length {
+                return Err(format!("Attribute '{}' may be at most {} characters long.", key, max_length));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Validate the complete attributes bag
+    pub fn validate_attributes(&self, attributes: &Map<String, Value>) -> Result<(), String> {
+        for (key, value) in attributes.iter() {
+            self.validate_attribute(key, value)?;
+        }
+
+        if Value::Object(attributes.clone()).to_string().len() > MAX_ATTRIBUTES_SIZE {
+            return Err(format!("Attributes may be at most {} bytes.", MAX_ATTRIBUTES_SIZE));
+        }
+
+        Ok(())
+    }
+
+    /// The attributes which should be included in the `/auth/session` response
+    pub fn session_attributes(&self, attributes: &Map<String, Value>) -> Map<String, Value> {
+        attributes.iter()
+            .filter(|(key, _)| self.attributes.get(*key).map(|schema| schema.in_session).unwrap_or(false))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+}
+
+/// Parse the attributes column, an absent or invalid value is treated as an empty bag
+pub fn parse_attributes(attributes: Option<String>) -> Map<String, Value> {
+    match attributes.map(|attributes| serde_json::from_str::<Value>(&attributes)) {
+        Some(Ok(Value::Object(attributes))) => attributes,
+        _ => Map::new()
+    }
+}
+
+pub fn validate_display_name(display_name: &str) -> Result<(), &'static str> {
+    if display_name.trim().is_empty() || display_name.chars().count() > 64 || display_name.chars().any(char::is_control) {
+        return Err("Display name must be 1 to 64 characters long, and may not contain control characters.");
+    }
+
+    Ok(())
+}
+
+/// Check that the locale is a BCP 47 language tag, e.g. 'en' or 'nl-NL'
+pub fn validate_locale(locale: &str) -> Result<(), &'static str> {
+    lazy_static! {
+        static ref LOCALE_REGEX: Regex = Regex::new(r"^[A-Za-z]{2,3}(-[A-Za-z0-9]{2,8}){0,3}$").unwrap();
+    }
+
+    if !LOCALE_REGEX.is_match(locale) {
+        return Err("Locale must be a BCP 47 language tag, e.g. 'en' or 'nl-NL'.");
+    }
+
+    Ok(())
+}
+
+/// Check that the timezone looks like an IANA timezone name, e.g. 'UTC' or 'Europe/Amsterdam'
+pub fn validate_timezone(timezone: &str) -> Result<(), &'static str> {
+    lazy_static! {
+        static ref TIMEZONE_REGEX: Regex = Regex::new(r"^[A-Za-z][A-Za-z0-9_+\-]*(/[A-Za-z0-9_+\-]+){0,2}$").unwrap();
+    }
+
+    if timezone.len() > 64 || !TIMEZONE_REGEX.is_match(timezone) {
+        return Err("Timezone must be an IANA timezone name, e.g. 'Europe/Amsterdam'.");
+    }
+
+    Ok(())
+}
+
+pub fn validate_avatar_url(avatar_url: &str) -> Result<(), &'static str> {
+    let url = reqwest::Url::parse(avatar_url);
+    if avatar_url.len() > 2048 || url.is_err() || !matches!(url.unwrap().scheme(), "http" | "https") {
+        return Err("Avatar URL must be an http(s) URL of at most 2048 characters.");
+    }
+
+    Ok(())
+}
@@ -0,0 +1,478 @@
+use crate::appdata::{AppData, Database, Environment};
+use crate::{client, email, username};
+
+use std::collections::HashMap;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use actix_web::{web, Error, HttpResponse};
+use actix_web::dev::{Service, ServiceRequest, ServiceResponse};
+use actix_web::http::Method;
+use mysql::prelude::Queryable;
+use mysql::{TxOpts, params};
+use serde::{Deserialize, Serialize};
+
+/// How long clients are asked to wait when the database backend can't be reached and `fail_open` is off
+const UNAVAILABLE_RETRY_SECONDS: u64 = 10;
+
+/// A token bucket. Every request takes a token, and tokens are added back at `refill_per_minute`, up to `capacity`
+#[derive(Deserialize, Serialize, Clone, Copy)]
+pub struct BucketConfig {
+    /// The amount of requests which can be made in a burst
+    pub capacity:           u32,
+    pub refill_per_minute:  u32
+}
+
+impl BucketConfig {
+    pub const fn new(capacity: u32, refill_per_minute: u32) -> Option<BucketConfig> {
+        Some(BucketConfig { capacity, refill_per_minute })
+    }
+
+    /// Parse `<capacity>/<refill per minute>`, or 'off' to disable the bucket
+    pub fn from_var(name: &str, default: Option<BucketConfig>) -> Option<BucketConfig> {
+        let value = match std::env::var(name) {
+            Ok(value) => value,
+            Err(_) => return default
+        };
+
+        if value.trim().eq_ignore_ascii_case("off") {
+            return None;
+        }
+
+        let parsed = value.split_once('/').and_then(|(capacity, refill_per_minute)| {
+            Some(BucketConfig { capacity: capacity.trim().parse().ok()?, refill_per_minute: refill_per_minute.trim().parse().ok()? })
+        });
+
+        if parsed.is_none() {
+            eprintln!("Environmental variable '{}' should be of the form '<capacity>/<refill per minute>' or 'off'. Exiting", name);
+            std::process::exit(1);
+        }
+
+        parsed
+    }
+
+    fn refill_per_second(&self) -> f64 {
+        self.refill_per_minute as f64 / 60.0
+    }
+
+    /// The tokens a bucket holding `tokens` has `elapsed` seconds after it was last updated
+    fn refill(&self, tokens: f64, elapsed: f64) -> f64 {
+        (tokens + elapsed * self.refill_per_second()).min(self.capacity as f64)
+    }
+
+    /// Take a token from a bucket holding `tokens`, `elapsed` seconds after it was last updated.
+    /// Returns the tokens left, or the amount of seconds until a token is available
+    fn take(&self, tokens: f64, elapsed: f64) -> Result<f64, u64> {
+        let tokens = self.refill(tokens, elapsed);
+        if tokens >= 1.0 {
+            return Ok(tokens - 1.0);
+        }
+
+        if self.refill_per_minute == 0 {
+            return Err(60);
+        }
+
+        Err(((1.0 - tokens) / self.refill_per_second()).ceil().max(1.0) as u64)
+    }
+}
+
+/// The buckets of an endpoint. A request has to get a token from each of them
+#[derive(Deserialize, Serialize, Clone, Copy, Default)]
+#[serde(default)]
+pub struct EndpointLimits {
+    /// Per client IP address
+    pub per_ip:     Option<BucketConfig>,
+    /// Per email address or username the request is for
+    pub per_email:  Option<BucketConfig>,
+    /// Shared by every client
+    pub global:     Option<BucketConfig>
+}
+
+impl EndpointLimits {
+    fn from_vars(prefix: &str, default: EndpointLimits) -> EndpointLimits {
+        EndpointLimits {
+            per_ip:     BucketConfig::from_var(&format!("{}_PER_IP", prefix), default.per_ip),
+            per_email:  BucketConfig::from_var(&format!("{}_PER_EMAIL", prefix), default.per_email),
+            global:     BucketConfig::from_var(&format!("{}_GLOBAL", prefix), default.global)
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum RateLimitBackend {
+    /// Buckets are kept by each server on its own
+    #[default]
+    Memory,
+    /// Buckets are kept in the database, shared by every server
+    Mysql
+}
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct RateLimitConfig {
+    pub enabled:        bool,
+    pub backend:        RateLimitBackend,
+    /// Let requests through if the database backend can't be reached, so a database problem doesn't lock everyone out.
+    /// Without it such requests are throttled, so a database problem doesn't lift the limits either
+    pub fail_open:      bool,
+    /// How often the memory backend forgets full buckets
+    pub prune_seconds:  u64,
+    pub login:          EndpointLimits,
+    pub register:       EndpointLimits,
+    pub session:        EndpointLimits
+}
+
+impl Default for RateLimitConfig {
+    fn default() -> Self {
+        RateLimitConfig {
+            enabled:        true,
+            backend:        RateLimitBackend::default(),
+            fail_open:      true,
+            prune_seconds:  60,
+            login:          EndpointLimits { per_ip: BucketConfig::new(20, 10), per_email: BucketConfig::new(10, 5), global: BucketConfig::new(1000, 600) },
+            register:       EndpointLimits { per_ip: BucketConfig::new(5, 1), per_email: BucketConfig::new(3, 1), global: BucketConfig::new(200, 60) },
+            session:        EndpointLimits { per_ip: BucketConfig::new(120, 60), per_email: None, global: BucketConfig::new(5000, 3000) }
+        }
+    }
+}
+
+impl RateLimitConfig {
+    pub fn from_vars() -> RateLimitConfig {
+        let default = RateLimitConfig::default();
+
+        let backend = match std::env::var("RATE_LIMIT_BACKEND").as_deref() {
+            Ok("memory") | Err(_) => RateLimitBackend::Memory,
+            Ok("mysql") => RateLimitBackend::Mysql,
+            Ok(backend) => {
+                eprintln!("Environmental variable 'RATE_LIMIT_BACKEND' has an invalid value '{}', expected 'memory' or 'mysql'. Exiting", backend);
+                std::process::exit(1);
+            }
+        };
+
+        //Buckets are configured with e.g. RATE_LIMIT_LOGIN_PER_IP=20/10
+        RateLimitConfig {
+            enabled:        std::env::var("RATE_LIMIT_ENABLED").map(|enabled| enabled != "FALSE").unwrap_or(true),
+            backend,
+            fail_open:      std::env::var("RATE_LIMIT_FAIL_OPEN").map(|fail_open| fail_open != "FALSE").unwrap_or(default.fail_open),
+            prune_seconds:  Environment::optional_var("RATE_LIMIT_PRUNE_SECONDS", default.prune_seconds),
+            login:          EndpointLimits::from_vars("RATE_LIMIT_LOGIN", default.login),
+            register:       EndpointLimits::from_vars("RATE_LIMIT_REGISTER", default.register),
+            session:        EndpointLimits::from_vars("RATE_LIMIT_SESSION", default.session)
+        }
+    }
+
+    fn limits(&self, endpoint: Endpoint) -> &EndpointLimits {
+        match endpoint {
+            Endpoint::Login => &self.login,
+            Endpoint::Register => &self.register,
+            Endpoint::Session => &self.session
+        }
+    }
+}
+
+/// The endpoints which are rate limited
+#[derive(Clone, Copy)]
+pub enum Endpoint {
+    Login,
+    Register,
+    Session
+}
+
+impl Endpoint {
+    fn name(&self) -> &'static str {
+        match self {
+            Endpoint::Login => "login",
+            Endpoint::Register => "register",
+            Endpoint::Session => "session"
+        }
+    }
+
+    fn from_request(req: &ServiceRequest) -> Option<Endpoint> {
+        if req.method() != Method::POST {
+            return None;
+        }
+
+        match req.path() {
+            "/auth/login" => Some(Endpoint::Login),
+            "/auth/register" => Some(Endpoint::Register),
+            "/auth/session" => Some(Endpoint::Session),
+            _ => None
+        }
+    }
+}
+
+struct MemoryBucket {
+    config:     BucketConfig,
+    tokens:     f64,
+    updated_at: Instant
+}
+
+/// Hands out tokens from the configured buckets. Clones share the in-memory buckets
+#[derive(Clone)]
+pub struct RateLimiter {
+    memory:     Arc<Mutex<HashMap<String, MemoryBucket>>>
+}
+
+impl RateLimiter {
+    pub fn new() -> RateLimiter {
+        RateLimiter {
+            memory: Arc::new(Mutex::new(HashMap::new()))
+        }
+    }
+
+    /// Forget full buckets of the memory backend on a separate thread, every `prune_seconds`.
+    /// Full buckets behave the same as buckets which don't exist, so nothing changes for the clients
+    pub fn spawn_pruner(&self, config: &RateLimitConfig) {
+        if config.backend != RateLimitBackend::Memory {
+            return;
+        }
+
+        let memory = self.memory.clone();
+        let interval = Duration::from_secs(config.prune_seconds.max(1));
+        std::thread::spawn(move || loop {
+            std::thread::sleep(interval);
+
+            let now = Instant::now();
+            memory.lock().unwrap().retain(|_, state| state.config.refill(state.tokens, now.duration_since(state.updated_at).as_secs_f64()) < state.config.capacity as f64);
+        });
+    }
+
+    /// Take a token from the bucket with `key`. Returns the amount of seconds until a token is available if it is empty
+    fn take(&self, database: &Database, config: &RateLimitConfig, key: &str, bucket: &BucketConfig) -> Result<(), u64> {
+        match config.backend {
+            RateLimitBackend::Memory => self.take_memory(key, bucket),
+            RateLimitBackend::Mysql => match take_mysql(database, key, bucket) {
+                Ok(result) => result,
+                Err(_) if config.fail_open => Ok(()),
+                Err(_) => Err(UNAVAILABLE_RETRY_SECONDS)
+            }
+        }
+    }
+
+    fn take_memory(&self, key: &str, bucket: &BucketConfig) -> Result<(), u64> {
+        let mut buckets = self.memory.lock().unwrap();
+        let now = Instant::now();
+
+        let state = buckets.entry(key.to_string()).or_insert(MemoryBucket { config: *bucket, tokens: bucket.capacity as f64, updated_at: now });
+        let tokens = bucket.take(state.tokens, now.duration_since(state.updated_at).as_secs_f64())?;
+
+        state.tokens = tokens;
+        state.updated_at = now;
+        Ok(())
+    }
+
+    /// Take a token from a bucket which doesn't guard an endpoint, e.g. one counting failures. The bucket is kept by the
+    /// configured backend even if rate limiting is disabled. Returns whether a token was available
+    pub fn take_token(&self, data: &AppData, key: &str, bucket: &BucketConfig) -> bool {
+        self.take(&data.database, &data.environment.rate_limit, key, bucket).is_ok()
+    }
+
+    /// Check whether the bucket with `key` has a token, without taking it. Returns the amount of seconds until a token
+    /// is available if it is empty
+    fn peek(&self, database: &Database, config: &RateLimitConfig, key: &str, bucket: &BucketConfig) -> Result<(), u64> {
+        match config.backend {
+            RateLimitBackend::Memory => {
+                let buckets = self.memory.lock().unwrap();
+                match buckets.get(key) {
+                    Some(state) => bucket.take(state.tokens, state.updated_at.elapsed().as_secs_f64()).map(|_| ()),
+                    None => Ok(())
+                }
+            },
+            RateLimitBackend::Mysql => match peek_mysql(database, key, bucket) {
+                Ok(result) => result,
+                Err(_) if config.fail_open => Ok(()),
+                Err(_) => Err(UNAVAILABLE_RETRY_SECONDS)
+            }
+        }
+    }
+
+    /// Whether the bucket with `key` is empty, without taking a token from it
+    pub fn is_empty(&self, data: &AppData, key: &str, bucket: &BucketConfig) -> bool {
+        self.peek(&data.database, &data.environment.rate_limit, key, bucket).is_err()
+    }
+
+    /// Take a token from each of the `(kind, bucket, key)` buckets of `endpoint`. Disabled buckets are skipped.
+    /// Every bucket is checked before a token is taken from any of them, so a client which is throttled by its own bucket
+    /// doesn't use up the tokens of a shared one
+    fn check(&self, data: &AppData, endpoint: Endpoint, buckets: &[(&str, Option<BucketConfig>, String)]) -> Result<(), HttpResponse> {
+        let config = &data.environment.rate_limit;
+        if !config.enabled {
+            return Ok(());
+        }
+
+        let buckets: Vec<(&str, BucketConfig, String)> = buckets.iter()
+            .filter_map(|(kind, bucket, key)| bucket.map(|bucket| (*kind, bucket, format!("{}:{}:{}", endpoint.name(), kind, key))))
+            .collect();
+
+        for (kind, bucket, key) in buckets.iter() {
+            if let Err(retry_after) = self.peek(&data.database, config, key, bucket) {
+                println!("Throttled a {} request by its {} bucket ({}), retry after {} second(s)", endpoint.name(), kind, key, retry_after);
+                return Err(too_many_requests(retry_after));
+            }
+        }
+
+        //Another request may have emptied a bucket in the meantime, the buckets are ordered narrowest first so that is
+        //most likely noticed before a shared bucket loses a token
+        for (kind, bucket, key) in buckets.iter() {
+            if let Err(retry_after) = self.take(&data.database, config, key, bucket) {
+                println!("Throttled a {} request by its {} bucket ({}), retry after {} second(s)", endpoint.name(), kind, key, retry_after);
+                return Err(too_many_requests(retry_after));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Take a token from a bucket stored in the database. Buckets are created full the first time they are used
+fn take_mysql(database: &Database, key: &str, bucket: &BucketConfig) -> Result<Result<(), u64>, ()> {
+    let conn_wrapped = database.pool.get_conn();
+    if conn_wrapped.is_err() {
+        eprintln!("An error occurred (ratelimit.rs): {:?}", conn_wrapped.err().unwrap());
+        return Err(());
+    }
+    let mut conn = conn_wrapped.unwrap();
+
+    let tx = conn.start_transaction(TxOpts::default());
+    if tx.is_err() {
+        eprintln!("An error occurred (ratelimit.rs): {:?}", tx.err().unwrap());
+        return Err(());
+    }
+    let mut tx = tx.unwrap();
+
+    let now = chrono::Utc::now().timestamp_millis() as f64 / 1000.0;
+    let sql_insert_bucket = tx.exec_drop("INSERT IGNORE INTO rate_limits (bucket_key, tokens, updated_at) VALUES (:bucket_key, :tokens, :updated_at)", params! {
+        "bucket_key" => key,
+        "tokens" => bucket.capacity as f64,
+        "updated_at" => now
+    });
+
+    if sql_insert_bucket.is_err() {
+        eprintln!("An error occurred (ratelimit.rs): {:?}", sql_insert_bucket.err().unwrap());
+        return Err(());
+    }
+
+    //Lock the bucket, so concurrent requests on other servers take their tokens one after the other
+    let sql_fetch_bucket = tx.exec_first::<(f64, f64), &str, _>("SELECT tokens, updated_at FROM rate_limits WHERE bucket_key = :bucket_key FOR UPDATE", params! {
+        "bucket_key" => key
+    });
+
+    let (tokens, updated_at) = match sql_fetch_bucket {
+        Ok(Some(state)) => state,
+        Ok(None) => return Err(()),
+        Err(e) => {
+            eprintln!("An error occurred (ratelimit.rs): {:?}", e);
+            return Err(());
+        }
+    };
+
+    let tokens = match bucket.take(tokens, (now - updated_at).max(0.0)) {
+        Ok(tokens) => tokens,
+        Err(retry_after) => return Ok(Err(retry_after))
+    };
+
+    let sql_update_bucket = tx.exec_drop("UPDATE rate_limits SET tokens = :tokens, updated_at = :updated_at WHERE bucket_key = :bucket_key", params! {
+        "tokens" => tokens,
+        "updated_at" => now,
+        "bucket_key" => key
+    });
+
+    if sql_update_bucket.is_err() {
+        eprintln!("An error occurred (ratelimit.rs): {:?}", sql_update_bucket.err().unwrap());
+        return Err(());
+    }
+
+    if let Err(e) = tx.commit() {
+        eprintln!("An error occurred (ratelimit.rs): {:?}", e);
+        return Err(());
+    }
+
+    Ok(Ok(()))
+}
+
+/// Check whether a bucket stored in the database has a token, without taking it. Buckets which don't exist yet are full
+fn peek_mysql(database: &Database, key: &str, bucket: &BucketConfig) -> Result<Result<(), u64>, ()> {
+    let conn_wrapped = database.pool.get_conn();
+    if conn_wrapped.is_err() {
+        eprintln!("An error occurred (ratelimit.rs): {:?}", conn_wrapped.err().unwrap());
+        return Err(());
+    }
+    let mut conn = conn_wrapped.unwrap();
+
+    let sql_fetch_bucket = conn.exec_first::<(f64, f64), &str, _>("SELECT tokens, updated_at FROM rate_limits WHERE bucket_key = :bucket_key", params! {
+        "bucket_key" => key
+    });
+
+    match sql_fetch_bucket {
+        Ok(Some((tokens, updated_at))) => {
+            let now = chrono::Utc::now().timestamp_millis() as f64 / 1000.0;
+            Ok(bucket.take(tokens, (now - updated_at).max(0.0)).map(|_| ()))
+        },
+        Ok(None) => Ok(Ok(())),
+        Err(e) => {
+            eprintln!("An error occurred (ratelimit.rs): {:?}", e);
+            Err(())
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ThrottledResponse {
+    status:         i16,
+    message:        &'static str,
+    retry_after:    u64
+}
+
+fn too_many_requests(retry_after: u64) -> HttpResponse {
+    let response = ThrottledResponse { status: 429, message: "Too many requests, try again later.", retry_after };
+    HttpResponse::TooManyRequests()
+        .header("Retry-After", retry_after.to_string())
+        .json(&response)
+}
+
+type ResponseFuture = Pin<Box<dyn Future<Output = Result<ServiceResponse, Error>>>>;
+
+/// Middleware taking a token from the per IP and global buckets of the rate limited endpoints
+pub fn middleware<S>(req: ServiceRequest, srv: &mut S) -> ResponseFuture
+where
+    S: Service<Request = ServiceRequest, Response = ServiceResponse, Error = Error>,
+    S::Future: 'static
+{
+    let endpoint = Endpoint::from_request(&req);
+    let data = req.app_data::<web::Data<AppData>>().cloned();
+
+    if let (Some(endpoint), Some(data)) = (endpoint, data) {
+        let limits = data.environment.rate_limit.limits(endpoint);
+        let ip = client::resolve(req.peer_addr().map(|addr| addr.ip()), req.headers(), data.ip_filter.trusted_proxies())
+            .map(|ip| ip.to_string())
+            .unwrap_or_else(|| "unknown".to_string());
+
+        let buckets = [("ip", limits.per_ip, ip), ("global", limits.global, String::new())];
+        if let Err(response) = data.rate_limiter.check(&data, endpoint, &buckets) {
+            return Box::pin(async move { Ok(req.into_response(response)) });
+        }
+    }
+
+    Box::pin(srv.call(req))
+}
+
+/// Take a token from the per email bucket of an endpoint. `identifier` is an email address or a username,
+/// as the per email bucket guards an account rather than an address
+pub fn check_identifier(data: &AppData, endpoint: Endpoint, identifier: &str) -> Result<(), HttpResponse> {
+    let limits = data.environment.rate_limit.limits(endpoint);
+    data.rate_limiter.check(data, endpoint, &[("email", limits.per_email, identifier_key(data, identifier))])
+}
+
+/// The key of the buckets guarding an account, which is the same for every spelling of its email address or username.
+/// It is the blind index, so the shared backend doesn't store email addresses
+pub fn identifier_key(data: &AppData, identifier: &str) -> String {
+    let identifier = if identifier.contains('@') {
+        email::index_form(identifier, &data.environment.email_normalization)
+    } else {
+        username::normalize(identifier)
+    };
+
+    data.environment.email_encryption.blind_index(&identifier)
+}
@@ -0,0 +1,101 @@
+use crate::sessions;
+use crate::sessions::Session;
+
+use actix_web::{HttpRequest, HttpResponse};
+use lazy_static::lazy_static;
+use mysql::prelude::Queryable;
+use mysql::{PooledConn, params};
+use regex::Regex;
+
+/// Role which is created by the migrations, and granted every permission
+pub const ADMIN_ROLE: &str = "admin";
+
+/// View roles and their permissions
+pub const ROLES_READ: &str = "roles.read";
+/// Create, change and delete roles, and assign them to users
+pub const ROLES_MANAGE: &str = "roles.manage";
+/// Search users and view their details and sessions
+pub const USERS_READ: &str = "users.read";
+/// Log users out, disable and enable them, set their password and delete them
+pub const USERS_MANAGE: &str = "users.manage";
+/// Create users in bulk from an import file
+pub const USERS_IMPORT: &str = "users.import";
+
+pub fn is_valid_role(name: &str) -> bool {
+    lazy_static! {
+        static ref ROLE_REGEX: Regex = Regex::new(r"^[a-z0-9_.\-]{1,64}$").unwrap();
+    }
+
+    ROLE_REGEX.is_match(name)
+}
+
+/// Check if `permission` is granted by any of the `granted` permissions.
+/// A granted permission of '*' grants everything, and 'users.*' grants every permission starting with 'users.'
+pub fn is_granted(granted: &[String], permission: &str) -> bool {
+    granted.iter().any(|granted| {
+        if granted == "*" || granted == permission {
+            return true;
+        }
+
+        match granted.strip_suffix('*') {
+            Some(prefix) => prefix.ends_with('.') && permission.starts_with(prefix),
+            None => false
+        }
+    })
+}
+
+/// The roles assigned to a user
+pub fn user_roles(conn: &mut PooledConn, user_id: &str) -> Result<Vec<String>, ()> {
+    let sql_fetch_roles = conn.exec::<String, &str, _>("SELECT role FROM user_roles WHERE user_id = :user_id ORDER BY role", params! {
+        "user_id" => user_id
+    });
+
+    if sql_fetch_roles.is_err() {
+        eprintln!("An error occurred (rbac.rs): {:?}", sql_fetch_roles.err().unwrap());
+        return Err(());
+    }
+
+    Ok(sql_fetch_roles.unwrap())
+}
+
+/// The permissions granted to a user through their roles
+pub fn user_permissions(conn: &mut PooledConn, user_id: &str) -> Result<Vec<String>, ()> {
+    let sql_fetch_permissions = conn.exec::<String, &str, _>("SELECT DISTINCT role_permissions.permission FROM user_roles INNER JOIN role_permissions ON role_permissions.role = user_roles.role WHERE user_roles.user_id = :user_id ORDER BY role_permissions.permission", params! {
+        "user_id" => user_id
+    });
+
+    if sql_fetch_permissions.is_err() {
+        eprintln!("An error occurred (rbac.rs): {:?}", sql_fetch_permissions.err().unwrap());
+        return Err(());
+    }
+
+    Ok(sql_fetch_permissions.unwrap())
+}
+
+/// Whether `user_id` holds every permission `target_id` holds, so acting on the target can't be used to gain more access.
+/// A wildcard of the target is only covered by the same or a broader wildcard
+pub fn holds_permissions_of(conn: &mut PooledConn, user_id: &str, target_id: &str) -> Result<bool, ()> {
+    let permissions = user_permissions(conn, user_id)?;
+    let target_permissions = user_permissions(conn, target_id)?;
+
+    Ok(target_permissions.iter().all(|permission| is_granted(&permissions, permission)))
+}
+
+/// Guard for endpoints which require a permission. The session is taken from the request's `Authorization` header.
+///
+/// Returns the session if it is valid and its user has the permission,
+/// otherwise the response which should be returned to the client
+pub fn require_permission(conn: &mut PooledConn, req: &HttpRequest, permission: &str) -> Result<Session, HttpResponse> {
+    let session = sessions::require_session(conn, req)?;
+
+    let permissions = user_permissions(conn, &session.user_id);
+    if permissions.is_err() {
+        return Err(HttpResponse::InternalServerError().finish());
+    }
+
+    if !is_granted(&permissions.unwrap(), permission) {
+        return Err(sessions::deny(403, "Missing permission."));
+    }
+
+    Ok(session)
+}
@@ -0,0 +1,211 @@
+use crate::appdata::{Database, Environment};
+use crate::users;
+
+use std::collections::HashMap;
+use std::time::Duration;
+use mysql::prelude::Queryable;
+use mysql::{PooledConn, params};
+use serde::{Deserialize, Serialize};
+
+/// Purges at most `batch_size` rows older than the cutoff, returning the amount of rows purged.
+/// Used for tables whose rows can't simply be deleted
+type PurgeFn = fn(&mut PooledConn, i64, u64) -> Result<u64, ()>;
+
+/// A table containing time-bounded rows which should be purged by the reaper
+struct ReapTarget {
+    /// Name of the table
+    table:          &'static str,
+    /// Column containing the UNIX timestamp (in seconds) from which the retention window is counted
+    column:         &'static str,
+    /// Retention window in seconds used when none is configured
+    default_window: i64,
+    /// Purge rows with this function, rather than deleting them directly
+    purge:          Option<PurgeFn>
+}
+
+/// All tables the reaper knows about
+const REAP_TARGETS: &[ReapTarget] = &[
+    //Sessions are removed as soon as they expire
+    ReapTarget { table: "sessions", column: "expiry", default_window: 0, purge: None },
+    //Login history is kept for 90 days
+    ReapTarget { table: "login_history", column: "timestamp", default_window: 90 * 86400, purge: None },
+    //Pending email changes are removed as soon as their confirmation link expires
+    ReapTarget { table: "email_changes", column: "expiry", default_window: 0, purge: None },
+    //Organization invitations are removed once they expire
+    ReapTarget { table: "org_invitations", column: "expiry", default_window: 0, purge: None },
+    //Unlock links are removed once they expire
+    ReapTarget { table: "unlock_tokens", column: "expiry", default_window: 0, purge: None },
+    //Security events are kept as long as the login history
+    ReapTarget { table: "security_events", column: "timestamp", default_window: 90 * 86400, purge: None },
+    //Known devices are forgotten once they haven't been used to log in for 180 days
+    ReapTarget { table: "known_devices", column: "last_seen", default_window: 180 * 86400, purge: None },
+    //Links reporting a login are removed once they expire
+    ReapTarget { table: "login_reports", column: "expiry", default_window: 0, purge: None },
+    //Password reset links are removed once they expire
+    ReapTarget { table: "password_resets", column: "expiry", default_window: 0, purge: None },
+    //Spent challenges can't be replayed once they expire anyway
+    ReapTarget { table: "spent_challenges", column: "expiry", default_window: 0, purge: None },
+    //Session proofs are refused for their age once they expire
+    ReapTarget { table: "spent_proofs", column: "expiry", default_window: 0, purge: None },
+    //Rate limit buckets are full again well within a day of their last use, so they can be removed
+    ReapTarget { table: "rate_limits", column: "updated_at", default_window: 86400, purge: None },
+    //The window of deleted users is always their grace period, see `RetentionConfig::deletion_grace_seconds`
+    ReapTarget { table: "users", column: "deleted_at", default_window: 0, purge: Some(users::purge_deleted) },
+];
+
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct RetentionConfig {
+    /// How often the reaper runs, in seconds. At least 1
+    pub interval_seconds:   u64,
+    /// Maximum amount of rows deleted per query
+    pub batch_size:         u64,
+    /// Retention window per table, in seconds. Rows are kept for this long after their timestamp has passed.
+    /// A negative window disables reaping for that table
+    pub windows:            HashMap<String, i64>,
+    /// How long an account scheduled for deletion can still be restored by logging in, in seconds.
+    /// With a grace period of 0, accounts are deleted right away
+    pub deletion_grace_seconds: i64
+}
+
+impl Default for RetentionConfig {
+    fn default() -> Self {
+        let windows = REAP_TARGETS.iter()
+            .filter(|target| target.table != "users")
+            .map(|target| (target.table.to_string(), target.default_window))
+            .collect();
+
+        RetentionConfig {
+            interval_seconds:   300,
+            batch_size:         1000,
+            windows,
+            deletion_grace_seconds: 0
+        }
+    }
+}
+
+impl RetentionConfig {
+    pub fn from_vars() -> RetentionConfig {
+        let default = Self::default();
+
+        //RETENTION_WINDOWS is formatted as 'table=seconds,table=seconds'
+        let mut windows = default.windows;
+        let windows_var = std::env::var("RETENTION_WINDOWS");
+        if let Ok(windows_var) = windows_var {
+            for entry in windows_var.split(',').filter(|entry| !entry.trim().is_empty()) {
+                let parts: Vec<&str> = entry.splitn(2, '=').collect();
+                let window = parts.get(1).map(|window| window.trim().parse::<i64>());
+                match window {
+                    Some(Ok(window)) => { windows.insert(parts[0].trim().to_string(), window); },
+                    _ => {
+                        eprintln!("Environmental variable 'RETENTION_WINDOWS' has an invalid entry '{}'. Exiting", entry);
+                        std::process::exit(1);
+                    }
+                }
+            }
+        }
+
+        RetentionConfig {
+            interval_seconds:   Environment::optional_var("RETENTION_INTERVAL_SECONDS", default.interval_seconds),
+            batch_size:         Environment::optional_var("RETENTION_BATCH_SIZE", default.batch_size),
+            windows,
+            deletion_grace_seconds: Environment::optional_var("DELETION_GRACE_SECONDS", default.deletion_grace_seconds)
+        }
+    }
+
+    /// The retention window of a table, in seconds. Deleted users are kept for their grace period,
+    /// whatever window is configured for the `users` table
+    pub fn window(&self, table: &str) -> i64 {
+        if table == "users" {
+            return self.deletion_grace_seconds.max(0);
+        }
+
+        let default_window = REAP_TARGETS.iter()
+            .find(|target| target.table == table)
+            .map(|target| target.default_window)
+            .unwrap_or(-1);
+
+        *self.windows.get(table).unwrap_or(&default_window)
+    }
+}
+
+/// Start the reaper on a separate thread. It runs once immediately, and then every `interval_seconds`
+pub fn spawn(database: Database, config: RetentionConfig) {
+    //An interval of 0 would keep the reaper busy, and the database with it
+    let interval_seconds = config.interval_seconds.max(1);
+
+    std::thread::spawn(move || {
+        let mut totals: HashMap<&'static str, u64> = HashMap::new();
+
+        loop {
+            let reap_result = reap_expired(&database, &config);
+            match reap_result {
+                Ok(reaped) => {
+                    for (table, count) in reaped {
+                        let total = totals.entry(table).or_insert(0);
+                        *total += count;
+
+                        if count > 0 {
+                            println!("Reaped {} expired row(s) from '{}' ({} since startup)", count, table, total);
+                        }
+                    }
+                },
+                Err(_) => eprintln!("Reaping expired rows failed, retrying in {} seconds (reaper.rs)", interval_seconds)
+            }
+
+            std::thread::sleep(Duration::from_secs(interval_seconds));
+        }
+    });
+}
+
+/// Purge all rows whose retention window has passed, in batches of `batch_size`.
+/// Returns the amount of rows deleted per table
+pub fn reap_expired(database: &Database, config: &RetentionConfig) -> Result<Vec<(&'static str, u64)>, ()> {
+    let conn_wrapped = database.pool.get_conn();
+    if conn_wrapped.is_err() {
+        eprintln!("An error occurred (reaper.rs): {:?}", conn_wrapped.err().unwrap());
+        return Err(());
+    }
+    let mut conn = conn_wrapped.unwrap();
+
+    let now = chrono::Utc::now().timestamp();
+    let mut reaped = Vec::new();
+
+    for target in REAP_TARGETS {
+        let window = config.window(target.table);
+        if window < 0 {
+            continue;
+        }
+
+        let cutoff = now - window;
+        let mut deleted: u64 = 0;
+
+        //Delete in batches, so we don't hold locks on the table for too long
+        loop {
+            let affected = match target.purge {
+                Some(purge) => purge(&mut conn, cutoff, config.batch_size)?,
+                None => {
+                    let sql_delete = conn.exec_drop(format!("DELETE FROM {} WHERE {} < :cutoff LIMIT {}", target.table, target.column, config.batch_size), params! {
+                        "cutoff" => cutoff
+                    });
+
+                    if sql_delete.is_err() {
+                        eprintln!("An error occurred while reaping '{}' (reaper.rs): {:?}", target.table, sql_delete.err().unwrap());
+                        return Err(());
+                    }
+
+                    conn.affected_rows()
+                }
+            };
+            deleted += affected;
+
+            if affected == 0 || affected < config.batch_size {
+                break;
+            }
+        }
+
+        reaped.push((target.table, deleted));
+    }
+
+    Ok(reaped)
+}
@@ -0,0 +1,175 @@
+use crate::crypto;
+
+use actix_web::{HttpRequest, HttpResponse};
+use actix_web::http::StatusCode;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+pub const USER_SCHEMA: &str = "urn:ietf:params:scim:schemas:core:2.0:User";
+pub const GROUP_SCHEMA: &str = "urn:ietf:params:scim:schemas:core:2.0:Group";
+pub const LIST_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:ListResponse";
+const ERROR_SCHEMA: &str = "urn:ietf:params:scim:api:messages:2.0:Error";
+
+/// The amount of resources per page if the request doesn't say otherwise
+const DEFAULT_COUNT: u64 = 100;
+/// The largest page a request may ask for
+const MAX_COUNT: u64 = 500;
+
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct ScimConfig {
+    /// Bearer tokens identity providers authenticate with. SCIM is disabled if there are none
+    pub tokens:     Vec<String>
+}
+
+impl ScimConfig {
+    pub fn from_vars() -> ScimConfig {
+        //SCIM_TOKENS is a comma separated list of tokens
+        let tokens = std::env::var("SCIM_TOKENS")
+            .map(|tokens| tokens.split(',').map(|token| token.trim().to_string()).filter(|token| !token.is_empty()).collect())
+            .unwrap_or_default();
+
+        ScimConfig {
+            tokens
+        }
+    }
+
+    /// Guard for the SCIM endpoints. Returns the response which should be returned to the client if the request
+    /// doesn't carry one of the configured tokens
+    pub fn authorize(&self, req: &HttpRequest) -> Result<(), HttpResponse> {
+        if self.tokens.is_empty() {
+            return Err(error(StatusCode::NOT_FOUND, None, "SCIM provisioning is not enabled."));
+        }
+
+        let token = req.headers().get("Authorization")
+            .and_then(|header| header.to_str().ok())
+            .and_then(|header| header.strip_prefix("Bearer "))
+            .map(|token| crypto::hash_token(token.trim()));
+
+        //Compare hashes, so the comparison doesn't depend on how much of a token is right
+        match token {
+            Some(token) if self.tokens.iter().any(|configured| crypto::hash_token(configured) == token) => Ok(()),
+            _ => Err(error(StatusCode::UNAUTHORIZED, None, "Invalid or missing bearer token."))
+        }
+    }
+}
+
+/// A SCIM error response. Unlike the other endpoints, SCIM clients expect the HTTP status to carry the outcome
+pub fn error(status: StatusCode, scim_type: Option<&str>, detail: &str) -> HttpResponse {
+    let mut body = json!({
+        "schemas": [ERROR_SCHEMA],
+        "status": status.as_u16().to_string(),
+        "detail": detail
+    });
+
+    if let Some(scim_type) = scim_type {
+        body["scimType"] = Value::String(scim_type.to_string());
+    }
+
+    HttpResponse::build(status).content_type("application/scim+json").json(body)
+}
+
+/// A successful SCIM response
+pub fn respond(status: StatusCode, body: Value) -> HttpResponse {
+    HttpResponse::build(status).content_type("application/scim+json").json(body)
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ListQuery {
+    pub filter:     Option<String>,
+    start_index:    Option<u64>,
+    count:          Option<u64>
+}
+
+impl ListQuery {
+    /// The 1-based index of the first resource, and the amount of resources to return
+    pub fn page(&self) -> (u64, u64) {
+        (self.start_index.unwrap_or(1).max(1), self.count.unwrap_or(DEFAULT_COUNT).min(MAX_COUNT))
+    }
+}
+
+pub fn list_response(resources: Vec<Value>, total: u64, start_index: u64) -> HttpResponse {
+    respond(StatusCode::OK, json!({
+        "schemas": [LIST_SCHEMA],
+        "totalResults": total,
+        "startIndex": start_index,
+        "itemsPerPage": resources.len(),
+        "Resources": resources
+    }))
+}
+
+/// A filter of the form `<attribute> eq "<value>"`, the only form identity providers use for provisioning
+pub struct Filter {
+    /// Lowercased, as SCIM attribute names are case insensitive
+    pub attribute:  String,
+    pub value:      String
+}
+
+impl std::str::FromStr for Filter {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim().splitn(3, ' ');
+        let attribute = parts.next().ok_or(())?.to_lowercase();
+        let operator = parts.next().ok_or(())?;
+        let value = parts.next().ok_or(())?.trim();
+
+        if !operator.eq_ignore_ascii_case("eq") {
+            return Err(());
+        }
+
+        let value = match value.strip_prefix('"').and_then(|value| value.strip_suffix('"')) {
+            Some(value) => value.replace("\\\"", "\""),
+            //Booleans and numbers aren't quoted
+            None => value.to_string()
+        };
+
+        Ok(Filter { attribute, value })
+    }
+}
+
+/// A PATCH request
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+pub struct PatchRequest {
+    pub operations: Vec<PatchOperation>
+}
+
+#[derive(Deserialize)]
+pub struct PatchOperation {
+    /// 'add', 'replace' or 'remove'. Some identity providers capitalize it
+    pub op:     String,
+    pub path:   Option<String>,
+    pub value:  Option<Value>
+}
+
+impl PatchOperation {
+    pub fn op(&self) -> String {
+        self.op.to_lowercase()
+    }
+
+    /// The path, lowercased
+    pub fn path(&self) -> Option<String> {
+        self.path.as_ref().map(|path| path.trim().to_lowercase())
+    }
+}
+
+/// Read a boolean, which some identity providers send as a string
+pub fn as_bool(value: &Value) -> Option<bool> {
+    match value {
+        Value::Bool(value) => Some(*value),
+        Value::String(value) => value.to_lowercase().parse().ok(),
+        _ => None
+    }
+}
+
+/// Read an optional string, where `null` or an empty string clear the value
+pub fn as_optional_string(value: &Value) -> Result<Option<String>, ()> {
+    match value {
+        Value::Null => Ok(None),
+        Value::String(value) if value.is_empty() => Ok(None),
+        Value::String(value) => Ok(Some(value.clone())),
+        _ => Err(())
+    }
+}
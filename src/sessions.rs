@@ -0,0 +1,502 @@
+use crate::appdata::{Database, Environment};
+use crate::{client, crypto, devices, ipfilter, users};
+use crate::users::AccountState;
+
+use actix_web::{HttpRequest, HttpResponse};
+use mysql::prelude::Queryable;
+use mysql::{PooledConn, Row, Params, params};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// How long a session is valid for, in days
+const SESSION_VALIDITY_DAYS: i64 = 30;
+
+pub const REAUTHENTICATION_REQUIRED: &str = "Confirm your password to continue.";
+
+pub const PASSWORD_RESET_REQUIRED: &str = "Your password has to be changed with the link mailed to you before your account can be used.";
+
+/// How old the timestamp of a proof of possession of a session's key may be, in seconds
+const PROOF_MAX_AGE_SECONDS: i64 = 60;
+
+/// How a session's user proved who they are
+#[derive(Serialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMethod {
+    Password
+}
+
+impl AuthMethod {
+    fn name(&self) -> &'static str {
+        match self {
+            AuthMethod::Password => "password"
+        }
+    }
+
+    fn from_name(name: &str) -> Option<AuthMethod> {
+        match name {
+            "password" => Some(AuthMethod::Password),
+            _ => None
+        }
+    }
+}
+
+/// Sensitive operations can require that the session's user authenticated recently, rather than when the session was created
+#[derive(Deserialize, Serialize, Clone)]
+#[serde(default)]
+pub struct StepUpConfig {
+    pub enabled:        bool,
+    /// How long after authenticating a session may perform sensitive operations
+    pub window_seconds: i64
+}
+
+impl Default for StepUpConfig {
+    fn default() -> Self {
+        StepUpConfig {
+            enabled:        true,
+            window_seconds: 10 * 60
+        }
+    }
+}
+
+impl StepUpConfig {
+    pub fn from_vars() -> StepUpConfig {
+        let default = StepUpConfig::default();
+
+        StepUpConfig {
+            enabled:        Environment::optional_var("STEP_UP_ENABLED", "TRUE".to_string()) != "FALSE",
+            window_seconds: Environment::optional_var("STEP_UP_WINDOW_SECONDS", default.window_seconds)
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyBinding {
+    /// Sessions are never bound to a key
+    Disabled,
+    /// Sessions are bound to a key if the client sends one when logging in
+    #[default]
+    Optional,
+    /// Logging in requires a key
+    Required
+}
+
+impl std::str::FromStr for KeyBinding {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "disabled" => Ok(KeyBinding::Disabled),
+            "optional" => Ok(KeyBinding::Optional),
+            "required" => Ok(KeyBinding::Required),
+            _ => Err(())
+        }
+    }
+}
+
+/// What new sessions are bound to. A session used from elsewhere is revoked, as its ID has probably been stolen
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct SessionBindingConfig {
+    /// Bind sessions to the network they were created from, see `devices::ip_prefix`
+    pub ip_prefix:  bool,
+    /// Bind sessions to the browser and operating system they were created from, see `devices::user_agent_family`
+    pub user_agent: bool,
+    /// Bind sessions to a key held by the client, which every request has to prove it has
+    pub key:        KeyBinding
+}
+
+impl SessionBindingConfig {
+    pub fn from_vars() -> SessionBindingConfig {
+        SessionBindingConfig {
+            ip_prefix:  std::env::var("SESSION_BINDING_IP_PREFIX").map(|enabled| enabled == "TRUE").unwrap_or(false),
+            user_agent: std::env::var("SESSION_BINDING_USER_AGENT").map(|enabled| enabled == "TRUE").unwrap_or(false),
+            key:        Environment::optional_var("SESSION_BINDING_KEY", KeyBinding::default())
+        }
+    }
+}
+
+/// What a session is bound to
+#[derive(Default)]
+pub struct SessionBinding {
+    pub ip_prefix:  Option<String>,
+    pub user_agent: Option<String>,
+    /// Base64 encoded DER public key, see `crypto::parse_public_key`
+    pub key:        Option<String>
+}
+
+impl SessionBinding {
+    /// The binding of a session created by the request, with the key the client sent, if any.
+    /// Returns the message for the client if the key is invalid or missing while required
+    pub fn from_request(config: &SessionBindingConfig, req: &HttpRequest, key: Option<&str>) -> Result<SessionBinding, &'static str> {
+        let key = match (config.key, key) {
+            (KeyBinding::Disabled, _) => None,
+            (KeyBinding::Required, None) => return Err("A session binding key is required."),
+            (_, Some(key)) if crypto::parse_public_key(key).is_none() => return Err("Invalid session binding key. Send a base64 encoded DER P-256 or Ed25519 public key."),
+            (_, key) => key.map(str::to_string)
+        };
+
+        Ok(SessionBinding {
+            ip_prefix:  Some(client::ip(req).map(|ip| devices::ip_prefix(&ip)).unwrap_or_default()).filter(|_| config.ip_prefix),
+            user_agent: Some(devices::user_agent_family(client::user_agent(req).as_deref().unwrap_or_default())).filter(|_| config.user_agent),
+            key
+        })
+    }
+
+    /// Which part of the binding the request doesn't match, if any.
+    /// The key is proven with a `Session-Proof: <timestamp>.<nonce>.<base64 signature>` header, signing
+    /// `<method>\n<path>\n<timestamp>\n<nonce>\n<session ID>` with the session's key. The nonce is any string of up to
+    /// 64 letters and digits, picked by the client for every request, as each proof is only accepted once
+    fn mismatch(&self, conn: &mut PooledConn, req: &HttpRequest, session_id: &str) -> Result<Option<&'static str>, ()> {
+        if let Some(ip_prefix) = &self.ip_prefix {
+            if client::ip(req).map(|ip| devices::ip_prefix(&ip)).unwrap_or_default() != *ip_prefix {
+                return Ok(Some("ip_prefix"));
+            }
+        }
+
+        if let Some(user_agent) = &self.user_agent {
+            if devices::user_agent_family(client::user_agent(req).as_deref().unwrap_or_default()) != *user_agent {
+                return Ok(Some("user_agent"));
+            }
+        }
+
+        if let Some(key) = &self.key {
+            if !verify_proof(conn, req, session_id, key)? {
+                return Ok(Some("key"));
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+fn verify_proof(conn: &mut PooledConn, req: &HttpRequest, session_id: &str, key: &str) -> Result<bool, ()> {
+    let proof: Option<Vec<&str>> = req.headers().get("Session-Proof").and_then(|proof| proof.to_str().ok()).map(|proof| proof.splitn(3, '.').collect());
+    let (timestamp, nonce, signature) = match proof.as_deref() {
+        Some([timestamp, nonce, signature]) => (timestamp.parse::<i64>(), *nonce, *signature),
+        _ => return Ok(false)
+    };
+
+    //Old proofs are refused, so only recent ones have to be remembered to refuse them a second time
+    let timestamp = match timestamp {
+        Ok(timestamp) if (chrono::Utc::now().timestamp() - timestamp).abs() <= PROOF_MAX_AGE_SECONDS => timestamp,
+        _ => return Ok(false)
+    };
+
+    let key = crypto::parse_public_key(key);
+    let signature = base64::decode(signature);
+    if nonce.is_empty() || nonce.len() > 64 || !nonce.chars().all(|c| c.is_ascii_alphanumeric()) || key.is_none() || signature.is_err() {
+        return Ok(false);
+    }
+
+    let message = format!("{}\n{}\n{}\n{}\n{}", req.method(), req.path(), timestamp, nonce, session_id);
+    if !crypto::verify_signature(&key.unwrap(), message.as_bytes(), &signature.unwrap()) {
+        return Ok(false);
+    }
+
+    spend_proof(conn, &message, timestamp + PROOF_MAX_AGE_SECONDS)
+}
+
+/// Remember a proof until it is too old to be accepted anyway, so one which is intercepted can't be replayed.
+/// Proofs are kept in the database, so they can't be replayed on another server either. Returns false if it was used before
+fn spend_proof(conn: &mut PooledConn, message: &str, expiry: i64) -> Result<bool, ()> {
+    let sql_insert_proof = conn.exec_drop("INSERT INTO spent_proofs (proof, expiry) VALUES (:proof, :expiry)", params! {
+        "proof" => crypto::hash_token(message),
+        "expiry" => expiry
+    });
+
+    match sql_insert_proof {
+        Ok(_) => Ok(true),
+        Err(e) if Database::is_duplicate_entry(&e, "PRIMARY") => Ok(false),
+        Err(e) => {
+            eprintln!("An error occurred (sessions.rs): {:?}", e);
+            Err(())
+        }
+    }
+}
+
+/// A valid, unexpired session
+pub struct Session {
+    pub session_id:         String,
+    pub user_id:            String,
+    /// The organization the session is currently acting in
+    pub active_org_id:      Option<String>,
+    /// When the user last authenticated in this session, either by logging in or by re-authenticating
+    pub authenticated_at:   Option<i64>,
+    pub auth_methods:       Vec<AuthMethod>,
+    pub binding:            SessionBinding
+}
+
+impl Session {
+    /// Whether the user authenticated recently enough for a sensitive operation
+    pub fn is_fresh(&self, config: &StepUpConfig) -> bool {
+        if !config.enabled {
+            return true;
+        }
+
+        match self.authenticated_at {
+            Some(authenticated_at) => chrono::Utc::now().timestamp() < authenticated_at + config.window_seconds,
+            None => false
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct GuardResponse {
+    status:     i16,
+    message:    &'static str
+}
+
+/// The outcome of looking up a session ID
+pub enum SessionLookup {
+    Valid(Session),
+    NotFound,
+    Expired,
+    /// The session exists, but its account is not active
+    Inactive(AccountState),
+    /// The session is valid, but its user has to change their password first. It can only be used to request a link to do so
+    PasswordResetRequired(Session)
+}
+
+/// Create a new session for the user, who just authenticated with `method`. Returns the session ID and its expiry
+pub fn create(conn: &mut PooledConn, user_id: &str, method: AuthMethod, binding: &SessionBinding) -> Result<(String, i64), ()> {
+    let session_id: String = rand::thread_rng().sample_iter(&rand::distributions::Alphanumeric).take(64).map(char::from).collect();
+    let now = chrono::Utc::now();
+    let expiry = (now + chrono::Duration::days(SESSION_VALIDITY_DAYS)).timestamp();
+
+    let sql_insert_session = conn.exec_drop("INSERT INTO sessions (session_id, user_id, expiry, authenticated_at, auth_methods, bound_ip_prefix, bound_user_agent, bound_key) VALUES (:session_id, :user_id, :expiry, :authenticated_at, :auth_methods, :bound_ip_prefix, :bound_user_agent, :bound_key)", params! {
+        "session_id" => session_id.clone(),
+        "user_id" => user_id,
+        "expiry" => expiry,
+        "authenticated_at" => now.timestamp(),
+        "auth_methods" => method.name(),
+        "bound_ip_prefix" => &binding.ip_prefix,
+        "bound_user_agent" => &binding.user_agent,
+        "bound_key" => &binding.key
+    });
+
+    if sql_insert_session.is_err() {
+        eprintln!("An error occurred (sessions.rs): {:?}", sql_insert_session.err().unwrap());
+        return Err(());
+    }
+
+    Ok((session_id, expiry))
+}
+
+/// Record that the session's user authenticated again with `method`, which makes the session fresh for sensitive operations.
+/// Returns when the session stops being fresh
+pub fn reauthenticate(conn: &mut PooledConn, environment: &Environment, session: &Session, method: AuthMethod) -> Result<i64, ()> {
+    let mut auth_methods = session.auth_methods.clone();
+    if !auth_methods.contains(&method) {
+        auth_methods.push(method);
+    }
+
+    let now = chrono::Utc::now().timestamp();
+    let sql_update_session = conn.exec_drop("UPDATE sessions SET authenticated_at = :authenticated_at, auth_methods = :auth_methods WHERE session_id = :session_id", params! {
+        "authenticated_at" => now,
+        "auth_methods" => auth_methods.iter().map(|method| method.name()).collect::<Vec<_>>().join(","),
+        "session_id" => &session.session_id
+    });
+
+    if sql_update_session.is_err() {
+        eprintln!("An error occurred (sessions.rs): {:?}", sql_update_session.err().unwrap());
+        return Err(());
+    }
+
+    Ok(now + environment.step_up.window_seconds)
+}
+
+/// Guard for sensitive operations, which require the session's user to have authenticated recently.
+/// Returns the response which should be returned to the client if they haven't
+pub fn require_fresh(environment: &Environment, session: &Session) -> Result<(), HttpResponse> {
+    if session.is_fresh(&environment.step_up) {
+        return Ok(());
+    }
+
+    Err(deny(403, REAUTHENTICATION_REQUIRED))
+}
+
+/// Revoke all sessions of a user. Returns the amount of sessions revoked
+pub fn revoke_all(conn: &mut PooledConn, user_id: &str) -> Result<u64, ()> {
+    let sql_delete_sessions = conn.exec_drop("DELETE FROM sessions WHERE user_id = :user_id", params! {
+        "user_id" => user_id
+    });
+
+    if sql_delete_sessions.is_err() {
+        eprintln!("An error occurred (sessions.rs): {:?}", sql_delete_sessions.err().unwrap());
+        return Err(());
+    }
+
+    Ok(conn.affected_rows())
+}
+
+/// Revoke all sessions of a user except one, e.g. the session which changed the password. Returns the amount of sessions revoked
+pub fn revoke_others(conn: &mut PooledConn, user_id: &str, session_id: &str) -> Result<u64, ()> {
+    let sql_delete_sessions = conn.exec_drop("DELETE FROM sessions WHERE user_id = :user_id AND session_id != :session_id", params! {
+        "user_id" => user_id,
+        "session_id" => session_id
+    });
+
+    if sql_delete_sessions.is_err() {
+        eprintln!("An error occurred (sessions.rs): {:?}", sql_delete_sessions.err().unwrap());
+        return Err(());
+    }
+
+    Ok(conn.affected_rows())
+}
+
+/// Look up a session. Expired sessions are deleted right away, instead of waiting for the reaper
+pub fn lookup(conn: &mut PooledConn, session_id: &str) -> Result<SessionLookup, ()> {
+    let sql_fetch_session = conn.exec::<Row, &str, Params>("SELECT sessions.user_id, sessions.expiry, sessions.active_org_id, sessions.authenticated_at, sessions.auth_methods, sessions.bound_ip_prefix, sessions.bound_user_agent, sessions.bound_key, users.status, users.status_until, users.status_reason, users.password_reset_required FROM sessions INNER JOIN users ON users.user_id = sessions.user_id WHERE sessions.session_id = :session_id", params! {
+        "session_id" => session_id
+    });
+
+    if sql_fetch_session.is_err() {
+        eprintln!("An error occurred (sessions.rs): {:?}", sql_fetch_session.err().unwrap());
+        return Err(());
+    }
+
+    let sql_fetch_session = sql_fetch_session.unwrap();
+    let row = match sql_fetch_session.first() {
+        Some(row) => row,
+        None => return Ok(SessionLookup::NotFound)
+    };
+
+    let user_id = row.get::<String, &str>("user_id").unwrap();
+    let expiry = row.get::<i64, &str>("expiry").unwrap();
+    let active_org_id = row.get::<Option<String>, &str>("active_org_id").unwrap();
+    let authenticated_at = row.get::<Option<i64>, &str>("authenticated_at").unwrap();
+    let auth_methods = row.get::<String, &str>("auth_methods").unwrap().split(',').filter_map(AuthMethod::from_name).collect();
+    let binding = SessionBinding {
+        ip_prefix:  row.get::<Option<String>, &str>("bound_ip_prefix").unwrap(),
+        user_agent: row.get::<Option<String>, &str>("bound_user_agent").unwrap(),
+        key:        row.get::<Option<String>, &str>("bound_key").unwrap()
+    };
+    let account_state = AccountState::from_row(row);
+    let password_reset_required = row.get::<bool, &str>("password_reset_required").unwrap();
+
+    if chrono::Utc::now().timestamp() >= expiry {
+        let sql_delete_session = conn.exec_drop("DELETE FROM sessions WHERE session_id = :session_id", params! {
+            "session_id" => session_id
+        });
+
+        if sql_delete_session.is_err() {
+            eprintln!("An error occurred (sessions.rs): {:?}", sql_delete_session.err().unwrap());
+        }
+
+        return Ok(SessionLookup::Expired);
+    }
+
+    //Sessions of disabled and banned accounts are revoked, but locked accounts keep theirs until the lock runs out
+    if account_state != AccountState::Active {
+        return Ok(SessionLookup::Inactive(account_state));
+    }
+
+    let session = Session {
+        session_id: session_id.to_string(),
+        user_id,
+        active_org_id,
+        authenticated_at,
+        auth_methods,
+        binding
+    };
+
+    if password_reset_required {
+        return Ok(SessionLookup::PasswordResetRequired(session));
+    }
+
+    Ok(SessionLookup::Valid(session))
+}
+
+/// Check that the request matches what the session is bound to. A mismatching session is revoked and recorded
+/// as a security event, as its ID has probably been stolen. Returns whether the session may be used
+pub fn enforce_binding(conn: &mut PooledConn, session: &Session, req: &HttpRequest) -> Result<bool, ()> {
+    let mismatch = match session.binding.mismatch(conn, req, &session.session_id)? {
+        Some(mismatch) => mismatch,
+        None => return Ok(true)
+    };
+
+    let sql_delete_session = conn.exec_drop("DELETE FROM sessions WHERE session_id = :session_id", params! {
+        "session_id" => &session.session_id
+    });
+
+    if sql_delete_session.is_err() {
+        eprintln!("An error occurred (sessions.rs): {:?}", sql_delete_session.err().unwrap());
+        return Err(());
+    }
+
+    let details = format!("session {}... revoked, {} did not match", session.session_id.chars().take(8).collect::<String>(), mismatch);
+    users::record_security_event(conn, &session.user_id, req, "session_binding_mismatch", Some(&details))?;
+
+    Ok(false)
+}
+
+/// Get the session ID from the request's `Authorization: Bearer <session_id>` header
+pub fn bearer_session_id(req: &HttpRequest) -> Option<String> {
+    let header = req.headers().get("Authorization")?.to_str().ok()?;
+    let session_id = header.strip_prefix("Bearer ")?.trim();
+
+    if session_id.is_empty() {
+        return None;
+    }
+
+    Some(session_id.to_string())
+}
+
+
+/// Guard for endpoints which require a session, taken from the request's `Authorization` header.
+/// Returns the session if it is valid, otherwise the response which should be returned to the client
+pub fn require_session(conn: &mut PooledConn, req: &HttpRequest) -> Result<Session, HttpResponse> {
+    let session_id = match bearer_session_id(req) {
+        Some(session_id) => session_id,
+        None => return Err(deny(401, "Invalid or expired session."))
+    };
+
+    check_session(conn, req, &session_id, false)
+}
+
+/// Guard for endpoints which take the session ID in their form, rather than in the `Authorization` header.
+/// Returns the session if it is valid, the request matches its binding and the IP filter rules of its user accept the
+/// request's address, otherwise the response which should be returned to the client
+pub fn require_form_session(conn: &mut PooledConn, req: &HttpRequest, session_id: &str) -> Result<Session, HttpResponse> {
+    check_session(conn, req, session_id, false)
+}
+
+/// `require_form_session` for requesting a new password reset link, the only thing a session can be used for while its
+/// user has to change their password. Other sessions are refused, their users can change their password themselves
+pub fn require_session_for_password_reset(conn: &mut PooledConn, req: &HttpRequest, session_id: &str) -> Result<Session, HttpResponse> {
+    check_session(conn, req, session_id, true)
+}
+
+fn check_session(conn: &mut PooledConn, req: &HttpRequest, session_id: &str, password_reset: bool) -> Result<Session, HttpResponse> {
+    let session = match lookup(conn, session_id) {
+        Ok(SessionLookup::Valid(_)) if password_reset => return Err(deny(400, "Your password doesn't have to be reset.")),
+        Ok(SessionLookup::Valid(session)) => session,
+        Ok(SessionLookup::PasswordResetRequired(session)) if password_reset => session,
+        Ok(SessionLookup::PasswordResetRequired(_)) => return Err(deny(403, PASSWORD_RESET_REQUIRED)),
+        Ok(SessionLookup::Inactive(account_state)) => {
+            let (status, message) = account_state.denial();
+            return Err(deny(status, message));
+        },
+        Ok(_) => return Err(deny(401, "Invalid or expired session.")),
+        Err(_) => return Err(HttpResponse::InternalServerError().finish())
+    };
+
+    match enforce_binding(conn, &session, req) {
+        Ok(true) => {},
+        Ok(false) => return Err(deny(401, "Invalid or expired session.")),
+        Err(_) => return Err(HttpResponse::InternalServerError().finish())
+    }
+
+    //Roles and organizations can be restricted to networks, e.g. administrators to the corporate network
+    match ipfilter::permits_user(conn, req, &session.user_id) {
+        Ok(true) => Ok(session),
+        Ok(false) => Err(deny(403, "Requests from this network are not allowed.")),
+        Err(_) => Err(HttpResponse::InternalServerError().finish())
+    }
+}
+
+/// The response returned by guards when a request is not allowed
+pub fn deny(status: i16, message: &'static str) -> HttpResponse {
+    HttpResponse::Ok().json(&GuardResponse { status, message })
+}
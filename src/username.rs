@@ -0,0 +1,41 @@
+use regex::Regex;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+/// Whether a username has to be chosen at registration
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum UsernamePolicy {
+    Required,
+    #[default]
+    Optional,
+    Disabled
+}
+
+impl std::str::FromStr for UsernamePolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "required" => Ok(UsernamePolicy::Required),
+            "optional" => Ok(UsernamePolicy::Optional),
+            "disabled" => Ok(UsernamePolicy::Disabled),
+            _ => Err(())
+        }
+    }
+}
+
+/// Normalize a username to the form it is stored and looked up in. Usernames are case-insensitive
+pub fn normalize(username: &str) -> String {
+    username.trim().to_lowercase()
+}
+
+/// Check if a normalized username is valid: 3 to 32 characters, consisting of letters, digits, '_', '.' and '-'.
+/// Usernames can never contain an '@', so they can't be confused with email addresses when logging in
+pub fn is_valid(username: &str) -> bool {
+    lazy_static! {
+        static ref USERNAME_REGEX: Regex = Regex::new(r"^[a-z0-9_.\-]{3,32}$").unwrap();
+    }
+
+    USERNAME_REGEX.is_match(username)
+}
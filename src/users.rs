@@ -0,0 +1,449 @@
+use crate::{client, crypto, email, mail, orgs, password, password_policy, sessions, username};
+use crate::appdata::{Database, Environment};
+use crate::crypto::EncryptionConfig;
+use crate::password::ForeignAlgorithm;
+
+use actix_web::HttpRequest;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use mysql::prelude::Queryable;
+use mysql::{PooledConn, Row, TxOpts, params};
+
+/// Tables holding rows which belong to a user, keyed by a `user_id` column.
+/// All of these are removed when a user is deleted. The `users` table itself must come last
+const USER_TABLES: &[&str] = &["sessions", "login_history", "email_changes", "user_roles", "org_memberships", "unlock_tokens", "password_history", "known_devices", "login_reports", "password_resets", "security_events", "users"];
+
+/// How long a link for choosing a new password can be used, in hours
+const PASSWORD_RESET_VALIDITY_HOURS: i64 = 24;
+
+/// The state of an account. Only active accounts can log in and use their sessions
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(tag = "state", rename_all = "lowercase")]
+pub enum AccountState {
+    Active,
+    /// Disabled by an administrator until they enable it again
+    Disabled,
+    /// Locked until the given time, after which the account is active again
+    Locked { until: i64 },
+    Banned { reason: Option<String> }
+}
+
+impl AccountState {
+    /// The state stored in the `status`, `status_until` and `status_reason` columns of a row.
+    /// Locks which have run out are active
+    pub fn from_row(row: &Row) -> AccountState {
+        let status = row.get::<String, &str>("status").unwrap();
+        let until = row.get::<Option<i64>, &str>("status_until").unwrap();
+        let reason = row.get::<Option<String>, &str>("status_reason").unwrap();
+
+        match status.as_str() {
+            "disabled" => AccountState::Disabled,
+            "locked" => match until {
+                Some(until) if until > chrono::Utc::now().timestamp() => AccountState::Locked { until },
+                _ => AccountState::Active
+            },
+            "banned" => AccountState::Banned { reason },
+            _ => AccountState::Active
+        }
+    }
+
+    /// The value stored in the `status` column
+    pub fn name(&self) -> &'static str {
+        match self {
+            AccountState::Active => "active",
+            AccountState::Disabled => "disabled",
+            AccountState::Locked { .. } => "locked",
+            AccountState::Banned { .. } => "banned"
+        }
+    }
+
+    /// The status and message returned to a client whose account is not active
+    pub fn denial(&self) -> (i16, &'static str) {
+        match self {
+            AccountState::Active => (200, "This account is active."),
+            AccountState::Disabled => (403, "This account has been disabled."),
+            AccountState::Locked { .. } => (423, "This account is locked."),
+            AccountState::Banned { .. } => (403, "This account has been banned.")
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Default)]
+#[serde(default)]
+pub struct RegistrationConfig {
+    /// Answer every registration the same way, whether the email address is already registered or not.
+    /// The owner of an existing account is mailed instead, and new accounts have to log in after registering
+    pub enumeration_safe:   bool
+}
+
+impl RegistrationConfig {
+    pub fn from_vars() -> RegistrationConfig {
+        RegistrationConfig {
+            enumeration_safe: std::env::var("REGISTRATION_ENUMERATION_SAFE").map(|enabled| enabled == "TRUE").unwrap_or(false)
+        }
+    }
+}
+
+/// Why a user could not be created
+pub enum CreateError {
+    InvalidEmail,
+    EmailTaken,
+    UsernameTaken,
+    Failed
+}
+
+/// Create a user. `username` must already be normalized and valid. Returns the ID of the new user
+pub fn create(conn: &mut PooledConn, environment: &Environment, email: &str, username: Option<&str>, new_password: &str) -> Result<String, CreateError> {
+    let salt = password::generate_salt();
+    let password_hash = password::hash(new_password, &salt, &environment.password_pepper);
+
+    create_with_hash(conn, environment, email, username, &password_hash, &salt, None)
+}
+
+/// Create a user with an already hashed password. `algorithm` is `None` for hashes produced by `password::hash`,
+/// or the algorithm of a hash imported from another system. Returns the ID of the new user
+pub fn create_with_hash(conn: &mut PooledConn, environment: &Environment, email: &str, username: Option<&str>, password_hash: &str, salt: &str, algorithm: Option<ForeignAlgorithm>) -> Result<String, CreateError> {
+    let email = email.trim();
+    let email_normalized = match email::parse(email, &environment.email_normalization) {
+        Some(email_normalized) => email_normalized,
+        None => return Err(CreateError::InvalidEmail)
+    };
+
+    let email_encrypted = environment.email_encryption.encrypt(email);
+    if email_encrypted.is_err() {
+        eprintln!("Unable to encrypt email address (users.rs)");
+        return Err(CreateError::Failed);
+    }
+
+    let user_id: String = rand::thread_rng().sample_iter(&rand::distributions::Alphanumeric).take(64).map(char::from).collect();
+
+    let sql_insert_user = conn.exec_drop("INSERT INTO users (user_id, email, email_index, username, password, salt, password_algorithm) VALUES (:user_id, :email, :email_index, :username, :password, :salt, :password_algorithm)", params! {
+        "user_id" => user_id.clone(),
+        "email" => email_encrypted.unwrap(),
+        "email_index" => environment.email_encryption.blind_index(&email_normalized),
+        "username" => username,
+        "password" => password_hash,
+        "salt" => salt,
+        "password_algorithm" => algorithm.map(|algorithm| algorithm.as_str())
+    });
+
+    if let Err(e) = sql_insert_user {
+        if Database::is_duplicate_entry(&e, "users_email_index") {
+            return Err(CreateError::EmailTaken);
+        }
+
+        if Database::is_duplicate_entry(&e, "users_username") {
+            return Err(CreateError::UsernameTaken);
+        }
+
+        eprintln!("An error occurred (users.rs): {:?}", e);
+        return Err(CreateError::Failed);
+    }
+
+    Ok(user_id)
+}
+
+/// Find a user by email address, username or user ID. Returns the user's ID
+pub fn find(conn: &mut PooledConn, environment: &Environment, identifier: &str) -> Result<Option<String>, ()> {
+    let identifier = identifier.trim();
+
+    //Usernames can't contain an '@', so anything with one is an email address
+    let sql_find_user = if identifier.contains('@') {
+        conn.exec_first::<String, &str, _>("SELECT user_id FROM users WHERE email_index = :email_index", params! {
+            "email_index" => environment.email_encryption.blind_index(&email::index_form(identifier, &environment.email_normalization))
+        })
+    } else {
+        conn.exec_first::<String, &str, _>("SELECT user_id FROM users WHERE user_id = :user_id OR username = :username LIMIT 1", params! {
+            "user_id" => identifier,
+            "username" => username::normalize(identifier)
+        })
+    };
+
+    if sql_find_user.is_err() {
+        eprintln!("An error occurred (users.rs): {:?}", sql_find_user.err().unwrap());
+        return Err(());
+    }
+
+    Ok(sql_find_user.unwrap())
+}
+
+/// Fetch and decrypt the email address of a user
+pub fn fetch_email(conn: &mut PooledConn, encryption: &EncryptionConfig, user_id: &str) -> Result<String, ()> {
+    let sql_fetch_email = conn.exec_first::<String, &str, _>("SELECT email FROM users WHERE user_id = :user_id", params! {
+        "user_id" => user_id
+    });
+
+    let email_encrypted = match sql_fetch_email {
+        Ok(Some(email)) => email,
+        Ok(None) => {
+            eprintln!("User '{}' does not exist (users.rs)", user_id);
+            return Err(());
+        },
+        Err(e) => {
+            eprintln!("An error occurred (users.rs): {:?}", e);
+            return Err(());
+        }
+    };
+
+    let email = encryption.decrypt(&email_encrypted);
+    if email.is_err() {
+        eprintln!("Unable to decrypt the email address of user '{}' (users.rs)", user_id);
+    }
+
+    email
+}
+
+/// Check whether a user exists
+pub fn exists(conn: &mut PooledConn, user_id: &str) -> Result<bool, ()> {
+    let sql_check_user = conn.exec_first::<u8, &str, _>("SELECT 1 FROM users WHERE user_id = :user_id", params! {
+        "user_id" => user_id
+    });
+
+    if sql_check_user.is_err() {
+        eprintln!("An error occurred (users.rs): {:?}", sql_check_user.err().unwrap());
+        return Err(());
+    }
+
+    Ok(sql_check_user.unwrap().is_some())
+}
+
+/// Replace the password of a user, with a fresh salt. This also replaces imported hashes with a native one.
+/// The old password is kept in the password history, and links for resetting it can no longer be used
+pub fn set_password(conn: &mut PooledConn, user_id: &str, new_password: &str, environment: &Environment) -> Result<(), ()> {
+    password_policy::remember_password(conn, environment, user_id)?;
+
+    let sql_delete_resets = conn.exec_drop("DELETE FROM password_resets WHERE user_id = :user_id", params! {
+        "user_id" => user_id
+    });
+
+    if sql_delete_resets.is_err() {
+        eprintln!("An error occurred (users.rs): {:?}", sql_delete_resets.err().unwrap());
+        return Err(());
+    }
+
+    let salt = password::generate_salt();
+    let sql_update_password = conn.exec_drop("UPDATE users SET password = :password, salt = :salt, password_algorithm = NULL, password_reset_required = FALSE WHERE user_id = :user_id", params! {
+        "password" => password::hash(new_password, &salt, &environment.password_pepper),
+        "salt" => salt,
+        "user_id" => user_id
+    });
+
+    if sql_update_password.is_err() {
+        eprintln!("An error occurred (users.rs): {:?}", sql_update_password.err().unwrap());
+        return Err(());
+    }
+
+    Ok(())
+}
+
+/// Replace an imported hash with a native one, once the password is known from a login. Unlike `set_password`
+/// the password doesn't change, so it isn't added to the history and a required reset stays required
+pub fn rehash_password(conn: &mut PooledConn, user_id: &str, password: &str, environment: &Environment) -> Result<(), ()> {
+    let (password_hash, salt) = password::hash_with_new_salt(password, &environment.password_pepper);
+    let sql_update_password = conn.exec_drop("UPDATE users SET password = :password, salt = :salt, password_algorithm = NULL WHERE user_id = :user_id", params! {
+        "password" => password_hash,
+        "salt" => salt,
+        "user_id" => user_id
+    });
+
+    if sql_update_password.is_err() {
+        eprintln!("An error occurred (users.rs): {:?}", sql_update_password.err().unwrap());
+        return Err(());
+    }
+
+    Ok(())
+}
+
+/// Require a user to change their password, e.g. because it appeared in a data breach. Whoever else knows the password
+/// could change it too, so the new one can only be set with a link mailed to the account's address
+pub fn require_password_reset(conn: &mut PooledConn, environment: &Environment, user_id: &str) -> Result<(), ()> {
+    let sql_update_user = conn.exec_drop("UPDATE users SET password_reset_required = TRUE WHERE user_id = :user_id", params! {
+        "user_id" => user_id
+    });
+
+    if sql_update_user.is_err() {
+        eprintln!("An error occurred (users.rs): {:?}", sql_update_user.err().unwrap());
+        return Err(());
+    }
+
+    send_password_reset_link(conn, environment, user_id)
+}
+
+/// Mail a single-use link for choosing a new password to the account's address
+pub fn send_password_reset_link(conn: &mut PooledConn, environment: &Environment, user_id: &str) -> Result<(), ()> {
+    let token = crypto::random_token();
+    let sql_insert_token = conn.exec_drop("INSERT INTO password_resets (token, user_id, expiry) VALUES (:token, :user_id, :expiry)", params! {
+        "token" => crypto::hash_token(&token),
+        "user_id" => user_id,
+        "expiry" => chrono::Utc::now().timestamp() + PASSWORD_RESET_VALIDITY_HOURS * 3600
+    });
+
+    if sql_insert_token.is_err() {
+        eprintln!("An error occurred (users.rs): {:?}", sql_insert_token.err().unwrap());
+        return Err(());
+    }
+
+    let email = fetch_email(conn, &environment.email_encryption, user_id)?;
+    let mail_config = &environment.mail;
+    mail::send(mail_config, &email, "Choose a new password", &format!(
+        "Your password has to be changed before your account can be used again.\n\nOpen this link within {} hours to choose a new password. It can only be used once:\n{}\n\nOnce it has expired, you can request a new link after logging in.",
+        PASSWORD_RESET_VALIDITY_HOURS,
+        mail_config.link(&format!("/auth/password/reset?token={}", token))
+    ));
+
+    Ok(())
+}
+
+/// The user a password reset link belongs to. Returns `None` if the token is unknown or expired
+pub fn password_reset_user(conn: &mut PooledConn, token: &str) -> Result<Option<String>, ()> {
+    let sql_fetch_token = conn.exec_first::<String, &str, _>("SELECT user_id FROM password_resets WHERE token = :token AND expiry > :now", params! {
+        "token" => crypto::hash_token(token),
+        "now" => chrono::Utc::now().timestamp()
+    });
+
+    sql_fetch_token.map_err(|e| eprintln!("An error occurred (users.rs): {:?}", e))
+}
+
+/// Set a new password with a password reset link, using the link up. Returns false if the link has been used or has
+/// expired, e.g. because it was used at the same time
+pub fn reset_password_with_token(conn: &mut PooledConn, token: &str, user_id: &str, new_password: &str, environment: &Environment) -> Result<bool, ()> {
+    //Deleting the token first means only one of two concurrent uses of a link gets to set the password
+    let sql_delete_token = conn.exec_drop("DELETE FROM password_resets WHERE token = :token AND user_id = :user_id AND expiry > :now", params! {
+        "token" => crypto::hash_token(token),
+        "user_id" => user_id,
+        "now" => chrono::Utc::now().timestamp()
+    });
+
+    if sql_delete_token.is_err() {
+        eprintln!("An error occurred (users.rs): {:?}", sql_delete_token.err().unwrap());
+        return Err(());
+    }
+
+    if conn.affected_rows() == 0 {
+        return Ok(false);
+    }
+
+    set_password(conn, user_id, new_password, environment)?;
+    Ok(true)
+}
+
+/// Set the state of a user. Disabling or banning a user revokes their sessions right away
+pub fn set_state(conn: &mut PooledConn, user_id: &str, state: &AccountState) -> Result<(), ()> {
+    let (until, reason) = match state {
+        AccountState::Locked { until } => (Some(*until), None),
+        AccountState::Banned { reason } => (None, reason.clone()),
+        _ => (None, None)
+    };
+
+    let sql_update_state = conn.exec_drop("UPDATE users SET status = :status, status_until = :status_until, status_reason = :status_reason WHERE user_id = :user_id", params! {
+        "status" => state.name(),
+        "status_until" => until,
+        "status_reason" => reason,
+        "user_id" => user_id
+    });
+
+    if sql_update_state.is_err() {
+        eprintln!("An error occurred (users.rs): {:?}", sql_update_state.err().unwrap());
+        return Err(());
+    }
+
+    if matches!(state, AccountState::Disabled | AccountState::Banned { .. }) {
+        sessions::revoke_all(conn, user_id)?;
+    }
+
+    Ok(())
+}
+
+/// Permanently delete a user and everything stored about them. Returns false, deleting nothing, if the user is the only
+/// owner of an organization, as that would leave the organization without anyone to manage it
+pub fn delete(conn: &mut PooledConn, user_id: &str) -> Result<bool, ()> {
+    let tx = conn.start_transaction(TxOpts::default());
+    if tx.is_err() {
+        eprintln!("An error occurred (users.rs): {:?}", tx.err().unwrap());
+        return Err(());
+    }
+    let mut tx = tx.unwrap();
+
+    if !orgs::sole_owned(&mut tx, user_id)?.is_empty() {
+        return Ok(false);
+    }
+
+    for table in USER_TABLES {
+        let sql_delete = tx.exec_drop(format!("DELETE FROM {} WHERE user_id = :user_id", table), params! {
+            "user_id" => user_id
+        });
+
+        if sql_delete.is_err() {
+            eprintln!("An error occurred deleting from '{}' (users.rs): {:?}", table, sql_delete.err().unwrap());
+            return Err(());
+        }
+    }
+
+    let commit = tx.commit();
+    if commit.is_err() {
+        eprintln!("An error occurred (users.rs): {:?}", commit.err().unwrap());
+        return Err(());
+    }
+
+    Ok(true)
+}
+
+/// Permanently delete at most `batch_size` users who were scheduled for deletion before `cutoff`.
+/// Users who are the only owner of an organization are kept until it has another owner. Returns the amount of users deleted
+pub fn purge_deleted(conn: &mut PooledConn, cutoff: i64, batch_size: u64) -> Result<u64, ()> {
+    let sql_fetch_users = conn.exec::<String, String, _>(format!("SELECT user_id FROM users WHERE deleted_at < :cutoff AND user_id NOT IN (SELECT user_id FROM org_memberships WHERE {}) LIMIT {}", orgs::SOLE_OWNER_CONDITION, batch_size), params! {
+        "cutoff" => cutoff
+    });
+
+    if sql_fetch_users.is_err() {
+        eprintln!("An error occurred (users.rs): {:?}", sql_fetch_users.err().unwrap());
+        return Err(());
+    }
+
+    let mut deleted = 0;
+    for user_id in sql_fetch_users.unwrap().iter() {
+        if delete(conn, user_id)? {
+            deleted += 1;
+        }
+    }
+
+    Ok(deleted)
+}
+
+/// Record a login attempt for a known user in their login history
+pub fn record_login(conn: &mut PooledConn, user_id: &str, req: &HttpRequest, success: bool) -> Result<(), ()> {
+    let sql_insert_login = conn.exec_drop("INSERT INTO login_history (user_id, timestamp, ip, user_agent, success) VALUES (:user_id, :timestamp, :ip, :user_agent, :success)", params! {
+        "user_id" => user_id,
+        "timestamp" => chrono::Utc::now().timestamp(),
+        "ip" => client::ip(req).map(|ip| ip.to_string()),
+        "user_agent" => client::user_agent(req),
+        "success" => success
+    });
+
+    if sql_insert_login.is_err() {
+        eprintln!("An error occurred (users.rs): {:?}", sql_insert_login.err().unwrap());
+        return Err(());
+    }
+
+    Ok(())
+}
+
+/// Record something which may indicate the account is under attack, e.g. a session used from somewhere it isn't bound to
+pub fn record_security_event(conn: &mut PooledConn, user_id: &str, req: &HttpRequest, event: &str, details: Option<&str>) -> Result<(), ()> {
+    let sql_insert_event = conn.exec_drop("INSERT INTO security_events (user_id, timestamp, event, details, ip, user_agent) VALUES (:user_id, :timestamp, :event, :details, :ip, :user_agent)", params! {
+        "user_id" => user_id,
+        "timestamp" => chrono::Utc::now().timestamp(),
+        "event" => event,
+        "details" => details,
+        "ip" => client::ip(req).map(|ip| ip.to_string()),
+        "user_agent" => client::user_agent(req)
+    });
+
+    if sql_insert_event.is_err() {
+        eprintln!("An error occurred (users.rs): {:?}", sql_insert_event.err().unwrap());
+        return Err(());
+    }
+
+    println!("Security event '{}' for account '{}'{}", event, user_id, details.map(|details| format!(": {}", details)).unwrap_or_default());
+    Ok(())
+}